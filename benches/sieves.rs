@@ -114,8 +114,7 @@ fn create_sieves_cpu() -> cpu::Cpu<peripheral::Bus> {
         0x0100,
     ]
     .into_iter()
-    .map(|x| u32::to_le_bytes(x))
-    .flatten()
+    .flat_map(u32::to_le_bytes)
     .collect();
 
     cpu.store_at(0, bin).unwrap();
@@ -127,11 +126,10 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("sieves", |b| {
         let mut cpu = create_sieves_cpu();
         b.iter(|| {
-            black_box({
-                for _ in 0..2800 {
-                    cpu.clock();
-                }
-            })
+            for _ in 0..2800 {
+                cpu.clock();
+            }
+            black_box(cpu.pc)
         })
     });
 }
@@ -0,0 +1,415 @@
+//! dtekv-run, runs a DTEK-V program without a frontend
+//!
+//! Made for grading and testing: the program runs on the standard peripherals until it stops by
+//! itself or hits a limit, UART output goes to stdout and the state of the hex displays and LEDs
+//! is printed to stderr at the end. Switches and the button are scripted by cycle.
+
+use std::{
+    cell::RefCell,
+    io::{self, Stdout, Write},
+    path::PathBuf,
+    process::ExitCode,
+    rc::Rc,
+};
+
+use dtekv_emulator_core::{
+    cpu::{Cpu, StopReason, CLOCK_FEQ},
+    csr::Csr,
+    elf::Elf,
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    peripheral::{Board, Bus, UartWriter},
+    semihosting::Semihosting,
+};
+
+const USAGE: &str = "\
+Usage: dtekv-run [OPTIONS] <PROGRAM>
+
+Runs a DTEK-V program, either an ELF file or a raw binary that is loaded at address 0.
+
+Options:
+  --max-instructions <N>       Stop with a timeout after N retired instructions
+  --max-cycles <N>             Stop with a timeout after N cycles, 300000000 (10 seconds) by
+                               default and no limit if N is 0
+  --switch <CYCLE>:<INDEX>=<0|1>
+                               Flip switch INDEX down (0) or up (1) at CYCLE
+  --button <CYCLE>=<0|1>       Release (0) or press (1) the button at CYCLE
+  --root <DIR>                 Let semihosting calls open files in DIR
+  -h, --help                   Print this help
+
+Exit status:
+  0    The program finished, by looping forever or waiting for input that never comes
+  N    The program exited with code N through semihosting
+  1    The program exited with 2, 124, 132 or a code above 255, which are only printed
+  2    The arguments or the program are invalid
+  124  A limit was reached
+  132  The program executed an illegal instruction
+";
+
+/// What a program exit code is reported as if it is reserved or doesn't fit in the exit status
+const EXIT_OTHER: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_TIMEOUT: u8 = 124;
+/// What a shell reports for a process killed by SIGILL
+const EXIT_ILLEGAL_INSTRUCTION: u8 = 132;
+/// The statuses of the runner itself, which a program exit code can't be mistaken for
+const RESERVED_EXIT_CODES: [u8; 3] = [EXIT_USAGE, EXIT_TIMEOUT, EXIT_ILLEGAL_INSTRUCTION];
+
+/// Ten seconds on the DTEK-V, so a program that never stops doesn't hang a grading script
+const DEFAULT_MAX_CYCLES: u64 = 10 * CLOCK_FEQ as u64;
+
+const SWITCHES: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Options {
+    program: PathBuf,
+    max_instructions: Option<u64>,
+    max_cycles: Option<u64>,
+    /// Sorted by cycle, inputs at the same cycle keep their order from the command line
+    inputs: Vec<Input>,
+    root: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Input {
+    cycle: u64,
+    action: Action,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Action {
+    Switch { index: u32, up: bool },
+    Button { pressed: bool },
+}
+
+/// Why the run ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Outcome {
+    Finished { pc: u32 },
+    Exited { code: u32 },
+    IllegalInstruction { pc: u32 },
+    Timeout,
+}
+
+impl Outcome {
+    fn exit_code(self) -> u8 {
+        match self {
+            Outcome::Finished { .. } => 0,
+            Outcome::Exited { code } => match u8::try_from(code) {
+                Ok(code) if !RESERVED_EXIT_CODES.contains(&code) => code,
+                _ => EXIT_OTHER,
+            },
+            Outcome::IllegalInstruction { .. } => EXIT_ILLEGAL_INSTRUCTION,
+            Outcome::Timeout => EXIT_TIMEOUT,
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    value.map_err(|_| format!("invalid number {}", s))
+}
+
+fn parse_level(s: &str) -> Result<bool, String> {
+    match s {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(format!("expected 0 or 1, found {}", s)),
+    }
+}
+
+/// Parses `<CYCLE>:<INDEX>=<0|1>`
+fn parse_switch(s: &str) -> Result<Input, String> {
+    let invalid = || format!("invalid switch input {}, expected <CYCLE>:<INDEX>=<0|1>", s);
+
+    let (cycle, rest) = s.split_once(':').ok_or_else(invalid)?;
+    let (index, level) = rest.split_once('=').ok_or_else(invalid)?;
+    let index = parse_number(index)?;
+    if index >= SWITCHES as u64 {
+        return Err(format!("there is no switch {}", index));
+    }
+
+    Ok(Input {
+        cycle: parse_number(cycle)?,
+        action: Action::Switch {
+            index: index as u32,
+            up: parse_level(level)?,
+        },
+    })
+}
+
+/// Parses `<CYCLE>=<0|1>`
+fn parse_button(s: &str) -> Result<Input, String> {
+    let (cycle, level) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid button input {}, expected <CYCLE>=<0|1>", s))?;
+
+    Ok(Input {
+        cycle: parse_number(cycle)?,
+        action: Action::Button {
+            pressed: parse_level(level)?,
+        },
+    })
+}
+
+/// Parses the arguments after the program name. Returns None if help was asked for.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut args = args.into_iter();
+    let mut program = None;
+    let mut options = Options {
+        program: PathBuf::new(),
+        max_instructions: None,
+        max_cycles: Some(DEFAULT_MAX_CYCLES),
+        inputs: Vec::new(),
+        root: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--max-instructions" => options.max_instructions = Some(parse_number(&value()?)?),
+            "--max-cycles" => {
+                options.max_cycles = Some(parse_number(&value()?)?).filter(|&max| max != 0)
+            }
+            "--switch" => options.inputs.push(parse_switch(&value()?)?),
+            "--button" => options.inputs.push(parse_button(&value()?)?),
+            "--root" => options.root = Some(PathBuf::from(value()?)),
+            option if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+            _ if program.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => program = Some(PathBuf::from(arg)),
+        }
+    }
+
+    options.program = program.ok_or("no program given")?;
+    options.inputs.sort_by_key(|input| input.cycle);
+    Ok(Some(options))
+}
+
+/// Loads an ELF, or a raw binary at address 0, and sets pc to where it starts
+fn load(cpu: &mut Cpu<Bus>, bytes: &[u8]) -> Result<(), String> {
+    if bytes.starts_with(b"\x7fELF") {
+        let elf = Elf::parse(bytes).map_err(|err| err.to_string())?;
+        return elf.load(cpu).map_err(|err| err.to_string());
+    }
+
+    cpu.store_at(0, bytes.iter().copied())
+        .map_err(|_| "the program doesn't fit in memory".to_string())?;
+    cpu.pc = 0;
+    Ok(())
+}
+
+fn apply(board: &Board, action: Action) {
+    match action {
+        Action::Switch { index, up } => board.switch.borrow_mut().set(index, up),
+        Action::Button { pressed } => board.button.borrow_mut().set(pressed),
+    }
+}
+
+/// If an interrupt can still wake the program from a wfi without scripted input. Only the timer
+/// can, if its interrupt is enabled in mie and it is running with ITO set. The UART never gets
+/// any input here.
+fn can_wake(cpu: &Cpu<Bus>, board: &Board) -> bool {
+    let timer = InterruptSignal::TIMER_INTERRUPT.mip_bit();
+    cpu.csr.load(Csr::MIE) & timer != 0 && board.timer.borrow().can_interrupt()
+}
+
+fn run(
+    cpu: &mut Cpu<Bus>,
+    board: &Board,
+    output: &RefCell<UartWriter<Stdout>>,
+    options: &Options,
+) -> io::Result<Outcome> {
+    let mut inputs = options.inputs.iter().peekable();
+
+    loop {
+        while let Some(input) = inputs.next_if(|input| input.cycle <= cpu.cycles()) {
+            apply(board, input.action);
+        }
+
+        let out_of_instructions = options
+            .max_instructions
+            .is_some_and(|max| cpu.instructions_retired() >= max);
+        let out_of_cycles = options.max_cycles.is_some_and(|max| cpu.cycles() >= max);
+        if out_of_instructions || out_of_cycles {
+            return Ok(Outcome::Timeout);
+        }
+
+        let reason = cpu.step();
+        if let Some(err) = output.borrow_mut().take_error() {
+            return Err(err);
+        }
+
+        match reason {
+            Some(StopReason::HaltLoop { pc }) => return Ok(Outcome::Finished { pc }),
+            // Nothing is left that can wake the program up, otherwise it waits until a limit
+            Some(StopReason::WaitingForInterrupt { pc })
+                if inputs.peek().is_none() && !can_wake(cpu, board) =>
+            {
+                return Ok(Outcome::Finished { pc })
+            }
+            Some(StopReason::IllegalInstruction { pc }) => {
+                return Ok(Outcome::IllegalInstruction { pc })
+            }
+            Some(StopReason::Exit { code }) => return Ok(Outcome::Exited { code }),
+            _ => {}
+        }
+    }
+}
+
+/// Prints why the run ended and the state of the board
+fn report(cpu: &Cpu<Bus>, board: &Board, outcome: Outcome) {
+    match outcome {
+        Outcome::Finished { pc } => eprintln!("finished at pc {:#010x}", pc),
+        Outcome::Exited { code } => eprintln!("exited with code {}", code),
+        Outcome::IllegalInstruction { pc } => {
+            eprintln!("illegal instruction at pc {:#010x}", pc)
+        }
+        Outcome::Timeout => eprintln!("timeout at pc {:#010x}", cpu.pc),
+    }
+    eprintln!("instructions: {}", cpu.instructions_retired());
+    eprintln!("cycles: {}", cpu.cycles());
+
+    let hex_display = board.hex_display.borrow();
+    let displays: Vec<String> = (0..6)
+        .map(|i| format!("{:02x}", hex_display.get(i)))
+        .collect();
+    eprintln!("hex: {}", displays.join(" "));
+
+    let led_strip = board.led_strip.borrow();
+    let leds: String = (0..SWITCHES)
+        .rev()
+        .map(|i| if led_strip.get(i) { '1' } else { '0' })
+        .collect();
+    eprintln!("leds: {}", leds);
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("dtekv-run: {}\n\n{}", err, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let bytes = match std::fs::read(&options.program) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("dtekv-run: {}: {}", options.program.display(), err);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let mut bus = Bus::new();
+    let board = Board::attach(&mut bus);
+    let output = Rc::new(RefCell::new(UartWriter::new(io::stdout())));
+    board.uart.borrow_mut().set_sink(output.clone());
+    let mut semihosting = Semihosting::new();
+    if let Some(root) = &options.root {
+        semihosting = semihosting.with_root(root);
+    }
+    let mut cpu = Cpu::new_with_bus(bus).with_semihosting(semihosting);
+
+    if let Err(err) = load(&mut cpu, &bytes) {
+        eprintln!("dtekv-run: {}: {}", options.program.display(), err);
+        return ExitCode::from(EXIT_USAGE);
+    }
+
+    let outcome = match run(&mut cpu, &board, &output, &options) {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            eprintln!("dtekv-run: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let _ = io::stdout().flush();
+
+    report(&cpu, &board, outcome);
+    ExitCode::from(outcome.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = args(&[
+            "--max-cycles",
+            "0x1000",
+            "--button",
+            "500=1",
+            "main.elf",
+            "--switch",
+            "100:9=1",
+            "--button",
+            "100=0",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(options.program, PathBuf::from("main.elf"));
+        assert_eq!(options.max_cycles, Some(0x1000));
+        assert_eq!(options.max_instructions, None);
+        assert_eq!(
+            options.inputs,
+            vec![
+                Input {
+                    cycle: 100,
+                    action: Action::Switch { index: 9, up: true }
+                },
+                Input {
+                    cycle: 100,
+                    action: Action::Button { pressed: false }
+                },
+                Input {
+                    cycle: 500,
+                    action: Action::Button { pressed: true }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(args(&["--help"]), Ok(None));
+        assert!(args(&[]).is_err());
+        assert!(args(&["a.rom", "b.rom"]).is_err());
+        assert!(args(&["a.rom", "--max-cycles"]).is_err());
+        assert!(args(&["a.rom", "--switch", "100:10=1"]).is_err());
+        assert!(args(&["a.rom", "--button", "100=2"]).is_err());
+        assert!(args(&["a.rom", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_default_limit() {
+        let options = args(&["main.elf"]).unwrap().unwrap();
+        assert_eq!(options.max_cycles, Some(DEFAULT_MAX_CYCLES));
+
+        let options = args(&["--max-cycles", "0", "main.elf"]).unwrap().unwrap();
+        assert_eq!(options.max_cycles, None);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(Outcome::Exited { code: 3 }.exit_code(), 3);
+        assert_eq!(Outcome::Exited { code: 124 }.exit_code(), EXIT_OTHER);
+        assert_eq!(Outcome::Exited { code: 132 }.exit_code(), EXIT_OTHER);
+        assert_eq!(Outcome::Exited { code: 2 }.exit_code(), EXIT_OTHER);
+        assert_eq!(Outcome::Exited { code: 256 }.exit_code(), EXIT_OTHER);
+        assert_eq!(Outcome::Timeout.exit_code(), EXIT_TIMEOUT);
+    }
+}
@@ -0,0 +1,1004 @@
+//! A small assembler for writing test programs as source instead of hex
+//!
+//! It understands every instruction the Cpu implements, labels, the common pseudo-instructions
+//! and the `.word`, `.half`, `.byte` and `.align` directives. It's not meant to replace the GNU
+//! assembler, there are no sections, macros or relocations.
+//!
+//! ```rust
+//! # use dtekv_emulator_core::assembler::assemble;
+//! let program = assemble(
+//!     "
+//!     li t0, 10
+//! loop:
+//!     addi t0, t0, -1    # count down
+//!     bnez t0, loop
+//!     ",
+//!     0,
+//! )
+//! .unwrap();
+//! assert_eq!(program.label("loop"), Some(4));
+//! assert_eq!(program.bytes.len(), 12);
+//! ```
+//!
+//! Branch and jump targets can be labels or numbers. Numbers are offsets relative to the
+//! instruction, the way [Instruction]'s Display prints them, so a printed instruction assembles
+//! back into the same instruction.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    csr::{Csr, MAX_CSR},
+    instruction::{BTypeImm, ITypeImm, Instruction, JTypeImm, STypeImm, ShamtImm, UTypeImm},
+    register::Register,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownInstruction(String),
+    UnknownDirective(String),
+    /// A register, CSR, number or label that couldn't be parsed
+    InvalidOperand(String),
+    WrongOperandCount {
+        expected: usize,
+        found: usize,
+    },
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    /// An immediate or offset that doesn't fit in the instruction
+    OutOfRange(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// The line the error is on, starting at 1
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownInstruction(name) => write!(f, "unknown instruction {}", name),
+            AsmErrorKind::UnknownDirective(name) => write!(f, "unknown directive {}", name),
+            AsmErrorKind::InvalidOperand(operand) => write!(f, "invalid operand {}", operand),
+            AsmErrorKind::WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            AsmErrorKind::UnknownLabel(label) => write!(f, "unknown label {}", label),
+            AsmErrorKind::DuplicateLabel(label) => write!(f, "label {} is defined twice", label),
+            AsmErrorKind::OutOfRange(value) => write!(f, "{} is out of range", value),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// The output of [assemble]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The address of the first byte
+    pub origin: u32,
+    pub bytes: Vec<u8>,
+    labels: HashMap<String, u32>,
+}
+
+impl Program {
+    /// The address of a label
+    pub fn label(&self, name: &str) -> Option<u32> {
+        self.labels.get(name).copied()
+    }
+}
+
+/// Assembles a program that will be loaded at `origin`
+pub fn assemble(source: &str, origin: u32) -> Result<Program, AsmError> {
+    let statements = parse(source)?;
+
+    // The first pass only finds out where every label is
+    let mut labels = HashMap::new();
+    let mut addr = origin;
+    for statement in &statements {
+        match statement {
+            Statement::Label { line, name } => {
+                if labels.insert(name.clone(), addr).is_some() {
+                    return Err(AsmError {
+                        line: *line,
+                        kind: AsmErrorKind::DuplicateLabel(name.clone()),
+                    });
+                }
+            }
+            Statement::Operation(op) => {
+                addr = addr.wrapping_add(op.size(addr).map_err(|kind| op.error(kind))?);
+            }
+        }
+    }
+
+    let mut assembler = Assembler {
+        labels: &labels,
+        bytes: Vec::new(),
+        origin,
+    };
+    for statement in &statements {
+        if let Statement::Operation(op) = statement {
+            assembler.emit(op).map_err(|kind| op.error(kind))?;
+        }
+    }
+
+    Ok(Program {
+        origin,
+        bytes: assembler.bytes,
+        labels,
+    })
+}
+
+enum Statement {
+    Label { line: usize, name: String },
+    Operation(Operation),
+}
+
+/// An instruction or directive with its operands
+struct Operation {
+    line: usize,
+    name: String,
+    operands: Vec<String>,
+}
+
+fn parse(source: &str) -> Result<Vec<Statement>, AsmError> {
+    let mut statements = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let mut line = line;
+        if let Some(comment) = line.find('#').into_iter().chain(line.find("//")).min() {
+            line = &line[..comment];
+        }
+
+        let mut line = line.trim();
+        while let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(is_label_char) {
+                return Err(AsmError {
+                    line: line_number,
+                    kind: AsmErrorKind::InvalidOperand(label.to_string()),
+                });
+            }
+
+            statements.push(Statement::Label {
+                line: line_number,
+                name: label.to_string(),
+            });
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operands = operands
+            .split(',')
+            .map(|operand| operand.trim().to_string())
+            .filter(|operand| !operand.is_empty())
+            .collect();
+
+        statements.push(Statement::Operation(Operation {
+            line: line_number,
+            name: name.to_lowercase(),
+            operands,
+        }));
+    }
+
+    Ok(statements)
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if let Some(c) = digits.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        let c = chars.next()?;
+        chars.next().is_none().then_some(c as i64)?
+    } else {
+        digits.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(s: &str) -> Result<Register, AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidOperand(s.to_string());
+
+    if s == "fp" {
+        return Ok(Register::S0);
+    }
+    if let Some(index) = s.strip_prefix('x') {
+        if let Ok(index) = index.parse() {
+            return Register::new(index).ok_or_else(invalid);
+        }
+    }
+
+    (0..32)
+        .map(|i| Register::new(i).expect("Invalid register"))
+        .find(|reg| reg.name() == s)
+        .ok_or_else(invalid)
+}
+
+fn parse_csr(s: &str) -> Result<Csr, AsmErrorKind> {
+    if let Some(csr) = parse_number(s) {
+        return u32::try_from(csr)
+            .ok()
+            .and_then(Csr::new)
+            .ok_or(AsmErrorKind::OutOfRange(csr));
+    }
+
+    (0..=MAX_CSR)
+        .map(|i| Csr::new(i).expect("Invalid CSR"))
+        .find(|csr| csr.name() == Some(s))
+        .ok_or_else(|| AsmErrorKind::InvalidOperand(s.to_string()))
+}
+
+/// Parses the predecessor or successor set of a fence, like `rw` or `iorw`, 0 is the empty set
+fn parse_fence_set(s: &str) -> Result<u8, AsmErrorKind> {
+    if s == "0" {
+        return Ok(0);
+    }
+
+    let mut set = 0;
+    for c in s.chars() {
+        let bit = match c {
+            'i' => 8,
+            'o' => 4,
+            'r' => 2,
+            'w' => 1,
+            _ => return Err(AsmErrorKind::InvalidOperand(s.to_string())),
+        };
+        if set & bit != 0 {
+            return Err(AsmErrorKind::InvalidOperand(s.to_string()));
+        }
+        set |= bit;
+    }
+    Ok(set)
+}
+
+/// Splits `offset(register)` into its parts, the offset can be left out
+fn parse_memory(s: &str) -> Result<(&str, Register), AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidOperand(s.to_string());
+
+    let (offset, register) = s
+        .strip_suffix(')')
+        .and_then(|s| s.split_once('('))
+        .ok_or_else(invalid)?;
+    let offset = offset.trim();
+    Ok((
+        if offset.is_empty() { "0" } else { offset },
+        parse_register(register.trim())?,
+    ))
+}
+
+/// Splits a 32 bit value into the parts used by lui and addi
+fn split_hi_lo(value: u32) -> (u32, u32) {
+    let lo = ((value as i32) << 20 >> 20) as u32;
+    (value.wrapping_sub(lo), lo)
+}
+
+fn fits_in_i_type(value: i64) -> bool {
+    (-2048..2048).contains(&value)
+}
+
+impl Operation {
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.line,
+            kind,
+        }
+    }
+
+    fn expect_operands(&self, expected: usize) -> Result<(), AsmErrorKind> {
+        if self.operands.len() != expected {
+            return Err(AsmErrorKind::WrongOperandCount {
+                expected,
+                found: self.operands.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The number of bytes the operation takes up at `addr`
+    fn size(&self, addr: u32) -> Result<u32, AsmErrorKind> {
+        let count = self.operands.len() as u32;
+
+        Ok(match self.name.as_str() {
+            ".word" => 4 * count,
+            ".half" => 2 * count,
+            ".byte" => count,
+            ".align" => {
+                self.expect_operands(1)?;
+                let align = self.alignment()?;
+                (align - addr % align) % align
+            }
+            "la" | "call" => 8,
+            "li" => {
+                self.expect_operands(2)?;
+                let value = parse_number(&self.operands[1])
+                    .ok_or_else(|| AsmErrorKind::InvalidOperand(self.operands[1].clone()))?;
+                // Sized the same way it is emitted, 0xffffffff is -1
+                if fits_in_i_type(value as i32 as i64) {
+                    4
+                } else {
+                    8
+                }
+            }
+            _ => 4,
+        })
+    }
+
+    fn alignment(&self) -> Result<u32, AsmErrorKind> {
+        match parse_number(&self.operands[0]) {
+            Some(power @ 0..=12) => Ok(1 << power),
+            Some(value) => Err(AsmErrorKind::OutOfRange(value)),
+            None => Err(AsmErrorKind::InvalidOperand(self.operands[0].clone())),
+        }
+    }
+}
+
+struct Assembler<'a> {
+    labels: &'a HashMap<String, u32>,
+    bytes: Vec<u8>,
+    origin: u32,
+}
+
+impl Assembler<'_> {
+    fn pc(&self) -> u32 {
+        self.origin.wrapping_add(self.bytes.len() as u32)
+    }
+
+    /// A number or a label, optionally with an offset like `label+4`
+    fn value(&self, s: &str) -> Result<i64, AsmErrorKind> {
+        if let Some(value) = parse_number(s) {
+            return Ok(value);
+        }
+
+        let (label, offset) = match s.find(['+', '-']) {
+            Some(i) => (
+                &s[..i],
+                parse_number(&s[i..].replace('+', ""))
+                    .ok_or_else(|| AsmErrorKind::InvalidOperand(s.to_string()))?,
+            ),
+            None => (s, 0),
+        };
+        let addr = self
+            .labels
+            .get(label.trim())
+            .ok_or_else(|| AsmErrorKind::UnknownLabel(label.trim().to_string()))?;
+
+        Ok(*addr as i64 + offset)
+    }
+
+    /// A value that has to fit in 32 bits, either as a signed or an unsigned number
+    fn word(&self, s: &str) -> Result<u32, AsmErrorKind> {
+        let value = self.value(s)?;
+        if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+            return Err(AsmErrorKind::OutOfRange(value));
+        }
+        Ok(value as u32)
+    }
+
+    /// A branch or jump target, labels are turned into an offset from the current instruction
+    fn target(&self, s: &str) -> Result<u32, AsmErrorKind> {
+        if let Some(offset) = parse_number(s) {
+            return Ok(offset as u32);
+        }
+        Ok(self.word(s)?.wrapping_sub(self.pc()))
+    }
+
+    /// A 5 bit unsigned immediate, used by shifts and the CSR instructions
+    fn uimm(&self, s: &str) -> Result<u32, AsmErrorKind> {
+        match self.value(s)? {
+            value @ 0..=31 => Ok(value as u32),
+            value => Err(AsmErrorKind::OutOfRange(value)),
+        }
+    }
+
+    fn push(&mut self, instruction: Instruction) {
+        self.bytes
+            .extend_from_slice(&instruction.encode().to_le_bytes());
+    }
+
+    fn emit(&mut self, op: &Operation) -> Result<(), AsmErrorKind> {
+        use Instruction as I;
+
+        let ops = &op.operands;
+        let reg = |i: usize| parse_register(&ops[i]);
+        let csr = |i: usize| parse_csr(&ops[i]);
+        let out_of_range = |value: u32| AsmErrorKind::OutOfRange(value as i32 as i64);
+        let i_imm = |value: u32| ITypeImm::new(value).ok_or_else(|| out_of_range(value));
+        let b_imm = |value: u32| BTypeImm::new(value).ok_or_else(|| out_of_range(value));
+        let j_imm = |value: u32| JTypeImm::new(value).ok_or_else(|| out_of_range(value));
+
+        let expected = match op.name.as_str() {
+            ".word" | ".half" | ".byte" => None,
+            ".align" => Some(1),
+            "nop" | "ret" | "mret" | "ecall" | "ebreak" | "wfi" | "fence.i" => Some(0),
+            "j" | "jr" | "call" => Some(1),
+            "jal" if ops.len() == 1 => Some(1),
+            // Checked once it's known which form is used
+            "jalr" | "fence" => None,
+            "lui" | "auipc" | "li" | "la" | "mv" | "not" | "neg" | "seqz" | "snez" | "sltz"
+            | "sgtz" | "beqz" | "bnez" | "bltz" | "bgez" | "blez" | "bgtz" | "csrr" | "csrw"
+            | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => Some(2),
+            "jal" => Some(2),
+            "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and"
+            | "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" | "addi"
+            | "slti" | "sltiu" | "xori" | "ori" | "andi" | "slli" | "srli" | "srai" | "beq"
+            | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "bgt" | "ble" | "bgtu" | "bleu"
+            | "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => Some(3),
+            // Loads and stores check their operands themselves
+            _ => None,
+        };
+        if let Some(expected) = expected {
+            op.expect_operands(expected)?;
+        }
+
+        // Instructions with the same operands are grouped by building them from a constructor
+        type Rrr = fn(Register, Register, Register) -> Instruction;
+        type Rri = fn(Register, Register, ITypeImm) -> Instruction;
+        type Rrs = fn(Register, Register, ShamtImm) -> Instruction;
+        type Branch = fn(Register, Register, BTypeImm) -> Instruction;
+        type Load = fn(Register, Register, ITypeImm) -> Instruction;
+        type Store = fn(Register, Register, STypeImm) -> Instruction;
+        type CsrReg = fn(Register, Register, Csr) -> Instruction;
+        type CsrImm = fn(Register, u32, Csr) -> Instruction;
+
+        let rrr: Option<Rrr> = match op.name.as_str() {
+            "add" => Some(|rd, rs1, rs2| I::ADD { rd, rs1, rs2 }),
+            "sub" => Some(|rd, rs1, rs2| I::SUB { rd, rs1, rs2 }),
+            "sll" => Some(|rd, rs1, rs2| I::SLL { rd, rs1, rs2 }),
+            "slt" => Some(|rd, rs1, rs2| I::SLT { rd, rs1, rs2 }),
+            "sltu" => Some(|rd, rs1, rs2| I::SLTU { rd, rs1, rs2 }),
+            "xor" => Some(|rd, rs1, rs2| I::XOR { rd, rs1, rs2 }),
+            "srl" => Some(|rd, rs1, rs2| I::SRL { rd, rs1, rs2 }),
+            "sra" => Some(|rd, rs1, rs2| I::SRA { rd, rs1, rs2 }),
+            "or" => Some(|rd, rs1, rs2| I::OR { rd, rs1, rs2 }),
+            "and" => Some(|rd, rs1, rs2| I::AND { rd, rs1, rs2 }),
+            "mul" => Some(|rd, rs1, rs2| I::MUL { rd, rs1, rs2 }),
+            "mulh" => Some(|rd, rs1, rs2| I::MULH { rd, rs1, rs2 }),
+            "mulhsu" => Some(|rd, rs1, rs2| I::MULHSU { rd, rs1, rs2 }),
+            "mulhu" => Some(|rd, rs1, rs2| I::MULHU { rd, rs1, rs2 }),
+            "div" => Some(|rd, rs1, rs2| I::DIV { rd, rs1, rs2 }),
+            "divu" => Some(|rd, rs1, rs2| I::DIVU { rd, rs1, rs2 }),
+            "rem" => Some(|rd, rs1, rs2| I::REM { rd, rs1, rs2 }),
+            "remu" => Some(|rd, rs1, rs2| I::REMU { rd, rs1, rs2 }),
+            _ => None,
+        };
+        if let Some(build) = rrr {
+            self.push(build(reg(0)?, reg(1)?, reg(2)?));
+            return Ok(());
+        }
+
+        let rri: Option<Rri> = match op.name.as_str() {
+            "addi" => Some(|rd, rs1, imm| I::ADDI { rd, rs1, imm }),
+            "slti" => Some(|rd, rs1, imm| I::SLTI { rd, rs1, imm }),
+            "sltiu" => Some(|rd, rs1, imm| I::SLTIU { rd, rs1, imm }),
+            "xori" => Some(|rd, rs1, imm| I::XORI { rd, rs1, imm }),
+            "ori" => Some(|rd, rs1, imm| I::ORI { rd, rs1, imm }),
+            "andi" => Some(|rd, rs1, imm| I::ANDI { rd, rs1, imm }),
+            _ => None,
+        };
+        if let Some(build) = rri {
+            let imm = i_imm(self.word(&ops[2])?)?;
+            self.push(build(reg(0)?, reg(1)?, imm));
+            return Ok(());
+        }
+
+        let rrs: Option<Rrs> = match op.name.as_str() {
+            "slli" => Some(|rd, rs1, imm| I::SLLI { rd, rs1, imm }),
+            "srli" => Some(|rd, rs1, imm| I::SRLI { rd, rs1, imm }),
+            "srai" => Some(|rd, rs1, imm| I::SRAI { rd, rs1, imm }),
+            _ => None,
+        };
+        if let Some(build) = rrs {
+            let imm = ShamtImm::new(self.uimm(&ops[2])?).expect("Shift amount is checked");
+            self.push(build(reg(0)?, reg(1)?, imm));
+            return Ok(());
+        }
+
+        let branch: Option<Branch> = match op.name.as_str() {
+            "beq" => Some(|rs1, rs2, imm| I::BEQ { rs1, rs2, imm }),
+            "bne" => Some(|rs1, rs2, imm| I::BNE { rs1, rs2, imm }),
+            "blt" => Some(|rs1, rs2, imm| I::BLT { rs1, rs2, imm }),
+            "bge" => Some(|rs1, rs2, imm| I::BGE { rs1, rs2, imm }),
+            "bltu" => Some(|rs1, rs2, imm| I::BLTU { rs1, rs2, imm }),
+            "bgeu" => Some(|rs1, rs2, imm| I::BGEU { rs1, rs2, imm }),
+            // The operands are swapped
+            "bgt" => Some(|rs1, rs2, imm| I::BLT {
+                rs1: rs2,
+                rs2: rs1,
+                imm,
+            }),
+            "ble" => Some(|rs1, rs2, imm| I::BGE {
+                rs1: rs2,
+                rs2: rs1,
+                imm,
+            }),
+            "bgtu" => Some(|rs1, rs2, imm| I::BLTU {
+                rs1: rs2,
+                rs2: rs1,
+                imm,
+            }),
+            "bleu" => Some(|rs1, rs2, imm| I::BGEU {
+                rs1: rs2,
+                rs2: rs1,
+                imm,
+            }),
+            _ => None,
+        };
+        if let Some(build) = branch {
+            let imm = b_imm(self.target(&ops[2])?)?;
+            self.push(build(reg(0)?, reg(1)?, imm));
+            return Ok(());
+        }
+
+        // Branches comparing with zero, the zero is either the first or the second operand
+        let branch_zero: Option<(Branch, bool)> = match op.name.as_str() {
+            "beqz" => Some((|rs1, rs2, imm| I::BEQ { rs1, rs2, imm }, false)),
+            "bnez" => Some((|rs1, rs2, imm| I::BNE { rs1, rs2, imm }, false)),
+            "bltz" => Some((|rs1, rs2, imm| I::BLT { rs1, rs2, imm }, false)),
+            "bgez" => Some((|rs1, rs2, imm| I::BGE { rs1, rs2, imm }, false)),
+            "bgtz" => Some((|rs1, rs2, imm| I::BLT { rs1, rs2, imm }, true)),
+            "blez" => Some((|rs1, rs2, imm| I::BGE { rs1, rs2, imm }, true)),
+            _ => None,
+        };
+        if let Some((build, zero_first)) = branch_zero {
+            let imm = b_imm(self.target(&ops[1])?)?;
+            let reg = reg(0)?;
+            self.push(if zero_first {
+                build(Register::ZERO, reg, imm)
+            } else {
+                build(reg, Register::ZERO, imm)
+            });
+            return Ok(());
+        }
+
+        let load: Option<Load> = match op.name.as_str() {
+            "lb" => Some(|rd, rs1, imm| I::LB { rd, rs1, imm }),
+            "lh" => Some(|rd, rs1, imm| I::LH { rd, rs1, imm }),
+            "lw" => Some(|rd, rs1, imm| I::LW { rd, rs1, imm }),
+            "lbu" => Some(|rd, rs1, imm| I::LBU { rd, rs1, imm }),
+            "lhu" => Some(|rd, rs1, imm| I::LHU { rd, rs1, imm }),
+            _ => None,
+        };
+        if let Some(build) = load {
+            op.expect_operands(2)?;
+            let (offset, rs1) = parse_memory(&ops[1])?;
+            let imm = i_imm(self.word(offset)?)?;
+            self.push(build(reg(0)?, rs1, imm));
+            return Ok(());
+        }
+
+        let store: Option<Store> = match op.name.as_str() {
+            "sb" => Some(|rs1, rs2, imm| I::SB { rs1, rs2, imm }),
+            "sh" => Some(|rs1, rs2, imm| I::SH { rs1, rs2, imm }),
+            "sw" => Some(|rs1, rs2, imm| I::SW { rs1, rs2, imm }),
+            _ => None,
+        };
+        if let Some(build) = store {
+            op.expect_operands(2)?;
+            let (offset, rs1) = parse_memory(&ops[1])?;
+            let offset = self.word(offset)?;
+            let imm = STypeImm::new(offset).ok_or_else(|| out_of_range(offset))?;
+            self.push(build(rs1, reg(0)?, imm));
+            return Ok(());
+        }
+
+        let csr_reg: Option<CsrReg> = match op.name.as_str() {
+            "csrrw" | "csrw" => Some(|rd, rs1, csr| I::CSRRW { rd, rs1, csr }),
+            "csrrs" | "csrs" | "csrr" => Some(|rd, rs1, csr| I::CSRRS { rd, rs1, csr }),
+            "csrrc" | "csrc" => Some(|rd, rs1, csr| I::CSRRC { rd, rs1, csr }),
+            _ => None,
+        };
+        if let Some(build) = csr_reg {
+            self.push(match op.name.as_str() {
+                "csrr" => build(reg(0)?, Register::ZERO, csr(1)?),
+                "csrw" | "csrs" | "csrc" => build(Register::ZERO, reg(1)?, csr(0)?),
+                _ => build(reg(0)?, reg(2)?, csr(1)?),
+            });
+            return Ok(());
+        }
+
+        let csr_imm: Option<CsrImm> = match op.name.as_str() {
+            "csrrwi" | "csrwi" => Some(|rd, imm, csr| I::CSRRWI { rd, imm, csr }),
+            "csrrsi" | "csrsi" => Some(|rd, imm, csr| I::CSRRSI { rd, imm, csr }),
+            "csrrci" | "csrci" => Some(|rd, imm, csr| I::CSRRCI { rd, imm, csr }),
+            _ => None,
+        };
+        if let Some(build) = csr_imm {
+            self.push(match op.name.as_str() {
+                "csrwi" | "csrsi" | "csrci" => build(Register::ZERO, self.uimm(&ops[1])?, csr(0)?),
+                _ => build(reg(0)?, self.uimm(&ops[2])?, csr(1)?),
+            });
+            return Ok(());
+        }
+
+        match op.name.as_str() {
+            ".word" => {
+                for operand in ops {
+                    let word = self.word(operand)?;
+                    self.bytes.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            ".half" | ".byte" => {
+                let size = if op.name == ".half" { 2 } else { 1 };
+                for operand in ops {
+                    let value = self.value(operand)?;
+                    if value < -(1 << (8 * size - 1)) || value >= 1 << (8 * size) {
+                        return Err(AsmErrorKind::OutOfRange(value));
+                    }
+                    self.bytes
+                        .extend_from_slice(&(value as u32).to_le_bytes()[..size]);
+                }
+            }
+            ".align" => {
+                let align = op.alignment()?;
+                while self.pc() % align != 0 {
+                    self.bytes.push(0);
+                }
+            }
+            "lui" | "auipc" => {
+                let value = self.word(&ops[1])?;
+                if value >= 1 << 20 {
+                    return Err(out_of_range(value));
+                }
+                let imm = UTypeImm::new(value << 12).expect("Value is checked");
+                let rd = reg(0)?;
+                self.push(if op.name == "lui" {
+                    I::LUI { rd, imm }
+                } else {
+                    I::AUIPC { rd, imm }
+                });
+            }
+            "jal" if ops.len() == 1 => {
+                let imm = j_imm(self.target(&ops[0])?)?;
+                self.push(I::JAL {
+                    rd: Register::RA,
+                    imm,
+                });
+            }
+            "jal" => {
+                let imm = j_imm(self.target(&ops[1])?)?;
+                self.push(I::JAL { rd: reg(0)?, imm });
+            }
+            "j" => {
+                let imm = j_imm(self.target(&ops[0])?)?;
+                self.push(I::JAL {
+                    rd: Register::ZERO,
+                    imm,
+                });
+            }
+            "jalr" if ops.len() == 1 => self.push(I::JALR {
+                rd: Register::RA,
+                rs1: reg(0)?,
+                imm: i_imm(0)?,
+            }),
+            "jalr" => {
+                // Both `jalr rd, offset(rs1)` and `jalr rd, rs1, offset` are accepted
+                let (rs1, offset) = match parse_memory(&ops[1]) {
+                    Ok((offset, rs1)) if ops.len() == 2 => (rs1, offset),
+                    _ => {
+                        op.expect_operands(3)?;
+                        (reg(1)?, ops[2].as_str())
+                    }
+                };
+                let imm = i_imm(self.word(offset)?)?;
+                self.push(I::JALR {
+                    rd: reg(0)?,
+                    rs1,
+                    imm,
+                });
+            }
+            "jr" => self.push(I::JALR {
+                rd: Register::ZERO,
+                rs1: reg(0)?,
+                imm: i_imm(0)?,
+            }),
+            "ret" => self.push(I::JALR {
+                rd: Register::ZERO,
+                rs1: Register::RA,
+                imm: i_imm(0)?,
+            }),
+            "call" => {
+                let (hi, lo) = split_hi_lo(self.target(&ops[0])?);
+                self.push(I::AUIPC {
+                    rd: Register::RA,
+                    imm: UTypeImm::new(hi).expect("Lower bits are cleared"),
+                });
+                self.push(I::JALR {
+                    rd: Register::RA,
+                    rs1: Register::RA,
+                    imm: i_imm(lo)?,
+                });
+            }
+            "nop" => self.push(I::ADDI {
+                rd: Register::ZERO,
+                rs1: Register::ZERO,
+                imm: i_imm(0)?,
+            }),
+            "li" => {
+                let rd = reg(0)?;
+                let value = self.word(&ops[1])?;
+                if fits_in_i_type(value as i32 as i64) {
+                    self.push(I::ADDI {
+                        rd,
+                        rs1: Register::ZERO,
+                        imm: i_imm(value)?,
+                    });
+                } else {
+                    let (hi, lo) = split_hi_lo(value);
+                    self.push(I::LUI {
+                        rd,
+                        imm: UTypeImm::new(hi).expect("Lower bits are cleared"),
+                    });
+                    self.push(I::ADDI {
+                        rd,
+                        rs1: rd,
+                        imm: i_imm(lo)?,
+                    });
+                }
+            }
+            "la" => {
+                let rd = reg(0)?;
+                let (hi, lo) = split_hi_lo(self.word(&ops[1])?.wrapping_sub(self.pc()));
+                self.push(I::AUIPC {
+                    rd,
+                    imm: UTypeImm::new(hi).expect("Lower bits are cleared"),
+                });
+                self.push(I::ADDI {
+                    rd,
+                    rs1: rd,
+                    imm: i_imm(lo)?,
+                });
+            }
+            "mv" => self.push(I::ADDI {
+                rd: reg(0)?,
+                rs1: reg(1)?,
+                imm: i_imm(0)?,
+            }),
+            "not" => self.push(I::XORI {
+                rd: reg(0)?,
+                rs1: reg(1)?,
+                imm: i_imm(u32::MAX)?,
+            }),
+            "neg" => self.push(I::SUB {
+                rd: reg(0)?,
+                rs1: Register::ZERO,
+                rs2: reg(1)?,
+            }),
+            "seqz" => self.push(I::SLTIU {
+                rd: reg(0)?,
+                rs1: reg(1)?,
+                imm: i_imm(1)?,
+            }),
+            "snez" => self.push(I::SLTU {
+                rd: reg(0)?,
+                rs1: Register::ZERO,
+                rs2: reg(1)?,
+            }),
+            "sltz" => self.push(I::SLT {
+                rd: reg(0)?,
+                rs1: reg(1)?,
+                rs2: Register::ZERO,
+            }),
+            "sgtz" => self.push(I::SLT {
+                rd: reg(0)?,
+                rs1: Register::ZERO,
+                rs2: reg(1)?,
+            }),
+            "mret" => self.push(I::MRET),
+            "ecall" => self.push(I::ECALL),
+            "ebreak" => self.push(I::EBREAK),
+            "wfi" => self.push(I::WFI),
+            "fence.i" => self.push(I::FENCE_I),
+            // A plain fence orders everything
+            "fence" if ops.is_empty() => self.push(I::FENCE {
+                pred: 0xF,
+                succ: 0xF,
+            }),
+            "fence" => {
+                op.expect_operands(2)?;
+                self.push(I::FENCE {
+                    pred: parse_fence_set(&ops[0])?,
+                    succ: parse_fence_set(&ops[1])?,
+                });
+            }
+            name if name.starts_with('.') => {
+                return Err(AsmErrorKind::UnknownDirective(name.to_string()))
+            }
+            name => return Err(AsmErrorKind::UnknownInstruction(name.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Disassembler;
+
+    fn words(source: &str) -> Vec<u32> {
+        assemble(source, 0)
+            .unwrap()
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn error(source: &str) -> AsmErrorKind {
+        assemble(source, 0).unwrap_err().kind
+    }
+
+    #[test]
+    fn test_instructions() {
+        // Encodings checked with llvm-mc
+        assert_eq!(
+            words(
+                "
+                addi t0, zero, 7
+                sw t0, 0(t1)
+                lw a0, -4(sp)
+                csrrs a0, mstatus, zero
+                csrrwi zero, mip, 5
+                lui t0, 0x12345
+                srai t0, t0, 5
+                mul a0, a0, a1
+                jalr zero, 0(ra)
+                mret
+                ecall
+                ebreak
+                wfi
+                fence
+                fence rw, w
+                fence.i
+                "
+            ),
+            vec![
+                0x00700293, 0x00532023, 0xffc12503, 0x30002573, 0x3442d073, 0x123452b7, 0x4052d293,
+                0x02b50533, 0x00008067, 0x30200073, 0x00000073, 0x00100073, 0x10500073, 0x0ff0000f,
+                0x0310000f, 0x0000100f
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_pseudo_instructions() {
+        let program = assemble(
+            "
+            start:  li t0, 0x12345fff    # lui + addi
+                    li t1, -1
+                    la a0, data
+            loop:   beqz t0, done
+                    addi t0, t0, -1
+                    j loop
+            done:   call start
+                    ret
+                    nop
+            data:   .word 0xdeadbeef, data
+                    .byte 1, 'a'
+                    .align 2
+                    .half -1
+            ",
+            0x100,
+        )
+        .unwrap();
+
+        assert_eq!(program.label("loop"), Some(0x114));
+        assert_eq!(program.label("data"), Some(0x130));
+        assert_eq!(program.bytes.len(), 0x3e);
+
+        let disassembler = Disassembler::new();
+        let lines: Vec<String> = program.bytes[..0x30]
+            .chunks(4)
+            .enumerate()
+            .map(|(i, word)| {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                disassembler.disassemble_word(word, 0x100 + 4 * i as u32)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "lui t0, 0x12346",
+                "addi t0, t0, -1",
+                "li t1, -1",
+                "auipc a0, 0x0",
+                "addi a0, a0, 36",
+                "beqz t0, 0x120",
+                "addi t0, t0, -1",
+                "j 0x114",
+                "auipc ra, 0x0",
+                "jalr ra, -32(ra)",
+                "ret",
+                "nop",
+            ]
+        );
+        assert_eq!(
+            &program.bytes[0x30..0x38],
+            &[0xef, 0xbe, 0xad, 0xde, 0x30, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(&program.bytes[0x38..0x3a], &[1, b'a']);
+        assert_eq!(&program.bytes[0x3c..], &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_li_hex_negative() {
+        let source = "
+                    li t0, 0xffffffff
+            after:  j after
+            ";
+
+        assert_eq!(assemble(source, 0).unwrap().label("after"), Some(4));
+        assert_eq!(words(source), [0xfff00293, 0x0000006f]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error("foo t0"),
+            AsmErrorKind::UnknownInstruction("foo".to_string())
+        );
+        assert_eq!(
+            error("addi t0, t0"),
+            AsmErrorKind::WrongOperandCount {
+                expected: 3,
+                found: 2
+            }
+        );
+        assert_eq!(
+            error("addi t0, t9, 1"),
+            AsmErrorKind::InvalidOperand("t9".to_string())
+        );
+        assert_eq!(error("addi t0, t0, 2048"), AsmErrorKind::OutOfRange(2048));
+        assert_eq!(
+            error("j nowhere"),
+            AsmErrorKind::UnknownLabel("nowhere".to_string())
+        );
+        assert_eq!(
+            error("a:\na:"),
+            AsmErrorKind::DuplicateLabel("a".to_string())
+        );
+        assert_eq!(
+            assemble("nop\n.text", 0).unwrap_err(),
+            AsmError {
+                line: 2,
+                kind: AsmErrorKind::UnknownDirective(".text".to_string())
+            }
+        );
+    }
+
+    /// Everything the Display implementation prints assembles back into the same instruction
+    #[test]
+    fn test_display_round_trip() {
+        let mut word = 0x2545f491u32;
+        for _ in 0..100_000 {
+            word ^= word << 13;
+            word ^= word >> 17;
+            word ^= word << 5;
+
+            if let Ok(instruction) = Instruction::try_from(word) {
+                let source = instruction.to_string();
+                assert_eq!(words(&source), vec![instruction.encode()], "{}", source);
+            }
+        }
+    }
+}
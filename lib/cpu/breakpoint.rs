@@ -0,0 +1,245 @@
+//! Breakpoints and watchpoints
+//!
+//! Breakpoints stop the Cpu before the instruction at a given address is executed. Watchpoints
+//! stop the Cpu after an instruction has read or written a range of addresses. Both are reported
+//! through [StopReason](super::StopReason) by [Cpu::step] and [Cpu::run].
+
+use std::ops::Range;
+
+use crate::peripheral::Peripheral;
+
+use super::Cpu;
+
+/// The kind of memory access a watchpoint triggers on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes
+    Access,
+}
+
+impl WatchKind {
+    pub(crate) fn matches(&self, access: WatchKind) -> bool {
+        *self == WatchKind::Access || *self == access
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Watchpoint {
+    pub range: Range<u32>,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn new(range: Range<u32>, kind: WatchKind) -> Self {
+        Watchpoint { range, kind }
+    }
+
+    pub(crate) fn overlaps(&self, addr: u32, size: u32) -> bool {
+        let end = addr.saturating_add(size);
+        addr < self.range.end && self.range.start < end
+    }
+}
+
+/// A memory access that triggered a watchpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WatchpointHit {
+    /// The address of the instruction that accessed memory
+    pub pc: u32,
+    /// The address that was accessed
+    pub addr: u32,
+    /// The number of bytes that were accessed
+    pub size: u32,
+    /// The value that was read or written
+    pub value: u32,
+    /// Either [WatchKind::Read] or [WatchKind::Write]
+    pub kind: WatchKind,
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// Stops the Cpu before the instruction at `pc` is executed
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns true if there was a breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Returns true if the watchpoint existed
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|w| w == watchpoint) {
+            Some(i) => {
+                self.watchpoints.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// Checks if a breakpoint should stop the Cpu at the current pc. After a breakpoint has
+    /// stopped the Cpu, the next call lets the instruction through so execution can be resumed.
+    pub(crate) fn check_breakpoint(&mut self) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+
+        if self.breakpoint_resume.take() == Some(self.pc) {
+            return false;
+        }
+
+        if self.breakpoints.contains(&self.pc) {
+            self.breakpoint_resume = Some(self.pc);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records a hit if the access of `size` bytes at `addr` triggers a watchpoint
+    pub(crate) fn check_watchpoints(&self, addr: u32, size: u32, value: u32, access: WatchKind) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        let triggered = self
+            .watchpoints
+            .iter()
+            .any(|w| w.kind.matches(access) && w.overlaps(addr, size));
+
+        // Only the first hit of an instruction is reported
+        if triggered && self.watchpoint_hit.get().is_none() {
+            self.watchpoint_hit.set(Some(WatchpointHit {
+                pc: self.pc,
+                addr,
+                size,
+                value,
+                kind: access,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::StopReason,
+        memory_mapped::MemoryMapped,
+        test_utils::{new_io_cpu, new_sdram_cpu},
+    };
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0x00128293, // addi t0, t0, 1
+            0xff9ff06f, // j 0
+        ]);
+        cpu.add_breakpoint(4);
+
+        assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: 4 });
+        assert_eq!(cpu.instructions_retired(), 1);
+
+        // Resuming executes the instruction at the breakpoint and stops the next time around
+        assert_eq!(cpu.run(100), StopReason::Breakpoint { pc: 4 });
+        assert_eq!(cpu.instructions_retired(), 4);
+
+        assert!(cpu.remove_breakpoint(4));
+        assert_eq!(cpu.run(10), StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00700293, // li t0, 7
+            0x10502023, // sw t0, 256(zero)
+            0x10002303, // lw t1, 256(zero)
+            0x105020a3, // sw t0, 257(zero)
+        ]);
+        cpu.add_watchpoint(Watchpoint::new(0x100..0x104, WatchKind::Write));
+
+        let hit = WatchpointHit {
+            pc: 4,
+            addr: 0x100,
+            size: 4,
+            value: 7,
+            kind: WatchKind::Write,
+        };
+        assert_eq!(cpu.run(10), StopReason::Watchpoint(hit));
+        assert_eq!(cpu.pc, 8);
+        assert_eq!(cpu.load_word(0x100), Ok(7));
+
+        // The load doesn't trigger a write watchpoint
+        let hit = WatchpointHit {
+            pc: 12,
+            addr: 0x101,
+            size: 4,
+            value: 7,
+            kind: WatchKind::Write,
+        };
+        assert_eq!(cpu.run(10), StopReason::Watchpoint(hit));
+    }
+
+    #[test]
+    fn test_read_watchpoint() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00000013, // nop
+            0x10204303, // lbu t1, 258(zero)
+        ]);
+        cpu.store_word(0x100, 0xaabbccdd).unwrap();
+        cpu.add_watchpoint(Watchpoint::new(0x102..0x103, WatchKind::Read));
+
+        let hit = WatchpointHit {
+            pc: 4,
+            addr: 0x102,
+            size: 1,
+            value: 0xbb,
+            kind: WatchKind::Read,
+        };
+        assert_eq!(cpu.run(10), StopReason::Watchpoint(hit));
+    }
+
+    #[test]
+    fn test_access_watchpoint_overlap() {
+        let mut cpu = new_sdram_cpu(&[
+            0x0fe02303, // lw t1, 254(zero)
+        ]);
+        cpu.add_watchpoint(Watchpoint::new(0x100..0x101, WatchKind::Access));
+
+        assert!(matches!(
+            cpu.step(),
+            Some(StopReason::Watchpoint(WatchpointHit { addr: 0xfe, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_peek_is_not_an_access() {
+        let mut data = new_io_cpu();
+        let uart = crate::peripheral::UART_LOWER_ADDR;
+        data.cpu
+            .add_watchpoint(Watchpoint::new(uart..uart + 4, WatchKind::Access));
+        assert!(data.uart.borrow_mut().push_input(b'a'));
+
+        // What GDB and frontends read neither triggers the watchpoint nor takes the character
+        assert_eq!(data.cpu.peek_byte(uart), Ok(b'a'));
+        assert_eq!(data.cpu.watchpoint_hit.get(), None);
+        assert_eq!(data.cpu.load_byte(uart), Ok(b'a'));
+        assert!(data.cpu.watchpoint_hit.get().is_some());
+        assert_eq!(data.cpu.peek_byte(uart), Ok(0));
+    }
+}
@@ -0,0 +1,302 @@
+//! mcycle, minstret and the hardware performance counters mhpmcounter3-9
+//!
+//! The counters are stored in the CsrBlock like any other CSR, so reading them from a program
+//! works the same as reading any other CSR. The Cpu advances them every time an instruction is
+//! executed.
+
+use crate::{csr::Csr, instruction::Instruction, peripheral::Peripheral};
+
+use super::Cpu;
+
+/// The first and last hardware performance counter
+pub const HPM_COUNTERS: std::ops::RangeInclusive<u32> = 3..=9;
+
+const MCOUNTINHIBIT_CY: u32 = 1 << 0;
+const MCOUNTINHIBIT_IR: u32 = 1 << 2;
+
+/// Events that the hardware performance counters can count. The discriminant is the value written
+/// to mhpmevent to select the event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HpmEvent {
+    /// The counter doesn't count anything
+    None = 0,
+    /// Load instructions
+    Load = 1,
+    /// Store instructions
+    Store = 2,
+    /// Branch instructions where the branch was taken
+    BranchTaken = 3,
+    /// JAL and JALR instructions
+    Jump = 4,
+    /// Multiplication and division instructions
+    MExtension = 5,
+    /// Cycles spent waiting for memory
+    MemoryStall = 6,
+}
+
+impl HpmEvent {
+    pub fn new(event: u32) -> Option<Self> {
+        Some(match event {
+            0 => HpmEvent::None,
+            1 => HpmEvent::Load,
+            2 => HpmEvent::Store,
+            3 => HpmEvent::BranchTaken,
+            4 => HpmEvent::Jump,
+            5 => HpmEvent::MExtension,
+            6 => HpmEvent::MemoryStall,
+            _ => return None,
+        })
+    }
+
+    /// The event each counter counts after reset, mhpmcounter3 counts loads, mhpmcounter4 stores
+    /// and so on
+    pub fn reset_value(counter: u32) -> HpmEvent {
+        match counter {
+            3 => HpmEvent::Load,
+            4 => HpmEvent::Store,
+            5 => HpmEvent::BranchTaken,
+            6 => HpmEvent::Jump,
+            7 => HpmEvent::MExtension,
+            8 => HpmEvent::MemoryStall,
+            _ => HpmEvent::None,
+        }
+    }
+}
+
+/// The events caused by a single instruction
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Events {
+    pub(crate) load: bool,
+    pub(crate) store: bool,
+    pub(crate) branch_taken: bool,
+    pub(crate) jump: bool,
+    pub(crate) m_ext: bool,
+    pub(crate) stall_cycles: u64,
+}
+
+impl Events {
+    fn count(&self, event: HpmEvent) -> u64 {
+        match event {
+            HpmEvent::None => 0,
+            HpmEvent::Load => self.load as u64,
+            HpmEvent::Store => self.store as u64,
+            HpmEvent::BranchTaken => self.branch_taken as u64,
+            HpmEvent::Jump => self.jump as u64,
+            HpmEvent::MExtension => self.m_ext as u64,
+            HpmEvent::MemoryStall => self.stall_cycles,
+        }
+    }
+}
+
+fn hpm_counter(counter: u32) -> (Csr, Csr) {
+    (
+        Csr::new(Csr::MHPMCOUNTER3.as_u32() - 3 + counter).expect("Invalid counter"),
+        Csr::new(Csr::MHPMCOUNTER3H.as_u32() - 3 + counter).expect("Invalid counter"),
+    )
+}
+
+fn hpm_event(counter: u32) -> Csr {
+    Csr::new(Csr::MHPMEVENT3.as_u32() - 3 + counter).expect("Invalid counter")
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// The number of cycles executed, the same value as mcycleh:mcycle
+    pub fn cycles(&self) -> u64 {
+        self.csr.load_u64(Csr::MCYCLE, Csr::MCYCLEH)
+    }
+
+    /// The number of instructions retired, the same value as minstreth:minstret
+    pub fn instructions_retired(&self) -> u64 {
+        self.csr.load_u64(Csr::MINSTRET, Csr::MINSTRETH)
+    }
+
+    /// Sets the performance counters to their reset values
+    pub(crate) fn reset_counters(&mut self) {
+        for counter in HPM_COUNTERS {
+            let event = HpmEvent::reset_value(counter);
+            self.csr.store(hpm_event(counter), event as u32);
+        }
+    }
+
+    /// Events an instruction will cause, called before the instruction is executed since the
+    /// registers used for the address might be overwritten
+    pub(crate) fn instruction_events(&self, instruction: Instruction) -> Events {
+        use Instruction as I;
+
+        let mut events = Events::default();
+
+        let mem_addr = match instruction {
+            I::LB { rs1, imm, .. }
+            | I::LH { rs1, imm, .. }
+            | I::LW { rs1, imm, .. }
+            | I::LBU { rs1, imm, .. }
+            | I::LHU { rs1, imm, .. } => {
+                events.load = true;
+                Some(self.regs.get(rs1).wrapping_add(imm.as_u32()))
+            }
+            I::SB { rs1, imm, .. } | I::SH { rs1, imm, .. } | I::SW { rs1, imm, .. } => {
+                events.store = true;
+                Some(self.regs.get(rs1).wrapping_add(imm.as_u32()))
+            }
+            I::JAL { .. } | I::JALR { .. } => {
+                events.jump = true;
+                None
+            }
+            I::MUL { .. }
+            | I::MULH { .. }
+            | I::MULHSU { .. }
+            | I::MULHU { .. }
+            | I::DIV { .. }
+            | I::DIVU { .. }
+            | I::REM { .. }
+            | I::REMU { .. } => {
+                events.m_ext = true;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(addr) = mem_addr {
+            events.stall_cycles = self.timing.memory_stall(addr) as u64;
+        }
+
+        events
+    }
+
+    /// Advances mcycle by `cycles`, and minstret and the performance counters after an instruction
+    /// has executed
+    pub(crate) fn update_counters(&mut self, events: Events, cycles: u64, retired: bool) {
+        let inhibit = self.csr.load(Csr::MCOUNTINHIBIT);
+
+        if inhibit & MCOUNTINHIBIT_CY == 0 {
+            let mcycle = self.cycles();
+            self.csr
+                .store_u64(Csr::MCYCLE, Csr::MCYCLEH, mcycle.wrapping_add(cycles));
+        }
+
+        if !retired {
+            return;
+        }
+
+        if inhibit & MCOUNTINHIBIT_IR == 0 {
+            let instret = self.instructions_retired();
+            self.csr
+                .store_u64(Csr::MINSTRET, Csr::MINSTRETH, instret.wrapping_add(1));
+        }
+
+        for counter in HPM_COUNTERS {
+            if inhibit & (1 << counter) != 0 {
+                continue;
+            }
+
+            let Some(event) = HpmEvent::new(self.csr.load(hpm_event(counter))) else {
+                continue;
+            };
+
+            let count = events.count(event);
+            if count == 0 {
+                continue;
+            }
+
+            let (low, high) = hpm_counter(counter);
+            let value = self.csr.load_u64(low, high);
+            self.csr.store_u64(low, high, value.wrapping_add(count));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::Latencies, peripheral::SDRam, test_utils::new_sdram_cpu};
+
+    const SDRAM_STALL: u32 = 1;
+
+    fn new_cpu(program: &[u32]) -> Cpu<SDRam> {
+        let timing = Latencies {
+            sdram_stall: SDRAM_STALL,
+            ..Latencies::single_cycle()
+        };
+        let mut cpu = new_sdram_cpu(program).with_timing_model(timing);
+        cpu.reset();
+        cpu.pc = 0;
+        cpu
+    }
+
+    #[test]
+    fn test_mcycle_minstret() {
+        let mut cpu = new_cpu(&[
+            0x00700293, // li t0, 7
+            0x00502023, // sw t0, 0(zero)
+            0x00002303, // lw t1, 0(zero)
+            0x0000006f, // j .
+        ]);
+
+        for _ in 0..3 {
+            cpu.clock();
+        }
+
+        assert_eq!(cpu.instructions_retired(), 3);
+        assert_eq!(cpu.cycles(), 3 + 2 * SDRAM_STALL as u64);
+        assert_eq!(cpu.csr.load(Csr::MINSTRET), 3);
+    }
+
+    #[test]
+    fn test_64_bit_counters() {
+        let mut cpu = new_cpu(&[0x00000013]); // nop
+        cpu.csr.store(Csr::MINSTRET, u32::MAX);
+        cpu.clock();
+        assert_eq!(cpu.csr.load(Csr::MINSTRET), 0);
+        assert_eq!(cpu.csr.load(Csr::MINSTRETH), 1);
+    }
+
+    #[test]
+    fn test_hpm_events() {
+        let mut cpu = new_cpu(&[
+            0x00700293, // li t0, 7
+            0x00502023, // sw t0, 0(zero)
+            0x00002303, // lw t1, 0(zero)
+            0x02628333, // mul t1, t0, t1
+            0x00628463, // beq t0, t1, 0x18
+            0x00000463, // beqz zero, 0x18
+            0x00000013, // nop
+            0x0000006f, // j .
+        ]);
+        cpu.csr.store(Csr::MHPMEVENT9, HpmEvent::Load as u32);
+
+        for _ in 0..7 {
+            cpu.clock();
+        }
+
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER3), 1); // loads
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER4), 1); // stores
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER5), 1); // branches taken
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER6), 1); // jumps
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER7), 1); // mul
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER8), 2 * SDRAM_STALL);
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER9), 1); // configured to loads
+    }
+
+    #[test]
+    fn test_mcountinhibit() {
+        let mut cpu = new_cpu(&[
+            0x00002303, // lw t1, 0(zero)
+            0x00002303, // lw t1, 0(zero)
+        ]);
+        cpu.csr.store(
+            Csr::MCOUNTINHIBIT,
+            MCOUNTINHIBIT_CY | MCOUNTINHIBIT_IR | 1 << 3,
+        );
+
+        cpu.clock();
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.instructions_retired(), 0);
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER3), 0);
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER8), SDRAM_STALL);
+
+        cpu.csr.store(Csr::MCOUNTINHIBIT, 0);
+        cpu.clock();
+        assert_eq!(cpu.instructions_retired(), 1);
+        assert_eq!(cpu.csr.load(Csr::MHPMCOUNTER3), 1);
+    }
+}
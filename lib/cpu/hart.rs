@@ -0,0 +1,269 @@
+//! The identity and extensions of the emulated hart
+//!
+//! A [HartConfig] decides what the machine information CSRs read as and which extensions the Cpu
+//! decodes. Instructions from a disabled extension raise an illegal instruction exception, just
+//! like on a core that doesn't implement it, and misa only reports the enabled extensions. It
+//! also decides whether faulting loads and stores trap, see [MemoryFaultPolicy].
+
+use crate::{csr::Csr, instruction::Instruction, peripheral::Peripheral};
+
+use super::{Cpu, InstructionClass};
+
+/// misa.MXL for a 32-bit hart
+const MXL_32: u32 = 1 << 30;
+
+fn misa_bit(extension: u8) -> u32 {
+    1 << (extension - b'A')
+}
+
+/// What happens when a load or store is misaligned or the bus rejects it
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum MemoryFaultPolicy {
+    /// Misaligned accesses are carried out. A rejected load gives 0xDE, 0xDEAD or 0xDEADBEEF
+    /// depending on its size and a rejected store is dropped, both are only reported to the
+    /// debug console.
+    #[default]
+    Lenient,
+    /// Misaligned accesses raise an address misaligned exception and rejected ones an access
+    /// fault, with the address in mtval
+    Trap,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HartConfig {
+    pub mvendorid: u32,
+    pub marchid: u32,
+    pub mimpid: u32,
+    pub mhartid: u32,
+    /// The M extension, multiplication and division
+    pub m: bool,
+    /// The C extension, compressed instructions. These can only be decoded with the `compressed`
+    /// feature, without it this does nothing.
+    pub c: bool,
+    /// The Zicsr extension, the CSR instructions. Traps and mret work without it.
+    pub zicsr: bool,
+    /// The Zicntr extension, the read-only cycle, time and instret CSRs. time counts cycles, as
+    /// the DTEK-V has no real-time clock.
+    pub zicntr: bool,
+    /// What misaligned loads and stores and the ones the bus rejects do
+    pub memory_faults: MemoryFaultPolicy,
+}
+
+impl Default for HartConfig {
+    /// The DTEK-V, which leaves all the ID CSRs at 0. C is only enabled with the `compressed`
+    /// feature.
+    fn default() -> Self {
+        HartConfig {
+            mvendorid: 0,
+            marchid: 0,
+            mimpid: 0,
+            mhartid: 0,
+            m: true,
+            c: cfg!(feature = "compressed"),
+            zicsr: true,
+            zicntr: true,
+            memory_faults: MemoryFaultPolicy::Lenient,
+        }
+    }
+}
+
+impl HartConfig {
+    /// The value of misa: a 32-bit hart with the base integer ISA and the enabled extensions that
+    /// misa has a bit for
+    pub fn misa(&self) -> u32 {
+        let mut misa = MXL_32 | misa_bit(b'I');
+        if self.m {
+            misa |= misa_bit(b'M');
+        }
+        if self.compressed() {
+            misa |= misa_bit(b'C');
+        }
+        misa
+    }
+
+    /// If compressed instructions are decoded, which needs both C and the `compressed` feature
+    pub fn compressed(&self) -> bool {
+        self.c && cfg!(feature = "compressed")
+    }
+
+    /// The alignment instructions need, 2 with compressed instructions and 4 without
+    pub fn instruction_align(&self) -> u32 {
+        if self.compressed() {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// If the enabled extensions include the instruction
+    pub fn supports(&self, instruction: Instruction) -> bool {
+        match InstructionClass::of(instruction) {
+            InstructionClass::Multiply | InstructionClass::Divide => self.m,
+            InstructionClass::Csr => self.zicsr,
+            _ => true,
+        }
+    }
+
+    /// If the CSR exists with the enabled extensions
+    pub fn implements_csr(&self, csr: Csr) -> bool {
+        match csr {
+            Csr::CYCLE | Csr::CYCLEH | Csr::TIME | Csr::TIMEH | Csr::INSTRET | Csr::INSTRETH => {
+                self.zicntr
+            }
+            _ => csr.implemented(),
+        }
+    }
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    pub fn hart_config(&self) -> &HartConfig {
+        &self.hart
+    }
+
+    /// Sets the CSRs with a fixed value, on creation and reset
+    pub(crate) fn store_hart_csrs(&mut self) {
+        self.csr.store(Csr::MISA, self.hart.misa());
+        self.csr.store(Csr::MVENDORID, self.hart.mvendorid);
+        self.csr.store(Csr::MARCHID, self.hart.marchid);
+        self.csr.store(Csr::MIMPID, self.hart.mimpid);
+        self.csr.store(Csr::MHARTID, self.hart.mhartid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interrupt::InterruptSignal, register::Register, test_utils::new_trapping_cpu_with_config,
+    };
+
+    #[test]
+    fn test_id_csrs() {
+        let hart = HartConfig {
+            mvendorid: 0x5B7,
+            marchid: 0x8000_0001,
+            mimpid: 2,
+            mhartid: 3,
+            ..Default::default()
+        };
+        let mut cpu = new_trapping_cpu_with_config(
+            hart,
+            &[
+                0xf1102573, // csrr a0, mvendorid
+                0xf12025f3, // csrr a1, marchid
+                0xf1302673, // csrr a2, mimpid
+                0xf14026f3, // csrr a3, mhartid
+            ],
+        );
+
+        for _ in 0..4 {
+            cpu.clock();
+        }
+        assert_eq!(cpu.regs.get(Register::A0), 0x5B7);
+        assert_eq!(cpu.regs.get(Register::A1), 0x8000_0001);
+        assert_eq!(cpu.regs.get(Register::A2), 2);
+        assert_eq!(cpu.regs.get(Register::A3), 3);
+
+        // The IDs survive a reset
+        cpu.reset();
+        assert_eq!(cpu.csr.load(Csr::MHARTID), 3);
+        assert_eq!(cpu.csr.load(Csr::MISA), hart.misa());
+    }
+
+    #[test]
+    fn test_misa() {
+        let hart = HartConfig::default();
+        assert_eq!(
+            hart.misa(),
+            if cfg!(feature = "compressed") {
+                0x4000_1104
+            } else {
+                0x4000_1100
+            }
+        );
+
+        let hart = HartConfig {
+            m: false,
+            c: false,
+            ..Default::default()
+        };
+        assert_eq!(hart.misa(), 0x4000_0100);
+    }
+
+    #[test]
+    fn test_disabled_m_extension() {
+        let hart = HartConfig {
+            m: false,
+            ..Default::default()
+        };
+        let mut cpu = new_trapping_cpu_with_config(
+            hart,
+            &[
+                0x02b50533, // mul a0, a0, a1
+            ],
+        );
+        cpu.regs.set(Register::A0, 3);
+        cpu.regs.set(Register::A1, 4);
+
+        cpu.clock();
+        assert_eq!(cpu.regs.get(Register::A0), 3);
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0);
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn test_disabled_zicsr() {
+        let hart = HartConfig {
+            zicsr: false,
+            ..Default::default()
+        };
+        let mut cpu = new_trapping_cpu_with_config(
+            hart,
+            &[
+                0x34029073, // csrw mscratch, t0
+            ],
+        );
+
+        cpu.clock();
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+    }
+
+    #[test]
+    fn test_zicntr() {
+        let program = [
+            0x00000013, // nop
+            0xc0002573, // rdcycle a0
+            0xc02025f3, // rdinstret a1
+            0xc0102673, // rdtime a2
+        ];
+        let mut cpu = new_trapping_cpu_with_config(HartConfig::default(), &program);
+
+        for _ in 0..4 {
+            cpu.clock();
+        }
+        assert_ne!(cpu.regs.get(Register::A0), 0);
+        assert_eq!(cpu.regs.get(Register::A1), 2);
+        assert!(cpu.regs.get(Register::A2) > cpu.regs.get(Register::A0));
+        assert_eq!(cpu.pc, 0x10);
+
+        let hart = HartConfig {
+            zicntr: false,
+            ..Default::default()
+        };
+        let mut cpu = new_trapping_cpu_with_config(hart, &program);
+        cpu.clock();
+        cpu.clock();
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 4);
+    }
+}
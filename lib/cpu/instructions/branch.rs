@@ -8,7 +8,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if rs1 == rs2 {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 
@@ -19,7 +19,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if rs1 != rs2 {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 
@@ -30,7 +30,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if (rs1 as i32) < (rs2 as i32) {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 
@@ -41,7 +41,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if (rs1 as i32) >= (rs2 as i32) {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 
@@ -52,7 +52,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if rs1 < rs2 {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 
@@ -63,7 +63,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         if rs1 >= rs2 {
             self.pc = self.pc.wrapping_add(imm);
         } else {
-            self.pc += 4;
+            self.pc += self.instruction_len;
         }
     }
 }
@@ -1,4 +1,10 @@
-use crate::{cpu::Cpu, csr::Csr, peripheral::Peripheral, register::Register};
+use crate::{
+    cpu::Cpu,
+    csr::{Csr, CsrError},
+    interrupt::InterruptSignal,
+    peripheral::Peripheral,
+    register::Register,
+};
 
 fn debug_console_csr_helper<T: Peripheral<()>>(cpu: &mut Cpu<T>, csr: Csr) {
     #[cfg(feature = "debug-console")]
@@ -9,66 +15,210 @@ fn debug_console_csr_helper<T: Peripheral<()>>(cpu: &mut Cpu<T>, csr: Csr) {
     }
 }
 
-fn debug_console_not_implemented<T: Peripheral<()>>(cpu: &mut Cpu<T>, instruction: &'static str) {
+fn debug_console_illegal_access<T: Peripheral<()>>(cpu: &mut Cpu<T>, csr: Csr, error: CsrError) {
     #[cfg(feature = "debug-console")]
     if let Some(db) = &cpu.debug_console {
-        db.borrow_mut()
-            .instruction_not_implemented(instruction, cpu.pc);
+        db.borrow_mut().illegal_csr_access(csr, error, cpu.pc);
     }
 }
 
 impl<T: Peripheral<()>> Cpu<T> {
-    pub(crate) fn csrrw(&mut self, rs1: Register, csr: Csr, rd: Register) {
+    /// Reads the CSR into rd and writes back what `update` returns for the old value. `update`
+    /// returns None when csrrs and csrrc have nothing to set or clear, they don't write at all
+    /// then, so read-only CSRs can be read with them. An illegal access raises an exception and
+    /// leaves both the CSR and rd untouched.
+    fn access_csr(&mut self, csr: Csr, rd: Register, update: impl FnOnce(u32) -> Option<u32>) {
         debug_console_csr_helper(self, csr);
 
-        let value = self.csr.load(csr);
-        self.csr.store(csr, self.regs.get(rs1));
-        self.regs.set(rd, value);
-        self.pc += 4;
+        let result = if self.hart.implements_csr(csr) {
+            self.csr.read(csr)
+        } else {
+            Err(CsrError::Unimplemented)
+        };
+        let result = result.and_then(|value| {
+            if let Some(new_value) = update(value) {
+                self.csr.write(csr, new_value)?;
+            }
+            Ok(value)
+        });
+
+        match result {
+            Ok(value) => {
+                self.regs.set(rd, value);
+                self.pc += self.instruction_len;
+            }
+            Err(error) => {
+                debug_console_illegal_access(self, csr, error);
+                self.raise_exception(InterruptSignal::ILLEGAL_INSTRUCTION);
+            }
+        }
     }
 
-    pub(crate) fn csrrs(&mut self, rs1: Register, csr: Csr, rd: Register) {
-        debug_console_csr_helper(self, csr);
+    pub(crate) fn csrrw(&mut self, rs1: Register, csr: Csr, rd: Register) {
+        let rs1 = self.regs.get(rs1);
+        self.access_csr(csr, rd, |_| Some(rs1));
+    }
 
-        let value = self.csr.load(csr);
-        self.csr.store(csr, self.csr.load(csr) | self.regs.get(rs1));
-        self.regs.set(rd, value);
-        self.pc += 4;
+    pub(crate) fn csrrs(&mut self, rs1: Register, csr: Csr, rd: Register) {
+        let writes = rs1 != Register::ZERO;
+        let rs1 = self.regs.get(rs1);
+        self.access_csr(csr, rd, |value| writes.then_some(value | rs1));
     }
 
     pub(crate) fn csrrc(&mut self, rs1: Register, csr: Csr, rd: Register) {
-        debug_console_csr_helper(self, csr);
-
-        let value = self.csr.load(csr);
-        self.csr
-            .store(csr, self.csr.load(csr) & !self.regs.get(rs1));
-        self.regs.set(rd, value);
-        self.pc += 4;
+        let writes = rs1 != Register::ZERO;
+        let rs1 = self.regs.get(rs1);
+        self.access_csr(csr, rd, |value| writes.then_some(value & !rs1));
     }
 
-    pub(crate) fn csrrwi(&mut self, _imm: u32, _csr: Csr, _rd: Register) {
-        debug_console_not_implemented(self, "CSRRWI");
-
-        self.pc += 4;
+    pub(crate) fn csrrwi(&mut self, imm: u32, csr: Csr, rd: Register) {
+        self.access_csr(csr, rd, |_| Some(imm));
     }
 
     pub(crate) fn csrrsi(&mut self, imm: u32, csr: Csr, rd: Register) {
-        debug_console_csr_helper(self, csr);
-
-        let value = self.csr.load(csr);
-        self.csr.store(csr, self.csr.load(csr) | (1 << imm));
-        self.regs.set(rd, value);
-        self.pc += 4;
+        self.access_csr(csr, rd, |value| (imm != 0).then_some(value | imm));
     }
 
-    pub(crate) fn csrrci(&mut self, _imm: u32, _csr: Csr, _rd: Register) {
-        debug_console_not_implemented(self, "CSRRCI");
-
-        self.pc += 4;
+    pub(crate) fn csrrci(&mut self, imm: u32, csr: Csr, rd: Register) {
+        self.access_csr(csr, rd, |value| (imm != 0).then_some(value & !imm));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    // TODO: Add tests
+    use super::*;
+    use crate::{cpu::HartConfig, test_utils::*};
+
+    #[test]
+    fn test_csrrw() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.pc = 0;
+        cpu.regs.set(Register::T0, 0x1234);
+        cpu.csr.store(Csr::MSCRATCH, 0x5678);
+
+        cpu.csrrw(Register::T0, Csr::MSCRATCH, Register::T0);
+        assert_eq!(cpu.regs.get(Register::T0), 0x5678);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0x1234);
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_csrrs_csrrc() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.regs.set(Register::T0, 0b1100);
+        cpu.csr.store(Csr::MSCRATCH, 0b1010);
+
+        cpu.csrrs(Register::T0, Csr::MSCRATCH, Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0b1010);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0b1110);
+
+        cpu.csrrc(Register::T0, Csr::MSCRATCH, Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0b1110);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0b0010);
+    }
+
+    #[test]
+    fn test_immediate_variants() {
+        let mut cpu = new_panic_io_cpu();
+
+        cpu.csrrwi(0b10101, Csr::MSCRATCH, Register::T0);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0b10101);
+
+        // The immediate is a mask, not a bit index
+        cpu.csrrsi(0b01010, Csr::MSCRATCH, Register::T0);
+        assert_eq!(cpu.regs.get(Register::T0), 0b10101);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0b11111);
+
+        cpu.csrrci(0b00110, Csr::MSCRATCH, Register::T0);
+        assert_eq!(cpu.regs.get(Register::T0), 0b11111);
+        assert_eq!(cpu.csr.load(Csr::MSCRATCH), 0b11001);
+    }
+
+    #[test]
+    fn test_read_only_csrs() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.pc = 0x100;
+        cpu.regs.set(Register::T0, 1);
+
+        // Reads without writing are allowed
+        cpu.csrrs(Register::ZERO, Csr::MHARTID, Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0);
+        cpu.csrrci(0, Csr::MVENDORID, Register::T1);
+        assert_eq!(cpu.pc, 0x108);
+
+        // Even if nothing would change, the write isn't allowed
+        cpu.regs.set(Register::T1, 0x1234);
+        cpu.csrrc(Register::T0, Csr::MARCHID, Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0x1234);
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x108);
+    }
+
+    #[test]
+    fn test_unimplemented_csr() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.pc = 0x100;
+        let csr = Csr::new(0x7C0).unwrap();
+
+        cpu.csrrs(Register::ZERO, csr, Register::T0);
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x100);
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn test_warl() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.regs.set(Register::T0, u32::MAX);
+
+        cpu.csrrw(Register::T0, Csr::MSTATUS, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MSTATUS), 0x88);
+
+        cpu.csrrw(Register::T0, Csr::MIE, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MIE), 0xFFFF_0888);
+
+        // misa is writable but ignores writes
+        cpu.csrrw(Register::T0, Csr::MISA, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MISA), HartConfig::default().misa());
+
+        // The reserved modes are turned into direct mode
+        cpu.regs.set(Register::T0, 0x1002);
+        cpu.csrrw(Register::T0, Csr::MTVEC, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MTVEC), 0x1000);
+        cpu.csrrwi(1, Csr::MTVEC, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MTVEC), 1);
+    }
+
+    #[test]
+    fn test_mepc_alignment() {
+        let hart = HartConfig {
+            c: false,
+            ..HartConfig::default()
+        };
+        let mut cpu = new_sdram_cpu_with_config(hart, &[]);
+        cpu.regs.set(Register::T0, 0x107);
+        cpu.csrrw(Register::T0, Csr::MEPC, Register::ZERO);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x104);
+
+        let hart = HartConfig {
+            c: true,
+            ..HartConfig::default()
+        };
+        let mut cpu = new_sdram_cpu_with_config(hart, &[]);
+        cpu.regs.set(Register::T0, 0x107);
+        cpu.csrrw(Register::T0, Csr::MEPC, Register::ZERO);
+        // Compressed instructions only exist with the `compressed` feature
+        let expected = if cfg!(feature = "compressed") {
+            0x106
+        } else {
+            0x104
+        };
+        assert_eq!(cpu.csr.load(Csr::MEPC), expected);
+    }
 }
@@ -10,28 +10,28 @@ impl<T: Peripheral<()>> Cpu<T> {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         self.regs.set(rd, rs1.wrapping_add(imm));
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn andi(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         self.regs.set(rd, rs1 & imm);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn ori(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         self.regs.set(rd, rs1 | imm);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn xori(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         self.regs.set(rd, rs1 ^ imm);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn slli(&mut self, rs1: Register, imm: ShamtImm, rd: Register) {
@@ -42,7 +42,7 @@ impl<T: Peripheral<()>> Cpu<T> {
             "When creating an SLLI instruction, the imm value should be less than 32"
         );
         self.regs.set(rd, rs1 << imm);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn srli(&mut self, rs1: Register, imm: ShamtImm, rd: Register) {
@@ -53,7 +53,7 @@ impl<T: Peripheral<()>> Cpu<T> {
             "When creating an SRLI instruction, the imm value should be less than 32"
         );
         self.regs.set(rd, rs1 >> imm);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn srai(&mut self, rs1: Register, imm: ShamtImm, rd: Register) {
@@ -64,7 +64,7 @@ impl<T: Peripheral<()>> Cpu<T> {
             "When creating an SRAI instruction, the imm value should be less than 32"
         );
         self.regs.set(rd, ((rs1 as i32) >> imm) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn slti(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
@@ -72,14 +72,14 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1);
         self.regs
             .set(rd, if (rs1 as i32) < (imm as i32) { 1 } else { 0 });
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sltiu(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         self.regs.set(rd, if rs1 < imm { 1 } else { 0 });
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 }
 
@@ -8,24 +8,24 @@ use crate::{
 impl<T: Peripheral<()>> Cpu<T> {
     pub(crate) fn lui(&mut self, imm: UTypeImm, rd: Register) {
         self.regs.set(rd, imm.as_u32());
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn auipc(&mut self, imm: UTypeImm, rd: Register) {
         self.regs.set(rd, self.pc.wrapping_add(imm.as_u32()));
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn jal(&mut self, imm: JTypeImm, rd: Register) {
         let imm = imm.as_u32();
-        self.regs.set(rd, self.pc + 4);
+        self.regs.set(rd, self.pc + self.instruction_len);
         self.pc = self.pc.wrapping_add(imm);
     }
 
     pub(crate) fn jalr(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
-        self.regs.set(rd, self.pc + 4);
+        self.regs.set(rd, self.pc + self.instruction_len);
         self.pc = rs1.wrapping_add(imm);
     }
 }
@@ -1,6 +1,7 @@
 use crate::{
-    cpu::Cpu,
+    cpu::{Cpu, MemoryFaultPolicy},
     instruction::{ITypeImm, STypeImm},
+    interrupt::InterruptSignal,
     memory_mapped::MemoryMapped,
     peripheral::Peripheral,
     register::Register,
@@ -21,19 +22,69 @@ fn debug_console_store_oob<T: Peripheral<()>>(cpu: &mut Cpu<T>, addr: u32) {
 }
 
 impl<T: Peripheral<()>> Cpu<T> {
+    /// Loads `size` bytes at addr with `load`, following the memory fault policy. None if an
+    /// exception was raised, otherwise the loaded value or `garbage` if the bus rejected it.
+    fn load<V>(
+        &mut self,
+        addr: u32,
+        size: u32,
+        load: impl FnOnce(&Self, u32) -> Result<V, ()>,
+        garbage: V,
+    ) -> Option<V> {
+        let trap = self.hart.memory_faults == MemoryFaultPolicy::Trap;
+        if trap && addr & (size - 1) != 0 {
+            self.raise_exception_with_value(InterruptSignal::LOAD_ADDRESS_MISALIGNED, addr);
+            return None;
+        }
+
+        match load(self, addr) {
+            Ok(value) => Some(value),
+            Err(()) => {
+                debug_console_load_oob(self, addr);
+                if trap {
+                    self.raise_exception_with_value(InterruptSignal::LOAD_ACCESS_FAULT, addr);
+                    return None;
+                }
+                Some(garbage)
+            }
+        }
+    }
+
+    /// Stores `size` bytes at addr with `store`, following the memory fault policy. Returns false
+    /// if an exception was raised.
+    fn store(
+        &mut self,
+        addr: u32,
+        size: u32,
+        store: impl FnOnce(&mut Self, u32) -> Result<(), ()>,
+    ) -> bool {
+        let trap = self.hart.memory_faults == MemoryFaultPolicy::Trap;
+        if trap && addr & (size - 1) != 0 {
+            self.raise_exception_with_value(InterruptSignal::STORE_ADDRESS_MISALIGNED, addr);
+            return false;
+        }
+
+        if store(self, addr).is_err() {
+            debug_console_store_oob(self, addr);
+            if trap {
+                self.raise_exception_with_value(InterruptSignal::STORE_ACCESS_FAULT, addr);
+                return false;
+            }
+        }
+        true
+    }
+
     pub(crate) fn lb(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         let addr = rs1.wrapping_add(imm);
 
-        let byte = self.load_byte(addr).unwrap_or_else(|_| {
-            debug_console_load_oob(self, addr);
-
-            0xDE
-        }) as i8 as i32 as u32;
+        let Some(byte) = self.load(addr, 1, Self::load_byte, 0xDE) else {
+            return;
+        };
 
-        self.regs.set(rd, byte);
-        self.pc += 4;
+        self.regs.set(rd, byte as i8 as i32 as u32);
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn lh(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
@@ -41,28 +92,25 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1);
         let addr = rs1.wrapping_add(imm);
 
-        let halfword = self.load_halfword(addr).unwrap_or_else(|_| {
-            debug_console_load_oob(self, addr);
-
-            0xDEAD
-        }) as i16 as i32 as u32;
+        let Some(halfword) = self.load(addr, 2, Self::load_halfword, 0xDEAD) else {
+            return;
+        };
 
-        self.regs.set(rd, halfword);
-        self.pc += 4;
+        self.regs.set(rd, halfword as i16 as i32 as u32);
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn lw(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
         let imm = imm.as_u32();
         let rs1 = self.regs.get(rs1);
         let addr = rs1.wrapping_add(imm);
-        let word = self.load_word(addr).unwrap_or_else(|_| {
-            debug_console_load_oob(self, addr);
 
-            0xDEAD_BEEF
-        });
+        let Some(word) = self.load(addr, 4, Self::load_word, 0xDEAD_BEEF) else {
+            return;
+        };
 
         self.regs.set(rd, word);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn lbu(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
@@ -70,14 +118,12 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1);
         let addr = rs1.wrapping_add(imm);
 
-        let byte = self.load_byte(addr).unwrap_or_else(|_| {
-            debug_console_load_oob(self, addr);
-
-            0xDE
-        });
+        let Some(byte) = self.load(addr, 1, Self::load_byte, 0xDE) else {
+            return;
+        };
 
         self.regs.set(rd, byte as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn lhu(&mut self, rs1: Register, imm: ITypeImm, rd: Register) {
@@ -85,14 +131,12 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1);
         let addr = rs1.wrapping_add(imm);
 
-        let halfword = self.load_halfword(addr).unwrap_or_else(|_| {
-            debug_console_load_oob(self, addr);
-
-            0xDEAD
-        });
+        let Some(halfword) = self.load(addr, 2, Self::load_halfword, 0xDEAD) else {
+            return;
+        };
 
         self.regs.set(rd, halfword as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sb(&mut self, rs1: Register, rs2: Register, imm: STypeImm) {
@@ -101,11 +145,9 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs2 = self.regs.get(rs2);
         let addr = rs1.wrapping_add(imm);
 
-        if self.store_byte(addr, rs2 as u8).is_err() {
-            debug_console_store_oob(self, addr);
+        if self.store(addr, 1, |cpu, addr| cpu.store_byte(addr, rs2 as u8)) {
+            self.pc += self.instruction_len;
         }
-
-        self.pc += 4;
     }
 
     pub(crate) fn sh(&mut self, rs1: Register, rs2: Register, imm: STypeImm) {
@@ -114,11 +156,9 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs2 = self.regs.get(rs2);
         let addr = rs1.wrapping_add(imm);
 
-        if self.store_halfword(addr, rs2 as u16).is_err() {
-            debug_console_store_oob(self, addr);
+        if self.store(addr, 2, |cpu, addr| cpu.store_halfword(addr, rs2 as u16)) {
+            self.pc += self.instruction_len;
         }
-
-        self.pc += 4;
     }
 
     pub(crate) fn sw(&mut self, rs1: Register, rs2: Register, imm: STypeImm) {
@@ -127,18 +167,16 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs2 = self.regs.get(rs2);
         let addr = rs1.wrapping_add(imm);
 
-        if self.store_word(addr, rs2).is_err() {
-            debug_console_store_oob(self, addr);
+        if self.store(addr, 4, |cpu, addr| cpu.store_word(addr, rs2)) {
+            self.pc += self.instruction_len;
         }
-
-        self.pc += 4;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::*;
+    use crate::{cpu::HartConfig, csr::Csr, test_utils::*};
     use test_case::test_case;
 
     /// Helper to create imm value without the boilerplate
@@ -176,8 +214,10 @@ mod tests {
                 assert_eq!(
                     cpu.regs.get(Register::RA),
                     out,
-                    "{}",
-                    format!("i: {}, inp: {}, out: {}", i, inp, out)
+                    "i: {}, inp: {}, out: {}",
+                    i,
+                    inp,
+                    out
                 );
             }
         }
@@ -195,12 +235,7 @@ mod tests {
             for i in 0..4 {
                 cpu.store_word(i, v).unwrap();
                 cpu.lw(Register::ZERO, imm!(i), Register::RA);
-                assert_eq!(
-                    cpu.regs.get(Register::RA),
-                    v,
-                    "{}",
-                    format!("i: {}, value: {}", i, v)
-                );
+                assert_eq!(cpu.regs.get(Register::RA), v, "i: {}, value: {}", i, v);
             }
         }
     }
@@ -233,8 +268,10 @@ mod tests {
                 assert_eq!(
                     cpu.regs.get(Register::RA),
                     out,
-                    "{}",
-                    format!("i: {}, inp: {}, out: {}", i, inp, out)
+                    "i: {}, inp: {}, out: {}",
+                    i,
+                    inp,
+                    out
                 );
             }
         }
@@ -309,7 +346,7 @@ mod tests {
         let sdram = &mut s.sdram;
 
         cpu.regs.set(Register::T0, data.rs1);
-        cpu.regs.set(Register::T1, data.value as u32);
+        cpu.regs.set(Register::T1, data.value);
         cpu.sw(
             Register::T0,
             Register::T1,
@@ -317,4 +354,68 @@ mod tests {
         );
         sdram.load_word(data.exp_addr).unwrap()
     }
+
+    fn new_fault_trapping_cpu() -> Cpu<crate::peripheral::SDRam> {
+        let hart = HartConfig {
+            memory_faults: MemoryFaultPolicy::Trap,
+            ..Default::default()
+        };
+        let mut cpu = new_trapping_cpu_with_config(hart, &[]);
+        cpu.pc = 0x10;
+        cpu
+    }
+
+    #[test_case(0x4002, InterruptSignal::LOAD_ADDRESS_MISALIGNED; "misaligned")]
+    #[test_case(0x7000_0000, InterruptSignal::LOAD_ACCESS_FAULT; "rejected by the bus")]
+    fn test_load_fault(addr: u32, exception: InterruptSignal) {
+        let mut cpu = new_fault_trapping_cpu();
+        cpu.regs.set(Register::T0, addr);
+        cpu.regs.set(Register::T1, 0x1234);
+
+        cpu.lw(Register::T0, imm!(0), Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0x1234);
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), exception.mcause());
+        assert_eq!(cpu.csr.load(Csr::MTVAL), addr);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x10);
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test_case(0x4001, InterruptSignal::STORE_ADDRESS_MISALIGNED; "misaligned")]
+    #[test_case(0x7000_0000, InterruptSignal::STORE_ACCESS_FAULT; "rejected by the bus")]
+    fn test_store_fault(addr: u32, exception: InterruptSignal) {
+        let mut cpu = new_fault_trapping_cpu();
+        cpu.regs.set(Register::T0, addr);
+        cpu.regs.set(Register::T1, 0x1234);
+
+        cpu.sh(Register::T0, Register::T1, imm!(0));
+        assert_eq!(cpu.bus.load_word(0x4000), Ok(0));
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), exception.mcause());
+        assert_eq!(cpu.csr.load(Csr::MTVAL), addr);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x10);
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn test_aligned_access_with_trap_policy() {
+        let mut cpu = new_fault_trapping_cpu();
+        cpu.regs.set(Register::T0, 0x4002);
+        cpu.regs.set(Register::T1, 0xABCD);
+
+        cpu.sh(Register::T0, Register::T1, imm!(0));
+        cpu.lhu(Register::T0, imm!(0), Register::T2);
+        assert_eq!(cpu.regs.get(Register::T2), 0xABCD);
+        assert_eq!(cpu.pc, 0x18);
+    }
+
+    #[test]
+    fn test_lenient_bus_error() {
+        let mut cpu = Cpu::new_with_bus(crate::peripheral::SDRam::new());
+        cpu.pc = 0x10;
+        cpu.regs.set(Register::T0, 0x7000_0000);
+
+        cpu.lw(Register::T0, imm!(0), Register::T1);
+        assert_eq!(cpu.regs.get(Register::T1), 0xDEAD_BEEF);
+        cpu.sw(Register::T0, Register::T1, imm!(0));
+        assert_eq!(cpu.pc, 0x18);
+    }
 }
@@ -19,7 +19,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1) as i32 as i64;
         let rs2 = self.regs.get(rs2) as i32 as i64;
         self.regs.set(rd, rs1.wrapping_mul(rs2) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn mulh(&mut self, rs1: Register, rs2: Register, rd: Register) {
@@ -27,23 +27,23 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs2 = self.regs.get(rs2) as i32 as i64;
         let result = rs1.wrapping_mul(rs2);
         self.regs.set(rd, (result >> 32) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn mulhu(&mut self, rs1: Register, rs2: Register, rd: Register) {
-        let rs1 = self.regs.get(rs1) as u32 as u64;
-        let rs2 = self.regs.get(rs2) as u32 as u64;
+        let rs1 = self.regs.get(rs1) as u64;
+        let rs2 = self.regs.get(rs2) as u64;
         let result = rs1.wrapping_mul(rs2);
         self.regs.set(rd, (result >> 32) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn mulhsu(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1) as i32 as i64;
-        let rs2 = self.regs.get(rs2) as u32 as i64;
+        let rs2 = self.regs.get(rs2) as i64;
         let result = rs1.wrapping_mul(rs2);
         self.regs.set(rd, (result >> 32) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn div(&mut self, rs1: Register, rs2: Register, rd: Register) {
@@ -60,20 +60,21 @@ impl<T: Peripheral<()>> Cpu<T> {
                 self.regs.set(rd, (rs1 / rs2) as u32);
             }
         }
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn divu(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
-        if rs2 == 0 {
-            debug_console_division_by_zero(self);
+        match rs1.checked_div(rs2) {
+            Some(value) => self.regs.set(rd, value),
+            None => {
+                debug_console_division_by_zero(self);
 
-            self.regs.set(rd, 0xFFFFFFFF);
-        } else {
-            self.regs.set(rd, rs1 / rs2);
+                self.regs.set(rd, 0xFFFFFFFF);
+            }
         }
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn rem(&mut self, rs1: Register, rs2: Register, rd: Register) {
@@ -90,7 +91,7 @@ impl<T: Peripheral<()>> Cpu<T> {
                 self.regs.set(rd, (rs1 % rs2) as u32);
             }
         }
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn remu(&mut self, rs1: Register, rs2: Register, rd: Register) {
@@ -103,7 +104,7 @@ impl<T: Peripheral<()>> Cpu<T> {
         } else {
             self.regs.set(rd, rs1 % rs2);
         }
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 }
 
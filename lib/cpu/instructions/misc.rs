@@ -1,4 +1,12 @@
-use crate::{cpu::Cpu, csr::Csr, interrupt::InterruptSignal, peripheral::Peripheral};
+use crate::{
+    cpu::Cpu,
+    csr::Csr,
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    peripheral::Peripheral,
+    register::Register,
+    semihosting::{Call, SEMIHOSTING_ENTRY, SEMIHOSTING_EXIT},
+};
 
 impl<T: Peripheral<()>> Cpu<T> {
     pub(crate) fn mret(&mut self) {
@@ -8,7 +16,107 @@ impl<T: Peripheral<()>> Cpu<T> {
     }
 
     pub(crate) fn ecall(&mut self) {
-        self.pc += 4;
-        self.handle_interrupt(InterruptSignal::ENVIRONMENT_CALL_FROM_M_MODE);
+        self.raise_exception(InterruptSignal::ENVIRONMENT_CALL_FROM_M_MODE);
+    }
+
+    pub(crate) fn ebreak(&mut self) {
+        if self.semihosting.is_some() && self.is_semihosting_call() {
+            self.semihosting_call();
+        } else if self.ebreak_to_debugger {
+            // Nothing happens, the debugger takes over with the Cpu still at the ebreak
+            self.exception = Some(InterruptSignal::BREAKPOINT);
+        } else {
+            self.raise_exception_with_value(InterruptSignal::BREAKPOINT, self.pc);
+        }
+    }
+
+    /// If the ebreak at pc is surrounded by the nops that mark a semihosting call. All three
+    /// have to be 32-bit instructions.
+    fn is_semihosting_call(&self) -> bool {
+        let Some(entry) = self.pc.checked_sub(4) else {
+            return false;
+        };
+
+        self.instruction_len == 4
+            && self.bus.peek_word(entry) == Ok(SEMIHOSTING_ENTRY)
+            && self.bus.peek_word(self.pc.wrapping_add(4)) == Ok(SEMIHOSTING_EXIT)
+    }
+
+    /// Carries out the call in a0 and continues after the ebreak. SYS_EXIT leaves the Cpu at the
+    /// ebreak instead, so running it again doesn't get past the exit.
+    fn semihosting_call(&mut self) {
+        if self.journal_replaying() {
+            // The host already saw this call, it only gets the result from the first time
+            if let Some((value, stores)) = self.replayed_host_call() {
+                for (addr, byte) in stores {
+                    let _ = self.store_byte(addr, byte);
+                }
+                self.regs.set(Register::A0, value);
+                self.pc += self.instruction_len;
+            }
+            return;
+        }
+
+        let Some(mut semihosting) = self.semihosting.take() else {
+            return;
+        };
+
+        let op = self.regs.get(Register::A0);
+        let param = self.regs.get(Register::A1);
+        let cycles = self.cycles();
+        let mut memory = HostMemory {
+            cpu: self,
+            stores: Vec::new(),
+        };
+        let call = semihosting.call(op, param, &mut memory, cycles);
+        let stores = memory.stores;
+        self.semihosting = Some(semihosting);
+
+        if let Call::Return(value) = call {
+            self.journal_host_call(value, stores);
+            self.regs.set(Register::A0, value);
+            self.pc += self.instruction_len;
+        }
+    }
+
+    /// Starts waiting unless an interrupt that would wake the Cpu is already pending
+    pub(crate) fn wfi(&mut self) {
+        if self.csr.load(Csr::MIP) & self.csr.load(Csr::MIE) == 0 {
+            self.waiting_in_wfi = Some(self.pc);
+        }
+        self.pc += self.instruction_len;
+    }
+
+    /// Memory accesses happen in order on the DTEK-V, so there is nothing to wait for
+    pub(crate) fn fence(&mut self) {
+        self.pc += self.instruction_len;
+    }
+
+    /// Drops the whole instruction cache, so instructions written by the program are decoded again
+    pub(crate) fn fence_i(&mut self) {
+        self.instruction_cache.fill(None);
+        self.pc += self.instruction_len;
+    }
+}
+
+/// The memory a semihosting call accesses, keeping track of what the call stored so that the
+/// journal can replay it
+struct HostMemory<'a, T: Peripheral<()>> {
+    cpu: &'a mut Cpu<T>,
+    stores: Vec<(u32, u8)>,
+}
+
+impl<T: Peripheral<()>> MemoryMapped<()> for HostMemory<'_, T> {
+    fn load_byte(&self, addr: u32) -> Result<u8, ()> {
+        self.cpu.load_byte(addr)
+    }
+
+    fn store_byte(&mut self, addr: u32, byte: u8) -> Result<(), ()> {
+        self.stores.push((addr, byte));
+        self.cpu.store_byte(addr, byte)
+    }
+
+    fn load_word(&self, addr: u32) -> Result<u32, ()> {
+        self.cpu.load_word(addr)
     }
 }
@@ -7,14 +7,14 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, rs1.wrapping_add(rs2));
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sub(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, rs1.wrapping_sub(rs2));
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn slt(&mut self, rs1: Register, rs2: Register, rd: Register) {
@@ -22,56 +22,56 @@ impl<T: Peripheral<()>> Cpu<T> {
         let rs2 = self.regs.get(rs2);
         self.regs
             .set(rd, if (rs1 as i32) < (rs2 as i32) { 1 } else { 0 });
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sltu(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, if rs1 < rs2 { 1 } else { 0 });
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sll(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2) & XLEN_MASK;
         self.regs.set(rd, rs1 << rs2);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn srl(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2) & XLEN_MASK;
         self.regs.set(rd, rs1.wrapping_shr(rs2));
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn sra(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2) & XLEN_MASK;
         self.regs.set(rd, (rs1 as i32).wrapping_shr(rs2) as u32);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn and(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, rs1 & rs2);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn or(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, rs1 | rs2);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 
     pub(crate) fn xor(&mut self, rs1: Register, rs2: Register, rd: Register) {
         let rs1 = self.regs.get(rs1);
         let rs2 = self.regs.get(rs2);
         self.regs.set(rd, rs1 ^ rs2);
-        self.pc += 4;
+        self.pc += self.instruction_len;
     }
 }
 
@@ -0,0 +1,719 @@
+//! Undo journal for stepping backwards
+//!
+//! While the journal is enabled every step of the Cpu records what it's about to change: pc, the
+//! registers and CSRs it wrote and the state behind every store, including stores to peripherals.
+//! [Cpu::step_back] undoes the recorded steps one by one.
+//!
+//! The journal only keeps a limited number of steps. To rewind further the Cpu also takes a
+//! snapshot every [JournalConfig::checkpoint_interval] steps. Rewinding past the journal restores
+//! the closest checkpoint and executes forward again to the requested step. Replaying is only
+//! exact if the peripherals behave the same way the second time, which isn't the case for input
+//! from the user or a timer driven by wall-clock time. Replayed steps don't reach the host again:
+//! the UART doesn't pass their output on to its sink a second time and semihosting calls return
+//! what they returned the first time, without calling the host.
+//!
+//! Undoing a store to a peripheral restores the peripheral to what it was before the store, so
+//! the frontend shows what it showed back then. Characters the frontend already read from the
+//! UART can't be taken back, [UART::take_retracted](crate::peripheral::UART::take_retracted)
+//! tells it how many to remove instead. Characters the program read from the UART aren't put
+//! back either, and neither are events the [scheduler](crate::scheduler) already handled.
+
+use std::collections::VecDeque;
+
+use crate::{
+    csr::Csr,
+    instruction::Instruction,
+    peripheral::Peripheral,
+    register::{Register, RegisterBlock},
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+use super::Cpu;
+
+/// CSRs that can change without the instruction naming them, by traps or the counters
+const TRACKED_CSRS: [Csr; 23] = [
+    Csr::MSTATUS,
+    Csr::MEPC,
+    Csr::MCAUSE,
+    Csr::MTVAL,
+    Csr::MIP,
+    Csr::MCYCLE,
+    Csr::MCYCLEH,
+    Csr::MINSTRET,
+    Csr::MINSTRETH,
+    Csr::MHPMCOUNTER3,
+    Csr::MHPMCOUNTER3H,
+    Csr::MHPMCOUNTER4,
+    Csr::MHPMCOUNTER4H,
+    Csr::MHPMCOUNTER5,
+    Csr::MHPMCOUNTER5H,
+    Csr::MHPMCOUNTER6,
+    Csr::MHPMCOUNTER6H,
+    Csr::MHPMCOUNTER7,
+    Csr::MHPMCOUNTER7H,
+    Csr::MHPMCOUNTER8,
+    Csr::MHPMCOUNTER8H,
+    Csr::MHPMCOUNTER9,
+    Csr::MHPMCOUNTER9H,
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct JournalConfig {
+    /// The number of steps kept in the journal, older steps can only be reached through a
+    /// checkpoint
+    pub max_entries: usize,
+    /// Steps between checkpoints, 0 disables checkpoints
+    pub checkpoint_interval: u64,
+    /// The number of checkpoints kept, the oldest is dropped when a new one is taken
+    pub max_checkpoints: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            max_entries: 100_000,
+            checkpoint_interval: 100_000,
+            max_checkpoints: 10,
+        }
+    }
+}
+
+/// The state behind a store, written by [Peripheral::save_store_state]
+#[derive(Debug, Clone)]
+struct Store {
+    addr: u32,
+    size: u32,
+    state: Vec<u8>,
+}
+
+/// Everything a single step changed, with the values from before the step
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    pc: u32,
+    regs: Vec<(Register, u32)>,
+    csrs: Vec<(Csr, u32)>,
+    stores: Vec<Store>,
+}
+
+/// What a semihosting call did, so that replaying the step doesn't call the host again
+#[derive(Debug, Clone)]
+struct HostCall {
+    /// The position of the step that made the call
+    position: u64,
+    /// The value returned in a0
+    value: u32,
+    /// The bytes the call stored, like the ones SYS_READ read
+    stores: Vec<(u32, u8)>,
+}
+
+/// The state from before the step that is being recorded, compared with the state after it to
+/// find out what changed
+#[derive(Debug, Clone)]
+struct Recording {
+    entry: Entry,
+    regs: RegisterBlock,
+    csrs: [u32; TRACKED_CSRS.len()],
+}
+
+#[derive(Debug)]
+pub(crate) struct Journal {
+    config: JournalConfig,
+    entries: VecDeque<Entry>,
+    /// Snapshots taken before the step at the given position
+    checkpoints: VecDeque<(u64, Vec<u8>)>,
+    /// The number of steps taken since the journal was enabled
+    position: u64,
+    recording: Option<Recording>,
+    /// Semihosting calls made by steps that can still be replayed, oldest first
+    host_calls: VecDeque<HostCall>,
+    /// Set while steps are executed again from a checkpoint
+    replaying: bool,
+}
+
+impl Journal {
+    fn new(config: JournalConfig) -> Self {
+        Journal {
+            config,
+            entries: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            position: 0,
+            recording: None,
+            host_calls: VecDeque::new(),
+            replaying: false,
+        }
+    }
+
+    /// Drops everything recorded, used when the machine state is replaced
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.checkpoints.clear();
+        self.recording = None;
+        self.host_calls.clear();
+    }
+
+    fn oldest_reachable(&self) -> u64 {
+        let journal = self.position - self.entries.len() as u64;
+        match self.checkpoints.front() {
+            Some((position, _)) => journal.min(*position),
+            None => journal,
+        }
+    }
+
+    /// Drops the semihosting calls of steps that can't be reached anymore
+    fn prune_host_calls(&mut self) {
+        let oldest = self.oldest_reachable();
+        while self
+            .host_calls
+            .front()
+            .is_some_and(|call| call.position < oldest)
+        {
+            self.host_calls.pop_front();
+        }
+    }
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// Starts recording every step so that it can be undone with [Cpu::step_back]
+    pub fn enable_journal(&mut self, config: JournalConfig) {
+        self.journal = Some(Box::new(Journal::new(config)));
+    }
+
+    pub fn disable_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// How many steps [Cpu::step_back] can rewind
+    pub fn max_step_back(&self) -> u64 {
+        self.journal
+            .as_ref()
+            .map_or(0, |journal| journal.position - journal.oldest_reachable())
+    }
+
+    /// Rewinds `n` steps and returns how many steps were actually rewound, which is less than `n`
+    /// if the journal doesn't go back that far
+    pub fn step_back(&mut self, n: u64) -> u64 {
+        let Some(journal) = &self.journal else {
+            return 0;
+        };
+
+        let start = journal.position;
+        let target = start.saturating_sub(n).max(journal.oldest_reachable());
+
+        while self.journal.as_ref().is_some_and(|j| j.position > target) {
+            let journal = self.journal.as_mut().unwrap();
+            let Some(entry) = journal.entries.pop_back() else {
+                break;
+            };
+            journal.position -= 1;
+            self.undo(entry);
+        }
+
+        let journal = self.journal.as_mut().unwrap();
+        if journal.position > target {
+            self.replay_from_checkpoint(target);
+        }
+
+        // Checkpoints after this point belong to a future that might not happen again
+        let journal = self.journal.as_mut().unwrap();
+        journal
+            .checkpoints
+            .retain(|(position, _)| *position <= journal.position);
+        journal
+            .host_calls
+            .retain(|call| call.position < journal.position);
+        self.breakpoint_resume = None;
+        // Like a spurious wakeup, a wfi stepped back into is left right away
+        self.waiting_in_wfi = None;
+
+        start - self.journal.as_ref().unwrap().position
+    }
+
+    fn undo(&mut self, entry: Entry) {
+        for store in entry.stores.iter().rev() {
+            let mut state = SnapshotReader::new(&store.state);
+            // The state was written by the same peripheral, so this can't fail
+            let _ = self
+                .bus
+                .restore_store_state(store.addr, store.size, &mut state);
+
+            for i in 0..store.size {
+                self.clear_instruction_cache(store.addr.wrapping_add(i));
+            }
+        }
+
+        for (csr, value) in entry.csrs {
+            self.csr.store(csr, value);
+        }
+        for (reg, value) in entry.regs {
+            self.regs.set(reg, value);
+        }
+        self.pc = entry.pc;
+    }
+
+    /// Restores the latest checkpoint before `target` and executes forward until `target`
+    fn replay_from_checkpoint(&mut self, target: u64) {
+        let journal = self.journal.as_mut().unwrap();
+        let Some((position, checkpoint)) = journal
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(position, _)| *position <= target)
+            .cloned()
+        else {
+            return;
+        };
+
+        self.restore_snapshot_state(&checkpoint)
+            .expect("Checkpoint was taken from this machine");
+
+        let journal = self.journal.as_mut().unwrap();
+        journal.entries.clear();
+        journal.recording = None;
+        journal.position = position;
+        // The checkpoints after this one are taken again on the way
+        journal.checkpoints.retain(|(taken, _)| *taken <= position);
+        journal.replaying = true;
+        self.bus.set_replaying(true);
+
+        // Breakpoints would stop the replay early
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        while let Some(journal) = &self.journal {
+            let position = journal.position;
+            if position >= target {
+                break;
+            }
+
+            self.execute();
+            // A step that changed nothing will never get any further
+            if self.journal.as_ref().unwrap().position == position {
+                break;
+            }
+        }
+        self.breakpoints = breakpoints;
+        self.bus.set_replaying(false);
+        self.journal.as_mut().unwrap().replaying = false;
+    }
+
+    /// If the step being executed is replayed, in which case semihosting calls shouldn't reach
+    /// the host
+    pub(crate) fn journal_replaying(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| journal.replaying)
+    }
+
+    /// Records what the semihosting call made by the current step returned and stored
+    pub(crate) fn journal_host_call(&mut self, value: u32, stores: Vec<(u32, u8)>) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+
+        journal.host_calls.push_back(HostCall {
+            position: journal.position,
+            value,
+            stores,
+        });
+    }
+
+    /// The value and stores of the semihosting call made by the step being replayed, None if it
+    /// didn't return, like SYS_EXIT
+    pub(crate) fn replayed_host_call(&self) -> Option<(u32, Vec<(u32, u8)>)> {
+        let journal = self.journal.as_ref()?;
+        journal
+            .host_calls
+            .iter()
+            .find(|call| call.position == journal.position)
+            .map(|call| (call.value, call.stores.clone()))
+    }
+
+    /// Called at the start of every step, before anything has changed
+    pub(crate) fn journal_begin(&mut self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+
+        let interval = journal.config.checkpoint_interval;
+        let taken = journal.checkpoints.back().map(|(position, _)| *position);
+        if interval != 0 && journal.position % interval == 0 && taken != Some(journal.position) {
+            let checkpoint = self.save_snapshot();
+            let journal = self.journal.as_mut().unwrap();
+            journal
+                .checkpoints
+                .push_back((journal.position, checkpoint));
+            if journal.checkpoints.len() > journal.config.max_checkpoints {
+                journal.checkpoints.pop_front();
+                journal.prune_host_calls();
+            }
+        }
+
+        let recording = Recording {
+            entry: Entry {
+                pc: self.pc,
+                ..Default::default()
+            },
+            regs: self.regs.clone(),
+            csrs: TRACKED_CSRS.map(|csr| self.csr.load(csr)),
+        };
+        self.journal.as_mut().unwrap().recording = Some(recording);
+    }
+
+    /// Records the CSR an instruction is about to write, since it might not be tracked
+    pub(crate) fn journal_instruction(&mut self, instruction: Instruction) {
+        use Instruction as I;
+
+        let Some(recording) = self.journal.as_mut().and_then(|j| j.recording.as_mut()) else {
+            return;
+        };
+
+        if let I::CSRRW { csr, .. }
+        | I::CSRRS { csr, .. }
+        | I::CSRRC { csr, .. }
+        | I::CSRRWI { csr, .. }
+        | I::CSRRSI { csr, .. }
+        | I::CSRRCI { csr, .. } = instruction
+        {
+            recording.entry.csrs.push((csr, self.csr.load(csr)));
+        }
+    }
+
+    /// Saves the state behind a store before it happens
+    pub(crate) fn journal_store(&mut self, addr: u32, size: u32) {
+        let Some(recording) = self.journal.as_mut().and_then(|j| j.recording.as_mut()) else {
+            return;
+        };
+
+        let mut state = SnapshotWriter::new();
+        self.bus.save_store_state(addr, size, &mut state);
+        recording.entry.stores.push(Store {
+            addr,
+            size,
+            state: state.into_bytes(),
+        });
+    }
+
+    /// Called at the end of every step, compares the state with the state from the start of the
+    /// step and adds what changed to the journal
+    pub(crate) fn journal_end(&mut self) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let Some(recording) = journal.recording.take() else {
+            return;
+        };
+
+        let mut entry = recording.entry;
+        for i in 1..32 {
+            let reg = Register::new(i).expect("Invalid register");
+            let before = recording.regs.get(reg);
+            if self.regs.get(reg) != before {
+                entry.regs.push((reg, before));
+            }
+        }
+        for (csr, before) in TRACKED_CSRS.iter().zip(recording.csrs) {
+            if self.csr.load(*csr) != before {
+                entry.csrs.push((*csr, before));
+            }
+        }
+
+        // Nothing happens when the Cpu stops at a breakpoint, that isn't a step that can be undone
+        if entry.regs.is_empty()
+            && entry.csrs.is_empty()
+            && entry.stores.is_empty()
+            && entry.pc == self.pc
+        {
+            return;
+        }
+
+        journal.entries.push_back(entry);
+        journal.position += 1;
+        if journal.entries.len() > journal.config.max_entries {
+            journal.entries.pop_front();
+            journal.prune_host_calls();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::StopReason,
+        memory_mapped::MemoryMapped,
+        peripheral::{self, Bus, LEDStrip, SDRam, Timer, UartRingBuffer, UART},
+        test_utils::new_sdram_cpu,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    const COUNTER_PROGRAM: [u32; 4] = [
+        0x00128293, // addi t0, t0, 1
+        0x10502023, // sw t0, 256(zero)
+        0x34029073, // csrw mscratch, t0
+        0xff5ff06f, // j 0
+    ];
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = new_sdram_cpu(&COUNTER_PROGRAM);
+        cpu.enable_journal(JournalConfig::default());
+
+        cpu.run(10);
+        let pc = cpu.pc;
+        let t0 = cpu.regs.get(Register::T0);
+        let memory = cpu.load_word(0x100);
+        let cycles = cpu.cycles();
+
+        cpu.run(7);
+        assert_eq!(cpu.step_back(7), 7);
+
+        assert_eq!(cpu.pc, pc);
+        assert_eq!(cpu.regs.get(Register::T0), t0);
+        assert_eq!(cpu.load_word(0x100), memory);
+        assert_eq!(cpu.cycles(), cycles);
+        assert_eq!(cpu.csr.load(Csr::new(0x340).unwrap()), 2);
+
+        // Rewinding everything gets back to the start
+        assert_eq!(cpu.step_back(100), 10);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.load_word(0x100), Ok(0));
+        assert_eq!(cpu.instructions_retired(), 0);
+    }
+
+    #[test]
+    fn test_step_back_restores_instructions() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00002283, // lw t0, 0(zero)
+            0x00502223, // sw t0, 4(zero)
+            0x00000013, // nop
+        ]);
+        cpu.enable_journal(JournalConfig::default());
+
+        // The program overwrites its second instruction with the first
+        cpu.run(2);
+        assert_eq!(cpu.step_back(1), 1);
+        assert_eq!(cpu.load_word(4), Ok(0x00502223));
+
+        cpu.run(2);
+        assert_eq!(cpu.load_word(4), Ok(0x00002283));
+    }
+
+    #[test]
+    fn test_step_back_through_checkpoint() {
+        let mut cpu = new_sdram_cpu(&COUNTER_PROGRAM);
+        cpu.enable_journal(JournalConfig {
+            max_entries: 5,
+            checkpoint_interval: 8,
+            max_checkpoints: 10,
+        });
+
+        cpu.run(13);
+        let state = (cpu.pc, cpu.regs.clone(), cpu.cycles());
+        cpu.run(30);
+
+        // The journal only reaches back to step 38, the rest is replayed from the checkpoint at 8
+        assert_eq!(cpu.step_back(30), 30);
+        assert_eq!(cpu.pc, state.0);
+        assert_eq!(format!("{:?}", cpu.regs), format!("{:?}", state.1));
+        assert_eq!(cpu.cycles(), state.2);
+        assert_eq!(cpu.max_step_back(), 13);
+
+        cpu.disable_journal();
+        assert_eq!(cpu.step_back(1), 0);
+    }
+
+    #[test]
+    fn test_step_back_limit() {
+        let mut cpu = new_sdram_cpu(&COUNTER_PROGRAM);
+        cpu.enable_journal(JournalConfig {
+            max_entries: 5,
+            checkpoint_interval: 8,
+            max_checkpoints: 2,
+        });
+
+        // Only the checkpoints at 32 and 40 are kept
+        cpu.run(43);
+        assert_eq!(cpu.max_step_back(), 11);
+        assert_eq!(cpu.step_back(100), 11);
+        assert_eq!(cpu.instructions_retired(), 32);
+    }
+
+    #[test]
+    fn test_breakpoint_is_not_a_step() {
+        let mut cpu = new_sdram_cpu(&COUNTER_PROGRAM);
+        cpu.enable_journal(JournalConfig::default());
+        cpu.add_breakpoint(8);
+
+        assert_eq!(cpu.run(10), StopReason::Breakpoint { pc: 8 });
+        assert_eq!(cpu.max_step_back(), 2);
+        assert_eq!(cpu.step_back(1), 1);
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_step_back_peripheral() {
+        let leds = Rc::new(RefCell::new(LEDStrip::new()));
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (
+                peripheral::LED_STRIP_LOWER_ADDR,
+                peripheral::LED_STRIP_HIGHER_ADDR,
+            ),
+            Box::new(leds.clone()),
+        );
+        let mut cpu = Cpu::new_with_bus(bus);
+        let program: [u32; 3] = [
+            0x040002b7, // lui t0, 0x4000
+            0x00500313, // li t1, 5
+            0x0062a023, // sw t1, 0(t0)
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+        cpu.enable_journal(JournalConfig::default());
+
+        cpu.run(3);
+        assert!(leds.borrow().get(0));
+        assert!(leds.borrow().get(2));
+
+        cpu.step_back(1);
+        assert!(!leds.borrow().get(0));
+        assert!(!leds.borrow().get(2));
+    }
+
+    #[test]
+    fn test_step_back_timer() {
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (peripheral::TIMER_LOWER_ADDR, peripheral::TIMER_HIGHER_ADDR),
+            Box::new(Timer::new()),
+        );
+        let mut cpu = Cpu::new_with_bus(bus);
+        let program: [u32; 12] = [
+            0x04000337, // lui t1, 0x4000
+            0x02030313, // addi t1, t1, 32
+            0x01300293, // li t0, 19
+            0x00532423, // sw t0, 8(t1)
+            0x00600293, // li t0, 6
+            0x00532223, // sw t0, 4(t1)
+            0x00032823, // sw zero, 16(t1)
+            0x01032503, // lw a0, 16(t1)
+            0x00032583, // lw a1, 0(t1)
+            0x00000013, // nop
+            0x00000013, // nop
+            0xfedff06f, // j -20
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+        cpu.enable_journal(JournalConfig::default());
+
+        // Starts the timer in continuous mode with a period of 19
+        cpu.run(6);
+        let mut seen = Vec::new();
+        for _ in 0..40 {
+            cpu.step();
+            seen.push((cpu.regs.get(Register::A0), cpu.regs.get(Register::A1)));
+        }
+        // TO was set on the way
+        assert_eq!(seen[2].1, 0b10);
+        assert_eq!(seen[39].1, 0b11);
+
+        // Back to before the timeout and between two writes to the timer, the snapshot registers
+        // and TO read the same as the first time
+        cpu.step_back(38);
+        for expected in seen.into_iter().skip(2) {
+            cpu.step();
+            assert_eq!(
+                (cpu.regs.get(Register::A0), cpu.regs.get(Register::A1)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_back_uart() {
+        let uart = Rc::new(RefCell::new(UART::new()));
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (peripheral::UART_LOWER_ADDR, peripheral::UART_HIGHER_ADDR),
+            Box::new(uart.clone()),
+        );
+        let mut cpu = Cpu::new_with_bus(bus);
+        let program: [u32; 4] = [
+            0x040002b7, // lui t0, 0x4000
+            0x06100313, // li t1, 'a'
+            0x0462a023, // sw t1, 64(t0)
+            0x0462a023, // sw t1, 64(t0)
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+        cpu.enable_journal(JournalConfig::default());
+
+        cpu.run(3);
+        assert_eq!(uart.borrow_mut().next(), Some(b'a'));
+        cpu.run(1);
+
+        // The second character is still queued, the first was already read
+        cpu.step_back(2);
+        assert_eq!(uart.borrow_mut().next(), None);
+        assert_eq!(uart.borrow_mut().take_retracted(), 1);
+        assert_eq!(uart.borrow_mut().take_retracted(), 0);
+    }
+
+    #[test]
+    fn test_replay_skips_uart_sink() {
+        let output = Rc::new(RefCell::new(UartRingBuffer::new(16)));
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (peripheral::UART_LOWER_ADDR, peripheral::UART_HIGHER_ADDR),
+            Box::new(UART::new().with_sink(output.clone())),
+        );
+        let mut cpu = Cpu::new_with_bus(bus);
+        let program: [u32; 5] = [
+            0x040002b7, // lui t0, 0x4000
+            0x06100313, // li t1, 'a'
+            0x0462a023, // sw t1, 64(t0)
+            0x00138393, // addi t2, t2, 1
+            0xffdff06f, // j 12
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+        cpu.enable_journal(JournalConfig {
+            max_entries: 1,
+            checkpoint_interval: 100,
+            max_checkpoints: 1,
+        });
+
+        cpu.run(6);
+        // Replayed from the checkpoint at the start, across the print
+        assert_eq!(cpu.step_back(2), 2);
+        assert_eq!(cpu.pc, 16);
+        assert_eq!(
+            output
+                .borrow_mut()
+                .drain()
+                .map(|output| output.byte)
+                .collect::<Vec<_>>(),
+            b"a"
+        );
+
+        // Steps that aren't replayed reach the sink again
+        cpu.store_word(4, 0x06200313).unwrap(); // li t1, 'b'
+        cpu.pc = 4;
+        cpu.run(2);
+        assert_eq!(output.borrow_mut().drain().next().unwrap().byte, b'b');
+    }
+}
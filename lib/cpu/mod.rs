@@ -4,48 +4,155 @@
 //! Data. This is because the Cpu does some extra caching logic that speeds up the emulator.
 //! Otherwise the cache might get out of sync with the memory
 
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    rc::Rc,
+};
 
 #[cfg(feature = "debug-console")]
 use crate::debug_console::DebugConsole;
 use crate::{
-    csr::{Csr, CsrBlock},
+    csr::{Csr, CsrBlock, TrapVectorMode},
     instruction::Instruction,
-    interrupt::InterruptSignal,
+    interrupt::{self, InterruptSignal},
     memory_mapped::MemoryMapped,
     peripheral::{Peripheral, SDRAM_SIZE},
     register::RegisterBlock,
+    scheduler::Scheduler,
+    semihosting::Semihosting,
+};
+
+mod breakpoint;
+pub use breakpoint::{WatchKind, Watchpoint, WatchpointHit};
+
+mod counters;
+pub use counters::{HpmEvent, HPM_COUNTERS};
+
+mod hart;
+pub use hart::{HartConfig, MemoryFaultPolicy};
+
+mod journal;
+use journal::Journal;
+pub use journal::JournalConfig;
+
+mod snapshot;
+
+mod step;
+use step::Executed;
+pub use step::StopReason;
+
+mod timing;
+pub use timing::{InstructionClass, Latencies, TimingModel, SDRAM_STALL_CYCLES};
+
+mod trace;
+pub use trace::{
+    MemoryAccess, TraceFormat, TraceReader, TraceRecord, Tracer, Trap, Trigger, TRACE_MAGIC,
+    TRACE_VERSION,
 };
 
 mod instructions;
 
 pub const CLOCK_FEQ: u32 = 30_000_000;
 
+/// The alignment instructions need in memory, which is also the granularity of the instruction
+/// cache. Compressed instructions are only 2 bytes long, so 32-bit instructions can then start at
+/// any halfword.
+#[cfg(feature = "compressed")]
+pub const INSTRUCTION_ALIGN: u32 = 2;
+#[cfg(not(feature = "compressed"))]
+pub const INSTRUCTION_ALIGN: u32 = 4;
+
+/// An entry in the instruction cache, the instruction together with its length in bytes
+type CachedInstruction = (Instruction, u8);
+
+const FETCH_FAULT: InterruptSignal = InterruptSignal::INSTRUCTION_ACCESS_FAULT;
+const ILLEGAL: InterruptSignal = InterruptSignal::ILLEGAL_INSTRUCTION;
+
+/// The value mtvec is set to on reset. The DTEK-V boot code expects traps to jump to address 0,
+/// which is what direct mode with a base of 0 gives us
+pub const RESET_MTVEC: u32 = 0;
+
 #[derive(Debug)]
 pub struct Cpu<T: Peripheral<()>> {
     /// Data line struct that allows the CPU to communicate to memory and IO devices
     pub bus: T,
     /// Every time an instruction is fetched we store it into this vector
     /// Instead of fetching it again we can just use the instruction from the cache
-    instruction_cache: Vec<Option<Instruction>>,
+    instruction_cache: Vec<Option<CachedInstruction>>,
+    /// The length in bytes of the instruction being executed, which is how far pc moves when it
+    /// doesn't jump. Always 4 unless compressed instructions are enabled.
+    instruction_len: u32,
+    /// The identity and extensions of the hart, fixed once the Cpu is created
+    hart: HartConfig,
+    /// The exception raised by the instruction being executed, see [Cpu::raise_exception]
+    exception: Option<InterruptSignal>,
+    /// The address of the wfi the Cpu is waiting in, see [Cpu::waiting_for_interrupt]
+    waiting_in_wfi: Option<u32>,
+    /// Ebreaks stop the Cpu without trapping, see [Cpu::set_ebreak_to_debugger]
+    ebreak_to_debugger: bool,
+    /// Handles semihosting calls, without it they are regular ebreaks
+    semihosting: Option<Box<Semihosting>>,
+    /// Events scheduled by the peripherals, shared with them
+    scheduler: Option<Rc<RefCell<Scheduler>>>,
     #[cfg(feature = "debug-console")]
     debug_console: Option<Rc<RefCell<DebugConsole>>>,
     pub regs: RegisterBlock,
     pub csr: CsrBlock,
     pub pc: u32,
+    /// Interrupt lines set through [Cpu::set_interrupt_pending], these are combined with the lines
+    /// from the bus into mip
+    interrupt_lines: u32,
+    /// Decides how many cycles each instruction takes
+    timing: Box<dyn TimingModel>,
+    breakpoints: HashSet<u32>,
+    /// Set to the pc of the breakpoint that stopped the Cpu, so that it isn't hit again when
+    /// execution is resumed
+    breakpoint_resume: Option<u32>,
+    watchpoints: Vec<Watchpoint>,
+    /// Loads take &self, so the hit has to be recorded through a Cell
+    watchpoint_hit: Cell<Option<WatchpointHit>>,
+    /// Records every step while enabled so that it can be undone, see [Cpu::step_back]
+    journal: Option<Box<Journal>>,
+    tracer: Option<Box<Tracer>>,
 }
 
 impl<T: Peripheral<()>> Cpu<T> {
+    /// Creates a DTEK-V, see [HartConfig::default]
     pub fn new_with_bus(bus: T) -> Cpu<T> {
-        Cpu {
+        Self::new_with_bus_and_config(bus, HartConfig::default())
+    }
+
+    pub fn new_with_bus_and_config(bus: T, hart: HartConfig) -> Cpu<T> {
+        let mut cpu = Cpu {
             bus,
             #[cfg(feature = "debug-console")]
             debug_console: None,
             regs: RegisterBlock::new(),
-            instruction_cache: vec![None; SDRAM_SIZE / 4],
-            csr: CsrBlock::new(),
+            instruction_cache: vec![None; SDRAM_SIZE / INSTRUCTION_ALIGN as usize],
+            instruction_len: 4,
+            hart,
+            exception: None,
+            waiting_in_wfi: None,
+            ebreak_to_debugger: false,
+            semihosting: None,
+            scheduler: None,
+            csr: CsrBlock::new_with_instruction_align(hart.instruction_align()),
             pc: 0,
-        }
+            interrupt_lines: 0,
+            timing: Box::new(Latencies::dtekv()),
+            breakpoints: HashSet::new(),
+            breakpoint_resume: None,
+            watchpoints: Vec::new(),
+            watchpoint_hit: Cell::new(None),
+            journal: None,
+            tracer: None,
+        };
+
+        cpu.csr.store(Csr::MTVEC, RESET_MTVEC);
+        cpu.store_hart_csrs();
+        cpu.reset_counters();
+        cpu
     }
 
     #[cfg(feature = "debug-console")]
@@ -54,84 +161,199 @@ impl<T: Peripheral<()>> Cpu<T> {
         self
     }
 
+    /// Replaces the timing model, by default [Latencies::dtekv] is used
+    pub fn with_timing_model(mut self, timing: impl TimingModel + 'static) -> Self {
+        self.timing = Box::new(timing);
+        self
+    }
+
+    /// Handles semihosting calls from the program, see the [semihosting](crate::semihosting)
+    /// module
+    pub fn with_semihosting(mut self, semihosting: Semihosting) -> Self {
+        self.semihosting = Some(Box::new(semihosting));
+        self
+    }
+
+    pub fn semihosting(&self) -> Option<&Semihosting> {
+        self.semihosting.as_deref()
+    }
+
+    pub fn semihosting_mut(&mut self) -> Option<&mut Semihosting> {
+        self.semihosting.as_deref_mut()
+    }
+
+    /// Hands the events in the scheduler to the bus when they are due, see the
+    /// [scheduler](crate::scheduler) module. The peripherals that schedule events should be given
+    /// the same scheduler.
+    pub fn with_scheduler(mut self, scheduler: Rc<RefCell<Scheduler>>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    pub fn scheduler(&self) -> Option<&Rc<RefCell<Scheduler>>> {
+        self.scheduler.as_ref()
+    }
+
     /// Sends a reset signal to the CPU, the same as pressing the reset button on the DTEK-V board
     pub fn reset(&mut self) {
         self.regs.reset();
         self.csr.reset();
+        self.csr.store(Csr::MTVEC, RESET_MTVEC);
+        self.store_hart_csrs();
+        self.reset_counters();
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.borrow_mut().rebase(self.cycles());
+        }
+        self.waiting_in_wfi = None;
         self.pc = 4;
         // NOTE: Not sure if this happens when reset is triggered:
         self.csr.set_mstatus_mie(true);
     }
 
+    /// Makes ebreak stop the Cpu for an attached debugger instead of raising a breakpoint
+    /// exception. The ebreak isn't executed, so the Cpu stays at it and [Cpu::step] reports
+    /// [StopReason::Ebreak]. Resuming executes it again, the debugger has to move pc past it.
+    pub fn set_ebreak_to_debugger(&mut self, enabled: bool) {
+        self.ebreak_to_debugger = enabled;
+    }
+
+    pub fn ebreak_to_debugger(&self) -> bool {
+        self.ebreak_to_debugger
+    }
+
+    /// The address of the wfi the Cpu is idling in, if any. Steps taken while waiting don't
+    /// execute anything, they only let cycles pass until an interrupt enabled in mie is pending.
+    pub fn waiting_for_interrupt(&self) -> Option<u32> {
+        self.waiting_in_wfi
+    }
+
+    /// Drops the cached instruction that starts at addr. With compressed instructions enabled a
+    /// 32-bit instruction starting at the halfword before addr is dropped as well, since addr is
+    /// in the middle of it.
     pub fn clear_instruction_cache(&mut self, addr: u32) {
-        let addr = addr / 4;
+        let index = addr / INSTRUCTION_ALIGN;
+
+        if index < self.instruction_cache.len() as u32 {
+            self.instruction_cache[index as usize] = None;
+        }
+
+        #[cfg(feature = "compressed")]
+        if let Some(previous) = index.checked_sub(1) {
+            if previous < self.instruction_cache.len() as u32 {
+                self.instruction_cache[previous as usize] = None;
+            }
+        }
+    }
 
-        if addr < self.instruction_cache.len() as u32 {
-            self.instruction_cache[addr as usize] = None;
+    /// Drops every cached instruction that overlaps a store of `size` bytes at addr
+    fn invalidate_instruction_cache(&mut self, addr: u32, size: u32) {
+        let mut offset = 0;
+        while offset < size {
+            self.clear_instruction_cache(addr.wrapping_add(offset));
+            offset += INSTRUCTION_ALIGN;
         }
     }
 
     pub fn update_instruction_cache(&mut self, addr: u32) {
-        let instruction: Option<Instruction> =
-            self.load_word(addr).ok().and_then(|v| v.try_into().ok());
-        let addr = addr / 4;
+        let instruction = self.decode_at(addr).ok();
+        let addr = addr / INSTRUCTION_ALIGN;
         self.instruction_cache[addr as usize] = instruction;
     }
 
     pub fn generate_instruction_cache(&mut self) {
-        for i in 0..(SDRAM_SIZE / 4) {
-            let addr = i as u32;
-            let instruction = self
-                .bus
-                .load_word(addr * 4)
-                .map(|word| word.try_into().ok())
-                .ok()
-                .flatten();
-
-            if let Some(instruction) = instruction {
+        for i in 0..self.instruction_cache.len() {
+            let addr = i as u32 * INSTRUCTION_ALIGN;
+
+            if let Ok(instruction) = self.decode_at(addr) {
                 self.instruction_cache[i] = Some(instruction);
             }
         }
     }
 
+    /// Loads and decodes the instruction at addr without going through the cache. Fails with an
+    /// instruction access fault if the bus rejects the load and an illegal instruction otherwise.
+    #[cfg(not(feature = "compressed"))]
+    fn decode_at(&self, addr: u32) -> Result<CachedInstruction, InterruptSignal> {
+        let word = self.bus.load_word(addr).map_err(|_| FETCH_FAULT)?;
+        let instruction = word.try_into().map_err(|_| ILLEGAL)?;
+        self.legal((instruction, 4))
+    }
+
+    /// Loads and decodes the instruction at addr without going through the cache. Fails with an
+    /// instruction access fault if the bus rejects the load and an illegal instruction otherwise.
+    /// The upper half is only loaded for 32-bit instructions, as a compressed instruction can be
+    /// the last halfword of memory.
+    #[cfg(feature = "compressed")]
+    fn decode_at(&self, addr: u32) -> Result<CachedInstruction, InterruptSignal> {
+        let lower = self.bus.load_halfword(addr).map_err(|_| FETCH_FAULT)?;
+        if crate::instruction::is_compressed(lower) {
+            if !self.hart.compressed() {
+                return Err(ILLEGAL);
+            }
+            let instruction = Instruction::decompress(lower).ok_or(ILLEGAL)?;
+            return self.legal((instruction, 2));
+        }
+
+        let upper = self
+            .bus
+            .load_halfword(addr.wrapping_add(2))
+            .map_err(|_| FETCH_FAULT)?;
+        let word = (upper as u32) << 16 | lower as u32;
+        let instruction = word.try_into().map_err(|_| ILLEGAL)?;
+        self.legal((instruction, 4))
+    }
+
+    /// Rejects instructions from extensions the hart doesn't have
+    fn legal(&self, fetched: CachedInstruction) -> Result<CachedInstruction, InterruptSignal> {
+        if self.hart.supports(fetched.0) {
+            Ok(fetched)
+        } else {
+            Err(ILLEGAL)
+        }
+    }
+
     fn fetch_instruction(&mut self) -> Result<Instruction, InterruptSignal> {
-        if (self.pc & 3) != 0 {
+        if self.pc & (self.hart.instruction_align() - 1) != 0 {
             if let Some(db) = &self.debug_console {
                 db.borrow_mut().instruction_misaligned(self.pc);
             }
             return Err(InterruptSignal::INSTRUCTION_ADDRESS_MISALIGNED);
         }
 
-        let cache_index = self.pc / 4;
+        let cache_index = self.pc / INSTRUCTION_ALIGN;
         let can_cache = cache_index < self.instruction_cache.len() as u32;
 
         if can_cache {
-            if let Some(instruction) = self.instruction_cache[cache_index as usize] {
+            if let Some((instruction, len)) = self.instruction_cache[cache_index as usize] {
+                self.instruction_len = len as u32;
                 return Ok(instruction);
             }
         }
 
-        let instruction = self
-            .bus
-            .load_word(self.pc)
-            .and_then(|word| word.try_into())
-            .map_err(|_| {
-                #[cfg(feature = "debug-console")]
-                if let Some(db) = &self.debug_console {
-                    db.borrow_mut()
-                        .illegal_instruction(self.bus.load_word(self.pc).unwrap_or(0), self.pc);
-                }
+        let fetched = self.decode_at(self.pc).map_err(|exception| {
+            #[cfg(feature = "debug-console")]
+            if let Some(db) = &self.debug_console {
+                db.borrow_mut()
+                    .illegal_instruction(self.bus.peek_word(self.pc).unwrap_or(0), self.pc);
+            }
 
-                InterruptSignal::ILLEGAL_INSTRUCTION
-            });
+            // Leniently, a fetch the bus rejects is just an instruction that can't be decoded
+            match self.hart.memory_faults {
+                MemoryFaultPolicy::Lenient => ILLEGAL,
+                MemoryFaultPolicy::Trap => exception,
+            }
+        });
 
         if can_cache {
-            if let Ok(instruction) = instruction {
-                self.instruction_cache[cache_index as usize] = Some(instruction);
+            if let Ok(fetched) = fetched {
+                self.instruction_cache[cache_index as usize] = Some(fetched);
             }
         }
 
-        instruction
+        fetched.map(|(instruction, len)| {
+            self.instruction_len = len as u32;
+            instruction
+        })
     }
 
     fn exec_instruction(&mut self, instruction: Instruction) {
@@ -183,6 +405,10 @@ impl<T: Peripheral<()>> Cpu<T> {
             I::CSRRCI { imm, csr, rd } => self.csrrci(imm, csr, rd),
             I::MRET => self.mret(),
             I::ECALL => self.ecall(),
+            I::EBREAK => self.ebreak(),
+            I::WFI => self.wfi(),
+            I::FENCE { .. } => self.fence(),
+            I::FENCE_I => self.fence_i(),
             I::MUL { rs1, rs2, rd } => self.mul(rs1, rs2, rd),
             I::MULH { rs1, rs2, rd } => self.mulh(rs1, rs2, rd),
             I::MULHSU { rs1, rs2, rd } => self.mulhsu(rs1, rs2, rd),
@@ -194,43 +420,215 @@ impl<T: Peripheral<()>> Cpu<T> {
         }
     }
 
+    /// The address a trap jumps to, as configured by mtvec
+    fn trap_vector(&self, exception: InterruptSignal) -> u32 {
+        let base = self.csr.get_mtvec_base();
+
+        match self.csr.get_mtvec_mode() {
+            TrapVectorMode::Vectored if exception.external() => {
+                base.wrapping_add(4 * exception.cause())
+            }
+            _ => base,
+        }
+    }
+
+    /// Enters the trap handler, mepc is set to the current pc. For exceptions that is the
+    /// instruction that caused it, for interrupts it's the instruction that hasn't run yet.
+    /// mtval is set to `tval`, which is 0 unless the exception has an address to report.
+    fn trap(&mut self, exception: InterruptSignal, tval: u32) {
+        self.trace_trap(exception.mcause());
+        self.waiting_in_wfi = None;
+        self.csr.store(Csr::MEPC, self.pc);
+        self.csr.store(Csr::MCAUSE, exception.mcause());
+        self.csr.store(Csr::MTVAL, tval);
+        self.csr.set_mstatus_mpie(self.csr.get_mstatus_mie());
+        self.csr.set_mstatus_mie(false);
+        self.pc = self.trap_vector(exception);
+    }
+
+    /// Raises an exception for the instruction being executed, which then doesn't retire. pc
+    /// should still point at the instruction, so it ends up in mepc.
+    pub(crate) fn raise_exception(&mut self, exception: InterruptSignal) {
+        self.raise_exception_with_value(exception, 0);
+    }
+
+    /// Like [Cpu::raise_exception], with `tval` written to mtval, like the faulting address
+    pub(crate) fn raise_exception_with_value(&mut self, exception: InterruptSignal, tval: u32) {
+        debug_assert!(!exception.external(), "Only exceptions can be raised");
+        self.exception = Some(exception);
+        self.trap(exception, tval);
+    }
+
     /// Sends a interrupt signal to the CPU
+    ///
+    /// Exceptions are always taken. Interrupts are only taken if they are enabled in both mstatus
+    /// and mie, otherwise they are ignored. Interrupts raised by peripherals on the bus are kept
+    /// pending in mip and are taken by [Cpu::clock] once enabled, so you don't need to call this
+    /// for them. Use [Cpu::set_interrupt_pending] for interrupt sources that aren't on the bus.
     pub fn handle_interrupt(&mut self, exception: InterruptSignal) {
-        // If interrupts are disabled, ignore the interrupt
-        if !self.csr.get_mstatus_mie() {
+        if exception.external() && !self.interrupt_enabled(exception.cause()) {
             return;
         }
 
-        if exception.external() && self.csr.load(Csr::MIE) & (1 << exception.cause()) == 0 {
-            // This interrupt is disabled
-            return;
+        self.trap(exception, 0);
+    }
+
+    /// Sets or clears an interrupt line that isn't driven by the bus. The line stays pending in mip
+    /// until it's cleared again, just like a peripheral that holds its interrupt line high.
+    pub fn set_interrupt_pending(&mut self, interrupt: InterruptSignal, pending: bool) {
+        debug_assert!(interrupt.external(), "Only interrupts can be pending");
+        let bit = 1 << interrupt.cause();
+
+        if pending {
+            self.interrupt_lines |= bit;
+        } else {
+            self.interrupt_lines &= !bit;
         }
+    }
 
-        let exception_pc = self.pc.wrapping_sub(4);
-        self.pc = 0;
-        self.csr.store(Csr::MEPC, exception_pc);
-        self.csr.store(Csr::MCAUSE, exception.cause());
-        self.csr.set_mstatus_mpie(self.csr.get_mstatus_mie());
-        self.csr.set_mstatus_mie(false);
+    fn interrupt_enabled(&self, cause: u32) -> bool {
+        self.csr.get_mstatus_mie() && self.csr.load(Csr::MIE) & (1 << cause) != 0
+    }
+
+    /// Updates mip with the interrupt lines from the bus and takes the highest priority interrupt
+    /// that is both pending and enabled. Returns true if an interrupt was taken.
+    ///
+    /// This is called by [Cpu::clock] at every instruction boundary.
+    pub fn poll_interrupts(&mut self) -> bool {
+        let mip = self.bus.pending_interrupts() | self.interrupt_lines;
+        self.csr.store(Csr::MIP, mip);
 
-        if exception.external() {
-            // When it's an external interrupt, we need to increment the MEPC by 4
-            let exception_pc = self.csr.load(Csr::MEPC);
-            let exception_pc = exception_pc.wrapping_add(4);
-            self.csr.store(Csr::MEPC, exception_pc);
+        if !self.csr.get_mstatus_mie() {
+            return false;
+        }
+
+        let pending = mip & self.csr.load(Csr::MIE);
+        if pending == 0 {
+            return false;
+        }
+
+        match interrupt::highest_priority(pending) {
+            Some(interrupt) => {
+                self.trap(interrupt, 0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Executes one instruction and returns the number of cycles it took according to the timing
+    /// model. If there is a breakpoint at pc nothing is executed and 0 is returned, see
+    /// [Cpu::step] for a way to find out why.
+    pub fn clock(&mut self) -> u32 {
+        self.execute().cycles
+    }
+
+    /// Hands every event that is due at `cycle` to the bus, in order. The scheduler isn't
+    /// borrowed while an event is handled, so the peripheral can schedule its next one.
+    fn handle_events(&mut self, cycle: u64) {
+        let Some(scheduler) = self.scheduler.clone() else {
+            return;
+        };
+
+        scheduler.borrow_mut().advance(cycle);
+        loop {
+            let event = scheduler.borrow_mut().pop_due();
+            match event {
+                Some(event) => self.bus.handle_event(event),
+                None => break,
+            }
         }
     }
 
-    pub fn clock(&mut self) {
+    /// Handles the events that are due and takes any pending interrupt, then fetches and executes
+    /// one instruction
+    fn execute(&mut self) -> Executed {
+        self.journal_begin();
+        let cycle = self.cycles();
+        self.bus.update_cycle(cycle);
+        self.handle_events(cycle);
+        self.poll_interrupts();
+
+        if let Some(pc) = self.waiting_in_wfi {
+            // An enabled interrupt wakes the Cpu even if mstatus.MIE keeps it from being taken
+            if self.csr.load(Csr::MIP) & self.csr.load(Csr::MIE) == 0 {
+                return self.idle(pc);
+            }
+            self.waiting_in_wfi = None;
+        }
+
+        let pc = self.pc;
         let instr: Result<Instruction, InterruptSignal> = self.fetch_instruction();
 
-        match instr {
+        if self.check_breakpoint() {
+            self.journal_end();
+            return Executed {
+                pc,
+                instruction: instr,
+                exception: None,
+                cycles: 0,
+                breakpoint: true,
+            };
+        }
+        self.trace_fetch(pc);
+
+        let cycles = match instr {
             Ok(instr) => {
+                let class = InstructionClass::of(instr);
+                let mut events = self.instruction_events(instr);
+                self.journal_instruction(instr);
+                self.exception = None;
                 self.exec_instruction(instr);
+
+                if class == InstructionClass::Branch {
+                    events.branch_taken = self.pc != pc.wrapping_add(self.instruction_len);
+                }
+
+                let cycles =
+                    self.timing.latency(class, events.branch_taken) + events.stall_cycles as u32;
+
+                // Instructions that raise an exception, like ecall, don't retire
+                let retired = self.exception.is_none();
+                self.update_counters(events, cycles as u64, retired);
+                cycles
             }
             Err(exception) => {
-                self.handle_interrupt(exception);
+                // Faults on the fetch report the address of the instruction
+                let tval = match exception {
+                    InterruptSignal::INSTRUCTION_ADDRESS_MISALIGNED
+                    | InterruptSignal::INSTRUCTION_ACCESS_FAULT => pc,
+                    _ => 0,
+                };
+                self.trap(exception, tval);
+
+                let cycles = self.timing.latency(InstructionClass::System, false);
+                self.update_counters(Default::default(), cycles as u64, false);
+                cycles
             }
+        };
+        self.trace_end(instr.ok());
+        self.journal_end();
+
+        Executed {
+            pc,
+            instruction: instr,
+            exception: instr.err().or(self.exception.take()),
+            cycles,
+            breakpoint: false,
+        }
+    }
+
+    /// A step spent waiting in the wfi at `pc`, a cycle passes without anything being executed
+    fn idle(&mut self, pc: u32) -> Executed {
+        self.update_counters(Default::default(), 1, false);
+        self.journal_end();
+
+        Executed {
+            pc,
+            instruction: Ok(Instruction::WFI),
+            exception: None,
+            cycles: 1,
+            breakpoint: false,
         }
     }
 }
@@ -240,29 +638,57 @@ where
     T: Peripheral<()>,
 {
     fn load_byte(&self, addr: u32) -> Result<u8, ()> {
-        self.bus.load_byte(addr)
+        let byte = self.bus.load_byte(addr)?;
+        self.check_watchpoints(addr, 1, byte as u32, WatchKind::Read);
+        self.trace_memory(addr, 1, byte as u32, false);
+        Ok(byte)
+    }
+
+    /// Goes straight to the bus, a peek isn't an access by the program so it doesn't trigger
+    /// watchpoints or show up in the trace
+    fn peek_byte(&self, addr: u32) -> Result<u8, ()> {
+        self.bus.peek_byte(addr)
+    }
+
+    fn peek_word(&self, addr: u32) -> Result<u32, ()> {
+        self.bus.peek_word(addr)
     }
 
     fn store_byte(&mut self, addr: u32, byte: u8) -> Result<(), ()> {
-        self.clear_instruction_cache(addr);
+        self.check_watchpoints(addr, 1, byte as u32, WatchKind::Write);
+        self.journal_store(addr, 1);
+        self.trace_memory(addr, 1, byte as u32, true);
+        self.invalidate_instruction_cache(addr, 1);
         self.bus.store_byte(addr, byte)
     }
 
     fn load_halfword(&self, addr: u32) -> Result<u16, ()> {
-        self.bus.load_halfword(addr)
+        let halfword = self.bus.load_halfword(addr)?;
+        self.check_watchpoints(addr, 2, halfword as u32, WatchKind::Read);
+        self.trace_memory(addr, 2, halfword as u32, false);
+        Ok(halfword)
     }
 
     fn store_halfword(&mut self, addr: u32, halfword: u16) -> Result<(), ()> {
-        self.clear_instruction_cache(addr);
+        self.check_watchpoints(addr, 2, halfword as u32, WatchKind::Write);
+        self.journal_store(addr, 2);
+        self.trace_memory(addr, 2, halfword as u32, true);
+        self.invalidate_instruction_cache(addr, 2);
         self.bus.store_halfword(addr, halfword)
     }
 
     fn load_word(&self, addr: u32) -> Result<u32, ()> {
-        self.bus.load_word(addr)
+        let word = self.bus.load_word(addr)?;
+        self.check_watchpoints(addr, 4, word, WatchKind::Read);
+        self.trace_memory(addr, 4, word, false);
+        Ok(word)
     }
 
     fn store_word(&mut self, addr: u32, word: u32) -> Result<(), ()> {
-        self.clear_instruction_cache(addr);
+        self.check_watchpoints(addr, 4, word, WatchKind::Write);
+        self.journal_store(addr, 4);
+        self.trace_memory(addr, 4, word, true);
+        self.invalidate_instruction_cache(addr, 4);
         self.bus.store_word(addr, word)
     }
 }
@@ -319,6 +745,130 @@ mod tests {
         assert_eq!(cpu.regs.get(Register::GP), 0);
     }
 
+    #[test]
+    fn test_trap_vector_direct() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr.store(Csr::MIE, u32::MAX);
+        cpu.csr.store(Csr::MTVEC, 0x1000);
+
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::TIMER_INTERRUPT);
+        assert_eq!(cpu.pc, 0x1000);
+
+        cpu.csr.set_mstatus_mie(true);
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::ILLEGAL_INSTRUCTION);
+        assert_eq!(cpu.pc, 0x1000);
+    }
+
+    #[test]
+    fn test_trap_vector_vectored() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr.store(Csr::MIE, u32::MAX);
+        cpu.csr.store(Csr::MTVEC, 0x1000 | 1);
+
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::TIMER_INTERRUPT);
+        assert_eq!(cpu.pc, 0x1000 + 4 * 16);
+
+        // Exceptions always go to the base address
+        cpu.csr.set_mstatus_mie(true);
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::ILLEGAL_INSTRUCTION);
+        assert_eq!(cpu.pc, 0x1000);
+    }
+
+    #[test]
+    fn test_reset_mtvec() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.csr.store(Csr::MTVEC, 0x1234);
+        cpu.reset();
+        assert_eq!(cpu.csr.load(Csr::MTVEC), RESET_MTVEC);
+
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::ILLEGAL_INSTRUCTION);
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn test_interrupt_mcause() {
+        let mut cpu = new_panic_io_cpu();
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr
+            .store(Csr::MIE, InterruptSignal::TIMER_INTERRUPT.mip_bit());
+
+        cpu.pc = 0x200;
+        cpu.handle_interrupt(InterruptSignal::TIMER_INTERRUPT);
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0x8000_0010);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x200);
+        assert!(!cpu.csr.get_mstatus_mie());
+        assert!(cpu.csr.get_mstatus_mpie());
+
+        cpu.pc = 0x300;
+        cpu.handle_interrupt(InterruptSignal::ILLEGAL_INSTRUCTION);
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 2);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x300);
+    }
+
+    #[test]
+    fn test_interrupt_kept_pending() {
+        let data = new_io_cpu();
+        let mut cpu = data.cpu;
+        for addr in (0..0x200).step_by(4) {
+            cpu.store_word(addr, 0x00000013).unwrap(); // nop
+        }
+        cpu.csr.store(Csr::MTVEC, 0x100);
+        cpu.csr
+            .store(Csr::MIE, InterruptSignal::BUTTON_INTERRUPT.mip_bit());
+        // Enable button interrupts in the button peripheral
+        cpu.store_word(peripheral::BUTTON_LOWER_ADDR + 8, 1)
+            .unwrap();
+        data.button.borrow_mut().set(true);
+
+        cpu.clock();
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(
+            cpu.csr.load(Csr::MIP),
+            InterruptSignal::BUTTON_INTERRUPT.mip_bit()
+        );
+
+        cpu.csr.set_mstatus_mie(true);
+        cpu.clock();
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0x8000_0012);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 4);
+        // The first instruction of the handler has been executed
+        assert_eq!(cpu.pc, 0x104);
+
+        // Acknowledge the interrupt by clearing the edge capture
+        cpu.store_word(peripheral::BUTTON_LOWER_ADDR + 12, 0)
+            .unwrap();
+        cpu.clock();
+        assert_eq!(cpu.csr.load(Csr::MIP), 0);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr.store(Csr::MIE, u32::MAX);
+        cpu.csr.store(Csr::MTVEC, 0x100);
+
+        cpu.set_interrupt_pending(InterruptSignal::BUTTON_INTERRUPT, true);
+        cpu.set_interrupt_pending(InterruptSignal::TIMER_INTERRUPT, true);
+        assert!(cpu.poll_interrupts());
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0x8000_0010);
+
+        // Interrupts are disabled while in the handler
+        assert!(!cpu.poll_interrupts());
+
+        cpu.set_interrupt_pending(InterruptSignal::TIMER_INTERRUPT, false);
+        cpu.csr.set_mstatus_mie(true);
+        assert!(cpu.poll_interrupts());
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0x8000_0012);
+    }
+
     #[test]
     fn test_load_and_save() {
         let sdram = peripheral::SDRam::new();
@@ -354,4 +904,128 @@ mod tests {
         cpu.exec_instruction(0x00105103.try_into().unwrap()); // lhu x2, 1(x0)
         assert_eq!(cpu.regs.get(Register::SP), 0x1887);
     }
+
+    #[test]
+    #[cfg(feature = "compressed")]
+    fn test_compressed_instructions() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        let program = [
+            0x15, 0x45, // c.li a0, 5
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x05, 0x05, // c.addi a0, 1
+            0x11, 0x20, // c.jal 4
+            0x01, 0xa0, // c.j 0
+            0x86, 0x85, // c.mv a1, ra
+        ];
+        cpu.store_at(0, program).unwrap();
+        cpu.pc = 0;
+
+        for _ in 0..5 {
+            cpu.clock();
+        }
+
+        assert_eq!(cpu.regs.get(Register::A0), 7);
+        assert_eq!(cpu.regs.get(Register::RA), 0xA);
+        assert_eq!(cpu.regs.get(Register::A1), 0xA);
+        assert_eq!(cpu.pc, 0xE);
+    }
+
+    #[test]
+    #[cfg(feature = "compressed")]
+    fn test_compressed_cache_invalidation() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        let program = [
+            0x01, 0x00, // c.nop
+            0x13, 0x05, 0x10, 0x00, // addi a0, zero, 1
+        ];
+        cpu.store_at(0, program).unwrap();
+        cpu.generate_instruction_cache();
+        cpu.pc = 0;
+        cpu.clock();
+
+        // Only the upper half of the cached addi is changed, to addi a0, zero, 2
+        cpu.store_halfword(4, 0x0020).unwrap();
+        cpu.clock();
+        assert_eq!(cpu.regs.get(Register::A0), 2);
+        assert_eq!(cpu.pc, 6);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        cpu.store_word(0, 0x00100513).unwrap(); // li a0, 1
+        cpu.store_word(4, 0x0000100f).unwrap(); // fence.i
+        cpu.generate_instruction_cache();
+        cpu.pc = 0;
+
+        // Changed behind the Cpu's back, e.g. by DMA, to li a0, 2
+        cpu.bus.store_word(0, 0x00200513).unwrap();
+        cpu.clock();
+        assert_eq!(cpu.regs.get(Register::A0), 1);
+
+        cpu.clock();
+        cpu.pc = 0;
+        cpu.clock();
+        assert_eq!(cpu.regs.get(Register::A0), 2);
+    }
+
+    #[test]
+    fn test_fence() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        cpu.store_word(0, 0x0ff0000f).unwrap(); // fence
+        cpu.pc = 0;
+        cpu.clock();
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.instructions_retired(), 1);
+    }
+
+    #[test]
+    fn test_misaligned_pc() {
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        cpu.pc = 1;
+        cpu.clock();
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::INSTRUCTION_ADDRESS_MISALIGNED.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 1);
+
+        // Without compressed instructions halfword aligned instructions are misaligned as well,
+        // with them the zero halfword is fetched, which is illegal
+        cpu.pc = 2;
+        cpu.clock();
+        let expected = if cfg!(feature = "compressed") {
+            InterruptSignal::ILLEGAL_INSTRUCTION
+        } else {
+            InterruptSignal::INSTRUCTION_ADDRESS_MISALIGNED
+        };
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), expected.mcause());
+        assert_eq!(cpu.csr.load(Csr::MEPC), 2);
+    }
+
+    #[test]
+    fn test_fetch_fault() {
+        let hart = HartConfig {
+            memory_faults: MemoryFaultPolicy::Trap,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::new_with_bus_and_config(peripheral::SDRam::new(), hart);
+        cpu.pc = 0x7000_0000;
+        cpu.clock();
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::INSTRUCTION_ACCESS_FAULT.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MTVAL), 0x7000_0000);
+
+        // Leniently it's an illegal instruction
+        let mut cpu = Cpu::new_with_bus(peripheral::SDRam::new());
+        cpu.pc = 0x7000_0000;
+        cpu.clock();
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::ILLEGAL_INSTRUCTION.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MTVAL), 0);
+    }
 }
@@ -0,0 +1,373 @@
+//! Saving and restoring the Cpu, see the [snapshot](crate::snapshot) module for the format
+
+use crate::{
+    cpu::HartConfig,
+    csr::{Csr, MAX_CSR},
+    peripheral::Peripheral,
+    register::Register,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, SNAPSHOT_MAGIC, SNAPSHOT_VERSION},
+};
+
+use super::Cpu;
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// Saves the registers, CSRs, pc, the pending events in the scheduler and the state of every
+    /// peripheral on the bus
+    ///
+    /// Breakpoints, watchpoints, whether ebreaks go to the debugger and the timing model aren't
+    /// part of the machine state and are not saved. The hart configuration is saved to check that
+    /// the snapshot is restored into the same kind of machine.
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        let mut snapshot = SnapshotWriter::new();
+        snapshot.write_raw(&SNAPSHOT_MAGIC);
+        snapshot.write_u32(SNAPSHOT_VERSION);
+        write_hart_config(&self.hart, &mut snapshot);
+
+        snapshot.write_u32(self.pc);
+        for i in 1..32 {
+            let reg = Register::new(i).expect("Invalid register");
+            snapshot.write_u32(self.regs.get(reg));
+        }
+
+        // Most CSRs are 0, so only the others are stored
+        let csrs: Vec<(Csr, u32)> = (0..=MAX_CSR)
+            .map(|i| Csr::new(i).expect("Invalid CSR"))
+            .map(|csr| (csr, self.csr.load(csr)))
+            .filter(|(_, value)| *value != 0)
+            .collect();
+        snapshot.write_u32(csrs.len() as u32);
+        for (csr, value) in csrs {
+            snapshot.write_u32(csr.as_u32());
+            snapshot.write_u32(value);
+        }
+
+        snapshot.write_u32(self.interrupt_lines);
+        snapshot.write_bool(self.waiting_in_wfi.is_some());
+        snapshot.write_u32(self.waiting_in_wfi.unwrap_or(0));
+
+        snapshot.write_bool(self.scheduler.is_some());
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.borrow().save_state(&mut snapshot);
+        }
+
+        self.bus.save_state(&mut snapshot);
+        snapshot.into_bytes()
+    }
+
+    /// Restores a snapshot made by [Cpu::save_snapshot] and clears the instruction cache, since
+    /// the memory it was built from has been replaced. If the snapshot is invalid nothing is
+    /// changed.
+    ///
+    /// Steps recorded in the journal belong to the replaced state, so they are dropped.
+    pub fn restore_snapshot(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.restore_snapshot_state(data)?;
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+        Ok(())
+    }
+
+    /// Restores a snapshot without touching the journal
+    pub(crate) fn restore_snapshot_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut snapshot = SnapshotReader::new(data);
+
+        if snapshot.read_raw(SNAPSHOT_MAGIC.len()) != Ok(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::NotSnapshot);
+        }
+        let version = snapshot.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut hart = SnapshotWriter::new();
+        write_hart_config(&self.hart, &mut hart);
+        let hart = hart.into_bytes();
+        if snapshot.read_raw(hart.len())? != hart {
+            return Err(SnapshotError::HartMismatch);
+        }
+
+        let pc = snapshot.read_u32()?;
+        let mut regs = [0; 31];
+        for value in &mut regs {
+            *value = snapshot.read_u32()?;
+        }
+
+        let mut csrs = Vec::new();
+        for _ in 0..snapshot.read_u32()? {
+            let csr = Csr::new(snapshot.read_u32()?).ok_or(SnapshotError::Invalid)?;
+            csrs.push((csr, snapshot.read_u32()?));
+        }
+
+        let interrupt_lines = snapshot.read_u32()?;
+        let waiting = snapshot.read_bool()?;
+        let wfi_pc = snapshot.read_u32()?;
+
+        let scheduler = match (snapshot.read_bool()?, &self.scheduler) {
+            (true, Some(scheduler)) => {
+                let mut restored = scheduler.borrow().clone();
+                restored.restore_state(&mut snapshot)?;
+                Some(restored)
+            }
+            (false, None) => None,
+            _ => return Err(SnapshotError::DeviceMismatch),
+        };
+
+        // The peripherals can only check their state while restoring it, so the bus is put back
+        // the way it was if one of them fails
+        let mut backup = SnapshotWriter::new();
+        self.bus.save_state(&mut backup);
+        let restored = self.bus.restore_state(&mut snapshot).and_then(|()| {
+            if snapshot.is_empty() {
+                Ok(())
+            } else {
+                Err(SnapshotError::DeviceMismatch)
+            }
+        });
+        if let Err(e) = restored {
+            self.bus
+                .restore_state(&mut SnapshotReader::new(&backup.into_bytes()))
+                .expect("Backup was saved from this bus");
+            return Err(e);
+        }
+
+        self.pc = pc;
+        for (i, value) in regs.into_iter().enumerate() {
+            let reg = Register::new(i as u32 + 1).expect("Invalid register");
+            self.regs.set(reg, value);
+        }
+        self.csr.reset();
+        for (csr, value) in csrs {
+            self.csr.store(csr, value);
+        }
+        self.interrupt_lines = interrupt_lines;
+        if let (Some(scheduler), Some(restored)) = (&self.scheduler, scheduler) {
+            *scheduler.borrow_mut() = restored;
+        }
+
+        self.instruction_cache.fill(None);
+        self.breakpoint_resume = None;
+        self.waiting_in_wfi = waiting.then_some(wfi_pc);
+
+        Ok(())
+    }
+}
+
+/// Everything in [HartConfig] changes how the program runs, so it has to match exactly
+fn write_hart_config(hart: &HartConfig, snapshot: &mut SnapshotWriter) {
+    snapshot.write_u32(hart.mvendorid);
+    snapshot.write_u32(hart.marchid);
+    snapshot.write_u32(hart.mimpid);
+    snapshot.write_u32(hart.mhartid);
+    snapshot.write_bool(hart.m);
+    snapshot.write_bool(hart.c);
+    snapshot.write_bool(hart.zicsr);
+    snapshot.write_bool(hart.zicntr);
+    snapshot.write_u8(hart.memory_faults as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        interrupt::InterruptSignal,
+        memory_mapped::MemoryMapped,
+        peripheral::{self, Bus, Button, SDRam, Timer, UART},
+        scheduler::Scheduler,
+    };
+
+    fn new_machine() -> (Cpu<Bus>, Rc<RefCell<Button>>) {
+        let button = Rc::new(RefCell::new(Button::new()));
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (peripheral::TIMER_LOWER_ADDR, peripheral::TIMER_HIGHER_ADDR),
+            Box::new(Timer::new()),
+        );
+        bus.attach_device(
+            (peripheral::UART_LOWER_ADDR, peripheral::UART_HIGHER_ADDR),
+            Box::new(UART::new()),
+        );
+        bus.attach_device(
+            (
+                peripheral::BUTTON_LOWER_ADDR,
+                peripheral::BUTTON_HIGHER_ADDR,
+            ),
+            Box::new(button.clone()),
+        );
+
+        (Cpu::new_with_bus(bus), button)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut cpu, button) = new_machine();
+        let program: [u32; 3] = [
+            0x00128293, // addi t0, t0, 1
+            0x10502023, // sw t0, 256(zero)
+            0xff9ff06f, // j 0
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+        cpu.store_word(0x100_0000, 0xdeadbeef).unwrap();
+        cpu.store_word(peripheral::TIMER_LOWER_ADDR + 8, 1234)
+            .unwrap();
+        cpu.store_word(peripheral::BUTTON_LOWER_ADDR + 8, 1)
+            .unwrap();
+        button.borrow_mut().set(true);
+        cpu.csr.store(Csr::MEPC, 0x40);
+
+        cpu.run(10);
+        let snapshot = cpu.save_snapshot();
+        let state = (cpu.pc, cpu.regs.clone(), cpu.cycles());
+
+        // Keep running and change the program, restoring should undo all of it
+        cpu.run(10);
+        cpu.store_word(4, 0x00000013).unwrap();
+        cpu.store_word(0x100_0000, 0).unwrap();
+        *button.borrow_mut() = Button::new();
+
+        cpu.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(cpu.pc, state.0);
+        assert_eq!(format!("{:?}", cpu.regs), format!("{:?}", state.1));
+        assert_eq!(cpu.cycles(), state.2);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x40);
+        assert_eq!(cpu.load_word(0x100_0000), Ok(0xdeadbeef));
+        assert_eq!(
+            cpu.bus.pending_interrupts(),
+            InterruptSignal::BUTTON_INTERRUPT.mip_bit()
+        );
+
+        // The instruction cache has to be rebuilt, otherwise the nop would still be executed
+        let t0 = cpu.regs.get(Register::T0);
+        cpu.run(3);
+        assert_eq!(cpu.load_word(0x100), Ok(t0));
+    }
+
+    #[test]
+    fn test_rejects_other_snapshots() {
+        let (mut cpu, _) = new_machine();
+        assert_eq!(
+            cpu.restore_snapshot(b"not a snapshot"),
+            Err(SnapshotError::NotSnapshot)
+        );
+
+        let mut snapshot = cpu.save_snapshot();
+        snapshot[8] = SNAPSHOT_VERSION as u8 + 1;
+        assert_eq!(
+            cpu.restore_snapshot(&snapshot),
+            Err(SnapshotError::UnsupportedVersion {
+                version: SNAPSHOT_VERSION + 1
+            })
+        );
+
+        // A machine with only SDRAM can't restore a snapshot of one with more peripherals
+        let snapshot = cpu.save_snapshot();
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        let mut other = Cpu::new_with_bus(bus);
+        assert_eq!(
+            other.restore_snapshot(&snapshot),
+            Err(SnapshotError::DeviceMismatch)
+        );
+    }
+
+    #[test]
+    fn test_invalid_snapshot_changes_nothing() {
+        let (mut cpu, _) = new_machine();
+        cpu.store_word(0, 0x00128293).unwrap(); // addi t0, t0, 1
+        let mut snapshot = cpu.save_snapshot();
+        // Cuts into the button, the last device on the bus
+        snapshot.pop();
+
+        cpu.enable_journal(crate::cpu::JournalConfig::default());
+        cpu.store_word(0x100, 0xdeadbeef).unwrap();
+        cpu.store_word(peripheral::TIMER_LOWER_ADDR + 8, 1234)
+            .unwrap();
+        cpu.csr.store(Csr::MEPC, 0x40);
+        cpu.step();
+        let pc = cpu.pc;
+
+        assert_eq!(
+            cpu.restore_snapshot(&snapshot),
+            Err(SnapshotError::Truncated)
+        );
+        assert_eq!(cpu.pc, pc);
+        assert_eq!(cpu.regs.get(Register::T0), 1);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 0x40);
+        assert_eq!(cpu.load_word(0x100), Ok(0xdeadbeef));
+        assert_eq!(cpu.load_word(peripheral::TIMER_LOWER_ADDR + 8), Ok(1234));
+
+        // The journal still belongs to the machine
+        assert_eq!(cpu.step_back(1), 1);
+        assert_eq!(cpu.regs.get(Register::T0), 0);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Rc::new(RefCell::new(Scheduler::new()));
+        let device = scheduler.borrow_mut().register();
+        let (cpu, _) = new_machine();
+        let mut cpu = cpu.with_scheduler(scheduler.clone());
+
+        scheduler.borrow_mut().schedule(1000, device, 7);
+        let snapshot = cpu.save_snapshot();
+        scheduler.borrow_mut().cancel(device, 7);
+
+        cpu.restore_snapshot(&snapshot).unwrap();
+        assert!(scheduler.borrow().is_scheduled(device, 7));
+        assert_eq!(scheduler.borrow().next_cycle(), Some(1000));
+
+        // The events need a scheduler to go into
+        let (mut other, _) = new_machine();
+        assert_eq!(
+            other.restore_snapshot(&snapshot),
+            Err(SnapshotError::DeviceMismatch)
+        );
+    }
+
+    #[test]
+    fn test_waiting_in_wfi() {
+        let (mut cpu, _) = new_machine();
+        cpu.store_word(0, 0x10500073).unwrap(); // wfi
+        cpu.step();
+        assert_eq!(cpu.waiting_for_interrupt(), Some(0));
+        let snapshot = cpu.save_snapshot();
+
+        // Still waiting after the restore, nothing has woken it up
+        let (mut other, _) = new_machine();
+        other.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(other.waiting_for_interrupt(), Some(0));
+        other.step();
+        assert_eq!(other.waiting_for_interrupt(), Some(0));
+        assert_eq!(other.instructions_retired(), cpu.instructions_retired());
+    }
+
+    #[test]
+    fn test_rejects_other_harts() {
+        let (mut cpu, _) = new_machine();
+        let snapshot = cpu.save_snapshot();
+
+        let hart = HartConfig {
+            memory_faults: crate::cpu::MemoryFaultPolicy::Trap,
+            ..HartConfig::default()
+        };
+        let mut other = Cpu::new_with_bus_and_config(SDRam::new(), hart);
+        assert_eq!(
+            other.restore_snapshot(&snapshot),
+            Err(SnapshotError::HartMismatch)
+        );
+
+        // A debugger attaching or detaching doesn't change the machine
+        cpu.set_ebreak_to_debugger(true);
+        assert_eq!(cpu.restore_snapshot(&snapshot), Ok(()));
+        assert!(cpu.ebreak_to_debugger());
+    }
+}
@@ -0,0 +1,287 @@
+//! Running the Cpu until something interesting happens
+//!
+//! [Cpu::clock] runs a single instruction and doesn't say anything about what happened.
+//! [Cpu::step] and [Cpu::run] do the same work but report why execution should stop, so a
+//! frontend or a test doesn't have to inspect the Cpu after every instruction.
+
+use crate::{
+    csr::Csr, instruction::Instruction, interrupt::InterruptSignal, peripheral::Peripheral,
+};
+
+use super::{Cpu, InstructionClass, WatchpointHit};
+
+/// Why [Cpu::step] or [Cpu::run] stopped
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StopReason {
+    /// The Cpu executed the amount of instructions it was allowed to
+    BudgetExhausted,
+    /// The instruction at `pc` has a breakpoint and hasn't been executed yet. Stepping again
+    /// executes it.
+    Breakpoint { pc: u32 },
+    /// An instruction accessed memory watched by a watchpoint. The instruction has been executed.
+    Watchpoint(WatchpointHit),
+    /// The instruction at `pc` couldn't be decoded or accessed a CSR it isn't allowed to. The trap
+    /// has already been taken, so the Cpu is at the start of the trap handler.
+    IllegalInstruction { pc: u32 },
+    /// The instruction at `pc` was an ecall. The trap has already been taken, so the Cpu is at the
+    /// start of the trap handler.
+    Ecall { pc: u32 },
+    /// The instruction at `pc` was an ebreak. The trap has already been taken, unless ebreaks are
+    /// handed to a debugger, see [Cpu::set_ebreak_to_debugger]. Then the Cpu is still at `pc`.
+    Ebreak { pc: u32 },
+    /// The program exited through semihosting with the exit code, see the
+    /// [semihosting](crate::semihosting) module
+    Exit { code: u32 },
+    /// The Cpu is idling in the wfi at `pc` until an interrupt enabled in mie is pending. Stepping
+    /// again lets a cycle pass, so a frontend can advance its peripherals or sleep instead.
+    WaitingForInterrupt { pc: u32 },
+    /// The instruction at `pc` jumps to itself, or is a wfi, and no interrupt is enabled that
+    /// could break the loop, so the Cpu will never do anything else
+    HaltLoop { pc: u32 },
+}
+
+/// What happened during a single call to [Cpu::clock]
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Executed {
+    /// The address of the instruction, after any pending interrupt was taken
+    pub(crate) pc: u32,
+    pub(crate) instruction: Result<Instruction, InterruptSignal>,
+    /// The exception raised while fetching or executing the instruction
+    pub(crate) exception: Option<InterruptSignal>,
+    pub(crate) cycles: u32,
+    /// The instruction wasn't executed since there is a breakpoint at pc
+    pub(crate) breakpoint: bool,
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// Executes one instruction, taking any pending interrupt first. Returns the reason execution
+    /// should stop, or None if the Cpu can keep going.
+    pub fn step(&mut self) -> Option<StopReason> {
+        // Memory accessed by the frontend since the last step shouldn't count
+        self.watchpoint_hit.set(None);
+
+        let executed = self.execute();
+        let pc = executed.pc;
+
+        if executed.breakpoint {
+            return Some(StopReason::Breakpoint { pc });
+        }
+
+        if let Some(hit) = self.watchpoint_hit.take() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+
+        if let Some(pc) = self.waiting_in_wfi {
+            // Unlike a loop, wfi doesn't need mstatus.MIE to be woken
+            return Some(if self.csr.load(Csr::MIE) == 0 {
+                StopReason::HaltLoop { pc }
+            } else {
+                StopReason::WaitingForInterrupt { pc }
+            });
+        }
+
+        match (executed.instruction, executed.exception) {
+            (_, Some(InterruptSignal::ILLEGAL_INSTRUCTION)) => {
+                Some(StopReason::IllegalInstruction { pc })
+            }
+            (Ok(Instruction::ECALL), _) => Some(StopReason::Ecall { pc }),
+            // A semihosting call, which continues after the ebreak unless it was SYS_EXIT
+            (Ok(Instruction::EBREAK), None) if self.pc == pc => self
+                .semihosting
+                .as_ref()
+                .and_then(|semihosting| semihosting.exit_code())
+                .map(|code| StopReason::Exit { code }),
+            (Ok(Instruction::EBREAK), Some(_)) => Some(StopReason::Ebreak { pc }),
+            (Err(_), _) | (_, Some(_)) => None,
+            (Ok(instruction), None) => {
+                let class = InstructionClass::of(instruction);
+                let jumps_to_itself =
+                    matches!(class, InstructionClass::Jump | InstructionClass::Branch)
+                        && self.pc == pc;
+
+                if jumps_to_itself && !self.can_be_woken() {
+                    Some(StopReason::HaltLoop { pc })
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Executes at most `budget` instructions, stopping early if [Cpu::step] reports a reason to
+    pub fn run(&mut self, budget: u64) -> StopReason {
+        for _ in 0..budget {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+
+        StopReason::BudgetExhausted
+    }
+
+    /// If any interrupt is enabled, a peripheral could still interrupt the Cpu
+    fn can_be_woken(&self) -> bool {
+        self.csr.get_mstatus_mie() && self.csr.load(Csr::MIE) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory_mapped::MemoryMapped, test_utils::new_trapping_cpu};
+
+    #[test]
+    fn test_budget_exhausted() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0xffdff06f, // j 0
+        ]);
+
+        assert_eq!(cpu.run(10), StopReason::BudgetExhausted);
+        assert_eq!(cpu.instructions_retired(), 10);
+        assert_eq!(cpu.regs.get(crate::register::Register::T0), 5);
+    }
+
+    #[test]
+    fn test_ecall() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00000013, // nop
+            0x00000073, // ecall
+        ]);
+
+        assert_eq!(cpu.run(10), StopReason::Ecall { pc: 4 });
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 4);
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00000013, // nop
+            0x00100073, // ebreak
+        ]);
+
+        assert_eq!(cpu.run(10), StopReason::Ebreak { pc: 4 });
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(
+            cpu.csr.load(Csr::MCAUSE),
+            InterruptSignal::BREAKPOINT.mcause()
+        );
+        assert_eq!(cpu.csr.load(Csr::MEPC), 4);
+        assert_eq!(cpu.csr.load(Csr::MTVAL), 4);
+        assert_eq!(cpu.instructions_retired(), 1);
+    }
+
+    #[test]
+    fn test_ebreak_to_debugger() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00100073, // ebreak
+        ]);
+        cpu.set_ebreak_to_debugger(true);
+
+        assert_eq!(cpu.step(), Some(StopReason::Ebreak { pc: 0 }));
+        assert_eq!(cpu.step(), Some(StopReason::Ebreak { pc: 0 }));
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0);
+        assert_eq!(cpu.instructions_retired(), 0);
+    }
+
+    #[test]
+    fn test_wfi() {
+        let mut cpu = new_trapping_cpu(&[
+            0x10500073, // wfi
+            0x00128293, // addi t0, t0, 1
+        ]);
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr
+            .store(Csr::MIE, InterruptSignal::TIMER_INTERRUPT.mip_bit());
+
+        assert_eq!(cpu.run(10), StopReason::WaitingForInterrupt { pc: 0 });
+        assert_eq!(cpu.waiting_for_interrupt(), Some(0));
+        let cycles = cpu.cycles();
+        assert_eq!(cpu.step(), Some(StopReason::WaitingForInterrupt { pc: 0 }));
+        assert_eq!(cpu.cycles(), cycles + 1);
+        assert_eq!(cpu.instructions_retired(), 1);
+        assert_eq!(cpu.pc, 4);
+
+        // The interrupt is taken with mepc after the wfi
+        cpu.store_word(0x100, 0x00000013).unwrap(); // nop
+        cpu.set_interrupt_pending(InterruptSignal::TIMER_INTERRUPT, true);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.waiting_for_interrupt(), None);
+        assert_eq!(cpu.csr.load(Csr::MEPC), 4);
+    }
+
+    #[test]
+    fn test_wfi_with_interrupts_disabled() {
+        let mut cpu = new_trapping_cpu(&[
+            0x10500073, // wfi
+            0x00128293, // addi t0, t0, 1
+        ]);
+        cpu.csr
+            .store(Csr::MIE, InterruptSignal::TIMER_INTERRUPT.mip_bit());
+
+        assert_eq!(cpu.step(), Some(StopReason::WaitingForInterrupt { pc: 0 }));
+
+        // mstatus.MIE is clear, so the Cpu wakes up without taking the interrupt
+        cpu.set_interrupt_pending(InterruptSignal::TIMER_INTERRUPT, true);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.regs.get(crate::register::Register::T0), 1);
+        assert_eq!(cpu.pc, 8);
+
+        // Nothing can wake a wfi with mie clear
+        let mut cpu = new_trapping_cpu(&[
+            0x10500073, // wfi
+        ]);
+        assert_eq!(cpu.run(10), StopReason::HaltLoop { pc: 0 });
+    }
+
+    #[test]
+    fn test_illegal_instruction() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00000013, // nop
+            0xffffffff,
+        ]);
+
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.step(), Some(StopReason::IllegalInstruction { pc: 4 }));
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn test_illegal_csr_access() {
+        let mut cpu = new_trapping_cpu(&[
+            0xf1402573, // csrr a0, mhartid
+            0xf1451073, // csrw mhartid, a0
+        ]);
+
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.step(), Some(StopReason::IllegalInstruction { pc: 4 }));
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.instructions_retired(), 1);
+    }
+
+    #[test]
+    fn test_halt_loop() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00000013, // nop
+            0x0000006f, // j .
+        ]);
+
+        assert_eq!(cpu.run(10), StopReason::HaltLoop { pc: 4 });
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_halt_loop_with_interrupts_enabled() {
+        let mut cpu = new_trapping_cpu(&[
+            0x00000063, // beqz zero, 0
+        ]);
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr
+            .store(Csr::MIE, 1 << InterruptSignal::TIMER_INTERRUPT.cause());
+
+        // A timer interrupt could still happen, so it isn't a halt loop
+        assert_eq!(cpu.run(10), StopReason::BudgetExhausted);
+    }
+}
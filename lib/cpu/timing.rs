@@ -0,0 +1,246 @@
+//! Instruction timing
+//!
+//! A timing model decides how many cycles each instruction takes, which is what
+//! [Cpu::clock](super::Cpu::clock) returns and what mcycle counts. By default the Cpu uses
+//! [Latencies::dtekv], which approximates the DTEK-V pipeline: taken branches and jumps take 3
+//! cycles, loads 2, divisions 34 and every SDRAM access stalls for [SDRAM_STALL_CYCLES] more. To
+//! count one cycle per instruction instead, pass [Latencies::single_cycle] to
+//! [Cpu::with_timing_model](super::Cpu::with_timing_model).
+
+use crate::{
+    instruction::Instruction,
+    peripheral::{SDRAM_HIGHER_ADDR, SDRAM_LOWER_ADDR},
+};
+
+/// Instructions grouped by how long they take to execute
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum InstructionClass {
+    /// Arithmetic, logic, shifts, LUI and AUIPC
+    Alu,
+    Branch,
+    /// JAL and JALR
+    Jump,
+    Load,
+    Store,
+    /// MUL, MULH, MULHSU and MULHU
+    Multiply,
+    /// DIV, DIVU, REM and REMU
+    Divide,
+    /// CSR reads and writes
+    Csr,
+    /// ECALL, EBREAK, MRET, WFI, fences and traps
+    System,
+}
+
+impl InstructionClass {
+    pub fn of(instruction: Instruction) -> Self {
+        use Instruction as I;
+
+        match instruction {
+            I::LUI { .. }
+            | I::AUIPC { .. }
+            | I::ADDI { .. }
+            | I::ANDI { .. }
+            | I::ORI { .. }
+            | I::XORI { .. }
+            | I::SLLI { .. }
+            | I::SRLI { .. }
+            | I::SRAI { .. }
+            | I::SLTI { .. }
+            | I::SLTIU { .. }
+            | I::ADD { .. }
+            | I::SUB { .. }
+            | I::SLT { .. }
+            | I::SLTU { .. }
+            | I::SLL { .. }
+            | I::SRL { .. }
+            | I::SRA { .. }
+            | I::AND { .. }
+            | I::OR { .. }
+            | I::XOR { .. } => InstructionClass::Alu,
+            I::BEQ { .. }
+            | I::BNE { .. }
+            | I::BLT { .. }
+            | I::BGE { .. }
+            | I::BLTU { .. }
+            | I::BGEU { .. } => InstructionClass::Branch,
+            I::JAL { .. } | I::JALR { .. } => InstructionClass::Jump,
+            I::LB { .. } | I::LH { .. } | I::LW { .. } | I::LBU { .. } | I::LHU { .. } => {
+                InstructionClass::Load
+            }
+            I::SB { .. } | I::SH { .. } | I::SW { .. } => InstructionClass::Store,
+            I::MUL { .. } | I::MULH { .. } | I::MULHSU { .. } | I::MULHU { .. } => {
+                InstructionClass::Multiply
+            }
+            I::DIV { .. } | I::DIVU { .. } | I::REM { .. } | I::REMU { .. } => {
+                InstructionClass::Divide
+            }
+            I::CSRRW { .. }
+            | I::CSRRS { .. }
+            | I::CSRRC { .. }
+            | I::CSRRWI { .. }
+            | I::CSRRSI { .. }
+            | I::CSRRCI { .. } => InstructionClass::Csr,
+            I::MRET | I::ECALL | I::EBREAK | I::WFI | I::FENCE { .. } | I::FENCE_I => {
+                InstructionClass::System
+            }
+        }
+    }
+}
+
+/// Decides how many cycles an instruction takes
+pub trait TimingModel: std::fmt::Debug {
+    /// Cycles an instruction of the given class takes, not counting time spent waiting for memory.
+    /// `branch_taken` is only ever true for branches.
+    fn latency(&self, class: InstructionClass, branch_taken: bool) -> u32;
+
+    /// Extra cycles spent waiting for memory when a load or store accesses `addr`
+    fn memory_stall(&self, _addr: u32) -> u32 {
+        0
+    }
+}
+
+/// Extra cycles a load or store has to wait for the SDRAM in [Latencies::dtekv]
+pub const SDRAM_STALL_CYCLES: u32 = 1;
+
+/// A timing model with a fixed latency for each instruction class
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Latencies {
+    pub alu: u32,
+    pub branch_taken: u32,
+    pub branch_not_taken: u32,
+    pub jump: u32,
+    pub load: u32,
+    pub store: u32,
+    pub multiply: u32,
+    pub divide: u32,
+    pub csr: u32,
+    pub system: u32,
+    /// Extra cycles for every load or store that goes to the SDRAM
+    pub sdram_stall: u32,
+}
+
+impl Latencies {
+    /// Approximates the DTEK-V pipeline. Taken branches and jumps flush the instructions fetched
+    /// after them, loads can't forward their result to the next instruction, division is
+    /// calculated one bit per cycle and every SDRAM access has to wait for the memory controller.
+    pub fn dtekv() -> Self {
+        Latencies {
+            alu: 1,
+            branch_taken: 3,
+            branch_not_taken: 1,
+            jump: 3,
+            load: 2,
+            store: 1,
+            multiply: 3,
+            divide: 34,
+            csr: 1,
+            system: 3,
+            sdram_stall: SDRAM_STALL_CYCLES,
+        }
+    }
+
+    /// Every instruction takes one cycle, this is what the emulator did before it had a timing
+    /// model
+    pub fn single_cycle() -> Self {
+        Latencies {
+            alu: 1,
+            branch_taken: 1,
+            branch_not_taken: 1,
+            jump: 1,
+            load: 1,
+            store: 1,
+            multiply: 1,
+            divide: 1,
+            csr: 1,
+            system: 1,
+            sdram_stall: 0,
+        }
+    }
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Self::dtekv()
+    }
+}
+
+impl TimingModel for Latencies {
+    fn latency(&self, class: InstructionClass, branch_taken: bool) -> u32 {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::Branch if branch_taken => self.branch_taken,
+            InstructionClass::Branch => self.branch_not_taken,
+            InstructionClass::Jump => self.jump,
+            InstructionClass::Load => self.load,
+            InstructionClass::Store => self.store,
+            InstructionClass::Multiply => self.multiply,
+            InstructionClass::Divide => self.divide,
+            InstructionClass::Csr => self.csr,
+            InstructionClass::System => self.system,
+        }
+    }
+
+    fn memory_stall(&self, addr: u32) -> u32 {
+        if (SDRAM_LOWER_ADDR..=SDRAM_HIGHER_ADDR).contains(&addr) {
+            self.sdram_stall
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_sdram_cpu;
+
+    #[test]
+    fn test_clock_returns_cycles() {
+        let model = Latencies::dtekv();
+
+        let program = [
+            0x00700293, // li t0, 7
+            0x00502023, // sw t0, 0(zero)
+            0x00002303, // lw t1, 0(zero)
+            0x0262c333, // div t1, t0, t1
+            0x00000463, // beqz zero, 0x18
+            0x00000013, // nop
+            0x0000006f, // j .
+        ];
+        let mut cpu = new_sdram_cpu(&program).with_timing_model(model);
+
+        assert_eq!(cpu.clock(), model.alu);
+        assert_eq!(cpu.clock(), model.store + model.sdram_stall);
+        assert_eq!(cpu.clock(), model.load + model.sdram_stall);
+        assert_eq!(cpu.clock(), model.divide);
+        assert_eq!(cpu.clock(), model.branch_taken);
+        assert_eq!(cpu.clock(), model.jump);
+
+        let total = model.alu
+            + model.store
+            + model.load
+            + 2 * model.sdram_stall
+            + model.divide
+            + model.branch_taken
+            + model.jump;
+        assert_eq!(cpu.cycles(), total as u64);
+        assert_eq!(cpu.instructions_retired(), 6);
+    }
+
+    #[test]
+    fn test_branch_not_taken() {
+        let model = Latencies {
+            branch_not_taken: 2,
+            ..Latencies::single_cycle()
+        };
+        let mut cpu = new_sdram_cpu(&[
+            0x00100463, // beq zero, ra, 8
+        ])
+        .with_timing_model(model);
+        cpu.regs.set(crate::register::Register::RA, 1);
+
+        assert_eq!(cpu.clock(), 2);
+        assert_eq!(cpu.pc, 4);
+    }
+}
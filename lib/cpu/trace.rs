@@ -0,0 +1,736 @@
+//! Recording every executed instruction
+//!
+//! A [Tracer] attached with [Cpu::with_tracer] writes a record for every step: the pc, the raw
+//! instruction word, the registers and CSRs it wrote, the memory it accessed through the Cpu and
+//! the traps that were taken. Records are written in one of two formats:
+//!
+//! - [TraceFormat::Spike] is the text format of `spike --log-commits`, so a trace can be diffed
+//!   against Spike directly. Traps are written the way `spike -l` writes them, without the tval
+//!   line.
+//! - [TraceFormat::Binary] is a compact format that can be read back with [TraceReader]. It
+//!   starts with [TRACE_MAGIC] and [TRACE_VERSION], followed by the records. All values are
+//!   little endian, each record is:
+//!
+//!   | Field | Size |
+//!   |-------|------|
+//!   | pc | 4 |
+//!   | instruction word | 4 |
+//!   | number of traps, registers, CSRs and memory accesses | 4 each |
+//!   | traps: mcause, epc | 4 + 4 |
+//!   | registers: register, value | 1 + 4 |
+//!   | CSRs: CSR, value | 2 + 4 |
+//!   | memory accesses: address, size with bit 7 set for writes, value | 4 + 1 + 4 |
+//!
+//! Tracing millions of instructions produces a lot of output, so a tracer can be limited to a part
+//! of the run with [Tracer::with_start] and [Tracer::with_stop].
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, BufWriter, Read, Write},
+    ops::Range,
+};
+
+use crate::{
+    csr::Csr, instruction::Instruction, interrupt::MCAUSE_INTERRUPT, peripheral::Peripheral,
+    register::Register,
+};
+
+use super::Cpu;
+
+/// The bytes every binary trace starts with
+pub const TRACE_MAGIC: [u8; 8] = *b"DTEKVTRC";
+
+/// Increased every time the binary format changes
+pub const TRACE_VERSION: u32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TraceFormat {
+    Binary,
+    Spike,
+}
+
+/// A condition that starts or stops tracing
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    /// Fires for every instruction with a pc in the range
+    PcRange(Range<u32>),
+    /// Fires when the given number of steps have been executed since the tracer was attached
+    InstructionCount(u64),
+}
+
+impl Trigger {
+    fn fires(&self, pc: u32, count: u64) -> bool {
+        match self {
+            Trigger::PcRange(range) => range.contains(&pc),
+            Trigger::InstructionCount(n) => count == *n,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MemoryAccess {
+    pub addr: u32,
+    /// The size in bytes, 1, 2 or 4
+    pub size: u8,
+    /// The value loaded or stored
+    pub value: u32,
+    pub write: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Trap {
+    pub mcause: u32,
+    /// The pc of the interrupted or faulting instruction
+    pub epc: u32,
+}
+
+impl Trap {
+    pub fn is_interrupt(&self) -> bool {
+        self.mcause & MCAUSE_INTERRUPT != 0
+    }
+}
+
+/// Everything that happened during a single step
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// The address of the instruction, after any interrupt was taken
+    pub pc: u32,
+    /// The raw instruction word, 0 if it couldn't be loaded. Compressed instructions only use the
+    /// lower half.
+    pub word: u32,
+    /// The decoded instruction, None if the word isn't a valid instruction
+    pub instruction: Option<Instruction>,
+    /// Traps in the order they were taken. Interrupts are taken before the instruction is
+    /// executed, an exception means the instruction didn't complete.
+    pub traps: Vec<Trap>,
+    /// Registers written by the instruction with their new value, writes to zero are left out
+    pub registers: Vec<(Register, u32)>,
+    /// CSRs written by the instruction with their new value
+    pub csrs: Vec<(Csr, u32)>,
+    pub memory: Vec<MemoryAccess>,
+}
+
+impl TraceRecord {
+    /// True if the instruction raised an exception instead of completing
+    pub fn trapped(&self) -> bool {
+        self.traps.iter().any(|trap| !trap.is_interrupt())
+    }
+
+    fn write_binary(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.pc.to_le_bytes())?;
+        out.write_all(&self.word.to_le_bytes())?;
+        // A semihosting call can access thousands of bytes in a single step
+        for len in [
+            self.traps.len(),
+            self.registers.len(),
+            self.csrs.len(),
+            self.memory.len(),
+        ] {
+            out.write_all(&(len as u32).to_le_bytes())?;
+        }
+
+        for trap in &self.traps {
+            out.write_all(&trap.mcause.to_le_bytes())?;
+            out.write_all(&trap.epc.to_le_bytes())?;
+        }
+        for (reg, value) in &self.registers {
+            out.write_all(&[reg.as_u32() as u8])?;
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for (csr, value) in &self.csrs {
+            out.write_all(&(csr.as_u32() as u16).to_le_bytes())?;
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for access in &self.memory {
+            out.write_all(&access.addr.to_le_bytes())?;
+            out.write_all(&[access.size | (access.write as u8) << 7])?;
+            out.write_all(&access.value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a record written by [TraceRecord::write_binary], None if the input ended before it
+    fn read_binary(input: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0; 24];
+        match input.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let field = |i: usize| u32::from_le_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+        let pc = field(0);
+        let word = field(1);
+        let mut record = TraceRecord {
+            pc,
+            word,
+            instruction: decode(word),
+            ..Default::default()
+        };
+
+        for _ in 0..field(2) {
+            record.traps.push(Trap {
+                mcause: read_u32(input)?,
+                epc: read_u32(input)?,
+            });
+        }
+        for _ in 0..field(3) {
+            let reg = Register::new(read_u8(input)? as u32).ok_or_else(invalid)?;
+            record.registers.push((reg, read_u32(input)?));
+        }
+        for _ in 0..field(4) {
+            let mut csr = [0; 2];
+            input.read_exact(&mut csr)?;
+            let csr = Csr::new(u16::from_le_bytes(csr) as u32).ok_or_else(invalid)?;
+            record.csrs.push((csr, read_u32(input)?));
+        }
+        for _ in 0..field(5) {
+            let addr = read_u32(input)?;
+            let size = read_u8(input)?;
+            record.memory.push(MemoryAccess {
+                addr,
+                size: size & 0x7f,
+                value: read_u32(input)?,
+                write: size & 0x80 != 0,
+            });
+        }
+
+        Ok(Some(record))
+    }
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Decodes a recorded instruction word, which is a halfword for compressed instructions
+fn decode(word: u32) -> Option<Instruction> {
+    #[cfg(feature = "compressed")]
+    if crate::instruction::is_compressed(word as u16) {
+        return Instruction::decompress(word as u16);
+    }
+
+    word.try_into().ok()
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid trace record")
+}
+
+/// The name Spike uses for an exception
+fn spike_exception_name(cause: u32) -> &'static str {
+    match cause {
+        0 => "trap_instruction_address_misaligned",
+        1 => "trap_instruction_access_fault",
+        2 => "trap_illegal_instruction",
+        3 => "trap_breakpoint",
+        4 => "trap_load_address_misaligned",
+        5 => "trap_load_access_fault",
+        6 => "trap_store_address_misaligned",
+        7 => "trap_store_access_fault",
+        8 => "trap_user_ecall",
+        9 => "trap_supervisor_ecall",
+        11 => "trap_machine_ecall",
+        _ => "trap_unknown",
+    }
+}
+
+/// Formats a record the way `spike --log-commits` does, the DTEK-V only has machine mode
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trap in &self.traps {
+            if trap.is_interrupt() {
+                writeln!(
+                    f,
+                    "core   0: interrupt #{}, epc 0x{:08x}",
+                    trap.mcause & !MCAUSE_INTERRUPT,
+                    trap.epc
+                )?;
+            } else {
+                // The instruction didn't commit, so there is nothing else to show
+                return writeln!(
+                    f,
+                    "core   0: exception {}, epc 0x{:08x}",
+                    spike_exception_name(trap.mcause),
+                    trap.epc
+                );
+            }
+        }
+
+        write!(f, "core   0: 3 0x{:08x} (0x{:08x})", self.pc, self.word)?;
+        for (reg, value) in &self.registers {
+            write!(f, " x{:<2} 0x{:08x}", reg.as_u32(), value)?;
+        }
+        for (csr, value) in &self.csrs {
+            let name = csr.name().unwrap_or("unknown");
+            write!(f, " c{}_{} 0x{:08x}", csr.as_u32(), name, value)?;
+        }
+        for access in self.memory.iter().filter(|access| !access.write) {
+            write!(f, " mem 0x{:08x}", access.addr)?;
+        }
+        for access in self.memory.iter().filter(|access| access.write) {
+            let width = access.size as usize * 2;
+            write!(f, " mem 0x{:08x} 0x{:0width$x}", access.addr, access.value)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Writes a [TraceRecord] for every step of the Cpu it's attached to
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    start: Option<Trigger>,
+    stop: Option<Trigger>,
+    active: bool,
+    /// Steps since the tracer was attached
+    count: u64,
+    /// True between fetching and finishing an instruction, accesses outside of that are made by
+    /// the frontend and aren't recorded
+    recording: bool,
+    /// Loads take &self, so the record has to be updated through a RefCell
+    record: RefCell<TraceRecord>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// Creates a tracer that traces everything, the output is buffered
+    pub fn new(out: impl Write + 'static, format: TraceFormat) -> Self {
+        let mut out = BufWriter::new(Box::new(out) as Box<dyn Write>);
+        if format == TraceFormat::Binary {
+            // Fits in the buffer, so writing it can't fail
+            out.write_all(&TRACE_MAGIC).unwrap();
+            out.write_all(&TRACE_VERSION.to_le_bytes()).unwrap();
+        }
+
+        Tracer {
+            out,
+            format,
+            start: None,
+            stop: None,
+            active: false,
+            count: 0,
+            recording: false,
+            record: RefCell::new(TraceRecord::default()),
+            error: None,
+        }
+    }
+
+    /// Tracing starts when the trigger fires instead of at the first instruction
+    pub fn with_start(mut self, trigger: Trigger) -> Self {
+        self.start = Some(trigger);
+        self
+    }
+
+    /// Tracing stops when the trigger fires, the instruction that fired it isn't traced. Tracing
+    /// starts again if the start trigger fires again, so a [Trigger::PcRange] can be used to trace
+    /// every call to a function.
+    pub fn with_stop(mut self, trigger: Trigger) -> Self {
+        self.stop = Some(trigger);
+        self
+    }
+
+    /// Flushes the output and returns the first error that happened while writing, tracing stops
+    /// at the first error
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.flush()
+    }
+
+    fn update_active(&mut self, pc: u32) {
+        if self.active {
+            if self
+                .stop
+                .as_ref()
+                .is_some_and(|stop| stop.fires(pc, self.count))
+            {
+                self.active = false;
+            }
+        } else {
+            self.active = match &self.start {
+                Some(start) => start.fires(pc, self.count),
+                None => self.count == 0,
+            };
+        }
+    }
+
+    fn write(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Binary => record.write_binary(&mut self.out),
+            TraceFormat::Spike => write!(self.out, "{}", record),
+        };
+
+        if let Err(err) = result {
+            self.error = Some(err);
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("start", &self.start)
+            .field("stop", &self.stop)
+            .field("active", &self.active)
+            .field("count", &self.count)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reads the records of a binary trace
+#[derive(Debug)]
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the header of the trace, fails if it isn't a binary trace of this version
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != TRACE_MAGIC || read_u32(&mut input)? != TRACE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a binary trace of a supported version",
+            ));
+        }
+
+        Ok(TraceReader { input })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        TraceRecord::read_binary(&mut self.input).transpose()
+    }
+}
+
+/// The register an instruction writes its result to
+fn destination(instruction: Instruction) -> Option<Register> {
+    use Instruction as I;
+
+    match instruction {
+        I::LUI { rd, .. }
+        | I::AUIPC { rd, .. }
+        | I::JAL { rd, .. }
+        | I::JALR { rd, .. }
+        | I::LB { rd, .. }
+        | I::LH { rd, .. }
+        | I::LW { rd, .. }
+        | I::LBU { rd, .. }
+        | I::LHU { rd, .. }
+        | I::ADDI { rd, .. }
+        | I::ANDI { rd, .. }
+        | I::ORI { rd, .. }
+        | I::XORI { rd, .. }
+        | I::SLLI { rd, .. }
+        | I::SRLI { rd, .. }
+        | I::SRAI { rd, .. }
+        | I::SLTI { rd, .. }
+        | I::SLTIU { rd, .. }
+        | I::ADD { rd, .. }
+        | I::SUB { rd, .. }
+        | I::SLT { rd, .. }
+        | I::SLTU { rd, .. }
+        | I::SLL { rd, .. }
+        | I::SRL { rd, .. }
+        | I::SRA { rd, .. }
+        | I::AND { rd, .. }
+        | I::OR { rd, .. }
+        | I::XOR { rd, .. }
+        | I::CSRRW { rd, .. }
+        | I::CSRRS { rd, .. }
+        | I::CSRRC { rd, .. }
+        | I::CSRRWI { rd, .. }
+        | I::CSRRSI { rd, .. }
+        | I::CSRRCI { rd, .. }
+        | I::MUL { rd, .. }
+        | I::MULH { rd, .. }
+        | I::MULHSU { rd, .. }
+        | I::MULHU { rd, .. }
+        | I::DIV { rd, .. }
+        | I::DIVU { rd, .. }
+        | I::REM { rd, .. }
+        | I::REMU { rd, .. } => Some(rd).filter(|rd| *rd != Register::ZERO),
+        I::BEQ { .. }
+        | I::BNE { .. }
+        | I::BLT { .. }
+        | I::BGE { .. }
+        | I::BLTU { .. }
+        | I::BGEU { .. }
+        | I::SB { .. }
+        | I::SH { .. }
+        | I::SW { .. }
+        | I::MRET
+        | I::ECALL
+        | I::EBREAK
+        | I::WFI
+        | I::FENCE { .. }
+        | I::FENCE_I => None,
+    }
+}
+
+/// The CSR an instruction writes, reads through csrrs and csrrc with nothing to set or clear
+/// don't write anything
+fn written_csr(instruction: Instruction) -> Option<Csr> {
+    use Instruction as I;
+
+    match instruction {
+        I::CSRRW { csr, .. } | I::CSRRWI { csr, .. } => Some(csr),
+        I::CSRRS { csr, rs1, .. } | I::CSRRC { csr, rs1, .. } if rs1 != Register::ZERO => Some(csr),
+        I::CSRRSI { csr, imm, .. } | I::CSRRCI { csr, imm, .. } if imm != 0 => Some(csr),
+        I::MRET => Some(Csr::MSTATUS),
+        _ => None,
+    }
+}
+
+impl<T: Peripheral<()>> Cpu<T> {
+    /// Attaches a tracer that records every step from now on
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(Box::new(tracer));
+        self
+    }
+
+    /// Detaches the tracer, call [Tracer::finish] on it to flush the output
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take().map(|tracer| *tracer)
+    }
+
+    /// Called after the instruction at `pc` has been fetched and is about to be executed
+    pub(crate) fn trace_fetch(&mut self, pc: u32) {
+        let Some(tracer) = &mut self.tracer else {
+            return;
+        };
+
+        tracer.update_active(pc);
+        tracer.recording = true;
+
+        let word = self.bus.peek_word(pc).unwrap_or(0);
+        // A compressed instruction only takes up the lower half
+        #[cfg(feature = "compressed")]
+        let word = if crate::instruction::is_compressed(word as u16) {
+            word & 0xFFFF
+        } else {
+            word
+        };
+        let record = tracer.record.get_mut();
+        record.pc = pc;
+        record.word = word;
+    }
+
+    pub(crate) fn trace_trap(&mut self, mcause: u32) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record.get_mut().traps.push(Trap {
+                mcause,
+                epc: self.pc,
+            });
+        }
+    }
+
+    pub(crate) fn trace_memory(&self, addr: u32, size: u8, value: u32, write: bool) {
+        if let Some(tracer) = self.tracer.as_ref().filter(|tracer| tracer.recording) {
+            tracer.record.borrow_mut().memory.push(MemoryAccess {
+                addr,
+                size,
+                value,
+                write,
+            });
+        }
+    }
+
+    /// Called once the instruction has been executed, writes the record if tracing is active
+    pub(crate) fn trace_end(&mut self, instruction: Option<Instruction>) {
+        let Some(tracer) = &mut self.tracer else {
+            return;
+        };
+
+        let mut record = tracer.record.take();
+        record.instruction = instruction;
+        if let Some(instruction) = instruction.filter(|_| !record.trapped()) {
+            if let Some(rd) = destination(instruction) {
+                record.registers.push((rd, self.regs.get(rd)));
+            }
+            if let Some(csr) = written_csr(instruction) {
+                record.csrs.push((csr, self.csr.load(csr)));
+            }
+        }
+
+        if tracer.active {
+            tracer.write(&record);
+        }
+        tracer.count += 1;
+        tracer.recording = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interrupt::InterruptSignal,
+        memory_mapped::MemoryMapped,
+        peripheral::SDRam,
+        test_utils::{new_sdram_cpu, SharedBuffer},
+    };
+
+    fn run_traced(program: &[u32], tracer: Tracer, steps: u64) -> Tracer {
+        let mut cpu = new_sdram_cpu(program).with_tracer(tracer);
+        cpu.run(steps);
+        cpu.take_tracer().unwrap()
+    }
+
+    const PROGRAM: [u32; 6] = [
+        0x10000293, // li t0, 256
+        0x00a00313, // li t1, 10
+        0x00629023, // sh t1, 0(t0)
+        0x0002a383, // lw t2, 0(t0)
+        0x34029073, // csrw mscratch, t0
+        0xffffffff, // illegal
+    ];
+
+    #[test]
+    fn test_spike_format() {
+        let out = SharedBuffer::default();
+        let tracer = run_traced(&PROGRAM, Tracer::new(out.clone(), TraceFormat::Spike), 6);
+        tracer.finish().unwrap();
+
+        let trace = String::from_utf8(out.0.take()).unwrap();
+        assert_eq!(
+            trace,
+            "core   0: 3 0x00000000 (0x10000293) x5  0x00000100\n\
+             core   0: 3 0x00000004 (0x00a00313) x6  0x0000000a\n\
+             core   0: 3 0x00000008 (0x00629023) mem 0x00000100 0x000a\n\
+             core   0: 3 0x0000000c (0x0002a383) x7  0x0000000a mem 0x00000100\n\
+             core   0: 3 0x00000010 (0x34029073) c832_mscratch 0x00000100\n\
+             core   0: exception trap_illegal_instruction, epc 0x00000014\n"
+        );
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let out = SharedBuffer::default();
+        let tracer = run_traced(&PROGRAM, Tracer::new(out.clone(), TraceFormat::Binary), 6);
+        tracer.finish().unwrap();
+
+        let data = out.0.take();
+        let records: Vec<TraceRecord> = TraceReader::new(&data[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0].registers, vec![(Register::T0, 0x100)]);
+        assert_eq!(
+            records[2].memory,
+            vec![MemoryAccess {
+                addr: 0x100,
+                size: 2,
+                value: 10,
+                write: true
+            }]
+        );
+        assert_eq!(records[4].csrs, vec![(Csr::MSCRATCH, 0x100)]);
+        assert_eq!(records[5].instruction, None);
+        assert_eq!(
+            records[5].traps,
+            vec![Trap {
+                mcause: 2,
+                epc: 0x14
+            }]
+        );
+        assert!(records[5].trapped());
+
+        assert!(TraceReader::new(&b"not a trace"[..]).is_err());
+    }
+
+    #[test]
+    fn test_many_accesses() {
+        let record = TraceRecord {
+            memory: (0..300)
+                .map(|i| MemoryAccess {
+                    addr: 0x1000 + i,
+                    size: 1,
+                    value: i & 0xff,
+                    write: true,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut data = Vec::new();
+        record.write_binary(&mut data).unwrap();
+        let read = TraceRecord::read_binary(&mut &data[..]).unwrap().unwrap();
+        assert_eq!(read.memory, record.memory);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let program = [
+            0x00128293, // addi t0, t0, 1
+            0x00000013, // nop
+            0xff9ff06f, // j 0
+        ];
+
+        let out = SharedBuffer::default();
+        let tracer = Tracer::new(out.clone(), TraceFormat::Binary)
+            .with_start(Trigger::InstructionCount(4))
+            .with_stop(Trigger::InstructionCount(6));
+        run_traced(&program, tracer, 20).finish().unwrap();
+        let records: Vec<_> = TraceReader::new(&out.0.take()[..])
+            .unwrap()
+            .map(|record| record.unwrap().pc)
+            .collect();
+        assert_eq!(records, vec![4, 8]);
+
+        // Every time the loop reaches the nop
+        let out = SharedBuffer::default();
+        let tracer = Tracer::new(out.clone(), TraceFormat::Binary)
+            .with_start(Trigger::PcRange(4..8))
+            .with_stop(Trigger::PcRange(8..12));
+        run_traced(&program, tracer, 9).finish().unwrap();
+        let records: Vec<_> = TraceReader::new(&out.0.take()[..])
+            .unwrap()
+            .map(|record| record.unwrap().pc)
+            .collect();
+        assert_eq!(records, vec![4, 4, 4]);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let out = SharedBuffer::default();
+        let mut cpu = Cpu::new_with_bus(SDRam::new())
+            .with_tracer(Tracer::new(out.clone(), TraceFormat::Spike));
+        cpu.store_word(0, 0x00000013).unwrap();
+        cpu.pc = 8;
+        cpu.csr.set_mstatus_mie(true);
+        cpu.csr.store(Csr::MIE, 1 << 16);
+        cpu.set_interrupt_pending(InterruptSignal::TIMER_INTERRUPT, true);
+
+        assert_eq!(cpu.step(), None);
+        cpu.take_tracer().unwrap().finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out.0.take()).unwrap(),
+            "core   0: interrupt #16, epc 0x00000008\n\
+             core   0: 3 0x00000000 (0x00000013)\n"
+        );
+    }
+}
@@ -7,16 +7,20 @@ pub const MIN_CSR: u32 = 0;
 impl Csr {
     /// Creates a new CSR, returns None if the given CSR is not a valid address
     pub fn new(csr: u32) -> Option<Self> {
-        if csr > MAX_CSR || csr < MIN_CSR {
-            return None;
+        if csr > MAX_CSR {
+            None
         } else {
             Some(unsafe { Csr::new_unchecked(csr as u16) })
         }
     }
 
     /// Creates a new CSR without checking if the given CSR is a valid address
+    ///
+    /// # Safety
+    ///
+    /// The value has to be a valid CSR address, i.e. at most `MAX_CSR`
     pub unsafe fn new_unchecked(csr: u16) -> Self {
-        if (csr as u32) > MAX_CSR || (csr as u32) < MIN_CSR {
+        if (csr as u32) > MAX_CSR {
             if cfg!(debug_assertions) {
                 unreachable!("CSR is set to an invalid value");
             } else {
@@ -26,14 +30,47 @@ impl Csr {
         Csr(csr)
     }
 
+    pub fn as_u32(&self) -> u32 {
+        self.0 as u32
+    }
+
+    /// If the CSR exists on the DTEK-V. Accessing any other CSR raises an illegal instruction
+    /// exception.
+    pub fn implemented(&self) -> bool {
+        self.name().is_some()
+    }
+
+    /// CSRs with the two highest address bits set are read-only, writing to them raises an
+    /// illegal instruction exception
+    pub fn read_only(&self) -> bool {
+        self.0 >> 10 == 0b11
+    }
+
     /// If a given CSR is has a meaningful implementation. I.e if there is a reason to write and read
     /// from this register. This is useful for generating warnings when a CSR is accessed that wouldn't matter,
     /// informing the user that what is trying to be done is not implemented.
     pub fn meaningfully_emulated(&self) -> bool {
-        match *self {
-            Csr::MSTATUS | Csr::MIE | Csr::MEPC | Csr::MCAUSE => true,
-            _ => false,
-        }
+        matches!(
+            *self,
+            Csr::MSTATUS
+                | Csr::MIE
+                | Csr::MIP
+                | Csr::MEPC
+                | Csr::MTVEC
+                | Csr::MCAUSE
+                | Csr::MCOUNTINHIBIT
+                | Csr::MSCRATCH
+                | Csr::MTVAL
+                | Csr::MISA
+                | Csr::MVENDORID
+                | Csr::MARCHID
+                | Csr::MIMPID
+                | Csr::MHARTID
+        ) || (Csr::MHPMEVENT3.0..=Csr::MHPMEVENT9.0).contains(&self.0)
+            || (Csr::MCYCLE.0..=Csr::MHPMCOUNTER9.0).contains(&self.0)
+            || (Csr::MCYCLEH.0..=Csr::MHPMCOUNTER9H.0).contains(&self.0)
+            || (Csr::CYCLE.0..=Csr::INSTRET.0).contains(&self.0)
+            || (Csr::CYCLEH.0..=Csr::INSTRETH.0).contains(&self.0)
     }
 }
 
@@ -55,22 +92,22 @@ impl TryFrom<u32> for Csr {
     }
 }
 
-impl Into<u16> for Csr {
-    fn into(self) -> u16 {
-        self.0
+impl From<Csr> for u16 {
+    fn from(csr: Csr) -> u16 {
+        csr.0
     }
 }
 
-impl Into<u32> for Csr {
-    fn into(self) -> u32 {
-        self.0 as u32
+impl From<Csr> for u32 {
+    fn from(csr: Csr) -> u32 {
+        csr.0 as u32
     }
 }
 
-impl Into<usize> for Csr {
-    fn into(self) -> usize {
+impl From<Csr> for usize {
+    fn from(csr: Csr) -> usize {
         // Since CSR can't be negative, this is safe
-        self.0 as usize
+        csr.0 as usize
     }
 }
 
@@ -93,10 +130,22 @@ impl Csr {
 
 csr_list! {
     (MSTATUS, 0x300, "mstatus"),
+    (MISA, 0x301, "misa"),
     (MIE, 0x304, "mie"),
+    (MIP, 0x344, "mip"),
     (MEPC, 0x341, "mepc"),
     (MTVEC, 0x305, "mtvec"),
     (MCAUSE, 0x342, "mcause"),
+    (MSCRATCH, 0x340, "mscratch"),
+    (MTVAL, 0x343, "mtval"),
+    (MCOUNTINHIBIT, 0x320, "mcountinhibit"),
+    (MHPMEVENT3, 0x323, "mhpmevent3"),
+    (MHPMEVENT4, 0x324, "mhpmevent4"),
+    (MHPMEVENT5, 0x325, "mhpmevent5"),
+    (MHPMEVENT6, 0x326, "mhpmevent6"),
+    (MHPMEVENT7, 0x327, "mhpmevent7"),
+    (MHPMEVENT8, 0x328, "mhpmevent8"),
+    (MHPMEVENT9, 0x329, "mhpmevent9"),
     (MCYCLE, 0xB00, "mcycle"),
     (MCYCLEH, 0xB80, "mcycleh"),
     (MINSTRET, 0xB02, "minstret"),
@@ -115,4 +164,14 @@ csr_list! {
     (MHPMCOUNTER8H, 0xB88, "mhpmcounter8h"),
     (MHPMCOUNTER9, 0xB09, "mhpmcounter9"),
     (MHPMCOUNTER9H, 0xB89, "mhpmcounter9h"),
+    (CYCLE, 0xC00, "cycle"),
+    (CYCLEH, 0xC80, "cycleh"),
+    (TIME, 0xC01, "time"),
+    (TIMEH, 0xC81, "timeh"),
+    (INSTRET, 0xC02, "instret"),
+    (INSTRETH, 0xC82, "instreth"),
+    (MVENDORID, 0xF11, "mvendorid"),
+    (MARCHID, 0xF12, "marchid"),
+    (MIMPID, 0xF13, "mimpid"),
+    (MHARTID, 0xF14, "mhartid"),
 }
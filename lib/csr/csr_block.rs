@@ -1,12 +1,64 @@
+use std::fmt;
+
 use super::Csr;
 
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_MPIE: u32 = 1 << 7;
+
+/// Why a CSR instruction couldn't access a CSR, both raise an illegal instruction exception
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CsrError {
+    /// The CSR doesn't exist
+    Unimplemented,
+    /// The CSR was written but is read-only
+    ReadOnly,
+}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrError::Unimplemented => write!(f, "the CSR isn't implemented"),
+            CsrError::ReadOnly => write!(f, "the CSR is read-only"),
+        }
+    }
+}
+
+impl std::error::Error for CsrError {}
+
+/// How the trap handler address is calculated from mtvec
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TrapVectorMode {
+    /// All traps set pc to BASE
+    Direct,
+    /// Exceptions set pc to BASE, interrupts set pc to BASE + 4 * cause
+    Vectored,
+}
+
 pub struct CsrBlock {
     csrs: [u32; 4096],
+    /// The alignment instructions need, which decides the bits of mepc that can be written
+    instruction_align: u32,
+}
+
+impl Default for CsrBlock {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CsrBlock {
+    /// Creates a block for a hart without compressed instructions
     pub fn new() -> Self {
-        Self { csrs: [0; 4096] }
+        Self::new_with_instruction_align(4)
+    }
+
+    /// Creates a block for a hart whose instructions are aligned to `instruction_align` bytes,
+    /// 2 with compressed instructions and 4 without
+    pub fn new_with_instruction_align(instruction_align: u32) -> Self {
+        Self {
+            csrs: [0; 4096],
+            instruction_align,
+        }
     }
 
     /// Set all CSRs to 0
@@ -16,27 +68,113 @@ impl CsrBlock {
         }
     }
 
+    /// Reads a CSR the way a CSR instruction does. Reads don't have any side effects. The
+    /// unprivileged counters read the machine counters they shadow.
+    pub fn read(&self, csr: Csr) -> Result<u32, CsrError> {
+        if !csr.implemented() {
+            return Err(CsrError::Unimplemented);
+        }
+
+        let csr = match csr {
+            Csr::CYCLE | Csr::TIME => Csr::MCYCLE,
+            Csr::CYCLEH | Csr::TIMEH => Csr::MCYCLEH,
+            Csr::INSTRET => Csr::MINSTRET,
+            Csr::INSTRETH => Csr::MINSTRETH,
+            _ => csr,
+        };
+        Ok(self.load(csr))
+    }
+
+    /// Writes a CSR the way a CSR instruction does. Only the bits that software can change are
+    /// written, the others keep their value, and an mtvec with a reserved mode is turned into
+    /// direct mode. Use [CsrBlock::store] to change the value without these rules.
+    pub fn write(&mut self, csr: Csr, value: u32) -> Result<(), CsrError> {
+        if !csr.implemented() {
+            return Err(CsrError::Unimplemented);
+        }
+        if csr.read_only() {
+            return Err(CsrError::ReadOnly);
+        }
+
+        let value = match csr {
+            Csr::MTVEC if value & 0b11 >= 2 => value & !0b11,
+            _ => value,
+        };
+        let mask = self.write_mask(csr);
+        self.store(csr, self.load(csr) & !mask | value & mask);
+        Ok(())
+    }
+
+    /// The bits of a CSR that CSR instructions can change
+    fn write_mask(&self, csr: Csr) -> u32 {
+        match csr {
+            // Only machine mode exists, so everything but the interrupt enables is hardwired
+            Csr::MSTATUS => MSTATUS_MIE | MSTATUS_MPIE,
+            // The standard software, timer and external interrupts, and the platform interrupts
+            Csr::MIE => 0xFFFF_0888,
+            // The pending bits follow the interrupt lines, so they can't be written
+            Csr::MIP | Csr::MISA => 0,
+            Csr::MEPC => !(self.instruction_align - 1),
+            // There is no time CSR that could be inhibited
+            Csr::MCOUNTINHIBIT => !0b10,
+            _ => u32::MAX,
+        }
+    }
+
     pub fn get_mstatus_mpie(&self) -> bool {
-        self.load(Csr::MSTATUS) & (1 << 7) != 0
+        self.load(Csr::MSTATUS) & MSTATUS_MPIE != 0
     }
 
     pub fn set_mstatus_mpie(&mut self, value: bool) {
         let v = self.load(Csr::MSTATUS);
-        let v = if value { v | 1 << 7 } else { v & !(1 << 7) };
+        let v = if value {
+            v | MSTATUS_MPIE
+        } else {
+            v & !MSTATUS_MPIE
+        };
         self.store(Csr::MSTATUS, v);
     }
 
     pub fn get_mstatus_mie(&self) -> bool {
-        self.load(Csr::MSTATUS) & (1 << 3) != 0
+        self.load(Csr::MSTATUS) & MSTATUS_MIE != 0
     }
 
     /// Set's the mie bit in mstatus to either 0 or 1
     pub fn set_mstatus_mie(&mut self, value: bool) {
         let v = self.load(Csr::MSTATUS);
-        let v = if value { v | 1 << 3 } else { v & !(1 << 3) };
+        let v = if value {
+            v | MSTATUS_MIE
+        } else {
+            v & !MSTATUS_MIE
+        };
         self.store(Csr::MSTATUS, v);
     }
 
+    /// The trap vector base address stored in mtvec, always 4 byte aligned
+    pub fn get_mtvec_base(&self) -> u32 {
+        self.load(Csr::MTVEC) & !0b11
+    }
+
+    /// The trap vector mode stored in the lowest two bits of mtvec. The reserved modes (>= 2) are
+    /// treated as direct
+    pub fn get_mtvec_mode(&self) -> TrapVectorMode {
+        match self.load(Csr::MTVEC) & 0b11 {
+            1 => TrapVectorMode::Vectored,
+            _ => TrapVectorMode::Direct,
+        }
+    }
+
+    /// Loads a 64 bit value split into a low and a high CSR, like mcycle and mcycleh
+    pub fn load_u64(&self, low: Csr, high: Csr) -> u64 {
+        (self.load(high) as u64) << 32 | self.load(low) as u64
+    }
+
+    /// Stores a 64 bit value split into a low and a high CSR, like mcycle and mcycleh
+    pub fn store_u64(&mut self, low: Csr, high: Csr, value: u64) {
+        self.store(low, value as u32);
+        self.store(high, (value >> 32) as u32);
+    }
+
     pub fn load(&self, csr: Csr) -> u32 {
         // SAFETY: Csr is guaranteed to be in the range 0..4096
         let csr = Into::<usize>::into(csr);
@@ -56,7 +194,7 @@ impl std::fmt::Debug for CsrBlock {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Csr {{ ")?;
 
-        for res in vec![Csr::MSTATUS, Csr::MIE, Csr::MEPC, Csr::MCAUSE]
+        for res in vec![Csr::MSTATUS, Csr::MIE, Csr::MEPC, Csr::MTVEC, Csr::MCAUSE]
             .into_iter()
             .map(|csr| {
                 write!(
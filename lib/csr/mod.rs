@@ -1,7 +1,8 @@
 //! Control and Status Registers (CSRs) for the RISC-V architecture.
 
+#[allow(clippy::module_inception)]
 mod csr;
-pub use csr::Csr;
+pub use csr::{Csr, MAX_CSR, MIN_CSR};
 
 mod csr_block;
-pub use csr_block::CsrBlock;
+pub use csr_block::{CsrBlock, CsrError, TrapVectorMode};
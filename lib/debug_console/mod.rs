@@ -2,7 +2,7 @@
 
 use std::collections::LinkedList;
 
-use crate::csr::Csr;
+use crate::csr::{Csr, CsrError};
 
 #[derive(Debug)]
 pub enum Entry {
@@ -12,15 +12,15 @@ pub enum Entry {
 
 impl Entry {}
 
-impl Into<Entry> for Warning {
-    fn into(self) -> Entry {
-        return Entry::Warning(self);
+impl From<Warning> for Entry {
+    fn from(warning: Warning) -> Entry {
+        Entry::Warning(warning)
     }
 }
 
-impl Into<Entry> for Error {
-    fn into(self) -> Entry {
-        return Entry::Error(self);
+impl From<Error> for Entry {
+    fn from(error: Error) -> Entry {
+        Entry::Error(error)
     }
 }
 
@@ -43,6 +43,12 @@ pub enum Error {
     RemainderByZero { instr_addr: u32 },
     /// When an illegal instruction is executed
     IllegalInstruction { instr: u32, instr_addr: u32 },
+    /// When a CSR instruction accesses a CSR that doesn't exist or writes a read-only one
+    IllegalCsrAccess {
+        csr: Csr,
+        error: CsrError,
+        instr_addr: u32,
+    },
     /// When an instruction is executed that is not aligned to 4 bytes
     InstructionMisaligned { instr_addr: u32 },
     /// When a load instruction is out of bounds
@@ -57,11 +63,17 @@ pub struct DebugConsole {
     pub lines: LinkedList<Entry>,
 }
 
+impl Default for DebugConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DebugConsole {
     pub fn new() -> Self {
-        return Self {
+        Self {
             lines: LinkedList::new(),
-        };
+        }
     }
 
     pub fn push(&mut self, line: Entry) {
@@ -69,11 +81,11 @@ impl DebugConsole {
     }
 
     pub fn pop(&mut self) -> Option<Entry> {
-        return self.lines.pop_front();
+        self.lines.pop_front()
     }
 
     pub fn is_empty(&self) -> bool {
-        return self.lines.is_empty();
+        self.lines.is_empty()
     }
 
     pub(crate) fn access_useless_csr(&mut self, csr: Csr, instr_addr: u32) {
@@ -83,8 +95,15 @@ impl DebugConsole {
         self.push(Error::IllegalInstruction { instr, instr_addr }.into());
     }
 
-    pub(crate) fn instruction_not_implemented(&mut self, instr: &'static str, instr_addr: u32) {
-        self.push(Error::InstructionNotImplemented { instr, instr_addr }.into());
+    pub(crate) fn illegal_csr_access(&mut self, csr: Csr, error: CsrError, instr_addr: u32) {
+        self.push(
+            Error::IllegalCsrAccess {
+                csr,
+                error,
+                instr_addr,
+            }
+            .into(),
+        );
     }
 
     pub(crate) fn division_by_zero(&mut self, instr_addr: u32) {
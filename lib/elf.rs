@@ -0,0 +1,490 @@
+//! ELF loading
+//!
+//! Parses RV32 ELF32 executables, the kind `riscv64-unknown-elf-ld` outputs before running
+//! `objcopy -O binary` on it. Loading an ELF directly keeps the symbol table around, which is
+//! useful for looking up addresses of functions and variables after the program has been loaded.
+//!
+//! ```rust,no_run
+//! # use dtekv_emulator_core::*;
+//! let bytes = std::fs::read("path/to/main.elf").unwrap();
+//! let elf = elf::Elf::parse(&bytes).unwrap();
+//!
+//! let mut cpu = cpu::Cpu::new_with_bus(peripheral::SDRam::new());
+//! elf.load(&mut cpu).unwrap();
+//!
+//! let main = elf.symbol("main").unwrap();
+//! println!("main is located at {:#010x}", main.addr);
+//! ```
+
+use std::fmt;
+
+use crate::{cpu::Cpu, memory_mapped::MemoryMapped, peripheral::Peripheral};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ended before a header or segment it references
+    Truncated,
+    /// The file doesn't start with the ELF magic bytes
+    NotElf,
+    /// The file is not a 32 bit ELF file
+    Not32Bit,
+    /// The file is not little endian
+    NotLittleEndian,
+    /// The file is built for another architecture than RISC-V
+    NotRiscV { machine: u16 },
+    /// A segment couldn't be written to memory, most likely because nothing is attached at the
+    /// given address
+    LoadFailed { addr: u32 },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Not32Bit => write!(f, "ELF file is not 32 bit"),
+            ElfError::NotLittleEndian => write!(f, "ELF file is not little endian"),
+            ElfError::NotRiscV { machine } => {
+                write!(
+                    f,
+                    "ELF file is not built for RISC-V (e_machine = {})",
+                    machine
+                )
+            }
+            ElfError::LoadFailed { addr } => {
+                write!(f, "failed to load segment byte at address {:#010x}", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A loadable (PT_LOAD) segment of an ELF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The physical address the segment is loaded to
+    pub addr: u32,
+    /// The bytes stored in the file
+    pub data: Vec<u8>,
+    /// The size of the segment in memory, everything after `data` is zero filled (.bss)
+    pub mem_size: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+/// An entry in the ELF symbol table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+/// A parsed RV32 ELF file
+#[derive(Debug, Clone)]
+pub struct Elf {
+    entry: u32,
+    segments: Vec<Segment>,
+    symbols: Vec<Symbol>,
+}
+
+impl Elf {
+    /// Parses an ELF file, only little endian 32 bit RISC-V files are accepted
+    pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+        let header = bytes.get(..ELF_HEADER_SIZE).ok_or_else(|| {
+            if bytes.len() >= 4 && bytes[..4] != ELF_MAGIC {
+                ElfError::NotElf
+            } else {
+                ElfError::Truncated
+            }
+        })?;
+
+        if header[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if header[4] != ELFCLASS32 {
+            return Err(ElfError::Not32Bit);
+        }
+        if header[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+
+        let machine = read_u16(bytes, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::NotRiscV { machine });
+        }
+
+        let entry = read_u32(bytes, 24)?;
+        let phoff = read_u32(bytes, 28)? as usize;
+        let shoff = read_u32(bytes, 32)? as usize;
+        let phentsize = read_u16(bytes, 42)? as usize;
+        let phnum = read_u16(bytes, 44)? as usize;
+        let shentsize = read_u16(bytes, 46)? as usize;
+        let shnum = read_u16(bytes, 48)? as usize;
+
+        let mut segments = vec![];
+        if phnum != 0 && phentsize < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        for i in 0..phnum {
+            let ph = read_bytes(
+                bytes,
+                table_entry(phoff, i, phentsize)?,
+                PROGRAM_HEADER_SIZE,
+            )?;
+            if read_u32(ph, 0)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(ph, 4)? as usize;
+            let addr = read_u32(ph, 12)?;
+            let file_size = read_u32(ph, 16)? as usize;
+            let mem_size = read_u32(ph, 20)?;
+
+            let data = read_bytes(bytes, offset, file_size)?.to_vec();
+
+            segments.push(Segment {
+                addr,
+                data,
+                mem_size,
+            });
+        }
+
+        let mut symbols = vec![];
+        if shoff != 0 && shnum != 0 {
+            if shentsize < SECTION_HEADER_SIZE {
+                return Err(ElfError::Truncated);
+            }
+            for i in 0..shnum {
+                let sh = read_bytes(
+                    bytes,
+                    table_entry(shoff, i, shentsize)?,
+                    SECTION_HEADER_SIZE,
+                )?;
+                if read_u32(sh, 4)? != SHT_SYMTAB {
+                    continue;
+                }
+
+                let offset = read_u32(sh, 16)? as usize;
+                let size = read_u32(sh, 20)? as usize;
+                let link = read_u32(sh, 24)? as usize;
+
+                let strtab_header = read_bytes(
+                    bytes,
+                    table_entry(shoff, link, shentsize)?,
+                    SECTION_HEADER_SIZE,
+                )?;
+                let strtab_offset = read_u32(strtab_header, 16)? as usize;
+                let strtab_size = read_u32(strtab_header, 20)? as usize;
+                let strtab = read_bytes(bytes, strtab_offset, strtab_size)?;
+
+                let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+                // The first entry is always the undefined symbol
+                for sym in (offset..end).step_by(SYMBOL_SIZE).skip(1) {
+                    let sym = read_bytes(bytes, sym, SYMBOL_SIZE)?;
+                    let name = read_u32(sym, 0)? as usize;
+                    let addr = read_u32(sym, 4)?;
+                    let size = read_u32(sym, 8)?;
+                    let info = sym[12];
+
+                    let name = strtab
+                        .get(name..)
+                        .and_then(|s| s.split(|b| *b == 0).next())
+                        .map(|s| String::from_utf8_lossy(s).into_owned())
+                        .unwrap_or_default();
+
+                    if name.is_empty() {
+                        continue;
+                    }
+
+                    let kind = match info & 0xF {
+                        STT_FUNC => SymbolKind::Function,
+                        STT_OBJECT => SymbolKind::Object,
+                        _ => SymbolKind::Other,
+                    };
+
+                    symbols.push(Symbol {
+                        name,
+                        addr,
+                        size,
+                        kind,
+                    });
+                }
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    /// The address execution starts at
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Looks up a symbol by name
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Finds the function or object symbol that contains the given address
+    pub fn symbol_at(&self, addr: u32) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .filter(|sym| sym.kind != SymbolKind::Other)
+            .find(|sym| addr == sym.addr || (addr > sym.addr && addr - sym.addr < sym.size))
+    }
+
+    /// Writes every segment into memory through the Cpu and sets the pc to the entry point.
+    ///
+    /// Memory is written through the Cpu's implementation of MemoryMapped so that the instruction
+    /// cache stays in sync with memory.
+    pub fn load<T: Peripheral<()>>(&self, cpu: &mut Cpu<T>) -> Result<(), ElfError> {
+        for segment in &self.segments {
+            for (i, byte) in segment.data.iter().enumerate() {
+                let addr = segment.addr.wrapping_add(i as u32);
+                cpu.store_byte(addr, *byte)
+                    .map_err(|_| ElfError::LoadFailed { addr })?;
+            }
+
+            // Zero fill the rest of the segment, this is where .bss lives
+            for i in segment.data.len() as u32..segment.mem_size {
+                let addr = segment.addr.wrapping_add(i);
+                cpu.store_byte(addr, 0)
+                    .map_err(|_| ElfError::LoadFailed { addr })?;
+            }
+        }
+
+        cpu.pc = self.entry;
+
+        Ok(())
+    }
+}
+
+/// The offset of entry `index` in a table of `size` byte entries at `offset`. An offset that
+/// doesn't fit in a usize is past the end of the file as well.
+fn table_entry(offset: usize, index: usize, size: usize) -> Result<usize, ElfError> {
+    index
+        .checked_mul(size)
+        .and_then(|entry| offset.checked_add(entry))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_bytes(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    bytes.get(offset..end).ok_or(ElfError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read_bytes(bytes, offset, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read_bytes(bytes, offset, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peripheral::SDRam;
+
+    /// Builds a minimal ELF file with one PT_LOAD segment and a symbol table containing `main`
+    fn build_elf(code: &[u32], addr: u32, entry: u32, bss: u32) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let strtab = b"\0main\0buffer\0".to_vec();
+
+        let phoff = ELF_HEADER_SIZE;
+        let code_off = phoff + PROGRAM_HEADER_SIZE;
+        let symtab_off = code_off + code.len();
+        let strtab_off = symtab_off + 3 * SYMBOL_SIZE;
+        let shoff = strtab_off + strtab.len();
+
+        let mut out = vec![];
+        out.extend_from_slice(&ELF_MAGIC);
+        out.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, 1]);
+        out.resize(16, 0);
+        out.extend_from_slice(&2u16.to_le_bytes()); // e_type
+        out.extend_from_slice(&EM_RISCV.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        out.extend_from_slice(&entry.to_le_bytes());
+        out.extend_from_slice(&(phoff as u32).to_le_bytes());
+        out.extend_from_slice(&(shoff as u32).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&3u16.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+
+        // Program header
+        for v in [
+            PT_LOAD,
+            code_off as u32,
+            addr,
+            addr,
+            code.len() as u32,
+            code.len() as u32 + bss,
+            7,
+            4,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+
+        out.extend_from_slice(&code);
+
+        // Symbol table, null symbol, main and buffer
+        out.extend_from_slice(&[0; SYMBOL_SIZE]);
+        for (name, value, size, info) in [
+            (1u32, entry, 4u32, STT_FUNC),
+            (6, addr + code.len() as u32, bss, STT_OBJECT),
+        ] {
+            out.extend_from_slice(&name.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&[info, 0, 1, 0]);
+        }
+
+        out.extend_from_slice(&strtab);
+
+        // Section headers, null, .symtab and .strtab
+        out.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+        for (sh_type, offset, size, link) in [
+            (SHT_SYMTAB, symtab_off, 3 * SYMBOL_SIZE, 2u32),
+            (3, strtab_off, strtab.len(), 0),
+        ] {
+            for v in [
+                0,
+                sh_type,
+                0,
+                0,
+                offset as u32,
+                size as u32,
+                link,
+                0,
+                4,
+                SYMBOL_SIZE as u32,
+            ] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_parse() {
+        let bytes = build_elf(&[0x00700293, 0x0000006f], 0x100, 0x104, 16);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.entry(), 0x104);
+        assert_eq!(elf.segments().len(), 1);
+        assert_eq!(elf.segments()[0].addr, 0x100);
+        assert_eq!(elf.segments()[0].data.len(), 8);
+        assert_eq!(elf.segments()[0].mem_size, 24);
+
+        let main = elf.symbol("main").unwrap();
+        assert_eq!(main.addr, 0x104);
+        assert_eq!(main.kind, SymbolKind::Function);
+        assert_eq!(elf.symbol_at(0x108).unwrap().name, "buffer");
+        assert_eq!(elf.symbol_at(0x117).unwrap().name, "buffer");
+        assert!(elf.symbol_at(0x118).is_none());
+    }
+
+    #[test]
+    fn test_load() {
+        let bytes = build_elf(&[0x00700293, 0x0000006f], 0x100, 0x104, 16);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        let mut cpu = Cpu::new_with_bus(SDRam::new());
+        cpu.store_word(0x10C, 0xFFFF_FFFF).unwrap();
+        elf.load(&mut cpu).unwrap();
+
+        assert_eq!(cpu.pc, 0x104);
+        assert_eq!(cpu.load_word(0x100), Ok(0x00700293));
+        assert_eq!(cpu.load_word(0x104), Ok(0x0000006f));
+        // .bss is zero filled
+        assert_eq!(cpu.load_word(0x10C), Ok(0));
+    }
+
+    #[test]
+    fn test_reject_invalid() {
+        let bytes = build_elf(&[0x00000013], 0, 0, 0);
+
+        let mut wrong_machine = bytes.clone();
+        wrong_machine[18] = 0x3E; // x86-64
+        assert_eq!(
+            Elf::parse(&wrong_machine).unwrap_err(),
+            ElfError::NotRiscV { machine: 0x3E }
+        );
+
+        let mut elf64 = bytes.clone();
+        elf64[4] = 2;
+        assert_eq!(Elf::parse(&elf64).unwrap_err(), ElfError::Not32Bit);
+
+        let mut big_endian = bytes.clone();
+        big_endian[5] = 2;
+        assert_eq!(
+            Elf::parse(&big_endian).unwrap_err(),
+            ElfError::NotLittleEndian
+        );
+
+        assert_eq!(Elf::parse(b"\0asm").unwrap_err(), ElfError::NotElf);
+        assert_eq!(Elf::parse(&bytes[..40]).unwrap_err(), ElfError::Truncated);
+    }
+
+    #[test]
+    fn test_reject_out_of_range_offsets() {
+        let bytes = build_elf(&[0x00000013], 0, 0, 0);
+        let phoff = ELF_HEADER_SIZE;
+        let symtab_header = read_u32(&bytes, 32).unwrap() as usize + SECTION_HEADER_SIZE;
+
+        // Offsets and sizes are u32 in the file, on 32 bit hosts they add up past usize::MAX
+        for field in [
+            28,                 // e_phoff
+            phoff + 4,          // p_offset
+            phoff + 16,         // p_filesz
+            symtab_header + 16, // sh_offset
+            symtab_header + 20, // sh_size
+            symtab_header + 24, // sh_link
+        ] {
+            let mut elf = bytes.clone();
+            elf[field..field + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert_eq!(Elf::parse(&elf).unwrap_err(), ElfError::Truncated);
+        }
+    }
+}
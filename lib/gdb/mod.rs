@@ -0,0 +1,887 @@
+//! GDB remote serial protocol stub
+//!
+//! Lets `riscv64-unknown-elf-gdb` debug a program running on the emulator, the same way it would
+//! debug the DTEK-V board:
+//!
+//! ```rust,no_run
+//! # use dtekv_emulator_core::{cpu::Cpu, gdb::GdbStub, peripheral::SDRam};
+//! let mut cpu = Cpu::new_with_bus(SDRam::new());
+//! // In GDB: target remote localhost:1234
+//! let mut stub = GdbStub::listen("127.0.0.1:1234").unwrap();
+//! stub.run(&mut cpu).unwrap();
+//! ```
+//!
+//! The stub exposes the general purpose registers, pc and the CSRs, reads and writes memory
+//! through the Cpu and supports single-stepping, continuing, breakpoints and watchpoints.
+//!
+//! While the Cpu runs the stub checks the connection for Ctrl-C between chunks of instructions,
+//! so GDB can stop a program that never stops by itself. That takes a [NonBlockingStream], any
+//! other stream can be served with [GdbStub::new] but without Ctrl-C.
+
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    cpu::{Cpu, StopReason, WatchKind, Watchpoint},
+    csr::{Csr, MAX_CSR},
+    memory_mapped::MemoryMapped,
+    peripheral::Peripheral,
+    register::Register,
+};
+
+mod packet;
+use packet::{Connection, Received};
+
+/// GDB's register number for pc, x0-x31 come before it
+const PC_REGNUM: u32 = 32;
+/// GDB's register number for the first CSR, the CSR address is added to it
+const CSR_REGNUM: u32 = 65;
+
+/// Signal numbers used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Instructions executed between two checks for Ctrl-C while the Cpu runs
+const RUN_CHUNK: u64 = 100_000;
+
+/// The largest packet GDB may send or expect as a reply, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+/// A connection to GDB that can be checked for Ctrl-C without blocking while the Cpu runs
+pub trait NonBlockingStream: Read + Write {
+    /// Makes reads return [io::ErrorKind::WouldBlock] instead of waiting when nothing was sent
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl NonBlockingStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl NonBlockingStream for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+pub struct GdbStub<S: Read + Write> {
+    conn: Connection<S>,
+    /// Breakpoints inserted with Z0
+    sw_breakpoints: HashSet<u32>,
+    /// Breakpoints inserted with Z1, kept apart to report the correct kind of breakpoint
+    hw_breakpoints: HashSet<u32>,
+    /// Watchpoints inserted by GDB, used to report the kind of watchpoint that was hit
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl GdbStub<TcpStream> {
+    /// Waits for GDB to connect on `addr`
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub::new_interruptible(stream))
+    }
+}
+
+/// What to do after a packet has been handled
+enum Action {
+    Reply(Vec<u8>),
+    /// Reply if there is anything to reply with and close the connection
+    Quit(Option<Vec<u8>>),
+}
+
+impl<S: NonBlockingStream> GdbStub<S> {
+    /// Serves GDB over a stream that is checked for Ctrl-C while the Cpu runs
+    pub fn new_interruptible(stream: S) -> Self {
+        GdbStub::from_connection(Connection::new_interruptible(stream))
+    }
+}
+
+impl<S: Read + Write> GdbStub<S> {
+    /// Serves GDB over any stream. GDB can't interrupt the Cpu over it, so a continue only
+    /// returns once the program stops by itself.
+    pub fn new(stream: S) -> Self {
+        GdbStub::from_connection(Connection::new(stream))
+    }
+
+    fn from_connection(conn: Connection<S>) -> Self {
+        GdbStub {
+            conn,
+            sw_breakpoints: HashSet::new(),
+            hw_breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Serves GDB until it detaches, kills the program or closes the connection. Breakpoints and
+    /// watchpoints inserted by GDB are removed from the Cpu when it's done.
+    ///
+    /// While GDB is attached, ebreaks in the program stop the Cpu instead of trapping.
+    pub fn run<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>) -> io::Result<()> {
+        let ebreak_to_debugger = cpu.ebreak_to_debugger();
+        cpu.set_ebreak_to_debugger(true);
+        let result = self.serve(cpu);
+        cpu.set_ebreak_to_debugger(ebreak_to_debugger);
+
+        for pc in self
+            .sw_breakpoints
+            .drain()
+            .chain(self.hw_breakpoints.drain())
+        {
+            cpu.remove_breakpoint(pc);
+        }
+        for watchpoint in self.watchpoints.drain(..) {
+            cpu.remove_watchpoint(&watchpoint);
+        }
+
+        result
+    }
+
+    fn serve<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>) -> io::Result<()> {
+        while let Some(received) = self.conn.receive()? {
+            let packet = match received {
+                Received::Packet(packet) => packet,
+                // The Cpu isn't running, so there is nothing to interrupt
+                Received::Interrupt => continue,
+            };
+
+            match self.handle(cpu, &packet) {
+                Action::Reply(reply) => self.conn.send(&reply)?,
+                Action::Quit(reply) => {
+                    if let Some(reply) = reply {
+                        self.conn.send(&reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+
+            if packet == b"QStartNoAckMode" {
+                self.conn.no_ack = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>, packet: &[u8]) -> Action {
+        let Ok(packet) = std::str::from_utf8(packet) else {
+            return self.handle_binary(cpu, packet);
+        };
+
+        let command = if packet.is_char_boundary(1) {
+            packet.split_at(1)
+        } else {
+            ("", packet)
+        };
+
+        let reply = match command {
+            ("?", _) => stop_reply(SIGTRAP, ""),
+            ("g", _) => read_registers(cpu),
+            ("G", data) => write_registers(cpu, data),
+            ("p", regnum) => read_register(cpu, regnum),
+            ("P", assignment) => write_register(cpu, assignment),
+            ("m", args) => read_memory(cpu, args),
+            ("M", args) => write_memory(cpu, args),
+            ("X", _) => return self.handle_binary(cpu, packet.as_bytes()),
+            ("s", addr) => {
+                set_pc(cpu, addr);
+                self.resume(cpu, true)
+            }
+            ("c", addr) => {
+                set_pc(cpu, addr);
+                self.resume(cpu, false)
+            }
+            ("Z", args) => self.insert_breakpoint(cpu, args),
+            ("z", args) => self.remove_breakpoint(cpu, args),
+            ("k", _) => return Action::Quit(None),
+            ("D", _) => return Action::Quit(Some(b"OK".to_vec())),
+            // GDB sends the process id along, vKill;pid
+            _ if packet.starts_with("vKill") => return Action::Quit(Some(b"OK".to_vec())),
+            ("H", _) | ("T", _) => "OK".to_string(),
+            _ => self.handle_query(packet),
+        };
+
+        Action::Reply(reply.into_bytes())
+    }
+
+    /// Packets that can contain binary data
+    fn handle_binary<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>, packet: &[u8]) -> Action {
+        let reply = match packet.first() {
+            Some(b'X') => write_memory_binary(cpu, &packet[1..]),
+            _ => String::new(),
+        };
+
+        Action::Reply(reply.into_bytes())
+    }
+
+    fn handle_query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            );
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_target_xml(args);
+        }
+
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    /// Runs the Cpu until it stops for a reason GDB cares about, or until GDB interrupts it
+    fn resume<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>, single_step: bool) -> String {
+        let start = cpu.pc;
+        let mut first = true;
+        let mut polled_at = cpu.cycles();
+
+        loop {
+            let reason = if single_step {
+                cpu.step()
+            } else {
+                Some(cpu.run(RUN_CHUNK))
+            };
+
+            let resuming = first;
+            first = false;
+
+            match reason {
+                // GDB resumes from the breakpoint it just stopped at
+                Some(StopReason::Breakpoint { pc }) if resuming && pc == start => {}
+                // Ecalls are part of normal execution, the trap handler deals with them
+                Some(StopReason::Ecall { .. }) if !single_step => {}
+                // Nothing else drives the peripherals, so idling in wfi is just more steps
+                Some(StopReason::WaitingForInterrupt { .. }) if !single_step => {}
+                Some(StopReason::BudgetExhausted) => {}
+                Some(reason) => return self.stop_reply_for(reason),
+                None => return stop_reply(SIGTRAP, ""),
+            }
+
+            // Idling in wfi returns after every step, so time is counted in cycles as well
+            let exhausted = reason == Some(StopReason::BudgetExhausted);
+            if exhausted || cpu.cycles().wrapping_sub(polled_at) >= RUN_CHUNK {
+                polled_at = cpu.cycles();
+                if self.conn.poll_interrupt() {
+                    return format!("S{:02x}", SIGINT);
+                }
+            }
+        }
+    }
+
+    fn stop_reply_for(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint { pc } if self.hw_breakpoints.contains(&pc) => {
+                stop_reply(SIGTRAP, "hwbreak:;")
+            }
+            StopReason::Breakpoint { .. } => stop_reply(SIGTRAP, "swbreak:;"),
+            StopReason::Watchpoint(hit) => {
+                let watchpoint = self
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.kind.matches(hit.kind) && w.overlaps(hit.addr, hit.size));
+                let kind = watchpoint.map(|w| w.kind).unwrap_or(hit.kind);
+                // GDB finds its watchpoint by the address, which has to be inside the watched range
+                let addr = watchpoint
+                    .map(|w| hit.addr.max(w.range.start))
+                    .unwrap_or(hit.addr);
+
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                stop_reply(SIGTRAP, &format!("{}:{:x};", name, addr))
+            }
+            StopReason::IllegalInstruction { .. } => stop_reply(SIGILL, ""),
+            // The program exited through semihosting
+            StopReason::Exit { code } => format!("W{:02x}", code as u8),
+            _ => stop_reply(SIGTRAP, ""),
+        }
+    }
+
+    fn insert_breakpoint<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>, args: &str) -> String {
+        let Some((kind, addr, len)) = parse_breakpoint(args) else {
+            return error(1);
+        };
+
+        match kind {
+            0 => {
+                self.sw_breakpoints.insert(addr);
+                cpu.add_breakpoint(addr);
+            }
+            1 => {
+                self.hw_breakpoints.insert(addr);
+                cpu.add_breakpoint(addr);
+            }
+            2..=4 => {
+                let watchpoint = Watchpoint::new(addr..addr.saturating_add(len), watch_kind(kind));
+                cpu.add_watchpoint(watchpoint.clone());
+                self.watchpoints.push(watchpoint);
+            }
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint<T: Peripheral<()>>(&mut self, cpu: &mut Cpu<T>, args: &str) -> String {
+        let Some((kind, addr, len)) = parse_breakpoint(args) else {
+            return error(1);
+        };
+
+        match kind {
+            0 | 1 => {
+                let sw = self.sw_breakpoints.remove(&addr);
+                let hw = self.hw_breakpoints.remove(&addr);
+                // The other kind of breakpoint might still be at the same address
+                if kind == 0 && !hw || kind == 1 && !sw {
+                    cpu.remove_breakpoint(addr);
+                }
+            }
+            2..=4 => {
+                let watchpoint = Watchpoint::new(addr..addr.saturating_add(len), watch_kind(kind));
+                cpu.remove_watchpoint(&watchpoint);
+                self.watchpoints.retain(|w| *w != watchpoint);
+            }
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+}
+
+fn watch_kind(kind: u32) -> WatchKind {
+    match kind {
+        2 => WatchKind::Write,
+        3 => WatchKind::Read,
+        _ => WatchKind::Access,
+    }
+}
+
+fn stop_reply(signal: u8, info: &str) -> String {
+    format!("T{:02x}{}", signal, info)
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+/// Registers are sent as little endian hex
+fn encode_u32(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn decode_u32(hex: &str) -> Option<u32> {
+    if hex.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok().map(u32::swap_bytes)
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses `addr,len`
+fn parse_range(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+/// Parses `kind,addr,len` of Z and z packets
+fn parse_breakpoint(args: &str) -> Option<(u32, u32, u32)> {
+    let (kind, range) = args.split_once(',')?;
+    let (addr, len) = parse_range(range)?;
+    Some((parse_hex(kind)?, addr, len))
+}
+
+fn set_pc<T: Peripheral<()>>(cpu: &mut Cpu<T>, addr: &str) {
+    if let Some(addr) = parse_hex(addr) {
+        cpu.pc = addr;
+    }
+}
+
+fn read_registers<T: Peripheral<()>>(cpu: &Cpu<T>) -> String {
+    let mut reply = String::with_capacity(33 * 8);
+    for i in 0..32 {
+        let reg = Register::new(i).expect("Invalid register");
+        reply.push_str(&encode_u32(cpu.regs.get(reg)));
+    }
+    reply.push_str(&encode_u32(cpu.pc));
+    reply
+}
+
+fn write_registers<T: Peripheral<()>>(cpu: &mut Cpu<T>, data: &str) -> String {
+    let values: Option<Vec<u32>> = (0..=PC_REGNUM as usize)
+        .map(|i| decode_u32(data.get(i * 8..i * 8 + 8)?))
+        .collect();
+
+    let Some(values) = values else {
+        return error(1);
+    };
+
+    for (i, value) in values.iter().take(32).enumerate() {
+        let reg = Register::new(i as u32).expect("Invalid register");
+        cpu.regs.set(reg, *value);
+    }
+    cpu.pc = values[PC_REGNUM as usize];
+
+    "OK".to_string()
+}
+
+/// The CSR GDB means with a register number
+fn csr_for_regnum(regnum: u32) -> Option<Csr> {
+    Csr::new(regnum.checked_sub(CSR_REGNUM)?)
+}
+
+fn read_register<T: Peripheral<()>>(cpu: &Cpu<T>, regnum: &str) -> String {
+    let Some(regnum) = parse_hex(regnum) else {
+        return error(1);
+    };
+
+    if let Some(reg) = Register::new(regnum) {
+        return encode_u32(cpu.regs.get(reg));
+    }
+
+    if regnum == PC_REGNUM {
+        return encode_u32(cpu.pc);
+    }
+
+    match csr_for_regnum(regnum) {
+        Some(csr) => encode_u32(cpu.csr.load(csr)),
+        None => error(1),
+    }
+}
+
+fn write_register<T: Peripheral<()>>(cpu: &mut Cpu<T>, assignment: &str) -> String {
+    let Some((regnum, value)) = assignment.split_once('=') else {
+        return error(1);
+    };
+    let (Some(regnum), Some(value)) = (parse_hex(regnum), decode_u32(value)) else {
+        return error(1);
+    };
+
+    if let Some(reg) = Register::new(regnum) {
+        cpu.regs.set(reg, value);
+    } else if regnum == PC_REGNUM {
+        cpu.pc = value;
+    } else if let Some(csr) = csr_for_regnum(regnum) {
+        cpu.csr.store(csr, value);
+    } else {
+        return error(1);
+    }
+
+    "OK".to_string()
+}
+
+fn read_memory<T: Peripheral<()>>(cpu: &Cpu<T>, args: &str) -> String {
+    let Some((addr, len)) = parse_range(args) else {
+        return error(1);
+    };
+    // Every byte takes two hex digits in the reply
+    if len as usize > PACKET_SIZE / 2 {
+        return error(1);
+    }
+
+    let mut reply = String::with_capacity(len as usize * 2);
+    for i in 0..len {
+        match cpu.peek_byte(addr.wrapping_add(i)) {
+            Ok(byte) => reply.push_str(&format!("{:02x}", byte)),
+            // GDB accepts partial reads
+            Err(()) if i > 0 => break,
+            Err(()) => return error(14),
+        }
+    }
+    reply
+}
+
+fn store_bytes<T: Peripheral<()>>(cpu: &mut Cpu<T>, addr: u32, bytes: &[u8]) -> String {
+    for (i, byte) in bytes.iter().enumerate() {
+        if cpu.store_byte(addr.wrapping_add(i as u32), *byte).is_err() {
+            return error(14);
+        }
+    }
+
+    "OK".to_string()
+}
+
+fn write_memory<T: Peripheral<()>>(cpu: &mut Cpu<T>, args: &str) -> String {
+    let Some((range, data)) = args.split_once(':') else {
+        return error(1);
+    };
+    let (Some((addr, len)), Some(bytes)) = (parse_range(range), decode_bytes(data)) else {
+        return error(1);
+    };
+    if bytes.len() != len as usize {
+        return error(1);
+    }
+
+    store_bytes(cpu, addr, &bytes)
+}
+
+fn write_memory_binary<T: Peripheral<()>>(cpu: &mut Cpu<T>, args: &[u8]) -> String {
+    let Some(colon) = args.iter().position(|b| *b == b':') else {
+        return error(1);
+    };
+    let range = std::str::from_utf8(&args[..colon]).ok();
+    let Some((addr, len)) = range.and_then(parse_range) else {
+        return error(1);
+    };
+    let bytes = &args[colon + 1..];
+    if bytes.len() != len as usize {
+        return error(1);
+    }
+
+    store_bytes(cpu, addr, bytes)
+}
+
+/// The target description tells GDB which registers exist and what their numbers are
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv32</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+
+    for i in 0..32 {
+        let reg = Register::new(i).expect("Invalid register");
+        let kind = match reg {
+            Register::SP => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n",
+            reg.name(),
+            kind,
+            i
+        ));
+    }
+    xml.push_str(&format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n",
+        PC_REGNUM
+    ));
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+
+    for addr in 0..=MAX_CSR {
+        let csr = Csr::new(addr).expect("Invalid CSR");
+        if let Some(name) = csr.name() {
+            xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"csr\"/>\n",
+                name,
+                CSR_REGNUM + addr
+            ));
+        }
+    }
+
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+/// Replies to `qXfer:features:read:target.xml:offset,length`
+fn read_target_xml(args: &str) -> String {
+    let Some((offset, len)) = parse_range(args) else {
+        return error(1);
+    };
+
+    let xml = target_xml();
+    let start = (offset as usize).min(xml.len());
+    let end = start.saturating_add(len as usize).min(xml.len());
+
+    let marker = if end == xml.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &xml[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        peripheral::{Bus, SDRam, SDRAM_HIGHER_ADDR},
+        test_utils::new_sdram_cpu,
+    };
+    use std::{io::BufRead, io::BufReader, thread};
+
+    /// A minimal GDB client that sends packets and waits for the reply
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: std::net::SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        /// Sends a packet without waiting for a reply
+        fn send(&mut self, packet: &str) {
+            let checksum = packet::checksum(packet.as_bytes());
+            write!(self.writer, "${}#{:02x}", packet, checksum).unwrap();
+
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+', "Packet {} wasn't acknowledged", packet);
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.reply()
+        }
+
+        /// Waits for the reply to an earlier packet
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            self.reader.read_until(b'$', &mut reply).unwrap();
+            reply.clear();
+            self.reader.read_until(b'#', &mut reply).unwrap();
+            reply.pop();
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            self.writer.write_all(b"+").unwrap();
+
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    /// Runs the stub on a local socket, with `script` acting as GDB
+    fn with_client<T: Peripheral<()>>(
+        cpu: &mut Cpu<T>,
+        script: impl FnOnce(&mut Client) + Send + 'static,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client::connect(addr);
+            script(&mut client);
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new_interruptible(stream).run(cpu).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = new_sdram_cpu(&[]);
+        cpu.regs.set(Register::A0, 0x12345678);
+        cpu.csr.store(Csr::MEPC, 0x100);
+        cpu.store_word(0x200, 0xdeadbeef).unwrap();
+
+        with_client(&mut cpu, |gdb| {
+            assert!(gdb.request("qSupported:swbreak+").contains("swbreak+"));
+            assert_eq!(gdb.request("?"), "T05");
+
+            let regs = gdb.request("g");
+            assert_eq!(regs.len(), 33 * 8);
+            assert_eq!(&regs[10 * 8..11 * 8], "78563412");
+
+            assert_eq!(gdb.request("p20"), "00000000"); // pc
+            assert_eq!(gdb.request("p382"), "00010000"); // 65 + mepc
+            assert_eq!(gdb.request("Pb=efbeadde"), "OK"); // a1
+
+            assert_eq!(gdb.request("m200,4"), "efbeadde");
+            assert_eq!(gdb.request("M204,2:aabb"), "OK");
+            assert_eq!(gdb.request("m204,2"), "aabb");
+
+            assert_eq!(gdb.request("D"), "OK");
+        });
+
+        assert_eq!(cpu.regs.get(Register::A1), 0xdeadbeef);
+        assert_eq!(cpu.load_halfword(0x204), Ok(0xbbaa));
+    }
+
+    #[test]
+    fn test_read_memory_errors() {
+        let mut bus = Bus::new();
+        // The bus sends loads past the end of the SDRAM to it, which fails them
+        bus.attach_device((0, SDRAM_HIGHER_ADDR + 0x100), Box::new(SDRam::new()));
+        let mut cpu = Cpu::new_with_bus(bus);
+
+        with_client(&mut cpu, |gdb| {
+            let end = SDRAM_HIGHER_ADDR + 1;
+            assert_eq!(gdb.request(&format!("m{:x},4", end - 2)), "0000");
+            assert_eq!(gdb.request(&format!("m{:x},4", end)), "E0e");
+
+            assert_eq!(gdb.request("m0,2000").len(), PACKET_SIZE);
+            // The reply wouldn't fit in a packet
+            assert_eq!(gdb.request("m0,2001"), "E01");
+            assert_eq!(gdb.request("m0,ffffffff"), "E01");
+
+            assert_eq!(gdb.request("D"), "OK");
+        });
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0x00128293, // addi t0, t0, 1
+            0x10502023, // sw t0, 256(zero)
+            0x00128293, // addi t0, t0, 1
+            0x0000006f, // j .
+        ]);
+
+        with_client(&mut cpu, |gdb| {
+            assert_eq!(gdb.request("s"), "T05");
+            assert_eq!(gdb.request("p20"), "04000000");
+
+            assert_eq!(gdb.request("Z0,8,4"), "OK");
+            assert_eq!(gdb.request("c"), "T05swbreak:;");
+            assert_eq!(gdb.request("p20"), "08000000");
+            assert_eq!(gdb.request("z0,8,4"), "OK");
+
+            assert_eq!(gdb.request("Z2,100,4"), "OK");
+            assert_eq!(gdb.request("c"), "T05watch:100;");
+            assert_eq!(gdb.request("p20"), "0c000000");
+            assert_eq!(gdb.request("z2,100,4"), "OK");
+
+            // Runs into the halt loop
+            assert_eq!(gdb.request("c"), "T05");
+            assert_eq!(gdb.request("p20"), "10000000");
+            assert_eq!(gdb.request("p5"), "03000000");
+
+            gdb.send("k");
+        });
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0xffdff06f, // j 0
+        ]);
+
+        with_client(&mut cpu, |gdb| {
+            gdb.send("c");
+            thread::sleep(std::time::Duration::from_millis(50));
+            gdb.writer.write_all(&[0x03]).unwrap();
+            assert_eq!(gdb.reply(), "S02");
+
+            assert_eq!(gdb.request("vKill;1"), "OK");
+        });
+
+        assert!(cpu.regs.get(Register::T0) > 0);
+    }
+
+    #[test]
+    fn test_watchpoint_inside_access() {
+        let mut cpu = new_sdram_cpu(&[
+            0x10502023, // sw t0, 256(zero)
+        ]);
+
+        with_client(&mut cpu, |gdb| {
+            // The store starts before the access watchpoint and doesn't trigger the read one
+            assert_eq!(gdb.request("Z3,100,4"), "OK");
+            assert_eq!(gdb.request("Z4,102,1"), "OK");
+            assert_eq!(gdb.request("c"), "T05awatch:102;");
+
+            gdb.send("k");
+        });
+    }
+
+    /// A stream that can't be made non-blocking, with GDB's side written out in advance
+    struct Scripted(io::Cursor<Vec<u8>>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_plain_stream() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0x00100073, // ebreak
+        ]);
+
+        let mut input = Vec::new();
+        for packet in ["Pa=78563412", "c", "D"] {
+            let checksum = packet::checksum(packet.as_bytes());
+            input.extend_from_slice(format!("${}#{:02x}+", packet, checksum).as_bytes());
+        }
+        GdbStub::new(Scripted(io::Cursor::new(input)))
+            .run(&mut cpu)
+            .unwrap();
+
+        assert_eq!(cpu.regs.get(Register::A0), 0x12345678);
+        assert_eq!(cpu.regs.get(Register::T0), 1);
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let mut cpu = new_sdram_cpu(&[
+            0x00128293, // addi t0, t0, 1
+            0x00100073, // ebreak
+        ]);
+
+        with_client(&mut cpu, |gdb| {
+            assert_eq!(gdb.request("c"), "T05");
+            assert_eq!(gdb.request("p20"), "04000000");
+
+            gdb.send("k");
+        });
+
+        assert_eq!(cpu.csr.load(Csr::MCAUSE), 0);
+        assert!(!cpu.ebreak_to_debugger());
+    }
+
+    #[test]
+    fn test_target_xml() {
+        let mut cpu = new_sdram_cpu(&[]);
+
+        with_client(&mut cpu, |gdb| {
+            let first = gdb.request("qXfer:features:read:target.xml:0,40");
+            assert!(first.starts_with("m<?xml"));
+            let rest = gdb.request("qXfer:features:read:target.xml:40,100000");
+            assert!(rest.starts_with('l'));
+            assert!(
+                rest.contains("<reg name=\"mstatus\" bitsize=\"32\" type=\"int\" regnum=\"833\"")
+            );
+
+            gdb.request("D");
+        });
+    }
+}
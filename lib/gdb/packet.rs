@@ -0,0 +1,309 @@
+//! Framing of the remote serial protocol, packets look like `$data#checksum`
+
+use std::io::{self, Read, Write};
+
+use super::NonBlockingStream;
+
+const INTERRUPT: u8 = 0x03;
+
+/// Something received from GDB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Received {
+    Packet(Vec<u8>),
+    /// GDB sent Ctrl-C
+    Interrupt,
+}
+
+pub(crate) struct Connection<S> {
+    stream: S,
+    /// Set for streams that can be checked for Ctrl-C without blocking
+    set_nonblocking: Option<fn(&S, bool) -> io::Result<()>>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Set once GDB has asked for QStartNoAckMode, after which packets aren't acknowledged
+    pub(crate) no_ack: bool,
+}
+
+pub(crate) fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            set_nonblocking: None,
+            buf: Vec::new(),
+            pos: 0,
+            no_ack: false,
+        }
+    }
+
+    /// Reads one byte, returns None if the stream was closed
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pos == self.buf.len() {
+            self.buf.resize(4096, 0);
+            let n = loop {
+                match self.stream.read(&mut self.buf) {
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            self.buf.truncate(n);
+            self.pos = 0;
+
+            if n == 0 {
+                return Ok(None);
+            }
+        }
+
+        let byte = self.buf[self.pos];
+        self.pos += 1;
+        Ok(Some(byte))
+    }
+
+    fn read_hex_byte(&mut self) -> io::Result<Option<u8>> {
+        let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+            return Ok(None);
+        };
+
+        let digits = [high, low];
+        let value = std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        Ok(value)
+    }
+
+    /// Waits for the next packet or interrupt, acknowledging packets unless no-ack mode is on.
+    /// Returns None if the stream was closed.
+    pub(crate) fn receive(&mut self) -> io::Result<Option<Received>> {
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(None);
+            };
+
+            match byte {
+                b'$' => {}
+                INTERRUPT => return Ok(Some(Received::Interrupt)),
+                // Acknowledgements of our own packets and line noise
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                let Some(byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+
+                if byte == b'#' {
+                    break;
+                }
+                data.push(byte);
+            }
+
+            let expected = self.read_hex_byte()?;
+
+            if !self.no_ack {
+                if expected != Some(checksum(&data)) {
+                    self.stream.write_all(b"-")?;
+                    self.stream.flush()?;
+                    continue;
+                }
+
+                self.stream.write_all(b"+")?;
+                self.stream.flush()?;
+            }
+
+            return Ok(Some(Received::Packet(unescape(&data))));
+        }
+    }
+
+    /// Sends a packet, retransmitting it until GDB acknowledges it
+    pub(crate) fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = escape(data);
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(&data)).as_bytes());
+
+        loop {
+            self.stream.write_all(&packet)?;
+            self.stream.flush()?;
+
+            if self.no_ack {
+                return Ok(());
+            }
+
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => continue,
+                }
+            }
+        }
+    }
+}
+
+impl<S: NonBlockingStream> Connection<S> {
+    pub(crate) fn new_interruptible(stream: S) -> Self {
+        Connection {
+            set_nonblocking: Some(S::set_nonblocking),
+            ..Connection::new(stream)
+        }
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Checks for a Ctrl-C from GDB without waiting for one. Anything else GDB sent is kept for
+    /// [Connection::receive]. A closed or broken connection counts as an interrupt, so that the
+    /// Cpu stops and the next receive reports it. Always false if the stream can't be checked.
+    pub(crate) fn poll_interrupt(&mut self) -> bool {
+        let Some(set_nonblocking) = self.set_nonblocking else {
+            return false;
+        };
+
+        if let Err(e) = self.read_available(set_nonblocking) {
+            return !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            );
+        }
+
+        let unread = &self.buf[self.pos..];
+        match unread.iter().position(|byte| *byte == INTERRUPT) {
+            Some(i) => {
+                self.buf.remove(self.pos + i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Appends whatever GDB has sent to the unread bytes without blocking
+    fn read_available(
+        &mut self,
+        set_nonblocking: fn(&S, bool) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        set_nonblocking(&self.stream, true)?;
+        let read = self.stream.read(&mut chunk);
+        set_nonblocking(&self.stream, false)?;
+
+        match read? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Bytes that have to be escaped inside a packet
+fn needs_escape(byte: u8) -> bool {
+    matches!(byte, b'$' | b'#' | b'}' | b'*')
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if needs_escape(*byte) {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(*byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte == b'}' {
+            if let Some(next) = bytes.next() {
+                unescaped.push(next ^ 0x20);
+            }
+        } else {
+            unescaped.push(*byte);
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream that reads from a fixed input and records what is written
+    struct Scripted {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connection(input: &[u8]) -> Connection<Scripted> {
+        Connection::new(Scripted {
+            input: io::Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"qSupported"), 0x37);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut conn = connection(b"+$g#67$m0,4#fd\x03$bad#00");
+
+        assert_eq!(
+            conn.receive().unwrap(),
+            Some(Received::Packet(b"g".to_vec()))
+        );
+        assert_eq!(
+            conn.receive().unwrap(),
+            Some(Received::Packet(b"m0,4".to_vec()))
+        );
+        assert_eq!(conn.receive().unwrap(), Some(Received::Interrupt));
+        // The bad checksum is rejected, then the stream ends
+        assert_eq!(conn.receive().unwrap(), None);
+        assert_eq!(conn.stream.output, b"++-");
+    }
+
+    #[test]
+    fn test_send_retransmits() {
+        let mut conn = connection(b"-+");
+        conn.send(b"OK").unwrap();
+        assert_eq!(conn.stream.output, b"$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn test_escape() {
+        let data = b"a}b#c$d*";
+        assert_eq!(escape(data), b"a}]b}\x03c}\x04d}\x0a");
+        assert_eq!(unescape(&escape(data)), data);
+    }
+}
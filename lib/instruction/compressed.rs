@@ -0,0 +1,347 @@
+//! Decoding of the RV32C compressed instructions
+//!
+//! Every compressed instruction is an alias for a regular instruction, so they are expanded into
+//! the existing [Instruction] variants instead of getting their own. The floating point loads and
+//! stores aren't supported since the F and D extensions aren't either.
+
+use crate::register::Register;
+
+use super::{BTypeImm, ITypeImm, Instruction, JTypeImm, STypeImm, ShamtImm, UTypeImm};
+
+/// Returns true if `halfword`, the lower half of an instruction, starts a 16-bit instruction.
+/// 32-bit instructions always have the two lowest bits set.
+pub fn is_compressed(halfword: u16) -> bool {
+    halfword & 0b11 != 0b11
+}
+
+impl Instruction {
+    /// Expands a 16-bit compressed instruction into the instruction it's an alias for. None for
+    /// reserved encodings, including the all zero halfword.
+    pub fn decompress(halfword: u16) -> Option<Instruction> {
+        let c = halfword as u32;
+        let funct3 = (c >> 13) & 0b111;
+
+        match (c & 0b11, funct3) {
+            // C.ADDI4SPN
+            (0b00, 0b000) => {
+                let imm =
+                    bits(c, 11, 12, 4) | bits(c, 7, 10, 6) | bits(c, 6, 6, 2) | bits(c, 5, 5, 3);
+                if imm == 0 {
+                    return None;
+                }
+                Some(Instruction::ADDI {
+                    rd: reg_prime(c, 2),
+                    rs1: Register::SP,
+                    imm: itype(imm),
+                })
+            }
+            // C.LW
+            (0b00, 0b010) => Some(Instruction::LW {
+                rd: reg_prime(c, 2),
+                rs1: reg_prime(c, 7),
+                imm: itype(word_offset(c)),
+            }),
+            // C.SW
+            (0b00, 0b110) => Some(Instruction::SW {
+                rs1: reg_prime(c, 7),
+                rs2: reg_prime(c, 2),
+                imm: stype(word_offset(c)),
+            }),
+            // C.ADDI, C.NOP when rd is zero
+            (0b01, 0b000) => Some(Instruction::ADDI {
+                rd: reg(c, 7),
+                rs1: reg(c, 7),
+                imm: itype(imm6(c)),
+            }),
+            // C.JAL
+            (0b01, 0b001) => Some(Instruction::JAL {
+                rd: Register::RA,
+                imm: jump_offset(c),
+            }),
+            // C.LI
+            (0b01, 0b010) => Some(Instruction::ADDI {
+                rd: reg(c, 7),
+                rs1: Register::ZERO,
+                imm: itype(imm6(c)),
+            }),
+            // C.ADDI16SP
+            (0b01, 0b011) if reg(c, 7) == Register::SP => {
+                let imm = bits(c, 12, 12, 9)
+                    | bits(c, 6, 6, 4)
+                    | bits(c, 5, 5, 6)
+                    | bits(c, 3, 4, 7)
+                    | bits(c, 2, 2, 5);
+                if imm == 0 {
+                    return None;
+                }
+                Some(Instruction::ADDI {
+                    rd: Register::SP,
+                    rs1: Register::SP,
+                    imm: itype(sign_extend(imm, 10)),
+                })
+            }
+            // C.LUI
+            (0b01, 0b011) => {
+                let imm = imm6(c) << 12;
+                if imm == 0 {
+                    return None;
+                }
+                Some(Instruction::LUI {
+                    rd: reg(c, 7),
+                    imm: UTypeImm::new(imm)?,
+                })
+            }
+            (0b01, 0b100) => {
+                let rd = reg_prime(c, 7);
+                let rs2 = reg_prime(c, 2);
+
+                match (c >> 10) & 0b11 {
+                    // C.SRLI and C.SRAI, shamt[5] must be zero on RV32
+                    0b00 | 0b01 if c & (1 << 12) != 0 => None,
+                    0b00 => Some(Instruction::SRLI {
+                        rd,
+                        rs1: rd,
+                        imm: shamt(c),
+                    }),
+                    0b01 => Some(Instruction::SRAI {
+                        rd,
+                        rs1: rd,
+                        imm: shamt(c),
+                    }),
+                    // C.ANDI
+                    0b10 => Some(Instruction::ANDI {
+                        rd,
+                        rs1: rd,
+                        imm: itype(imm6(c)),
+                    }),
+                    // C.SUBW and C.ADDW are RV64 only
+                    _ if c & (1 << 12) != 0 => None,
+                    _ => match (c >> 5) & 0b11 {
+                        0b00 => Some(Instruction::SUB { rd, rs1: rd, rs2 }),
+                        0b01 => Some(Instruction::XOR { rd, rs1: rd, rs2 }),
+                        0b10 => Some(Instruction::OR { rd, rs1: rd, rs2 }),
+                        _ => Some(Instruction::AND { rd, rs1: rd, rs2 }),
+                    },
+                }
+            }
+            // C.J
+            (0b01, 0b101) => Some(Instruction::JAL {
+                rd: Register::ZERO,
+                imm: jump_offset(c),
+            }),
+            // C.BEQZ
+            (0b01, 0b110) => Some(Instruction::BEQ {
+                rs1: reg_prime(c, 7),
+                rs2: Register::ZERO,
+                imm: branch_offset(c),
+            }),
+            // C.BNEZ
+            (0b01, 0b111) => Some(Instruction::BNE {
+                rs1: reg_prime(c, 7),
+                rs2: Register::ZERO,
+                imm: branch_offset(c),
+            }),
+            // C.SLLI, shamt[5] must be zero on RV32
+            (0b10, 0b000) if c & (1 << 12) == 0 => Some(Instruction::SLLI {
+                rd: reg(c, 7),
+                rs1: reg(c, 7),
+                imm: shamt(c),
+            }),
+            // C.LWSP, rd can't be zero
+            (0b10, 0b010) if reg(c, 7) != Register::ZERO => {
+                let imm = bits(c, 12, 12, 5) | bits(c, 4, 6, 2) | bits(c, 2, 3, 6);
+                Some(Instruction::LW {
+                    rd: reg(c, 7),
+                    rs1: Register::SP,
+                    imm: itype(imm),
+                })
+            }
+            (0b10, 0b100) => {
+                let rs1 = reg(c, 7);
+                let rs2 = reg(c, 2);
+
+                match (c & (1 << 12) != 0, rs1, rs2) {
+                    // C.JR with rs1 zero is reserved
+                    (false, Register::ZERO, Register::ZERO) => None,
+                    // C.JR
+                    (false, _, Register::ZERO) => Some(Instruction::JALR {
+                        rd: Register::ZERO,
+                        rs1,
+                        imm: itype(0),
+                    }),
+                    // C.MV
+                    (false, _, _) => Some(Instruction::ADD {
+                        rd: rs1,
+                        rs1: Register::ZERO,
+                        rs2,
+                    }),
+                    // C.EBREAK
+                    (true, Register::ZERO, Register::ZERO) => Some(Instruction::EBREAK),
+                    // C.JALR
+                    (true, _, Register::ZERO) => Some(Instruction::JALR {
+                        rd: Register::RA,
+                        rs1,
+                        imm: itype(0),
+                    }),
+                    // C.ADD
+                    (true, _, _) => Some(Instruction::ADD { rd: rs1, rs1, rs2 }),
+                }
+            }
+            // C.SWSP
+            (0b10, 0b110) => {
+                let imm = bits(c, 9, 12, 2) | bits(c, 7, 8, 6);
+                Some(Instruction::SW {
+                    rs1: Register::SP,
+                    rs2: reg(c, 2),
+                    imm: stype(imm),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Takes bits `lo..=hi` of `c` and places them starting at bit `to`
+fn bits(c: u32, lo: u32, hi: u32, to: u32) -> u32 {
+    let mask = (1 << (hi - lo + 1)) - 1;
+    ((c >> lo) & mask) << to
+}
+
+fn sign_extend(value: u32, width: u32) -> u32 {
+    let shift = 32 - width;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+/// One of the full registers x0-x31, in the 5 bits starting at `lo`
+fn reg(c: u32, lo: u32) -> Register {
+    Register::new((c >> lo) & 0x1F).expect("Passed something other than a value between 0 and 31")
+}
+
+/// One of the registers x8-x15 that the 3-bit register fields can address
+fn reg_prime(c: u32, lo: u32) -> Register {
+    Register::new(8 + ((c >> lo) & 0b111))
+        .expect("Passed something other than a value between 8 and 15")
+}
+
+/// The sign-extended 6-bit immediate used by C.ADDI, C.LI, C.LUI and C.ANDI
+fn imm6(c: u32) -> u32 {
+    sign_extend(bits(c, 12, 12, 5) | bits(c, 2, 6, 0), 6)
+}
+
+/// The shift amount for C.SLLI, C.SRLI and C.SRAI
+fn shamt(c: u32) -> ShamtImm {
+    ShamtImm::new(bits(c, 2, 6, 0)).expect("A 5-bit value is always a valid shift amount")
+}
+
+/// The zero-extended word offset used by C.LW and C.SW
+fn word_offset(c: u32) -> u32 {
+    bits(c, 10, 12, 3) | bits(c, 6, 6, 2) | bits(c, 5, 5, 6)
+}
+
+fn jump_offset(c: u32) -> JTypeImm {
+    let imm = bits(c, 12, 12, 11)
+        | bits(c, 11, 11, 4)
+        | bits(c, 9, 10, 8)
+        | bits(c, 8, 8, 10)
+        | bits(c, 7, 7, 6)
+        | bits(c, 6, 6, 7)
+        | bits(c, 3, 5, 1)
+        | bits(c, 2, 2, 5);
+    JTypeImm::new(sign_extend(imm, 12)).expect("A 12-bit jump offset always fits in a JAL")
+}
+
+fn branch_offset(c: u32) -> BTypeImm {
+    let imm = bits(c, 12, 12, 8)
+        | bits(c, 10, 11, 3)
+        | bits(c, 5, 6, 6)
+        | bits(c, 3, 4, 1)
+        | bits(c, 2, 2, 5);
+    BTypeImm::new(sign_extend(imm, 9)).expect("A 9-bit branch offset always fits in a branch")
+}
+
+fn itype(imm: u32) -> ITypeImm {
+    ITypeImm::new(imm).expect("Compressed immediates always fit in 12 bits")
+}
+
+fn stype(imm: u32) -> STypeImm {
+    STypeImm::new(imm).expect("Compressed immediates always fit in 12 bits")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // The expected words were produced with llvm-mc, which expands compressed instructions the
+    // same way when disassembling with `-M no-aliases` and the C extension turned off
+    #[test_case(0x0040, 0x00410413; "c.addi4spn s0, sp, 4")]
+    #[test_case(0x1ff8, 0x3fc10713; "c.addi4spn a4, sp, 1020")]
+    #[test_case(0x4188, 0x0005a503; "c.lw a0, 0(a1)")]
+    #[test_case(0x5ffc, 0x07c7a783; "c.lw a5, 124(a5)")]
+    #[test_case(0xc188, 0x00a5a023; "c.sw a0, 0(a1)")]
+    #[test_case(0xdffc, 0x06f7ae23; "c.sw a5, 124(a5)")]
+    #[test_case(0x0001, 0x00000013; "c.nop")]
+    #[test_case(0x157d, 0xfff50513; "c.addi a0, -1")]
+    #[test_case(0x3001, 0x801ff0ef; "c.jal -2048")]
+    #[test_case(0x2001, 0x000000ef; "c.jal 0")]
+    #[test_case(0x2ffd, 0x7fe000ef; "c.jal 2046")]
+    #[test_case(0x4501, 0x00000513; "c.li a0, 0")]
+    #[test_case(0x5ffd, 0xfff00f93; "c.li t6, -1")]
+    #[test_case(0x7101, 0xe0010113; "c.addi16sp sp, -512")]
+    #[test_case(0x617d, 0x1f010113; "c.addi16sp sp, 496")]
+    #[test_case(0x6505, 0x00001537; "c.lui a0, 1")]
+    #[test_case(0x757d, 0xfffff537; "c.lui a0, 0xfffff")]
+    #[test_case(0x807d, 0x01f45413; "c.srli s0, 31")]
+    #[test_case(0x8405, 0x40145413; "c.srai s0, 1")]
+    #[test_case(0x987d, 0xfff47413; "c.andi s0, -1")]
+    #[test_case(0x8c05, 0x40940433; "c.sub s0, s1")]
+    #[test_case(0x8c25, 0x00944433; "c.xor s0, s1")]
+    #[test_case(0x8c45, 0x00946433; "c.or s0, s1")]
+    #[test_case(0x8c65, 0x00947433; "c.and s0, s1")]
+    #[test_case(0xa001, 0x0000006f; "c.j 0")]
+    #[test_case(0xbffd, 0xfffff06f; "c.j -2")]
+    #[test_case(0xd001, 0xf00400e3; "c.beqz s0, -256")]
+    #[test_case(0xeffd, 0x0e079f63; "c.bnez a5, 254")]
+    #[test_case(0x02fe, 0x01f29293; "c.slli t0, 31")]
+    #[test_case(0x4502, 0x00012503; "c.lwsp a0, 0(sp)")]
+    #[test_case(0x50fe, 0x0fc12083; "c.lwsp ra, 252(sp)")]
+    #[test_case(0x8082, 0x00008067; "c.jr ra")]
+    #[test_case(0x852e, 0x00b00533; "c.mv a0, a1")]
+    #[test_case(0x9502, 0x000500e7; "c.jalr a0")]
+    #[test_case(0x952e, 0x00b50533; "c.add a0, a1")]
+    #[test_case(0x9002, 0x00100073; "c.ebreak")]
+    #[test_case(0xc02a, 0x00a12023; "c.swsp a0, 0(sp)")]
+    #[test_case(0xdffe, 0x0ff12e23; "c.swsp t6, 252(sp)")]
+    fn test_decompress(compressed: u16, expanded: u32) {
+        assert!(is_compressed(compressed));
+        assert_eq!(
+            Instruction::decompress(compressed),
+            Instruction::try_from(expanded).ok()
+        );
+    }
+
+    #[test_case(0x0000; "all zeros")]
+    #[test_case(0x0004; "c.addi4spn with a zero immediate")]
+    #[test_case(0x2000; "c.fld")]
+    #[test_case(0x6000; "c.flw")]
+    #[test_case(0x8000; "reserved quadrant 0 encoding")]
+    #[test_case(0x6101; "c.addi16sp with a zero immediate")]
+    #[test_case(0x6501; "c.lui with a zero immediate")]
+    #[test_case(0x9005; "c.srli with shamt[5] set")]
+    #[test_case(0x9c05; "c.subw")]
+    #[test_case(0x1002; "c.slli with shamt[5] set")]
+    #[test_case(0x4002; "c.lwsp with rd zero")]
+    #[test_case(0x8002; "c.jr with rs1 zero")]
+    #[test_case(0xe002; "c.fswsp")]
+    fn test_decompress_reserved(compressed: u16) {
+        assert_eq!(Instruction::decompress(compressed), None);
+    }
+
+    #[test]
+    fn test_is_compressed() {
+        assert!(!is_compressed(0x0013));
+        assert!(is_compressed(0x0001));
+        assert!(is_compressed(0x0002));
+        assert!(is_compressed(0x0000));
+    }
+}
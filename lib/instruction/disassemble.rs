@@ -0,0 +1,578 @@
+//! Turning instructions back into assembly
+//!
+//! [Instruction] implements Display, which prints the canonical GNU assembler syntax with ABI
+//! register names, e.g. `addi t0, zero, 7`. Branch and jump offsets are printed relative to the
+//! instruction since the instruction doesn't know where it is.
+//!
+//! A [Disassembler] is used when the address of the instruction is known. It resolves branch and
+//! jump targets to absolute addresses and symbols, and can print pseudo-instructions like `li` and
+//! `ret` the way `objdump` does.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    csr::Csr,
+    elf::{Symbol, SymbolKind},
+    register::Register,
+};
+
+use super::Instruction;
+
+#[derive(Debug, Clone)]
+pub struct Disassembler {
+    pseudo_instructions: bool,
+    numeric_registers: bool,
+    symbols: Vec<Symbol>,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disassembler {
+    /// Creates a disassembler that prints pseudo-instructions and ABI register names
+    pub fn new() -> Self {
+        Disassembler {
+            pseudo_instructions: true,
+            numeric_registers: false,
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn with_pseudo_instructions(mut self, pseudo_instructions: bool) -> Self {
+        self.pseudo_instructions = pseudo_instructions;
+        self
+    }
+
+    /// Prints `x10` instead of `a0`
+    pub fn with_numeric_registers(mut self, numeric_registers: bool) -> Self {
+        self.numeric_registers = numeric_registers;
+        self
+    }
+
+    /// Symbols used to name branch and jump targets, usually [Elf::symbols](crate::elf::Elf)
+    pub fn with_symbols(mut self, symbols: &[Symbol]) -> Self {
+        self.symbols = symbols.to_vec();
+        self
+    }
+
+    /// Disassembles the instruction at `pc`
+    pub fn disassemble(&self, instruction: Instruction, pc: u32) -> String {
+        let mut out = String::new();
+        self.write(&mut out, instruction, Some(pc))
+            .expect("Writing to a String can't fail");
+        out
+    }
+
+    /// Disassembles a raw instruction word, words that aren't valid instructions are printed as
+    /// data
+    pub fn disassemble_word(&self, word: u32, pc: u32) -> String {
+        match Instruction::try_from(word) {
+            Ok(instruction) => self.disassemble(instruction, pc),
+            Err(()) => format!(".word 0x{:08x}", word),
+        }
+    }
+
+    /// The symbol a target address is in, functions and objects are preferred over labels
+    fn symbol(&self, addr: u32) -> Option<(&str, u32)> {
+        self.symbols
+            .iter()
+            .filter(|sym| sym.kind != SymbolKind::Other)
+            .find(|sym| addr.wrapping_sub(sym.addr) < sym.size)
+            .or_else(|| self.symbols.iter().find(|sym| sym.addr == addr))
+            .map(|sym| (sym.name.as_str(), addr - sym.addr))
+    }
+
+    fn reg(&self, reg: Register) -> RegName {
+        RegName {
+            reg,
+            numeric: self.numeric_registers,
+        }
+    }
+
+    fn write_target(&self, f: &mut impl Write, pc: Option<u32>, offset: u32) -> fmt::Result {
+        let Some(pc) = pc else {
+            return write!(f, "{}", offset as i32);
+        };
+
+        let target = pc.wrapping_add(offset);
+        write!(f, "0x{:x}", target)?;
+        match self.symbol(target) {
+            Some((name, 0)) => write!(f, " <{}>", name),
+            Some((name, offset)) => write!(f, " <{}+0x{:x}>", name, offset),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a pseudo-instruction if the instruction is a common alias, returns false if it isn't
+    fn write_pseudo(
+        &self,
+        f: &mut impl Write,
+        instruction: Instruction,
+        pc: Option<u32>,
+    ) -> Result<bool, fmt::Error> {
+        use Instruction as I;
+        const ZERO: Register = Register::ZERO;
+
+        match instruction {
+            I::ADDI { rd, rs1, imm } if rd == ZERO && rs1 == ZERO && imm == 0 => write!(f, "nop"),
+            I::ADDI { rd, rs1, imm } if rs1 == ZERO => {
+                write!(f, "li {}, {}", self.reg(rd), imm.as_u32() as i32)
+            }
+            I::ADDI { rd, rs1, imm } if imm == 0 => {
+                write!(f, "mv {}, {}", self.reg(rd), self.reg(rs1))
+            }
+            I::XORI { rd, rs1, imm } if imm == u32::MAX => {
+                write!(f, "not {}, {}", self.reg(rd), self.reg(rs1))
+            }
+            I::SUB { rd, rs1, rs2 } if rs1 == ZERO => {
+                write!(f, "neg {}, {}", self.reg(rd), self.reg(rs2))
+            }
+            I::SLTIU { rd, rs1, imm } if imm == 1 => {
+                write!(f, "seqz {}, {}", self.reg(rd), self.reg(rs1))
+            }
+            I::SLTU { rd, rs1, rs2 } if rs1 == ZERO => {
+                write!(f, "snez {}, {}", self.reg(rd), self.reg(rs2))
+            }
+            I::SLT { rd, rs1, rs2 } if rs2 == ZERO => {
+                write!(f, "sltz {}, {}", self.reg(rd), self.reg(rs1))
+            }
+            I::SLT { rd, rs1, rs2 } if rs1 == ZERO => {
+                write!(f, "sgtz {}, {}", self.reg(rd), self.reg(rs2))
+            }
+            I::BEQ { rs1, rs2, imm } if rs2 == ZERO => {
+                write!(f, "beqz {}, ", self.reg(rs1))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::BNE { rs1, rs2, imm } if rs2 == ZERO => {
+                write!(f, "bnez {}, ", self.reg(rs1))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::BLT { rs1, rs2, imm } if rs2 == ZERO => {
+                write!(f, "bltz {}, ", self.reg(rs1))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::BLT { rs1, rs2, imm } if rs1 == ZERO => {
+                write!(f, "bgtz {}, ", self.reg(rs2))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::BGE { rs1, rs2, imm } if rs2 == ZERO => {
+                write!(f, "bgez {}, ", self.reg(rs1))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::BGE { rs1, rs2, imm } if rs1 == ZERO => {
+                write!(f, "blez {}, ", self.reg(rs2))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::JAL { rd, imm } if rd == ZERO => {
+                write!(f, "j ")?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::JAL { rd, imm } if rd == Register::RA => {
+                write!(f, "jal ")?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::JALR { rd, rs1, imm } if rd == ZERO && rs1 == Register::RA && imm == 0 => {
+                write!(f, "ret")
+            }
+            I::JALR { rd, rs1, imm } if rd == ZERO && imm == 0 => {
+                write!(f, "jr {}", self.reg(rs1))
+            }
+            I::JALR { rd, rs1, imm } if rd == Register::RA && imm == 0 => {
+                write!(f, "jalr {}", self.reg(rs1))
+            }
+            I::CSRRS { rd, rs1, csr } if rs1 == ZERO => {
+                write!(f, "csrr {}, {}", self.reg(rd), CsrName(csr))
+            }
+            I::CSRRW { rd, rs1, csr } if rd == ZERO => {
+                write!(f, "csrw {}, {}", CsrName(csr), self.reg(rs1))
+            }
+            I::CSRRS { rd, rs1, csr } if rd == ZERO => {
+                write!(f, "csrs {}, {}", CsrName(csr), self.reg(rs1))
+            }
+            I::CSRRC { rd, rs1, csr } if rd == ZERO => {
+                write!(f, "csrc {}, {}", CsrName(csr), self.reg(rs1))
+            }
+            I::CSRRWI { rd, imm, csr } if rd == ZERO => {
+                write!(f, "csrwi {}, {}", CsrName(csr), imm)
+            }
+            I::CSRRSI { rd, imm, csr } if rd == ZERO => {
+                write!(f, "csrsi {}, {}", CsrName(csr), imm)
+            }
+            I::CSRRCI { rd, imm, csr } if rd == ZERO => {
+                write!(f, "csrci {}, {}", CsrName(csr), imm)
+            }
+            I::FENCE { pred, succ } if pred == 0xF && succ == 0xF => write!(f, "fence"),
+            _ => return Ok(false),
+        }?;
+
+        Ok(true)
+    }
+
+    fn write(&self, f: &mut impl Write, instruction: Instruction, pc: Option<u32>) -> fmt::Result {
+        use Instruction as I;
+
+        if self.pseudo_instructions && self.write_pseudo(f, instruction, pc)? {
+            return Ok(());
+        }
+
+        match instruction {
+            I::LUI { rd, imm } => write!(f, "lui {}, 0x{:x}", self.reg(rd), imm.as_u32() >> 12),
+            I::AUIPC { rd, imm } => {
+                write!(f, "auipc {}, 0x{:x}", self.reg(rd), imm.as_u32() >> 12)
+            }
+            I::JAL { rd, imm } => {
+                write!(f, "jal {}, ", self.reg(rd))?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::JALR { rd, rs1, imm } => write!(
+                f,
+                "jalr {}, {}({})",
+                self.reg(rd),
+                imm.as_u32() as i32,
+                self.reg(rs1)
+            ),
+            I::BEQ { rs1, rs2, imm }
+            | I::BNE { rs1, rs2, imm }
+            | I::BLT { rs1, rs2, imm }
+            | I::BGE { rs1, rs2, imm }
+            | I::BLTU { rs1, rs2, imm }
+            | I::BGEU { rs1, rs2, imm } => {
+                write!(
+                    f,
+                    "{} {}, {}, ",
+                    mnemonic(instruction),
+                    self.reg(rs1),
+                    self.reg(rs2)
+                )?;
+                self.write_target(f, pc, imm.as_u32())
+            }
+            I::LB { rd, rs1, imm }
+            | I::LH { rd, rs1, imm }
+            | I::LW { rd, rs1, imm }
+            | I::LBU { rd, rs1, imm }
+            | I::LHU { rd, rs1, imm } => write!(
+                f,
+                "{} {}, {}({})",
+                mnemonic(instruction),
+                self.reg(rd),
+                imm.as_u32() as i32,
+                self.reg(rs1)
+            ),
+            I::SB { rs1, rs2, imm } | I::SH { rs1, rs2, imm } | I::SW { rs1, rs2, imm } => write!(
+                f,
+                "{} {}, {}({})",
+                mnemonic(instruction),
+                self.reg(rs2),
+                imm.as_u32() as i32,
+                self.reg(rs1)
+            ),
+            I::ADDI { rd, rs1, imm }
+            | I::SLTI { rd, rs1, imm }
+            | I::SLTIU { rd, rs1, imm }
+            | I::XORI { rd, rs1, imm }
+            | I::ORI { rd, rs1, imm }
+            | I::ANDI { rd, rs1, imm } => write!(
+                f,
+                "{} {}, {}, {}",
+                mnemonic(instruction),
+                self.reg(rd),
+                self.reg(rs1),
+                imm.as_u32() as i32
+            ),
+            I::SLLI { rd, rs1, imm } | I::SRLI { rd, rs1, imm } | I::SRAI { rd, rs1, imm } => {
+                write!(
+                    f,
+                    "{} {}, {}, {}",
+                    mnemonic(instruction),
+                    self.reg(rd),
+                    self.reg(rs1),
+                    imm.as_u32()
+                )
+            }
+            I::ADD { rd, rs1, rs2 }
+            | I::SUB { rd, rs1, rs2 }
+            | I::SLL { rd, rs1, rs2 }
+            | I::SLT { rd, rs1, rs2 }
+            | I::SLTU { rd, rs1, rs2 }
+            | I::XOR { rd, rs1, rs2 }
+            | I::SRL { rd, rs1, rs2 }
+            | I::SRA { rd, rs1, rs2 }
+            | I::OR { rd, rs1, rs2 }
+            | I::AND { rd, rs1, rs2 }
+            | I::MUL { rd, rs1, rs2 }
+            | I::MULH { rd, rs1, rs2 }
+            | I::MULHSU { rd, rs1, rs2 }
+            | I::MULHU { rd, rs1, rs2 }
+            | I::DIV { rd, rs1, rs2 }
+            | I::DIVU { rd, rs1, rs2 }
+            | I::REM { rd, rs1, rs2 }
+            | I::REMU { rd, rs1, rs2 } => write!(
+                f,
+                "{} {}, {}, {}",
+                mnemonic(instruction),
+                self.reg(rd),
+                self.reg(rs1),
+                self.reg(rs2)
+            ),
+            I::CSRRW { rd, rs1, csr } | I::CSRRS { rd, rs1, csr } | I::CSRRC { rd, rs1, csr } => {
+                write!(
+                    f,
+                    "{} {}, {}, {}",
+                    mnemonic(instruction),
+                    self.reg(rd),
+                    CsrName(csr),
+                    self.reg(rs1)
+                )
+            }
+            I::CSRRWI { rd, imm, csr }
+            | I::CSRRSI { rd, imm, csr }
+            | I::CSRRCI { rd, imm, csr } => write!(
+                f,
+                "{} {}, {}, {}",
+                mnemonic(instruction),
+                self.reg(rd),
+                CsrName(csr),
+                imm
+            ),
+            I::FENCE { pred, succ } => write!(
+                f,
+                "{} {}, {}",
+                mnemonic(instruction),
+                FenceSet(pred),
+                FenceSet(succ)
+            ),
+            I::MRET | I::ECALL | I::EBREAK | I::WFI | I::FENCE_I => {
+                write!(f, "{}", mnemonic(instruction))
+            }
+        }
+    }
+}
+
+/// Prints the canonical form without pseudo-instructions, see the [module](self) documentation
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Disassembler::new()
+            .with_pseudo_instructions(false)
+            .write(f, *self, None)
+    }
+}
+
+struct RegName {
+    reg: Register,
+    numeric: bool,
+}
+
+impl fmt::Display for RegName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.numeric {
+            write!(f, "x{}", self.reg.as_u32())
+        } else {
+            write!(f, "{}", self.reg.name())
+        }
+    }
+}
+
+/// CSRs without a name are printed as their address, which the assembler also accepts
+struct CsrName(Csr);
+
+impl fmt::Display for CsrName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "0x{:x}", self.0.as_u32()),
+        }
+    }
+}
+
+/// The predecessor or successor set of a fence, the empty set is printed as 0
+struct FenceSet(u8);
+
+impl fmt::Display for FenceSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0");
+        }
+        for (bit, name) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')] {
+            if self.0 & bit != 0 {
+                write!(f, "{}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mnemonic(instruction: Instruction) -> &'static str {
+    use Instruction as I;
+
+    match instruction {
+        I::LUI { .. } => "lui",
+        I::AUIPC { .. } => "auipc",
+        I::JAL { .. } => "jal",
+        I::JALR { .. } => "jalr",
+        I::BEQ { .. } => "beq",
+        I::BNE { .. } => "bne",
+        I::BLT { .. } => "blt",
+        I::BGE { .. } => "bge",
+        I::BLTU { .. } => "bltu",
+        I::BGEU { .. } => "bgeu",
+        I::LB { .. } => "lb",
+        I::LH { .. } => "lh",
+        I::LW { .. } => "lw",
+        I::LBU { .. } => "lbu",
+        I::LHU { .. } => "lhu",
+        I::SB { .. } => "sb",
+        I::SH { .. } => "sh",
+        I::SW { .. } => "sw",
+        I::ADDI { .. } => "addi",
+        I::SLTI { .. } => "slti",
+        I::SLTIU { .. } => "sltiu",
+        I::XORI { .. } => "xori",
+        I::ORI { .. } => "ori",
+        I::ANDI { .. } => "andi",
+        I::SLLI { .. } => "slli",
+        I::SRLI { .. } => "srli",
+        I::SRAI { .. } => "srai",
+        I::ADD { .. } => "add",
+        I::SUB { .. } => "sub",
+        I::SLL { .. } => "sll",
+        I::SLT { .. } => "slt",
+        I::SLTU { .. } => "sltu",
+        I::XOR { .. } => "xor",
+        I::SRL { .. } => "srl",
+        I::SRA { .. } => "sra",
+        I::OR { .. } => "or",
+        I::AND { .. } => "and",
+        I::CSRRW { .. } => "csrrw",
+        I::CSRRS { .. } => "csrrs",
+        I::CSRRC { .. } => "csrrc",
+        I::CSRRWI { .. } => "csrrwi",
+        I::CSRRSI { .. } => "csrrsi",
+        I::CSRRCI { .. } => "csrrci",
+        I::MRET => "mret",
+        I::ECALL => "ecall",
+        I::EBREAK => "ebreak",
+        I::WFI => "wfi",
+        I::FENCE { .. } => "fence",
+        I::FENCE_I => "fence.i",
+        I::MUL { .. } => "mul",
+        I::MULH { .. } => "mulh",
+        I::MULHSU { .. } => "mulhsu",
+        I::MULHU { .. } => "mulhu",
+        I::DIV { .. } => "div",
+        I::DIVU { .. } => "divu",
+        I::REM { .. } => "rem",
+        I::REMU { .. } => "remu",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn decode(word: u32) -> Instruction {
+        Instruction::try_from(word).unwrap()
+    }
+
+    #[test_case(0x00700293 => "addi t0, zero, 7"; "addi")]
+    #[test_case(0x00532023 => "sw t0, 0(t1)"; "store")]
+    #[test_case(0xffc12503 => "lw a0, -4(sp)"; "negative offset")]
+    #[test_case(0x30002573 => "csrrs a0, mstatus, zero"; "csr")]
+    #[test_case(0x3442d073 => "csrrwi zero, mip, 5"; "csr immediate")]
+    #[test_case(0x123452b7 => "lui t0, 0x12345"; "lui")]
+    #[test_case(0x4052d293 => "srai t0, t0, 5"; "shift")]
+    #[test_case(0x02b50533 => "mul a0, a0, a1"; "mul")]
+    #[test_case(0xfeb50ee3 => "beq a0, a1, -4"; "branch")]
+    #[test_case(0x008000ef => "jal ra, 8"; "jal")]
+    #[test_case(0x00008067 => "jalr zero, 0(ra)"; "jalr")]
+    #[test_case(0x30200073 => "mret"; "mret")]
+    #[test_case(0x00100073 => "ebreak"; "ebreak")]
+    #[test_case(0x0310000f => "fence rw, w"; "fence")]
+    #[test_case(0x0000100f => "fence.i"; "fence.i")]
+    fn test_display(word: u32) -> String {
+        decode(word).to_string()
+    }
+
+    #[test_case(0x00000013 => "nop"; "nop")]
+    #[test_case(0x00700293 => "li t0, 7"; "li")]
+    #[test_case(0xfff00293 => "li t0, -1"; "negative li")]
+    #[test_case(0x00050593 => "mv a1, a0"; "mv")]
+    #[test_case(0xfff54513 => "not a0, a0"; "not")]
+    #[test_case(0x40a00533 => "neg a0, a0"; "neg")]
+    #[test_case(0x00153513 => "seqz a0, a0"; "seqz")]
+    #[test_case(0x00a03533 => "snez a0, a0"; "snez")]
+    #[test_case(0x00008067 => "ret"; "ret")]
+    #[test_case(0x00028067 => "jr t0"; "jr")]
+    #[test_case(0x000280e7 => "jalr t0"; "jalr")]
+    #[test_case(0x0000006f => "j 0x100"; "j")]
+    #[test_case(0x008000ef => "jal 0x108"; "jal")]
+    #[test_case(0x00050463 => "beqz a0, 0x108"; "beqz")]
+    #[test_case(0x00a04463 => "bgtz a0, 0x108"; "bgtz")]
+    #[test_case(0x30002573 => "csrr a0, mstatus"; "csrr")]
+    #[test_case(0x30029073 => "csrw mstatus, t0"; "csrw")]
+    #[test_case(0x30046073 => "csrsi mstatus, 8"; "csrsi")]
+    #[test_case(0x7c002573 => "csrr a0, 0x7c0"; "unnamed csr")]
+    #[test_case(0x0ff0000f => "fence"; "fence")]
+    fn test_pseudo_instructions(word: u32) -> String {
+        Disassembler::new().disassemble(decode(word), 0x100)
+    }
+
+    #[test]
+    fn test_options() {
+        let disassembler = Disassembler::new()
+            .with_pseudo_instructions(false)
+            .with_numeric_registers(true);
+        assert_eq!(
+            disassembler.disassemble(decode(0x00000013), 0),
+            "addi x0, x0, 0"
+        );
+        assert_eq!(
+            disassembler.disassemble(decode(0xfeb50ee3), 0x100),
+            "beq x10, x11, 0xfc"
+        );
+        assert_eq!(
+            disassembler.disassemble_word(0xffffffff, 0),
+            ".word 0xffffffff"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = [
+            Symbol {
+                name: "main".to_string(),
+                addr: 0x100,
+                size: 0x20,
+                kind: SymbolKind::Function,
+            },
+            Symbol {
+                name: "loop".to_string(),
+                addr: 0x140,
+                size: 0,
+                kind: SymbolKind::Other,
+            },
+        ];
+        let disassembler = Disassembler::new().with_symbols(&symbols);
+
+        // jal ra, -8 | j 0x20 | j 0x40
+        assert_eq!(
+            disassembler.disassemble(decode(0xff9ff0ef), 0x108),
+            "jal 0x100 <main>"
+        );
+        assert_eq!(
+            disassembler.disassemble(decode(0x0200006f), 0xe8),
+            "j 0x108 <main+0x8>"
+        );
+        assert_eq!(
+            disassembler.disassemble(decode(0x0400006f), 0x100),
+            "j 0x140 <loop>"
+        );
+        assert_eq!(
+            disassembler.disassemble(decode(0x0400006f), 0x200),
+            "j 0x240"
+        );
+    }
+}
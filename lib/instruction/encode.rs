@@ -0,0 +1,255 @@
+//! Turning instructions back into machine code, the inverse of [parse](super::parse)
+
+use crate::{csr::Csr, register::Register};
+
+use super::{parse::*, Instruction};
+
+fn r_type(opcode: u8, funct3: u8, funct7: u8, rd: Register, rs1: Register, rs2: Register) -> u32 {
+    (funct7 as u32) << 25
+        | rs2.as_u32() << 20
+        | rs1.as_u32() << 15
+        | (funct3 as u32) << 12
+        | rd.as_u32() << 7
+        | opcode as u32
+}
+
+fn i_type(opcode: u8, funct3: u8, rd: Register, rs1: u32, imm: u32) -> u32 {
+    (imm & 0xFFF) << 20 | rs1 << 15 | (funct3 as u32) << 12 | rd.as_u32() << 7 | opcode as u32
+}
+
+fn s_type(opcode: u8, funct3: u8, rs1: Register, rs2: Register, imm: u32) -> u32 {
+    ((imm >> 5) & 0x7F) << 25
+        | rs2.as_u32() << 20
+        | rs1.as_u32() << 15
+        | (funct3 as u32) << 12
+        | (imm & 0x1F) << 7
+        | opcode as u32
+}
+
+fn b_type(funct3: u8, rs1: Register, rs2: Register, imm: u32) -> u32 {
+    ((imm >> 12) & 0x1) << 31
+        | ((imm >> 5) & 0x3F) << 25
+        | rs2.as_u32() << 20
+        | rs1.as_u32() << 15
+        | (funct3 as u32) << 12
+        | ((imm >> 1) & 0xF) << 8
+        | ((imm >> 11) & 0x1) << 7
+        | BRANCH as u32
+}
+
+fn u_type(opcode: u8, rd: Register, imm: u32) -> u32 {
+    (imm & 0xFFFF_F000) | rd.as_u32() << 7 | opcode as u32
+}
+
+fn j_type(rd: Register, imm: u32) -> u32 {
+    ((imm >> 20) & 0x1) << 31
+        | ((imm >> 1) & 0x3FF) << 21
+        | ((imm >> 11) & 0x1) << 20
+        | ((imm >> 12) & 0xFF) << 12
+        | rd.as_u32() << 7
+        | JAL as u32
+}
+
+fn shift(funct3: u8, funct7: u8, rd: Register, rs1: Register, shamt: u32) -> u32 {
+    i_type(
+        OP_IMM,
+        funct3,
+        rd,
+        rs1.as_u32(),
+        (funct7 as u32) << 5 | shamt,
+    )
+}
+
+fn csr(funct3: u8, rd: Register, rs1: u32, csr: Csr) -> u32 {
+    i_type(SYSTEM, funct3, rd, rs1 & 0x1F, csr.as_u32())
+}
+
+impl Instruction {
+    /// Encodes the instruction as a 32 bit word, decoding the word gives back the same
+    /// instruction
+    pub fn encode(self) -> u32 {
+        use Instruction as I;
+
+        match self {
+            I::LUI { rd, imm } => u_type(LUI, rd, imm.as_u32()),
+            I::AUIPC { rd, imm } => u_type(AUIPC, rd, imm.as_u32()),
+            I::JAL { rd, imm } => j_type(rd, imm.as_u32()),
+            I::JALR { rd, rs1, imm } => i_type(JALR, 0, rd, rs1.as_u32(), imm.as_u32()),
+            I::BEQ { rs1, rs2, imm } => b_type(BEQ, rs1, rs2, imm.as_u32()),
+            I::BNE { rs1, rs2, imm } => b_type(BNE, rs1, rs2, imm.as_u32()),
+            I::BLT { rs1, rs2, imm } => b_type(BLT, rs1, rs2, imm.as_u32()),
+            I::BGE { rs1, rs2, imm } => b_type(BGE, rs1, rs2, imm.as_u32()),
+            I::BLTU { rs1, rs2, imm } => b_type(BLTU, rs1, rs2, imm.as_u32()),
+            I::BGEU { rs1, rs2, imm } => b_type(BGEU, rs1, rs2, imm.as_u32()),
+            I::LB { rd, rs1, imm } => i_type(LOAD, LB, rd, rs1.as_u32(), imm.as_u32()),
+            I::LH { rd, rs1, imm } => i_type(LOAD, LH, rd, rs1.as_u32(), imm.as_u32()),
+            I::LW { rd, rs1, imm } => i_type(LOAD, LW, rd, rs1.as_u32(), imm.as_u32()),
+            I::LBU { rd, rs1, imm } => i_type(LOAD, LBU, rd, rs1.as_u32(), imm.as_u32()),
+            I::LHU { rd, rs1, imm } => i_type(LOAD, LHU, rd, rs1.as_u32(), imm.as_u32()),
+            I::SB { rs1, rs2, imm } => s_type(STORE, SB, rs1, rs2, imm.as_u32()),
+            I::SH { rs1, rs2, imm } => s_type(STORE, SH, rs1, rs2, imm.as_u32()),
+            I::SW { rs1, rs2, imm } => s_type(STORE, SW, rs1, rs2, imm.as_u32()),
+            I::ADDI { rd, rs1, imm } => i_type(OP_IMM, ADDI, rd, rs1.as_u32(), imm.as_u32()),
+            I::SLTI { rd, rs1, imm } => i_type(OP_IMM, SLTI, rd, rs1.as_u32(), imm.as_u32()),
+            I::SLTIU { rd, rs1, imm } => i_type(OP_IMM, SLTIU, rd, rs1.as_u32(), imm.as_u32()),
+            I::XORI { rd, rs1, imm } => i_type(OP_IMM, XORI, rd, rs1.as_u32(), imm.as_u32()),
+            I::ORI { rd, rs1, imm } => i_type(OP_IMM, ORI, rd, rs1.as_u32(), imm.as_u32()),
+            I::ANDI { rd, rs1, imm } => i_type(OP_IMM, ANDI, rd, rs1.as_u32(), imm.as_u32()),
+            I::SLLI { rd, rs1, imm } => shift(SLLI, FUNCT7_SLLI, rd, rs1, imm.as_u32()),
+            I::SRLI { rd, rs1, imm } => shift(SRLI_SRAI, FUNCT7_SRLI, rd, rs1, imm.as_u32()),
+            I::SRAI { rd, rs1, imm } => shift(SRLI_SRAI, FUNCT7_SRAI, rd, rs1, imm.as_u32()),
+            I::ADD { rd, rs1, rs2 } => r_type(OP, ADD_SUB, FUNCT7_ADD, rd, rs1, rs2),
+            I::SUB { rd, rs1, rs2 } => r_type(OP, ADD_SUB, FUNCT7_SUB, rd, rs1, rs2),
+            I::SLL { rd, rs1, rs2 } => r_type(OP, SLL, FUNCT7_SLL, rd, rs1, rs2),
+            I::SLT { rd, rs1, rs2 } => r_type(OP, SLT, FUNCT7_SLT, rd, rs1, rs2),
+            I::SLTU { rd, rs1, rs2 } => r_type(OP, SLTU, FUNCT7_SLTU, rd, rs1, rs2),
+            I::XOR { rd, rs1, rs2 } => r_type(OP, XOR, FUNCT7_XOR, rd, rs1, rs2),
+            I::SRL { rd, rs1, rs2 } => r_type(OP, SRL_SRA, FUNCT7_SRL, rd, rs1, rs2),
+            I::SRA { rd, rs1, rs2 } => r_type(OP, SRL_SRA, FUNCT7_SRA, rd, rs1, rs2),
+            I::OR { rd, rs1, rs2 } => r_type(OP, OR, FUNCT7_OR, rd, rs1, rs2),
+            I::AND { rd, rs1, rs2 } => r_type(OP, AND, FUNCT7_AND, rd, rs1, rs2),
+            I::CSRRW { rd, rs1, csr: c } => csr(CSRRW, rd, rs1.as_u32(), c),
+            I::CSRRS { rd, rs1, csr: c } => csr(CSRRS, rd, rs1.as_u32(), c),
+            I::CSRRC { rd, rs1, csr: c } => csr(CSRRC, rd, rs1.as_u32(), c),
+            I::CSRRWI { imm, rd, csr: c } => csr(CSRRWI, rd, imm, c),
+            I::CSRRSI { imm, rd, csr: c } => csr(CSRRSI, rd, imm, c),
+            I::CSRRCI { imm, rd, csr: c } => csr(CSRRCI, rd, imm, c),
+            I::MRET => MRET,
+            I::ECALL => ECALL,
+            I::EBREAK => EBREAK,
+            I::WFI => WFI,
+            I::FENCE { pred, succ } => {
+                ((pred as u32) << 24 | (succ as u32) << 20) | (FENCE as u32) << 12 | MISC_MEM as u32
+            }
+            I::FENCE_I => FENCE_I_WORD,
+            I::MUL { rd, rs1, rs2 } => r_type(OP, MUL, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::MULH { rd, rs1, rs2 } => r_type(OP, MULH, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::MULHSU { rd, rs1, rs2 } => r_type(OP, MULHSU, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::MULHU { rd, rs1, rs2 } => r_type(OP, MULHU, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::DIV { rd, rs1, rs2 } => r_type(OP, DIV, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::DIVU { rd, rs1, rs2 } => r_type(OP, DIVU, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::REM { rd, rs1, rs2 } => r_type(OP, REM, FUNCT7_M_EXT, rd, rs1, rs2),
+            I::REMU { rd, rs1, rs2 } => r_type(OP, REMU, FUNCT7_M_EXT, rd, rs1, rs2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small xorshift generator, so the tests are random but reproducible
+    struct Words(u32);
+
+    impl Iterator for Words {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            Some(self.0)
+        }
+    }
+
+    /// Random words with the opcode of an instruction the Cpu implements, so most of them decode
+    fn instruction_words() -> impl Iterator<Item = u32> {
+        const OPCODES: [u8; 11] = [
+            LUI, AUIPC, JAL, JALR, BRANCH, LOAD, STORE, OP_IMM, OP, SYSTEM, MISC_MEM,
+        ];
+
+        Words(0x12345678)
+            .zip(Words(0x9abcdef0))
+            .map(|(word, opcode)| (word & !0x7F) | OPCODES[opcode as usize % OPCODES.len()] as u32)
+            .take(200_000)
+    }
+
+    #[test]
+    fn test_decode_encode() {
+        let mut decoded = 0;
+        for word in instruction_words() {
+            if let Ok(instruction) = Instruction::try_from(word) {
+                // Fences ignore their reserved fields, which are encoded as zero
+                let reserved = match word >> 12 & 0x7 {
+                    _ if word & 0x7F != MISC_MEM as u32 => 0,
+                    0 => 0xF00F_8F80,
+                    _ => 0xFFFF_8F80,
+                };
+                assert_eq!(
+                    instruction.encode(),
+                    word & !reserved,
+                    "{:08x} decoded as {:?}",
+                    word,
+                    instruction
+                );
+                decoded += 1;
+            }
+        }
+
+        // Make sure the test isn't just skipping everything
+        assert!(decoded > 50_000, "only {} words decoded", decoded);
+    }
+
+    #[test]
+    fn test_extreme_immediates() {
+        use crate::instruction::{BTypeImm, ITypeImm, JTypeImm, STypeImm, ShamtImm, UTypeImm};
+        use Instruction as I;
+
+        let (rd, rs1, rs2) = (Register::T6, Register::A0, Register::S11);
+        let mut instructions = vec![I::MRET, I::ECALL, I::EBREAK, I::WFI, I::FENCE_I];
+        for (pred, succ) in [(0, 0), (0xF, 0xF), (0x3, 0x1)] {
+            instructions.push(I::FENCE { pred, succ });
+        }
+        for imm in [-1_048_576, 1_048_574, -2, 2] {
+            let imm = JTypeImm::new(imm as u32).unwrap();
+            instructions.push(I::JAL { rd, imm });
+        }
+        for imm in [-4096, 4094, -2, 2] {
+            let imm = BTypeImm::new(imm as u32).unwrap();
+            instructions.push(I::BGEU { rs1, rs2, imm });
+        }
+        for imm in [-2048, 2047, -1, 0] {
+            instructions.push(I::SW {
+                rs1,
+                rs2,
+                imm: STypeImm::new(imm as u32).unwrap(),
+            });
+            instructions.push(I::JALR {
+                rd,
+                rs1,
+                imm: ITypeImm::new(imm as u32).unwrap(),
+            });
+        }
+        for imm in [0xFFFF_F000, 0x8000_0000, 0x1000] {
+            let imm = UTypeImm::new(imm).unwrap();
+            instructions.push(I::AUIPC { rd, imm });
+        }
+        for imm in [0, 31] {
+            let imm = ShamtImm::new(imm).unwrap();
+            instructions.push(I::SRAI { rd, rs1, imm });
+        }
+        for imm in [0, 31] {
+            instructions.push(I::CSRRCI {
+                imm,
+                rd,
+                csr: Csr::new(0xFFF).unwrap(),
+            });
+        }
+
+        for instruction in instructions {
+            assert_eq!(
+                Instruction::try_from(instruction.encode()),
+                Ok(instruction),
+                "{:?}",
+                instruction
+            );
+        }
+        assert_eq!(I::MRET.encode(), 0x30200073);
+    }
+
+    #[test]
+    fn test_reserved_jalr() {
+        // jalr with funct3 = 1
+        assert_eq!(Instruction::try_from(0x000090e7), Err(()));
+    }
+}
@@ -226,6 +226,16 @@ pub enum Instruction {
     },
     MRET,
     ECALL,
+    EBREAK,
+    WFI,
+    /// Orders the memory accesses in `pred` before the ones in `succ`. Both are sets of the bits
+    /// I (8), O (4), R (2) and W (1).
+    FENCE {
+        pred: u8,
+        succ: u8,
+    },
+    #[allow(non_camel_case_types)]
+    FENCE_I,
     MUL {
         rd: Register,
         rs1: Register,
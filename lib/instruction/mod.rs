@@ -1,11 +1,22 @@
 //! RISC-V instruction parsing
 
+#[allow(clippy::module_inception)]
 mod instruction;
 pub use instruction::Instruction;
 
 mod newtype;
 pub use newtype::*;
 
+#[cfg(feature = "compressed")]
+mod compressed;
+#[cfg(feature = "compressed")]
+pub use compressed::is_compressed;
+
+mod encode;
+
+mod disassemble;
+pub use disassemble::Disassembler;
+
 /// The RISC-V instruction types
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum InstructionType {
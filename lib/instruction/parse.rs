@@ -2,81 +2,88 @@ use crate::{csr::Csr, register::Register};
 
 use super::{BTypeImm, ITypeImm, Instruction, JTypeImm, STypeImm, ShamtImm, UTypeImm};
 
-const LUI: u8 = 0b0110111;
-const AUIPC: u8 = 0b0010111;
-const JAL: u8 = 0b1101111;
-const JALR: u8 = 0b1100111;
-const BRANCH: u8 = 0b1100011;
-const LOAD: u8 = 0b0000011;
-const STORE: u8 = 0b0100011;
-const OP_IMM: u8 = 0b0010011;
-const OP: u8 = 0b0110011;
-const SYSTEM: u8 = 0b1110011;
+pub(super) const LUI: u8 = 0b0110111;
+pub(super) const AUIPC: u8 = 0b0010111;
+pub(super) const JAL: u8 = 0b1101111;
+pub(super) const JALR: u8 = 0b1100111;
+pub(super) const BRANCH: u8 = 0b1100011;
+pub(super) const LOAD: u8 = 0b0000011;
+pub(super) const STORE: u8 = 0b0100011;
+pub(super) const OP_IMM: u8 = 0b0010011;
+pub(super) const OP: u8 = 0b0110011;
+pub(super) const SYSTEM: u8 = 0b1110011;
+pub(super) const MISC_MEM: u8 = 0b0001111;
 
-const BEQ: u8 = 0b000;
-const BNE: u8 = 0b001;
-const BLT: u8 = 0b100;
-const BGE: u8 = 0b101;
-const BLTU: u8 = 0b110;
-const BGEU: u8 = 0b111;
-const LB: u8 = 0b000;
-const LH: u8 = 0b001;
-const LW: u8 = 0b010;
-const LBU: u8 = 0b100;
-const LHU: u8 = 0b101;
-const SB: u8 = 0b000;
-const SH: u8 = 0b001;
-const SW: u8 = 0b010;
-const ADDI: u8 = 0b000;
-const SLTI: u8 = 0b010;
-const SLTIU: u8 = 0b011;
-const XORI: u8 = 0b100;
-const ORI: u8 = 0b110;
-const ANDI: u8 = 0b111;
-const SLLI: u8 = 0b001;
-const SRLI_SRAI: u8 = 0b101;
-const ADD_SUB: u8 = 0b000;
-const SLL: u8 = 0b001;
-const SLT: u8 = 0b010;
-const SLTU: u8 = 0b011;
-const XOR: u8 = 0b100;
-const SRL_SRA: u8 = 0b101;
-const OR: u8 = 0b110;
-const AND: u8 = 0b111;
-const MRET_SRET_ECALL: u8 = 0b000;
-const CSRRW: u8 = 0b001;
-const CSRRS: u8 = 0b010;
-const CSRRC: u8 = 0b011;
-const CSRRWI: u8 = 0b101;
-const CSRRSI: u8 = 0b110;
-const CSRRCI: u8 = 0b111;
-const MUL: u8 = 0b000;
-const MULH: u8 = 0b001;
-const MULHSU: u8 = 0b010;
-const MULHU: u8 = 0b011;
-const DIV: u8 = 0b100;
-const DIVU: u8 = 0b101;
-const REM: u8 = 0b110;
-const REMU: u8 = 0b111;
+pub(super) const BEQ: u8 = 0b000;
+pub(super) const BNE: u8 = 0b001;
+pub(super) const BLT: u8 = 0b100;
+pub(super) const BGE: u8 = 0b101;
+pub(super) const BLTU: u8 = 0b110;
+pub(super) const BGEU: u8 = 0b111;
+pub(super) const LB: u8 = 0b000;
+pub(super) const LH: u8 = 0b001;
+pub(super) const LW: u8 = 0b010;
+pub(super) const LBU: u8 = 0b100;
+pub(super) const LHU: u8 = 0b101;
+pub(super) const SB: u8 = 0b000;
+pub(super) const SH: u8 = 0b001;
+pub(super) const SW: u8 = 0b010;
+pub(super) const ADDI: u8 = 0b000;
+pub(super) const SLTI: u8 = 0b010;
+pub(super) const SLTIU: u8 = 0b011;
+pub(super) const XORI: u8 = 0b100;
+pub(super) const ORI: u8 = 0b110;
+pub(super) const ANDI: u8 = 0b111;
+pub(super) const SLLI: u8 = 0b001;
+pub(super) const SRLI_SRAI: u8 = 0b101;
+pub(super) const ADD_SUB: u8 = 0b000;
+pub(super) const SLL: u8 = 0b001;
+pub(super) const SLT: u8 = 0b010;
+pub(super) const SLTU: u8 = 0b011;
+pub(super) const XOR: u8 = 0b100;
+pub(super) const SRL_SRA: u8 = 0b101;
+pub(super) const OR: u8 = 0b110;
+pub(super) const AND: u8 = 0b111;
+pub(super) const MRET_SRET_ECALL: u8 = 0b000;
+pub(super) const CSRRW: u8 = 0b001;
+pub(super) const CSRRS: u8 = 0b010;
+pub(super) const CSRRC: u8 = 0b011;
+pub(super) const CSRRWI: u8 = 0b101;
+pub(super) const CSRRSI: u8 = 0b110;
+pub(super) const CSRRCI: u8 = 0b111;
+pub(super) const FENCE: u8 = 0b000;
+pub(super) const FENCE_I: u8 = 0b001;
+pub(super) const MUL: u8 = 0b000;
+pub(super) const MULH: u8 = 0b001;
+pub(super) const MULHSU: u8 = 0b010;
+pub(super) const MULHU: u8 = 0b011;
+pub(super) const DIV: u8 = 0b100;
+pub(super) const DIVU: u8 = 0b101;
+pub(super) const REM: u8 = 0b110;
+pub(super) const REMU: u8 = 0b111;
 
-const MRET: u32 = 0x30200073;
-const ECALL: u32 = 0x00000073;
+pub(super) const MRET: u32 = 0x30200073;
+pub(super) const ECALL: u32 = 0x00000073;
+pub(super) const EBREAK: u32 = 0x00100073;
+pub(super) const WFI: u32 = 0x10500073;
+/// fence.i, which has no operands
+pub(super) const FENCE_I_WORD: u32 = 0x0000100F;
 
-const FUNCT7_SLLI: u8 = 0b0000000;
-const FUNCT7_SRLI: u8 = 0b0000000;
-const FUNCT7_SRAI: u8 = 0b0100000;
+pub(super) const FUNCT7_SLLI: u8 = 0b0000000;
+pub(super) const FUNCT7_SRLI: u8 = 0b0000000;
+pub(super) const FUNCT7_SRAI: u8 = 0b0100000;
 
-const FUNCT7_ADD: u8 = 0b0000000;
-const FUNCT7_SUB: u8 = 0b0100000;
-const FUNCT7_SLL: u8 = 0b0000000;
-const FUNCT7_SLT: u8 = 0b0000000;
-const FUNCT7_SLTU: u8 = 0b0000000;
-const FUNCT7_XOR: u8 = 0b0000000;
-const FUNCT7_SRL: u8 = 0b0000000;
-const FUNCT7_SRA: u8 = 0b0100000;
-const FUNCT7_OR: u8 = 0b0000000;
-const FUNCT7_AND: u8 = 0b0000000;
-const FUNCT7_M_EXT: u8 = 0b0000001;
+pub(super) const FUNCT7_ADD: u8 = 0b0000000;
+pub(super) const FUNCT7_SUB: u8 = 0b0100000;
+pub(super) const FUNCT7_SLL: u8 = 0b0000000;
+pub(super) const FUNCT7_SLT: u8 = 0b0000000;
+pub(super) const FUNCT7_SLTU: u8 = 0b0000000;
+pub(super) const FUNCT7_XOR: u8 = 0b0000000;
+pub(super) const FUNCT7_SRL: u8 = 0b0000000;
+pub(super) const FUNCT7_SRA: u8 = 0b0100000;
+pub(super) const FUNCT7_OR: u8 = 0b0000000;
+pub(super) const FUNCT7_AND: u8 = 0b0000000;
+pub(super) const FUNCT7_M_EXT: u8 = 0b0000001;
 
 pub fn parse(raw: u32) -> Result<Instruction, ()> {
     let opcode = opcode(raw);
@@ -99,7 +106,8 @@ pub fn parse(raw: u32) -> Result<Instruction, ()> {
             rd,
             imm: JTypeImm::from_instr(raw),
         }),
-        JALR => Ok(Instruction::JALR {
+        // The other funct3 values are reserved
+        JALR if funct3 == 0 => Ok(Instruction::JALR {
             rd,
             rs1,
             imm: ITypeImm::from_instr(raw),
@@ -256,6 +264,8 @@ pub fn parse(raw: u32) -> Result<Instruction, ()> {
             MRET_SRET_ECALL => match raw {
                 MRET => Ok(Instruction::MRET),
                 ECALL => Ok(Instruction::ECALL),
+                EBREAK => Ok(Instruction::EBREAK),
+                WFI => Ok(Instruction::WFI),
                 _ => Err(()),
             },
             // We can call expect here because we and with 0xFFF, so the value is always valid
@@ -291,6 +301,16 @@ pub fn parse(raw: u32) -> Result<Instruction, ()> {
             }),
             _ => Err(()),
         },
+        // The fm, rs1 and rd fields are reserved for future fences, which have to behave like
+        // the plain ones until then, so they are ignored. This makes fence.tso a fence rw, rw.
+        MISC_MEM => match funct3 {
+            FENCE => Ok(Instruction::FENCE {
+                pred: (raw >> 24) as u8 & 0xF,
+                succ: (raw >> 20) as u8 & 0xF,
+            }),
+            FENCE_I => Ok(Instruction::FENCE_I),
+            _ => Err(()),
+        },
         _ => Err(()),
     }
 }
@@ -320,7 +340,7 @@ fn funct7(v: u32) -> u8 {
 }
 
 fn get_itype_imm(v: u32) -> u32 {
-    return v >> 20;
+    v >> 20
 }
 
 #[cfg(test)]
@@ -394,4 +414,49 @@ mod tests {
         };
         assert_eq!(Instruction::try_from(raw), Ok(instr));
     }
+
+    #[test]
+    fn test_parse_system_and_fences() {
+        let cases = vec![
+            (0x00100073, Instruction::EBREAK),
+            (0x10500073, Instruction::WFI),
+            (
+                0x0ff0000f,
+                Instruction::FENCE {
+                    pred: 0xF,
+                    succ: 0xF,
+                },
+            ),
+            (
+                0x0310000f,
+                Instruction::FENCE {
+                    pred: 0x3,
+                    succ: 0x1,
+                },
+            ),
+            (0x0000100f, Instruction::FENCE_I),
+        ];
+
+        for (raw, instr) in cases {
+            assert_eq!(Instruction::try_from(raw), Ok(instr));
+        }
+
+        // fence.tso and fences with the reserved fields set decode like the plain ones
+        assert_eq!(
+            Instruction::try_from(0x8330000f),
+            Ok(Instruction::FENCE {
+                pred: 0x3,
+                succ: 0x3,
+            })
+        );
+        assert_eq!(
+            Instruction::try_from(0x0ff2808f),
+            Ok(Instruction::FENCE {
+                pred: 0xF,
+                succ: 0xF,
+            })
+        );
+        assert_eq!(Instruction::try_from(0x0002908f), Ok(Instruction::FENCE_I));
+        assert_eq!(Instruction::try_from(0x0000200f), Err(()));
+    }
 }
@@ -1,12 +1,17 @@
 //! Exception codes for the DTEK-V
 
+/// Bit set in mcause when the trap was caused by an interrupt
+pub const MCAUSE_INTERRUPT: u32 = 0x80000000;
+
+/// An exception or interrupt cause. `external` is true for interrupts, which are asynchronous to
+/// the instruction stream, and false for exceptions, which are caused by an instruction.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct InterruptSignal(u32, bool);
 
 impl InterruptSignal {
     pub fn new(cause: u32, external: bool) -> Option<Self> {
         // Cause can't have highest bit set since that represents an hardware interrupt
-        if cause & 0x80000000 == 1 {
+        if cause & 0x80000000 != 0 {
             return None;
         }
 
@@ -14,7 +19,7 @@ impl InterruptSignal {
     }
 
     unsafe fn new_unchecked(cause: u32, external: bool) -> Self {
-        if cause & 0x80000000 == 1 {
+        if cause & 0x80000000 != 0 {
             debug_assert!(cause & 0x80000000 == 0);
             std::hint::unreachable_unchecked();
         }
@@ -28,6 +33,42 @@ impl InterruptSignal {
     pub fn external(&self) -> bool {
         self.1
     }
+
+    /// The value written to mcause when this trap is taken, interrupts have the highest bit set
+    pub fn mcause(&self) -> u32 {
+        if self.1 {
+            self.0 | MCAUSE_INTERRUPT
+        } else {
+            self.0
+        }
+    }
+
+    /// The bit representing this interrupt in mip and mie
+    pub fn mip_bit(&self) -> u32 {
+        debug_assert!(self.1, "Only interrupts have a bit in mip");
+        1 << self.0
+    }
+}
+
+/// Priority order of the standard machine interrupts, external, software and timer
+const STANDARD_PRIORITY: [u32; 3] = [11, 3, 7];
+
+/// Picks the interrupt that should be taken out of a set of pending interrupts, given as mip
+/// bits.
+///
+/// The platform specific interrupts (cause 16 and up) are prioritized over the standard ones, with
+/// lower cause numbers taken first. On the DTEK-V this means timer > switch > button > UART.
+pub fn highest_priority(pending: u32) -> Option<InterruptSignal> {
+    let platform = pending & 0xFFFF_0000;
+    let cause = if platform != 0 {
+        platform.trailing_zeros()
+    } else {
+        *STANDARD_PRIORITY
+            .iter()
+            .find(|cause| pending & (1 << **cause) != 0)?
+    };
+
+    Some(InterruptSignal(cause, true))
 }
 
 macro_rules! interrupt_list {
@@ -49,9 +90,31 @@ impl InterruptSignal {
 
 interrupt_list! {
     (INSTRUCTION_ADDRESS_MISALIGNED, 0, false, "Instruction address misaligned"),
+    (INSTRUCTION_ACCESS_FAULT, 1, false, "Instruction access fault"),
     (ILLEGAL_INSTRUCTION, 2, false, "Illegal instruction"),
+    (BREAKPOINT, 3, false, "Breakpoint"),
+    (LOAD_ADDRESS_MISALIGNED, 4, false, "Load address misaligned"),
+    (LOAD_ACCESS_FAULT, 5, false, "Load access fault"),
+    (STORE_ADDRESS_MISALIGNED, 6, false, "Store address misaligned"),
+    (STORE_ACCESS_FAULT, 7, false, "Store access fault"),
     (ENVIRONMENT_CALL_FROM_M_MODE, 11, false, "Environment call from M-mode"),
     (TIMER_INTERRUPT, 16, true, "Timer interrupt"),
     (SWITCH_INTERRUPT, 17, true, "Switch interrupt"),
     (BUTTON_INTERRUPT, 18, true, "Button interrupt"),
+    (UART_INTERRUPT, 19, true, "UART interrupt"),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(
+            InterruptSignal::new(2, false),
+            Some(InterruptSignal::ILLEGAL_INSTRUCTION)
+        );
+        // The highest bit belongs to mcause, not to the cause itself
+        assert_eq!(InterruptSignal::new(0x80000010, true), None);
+    }
 }
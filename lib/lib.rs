@@ -34,14 +34,20 @@
 //! let bytes = BufReader::new(file).bytes().map(Result::unwrap);
 //! cpu.store_at(0, bytes).unwrap();
 //!
+//! // Interrupts from the bus are kept pending in mip and taken by the Cpu as soon as they're
+//! // enabled, so there is no need to poll for them here
 //! loop {
-//!     cpu.clock();
-//!     if let Some(interrupt) = cpu.bus.poll_interrupt() {
-//!         cpu.handle_interrupt(interrupt);
+//!     match cpu.run(1_000_000) {
+//!         // Give the frontend a chance to redraw and handle input
+//!         cpu::StopReason::BudgetExhausted => {}
+//!         cpu::StopReason::HaltLoop { .. } => break,
+//!         reason => println!("{:?}", reason),
 //!     }
 //! }
 //!
 //! ```
+//!
+//! Programs can also be loaded from an ELF file instead of a raw binary, see the [elf] module.
 
 pub mod cpu;
 pub mod csr;
@@ -50,9 +56,19 @@ pub mod register;
 pub mod interrupt;
 pub mod memory_mapped;
 
+pub mod assembler;
 pub mod instruction;
 
+pub mod elf;
+
+pub mod gdb;
+
+pub mod semihosting;
+
 pub mod peripheral;
+pub mod scheduler;
+
+pub mod snapshot;
 
 #[cfg(feature = "debug-console")]
 pub mod debug_console;
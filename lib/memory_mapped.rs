@@ -8,7 +8,7 @@ pub trait MemoryMapped<T> {
     fn load_halfword(&self, addr: u32) -> Result<u16, T> {
         Ok(u16::from_be_bytes([
             self.load_byte(addr + 1)?,
-            self.load_byte(addr + 0)?,
+            self.load_byte(addr)?,
         ]))
     }
 
@@ -17,26 +17,43 @@ pub trait MemoryMapped<T> {
             self.load_byte(addr + 3)?,
             self.load_byte(addr + 2)?,
             self.load_byte(addr + 1)?,
-            self.load_byte(addr + 0)?,
+            self.load_byte(addr)?,
         ]))
     }
 
     fn store_halfword(&mut self, addr: u32, halfword: u16) -> Result<(), T> {
         let bytes = halfword.to_le_bytes();
-        self.store_byte(addr + 0, bytes[0])?;
+        self.store_byte(addr, bytes[0])?;
         self.store_byte(addr + 1, bytes[1])?;
         Ok(())
     }
 
     fn store_word(&mut self, addr: u32, word: u32) -> Result<(), T> {
         let bytes = word.to_le_bytes();
-        self.store_byte(addr + 0, bytes[0])?;
+        self.store_byte(addr, bytes[0])?;
         self.store_byte(addr + 1, bytes[1])?;
         self.store_byte(addr + 2, bytes[2])?;
         self.store_byte(addr + 3, bytes[3])?;
         Ok(())
     }
 
+    /// Reads a byte without the side effects a load by the program has, like taking a character
+    /// out of a FIFO. Debuggers and frontends read memory with this, for most devices it is the
+    /// same as a load.
+    fn peek_byte(&self, addr: u32) -> Result<u8, T> {
+        self.load_byte(addr)
+    }
+
+    /// Reads a word like [MemoryMapped::peek_byte]
+    fn peek_word(&self, addr: u32) -> Result<u32, T> {
+        Ok(u32::from_le_bytes([
+            self.peek_byte(addr)?,
+            self.peek_byte(addr.wrapping_add(1))?,
+            self.peek_byte(addr.wrapping_add(2))?,
+            self.peek_byte(addr.wrapping_add(3))?,
+        ]))
+    }
+
     fn store_at<K: Into<u8>, R: IntoIterator<Item = K>>(
         &mut self,
         offset: u32,
@@ -82,6 +99,14 @@ where
         self.borrow().load_word(addr)
     }
 
+    fn peek_byte(&self, addr: u32) -> Result<u8, T> {
+        self.borrow().peek_byte(addr)
+    }
+
+    fn peek_word(&self, addr: u32) -> Result<u32, T> {
+        self.borrow().peek_word(addr)
+    }
+
     fn store_halfword(&mut self, addr: u32, halfword: u16) -> Result<(), T> {
         self.borrow_mut().store_halfword(addr, halfword)
     }
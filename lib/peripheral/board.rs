@@ -0,0 +1,53 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    Bus, Button, HexDisplay, LEDStrip, SDRam, Switch, Timer, BUTTON_HIGHER_ADDR, BUTTON_LOWER_ADDR,
+    HEX_DISPLAY_HIGHER_ADDR, HEX_DISPLAY_LOWER_ADDR, LED_STRIP_HIGHER_ADDR, LED_STRIP_LOWER_ADDR,
+    SDRAM_HIGHER_ADDR, SDRAM_LOWER_ADDR, SWITCH_HIGHER_ADDR, SWITCH_LOWER_ADDR, TIMER_HIGHER_ADDR,
+    TIMER_LOWER_ADDR, UART, UART_HIGHER_ADDR, UART_LOWER_ADDR,
+};
+
+/// The standard set of DTEK-V peripherals, shared with the bus so a frontend can still reach them
+/// after they are attached
+#[derive(Debug, Clone)]
+pub struct Board {
+    pub sdram: Rc<RefCell<SDRam>>,
+    pub button: Rc<RefCell<Button>>,
+    pub hex_display: Rc<RefCell<HexDisplay>>,
+    pub led_strip: Rc<RefCell<LEDStrip>>,
+    pub switch: Rc<RefCell<Switch>>,
+    pub timer: Rc<RefCell<Timer>>,
+    pub uart: Rc<RefCell<UART>>,
+}
+
+macro_rules! attach {
+    ($bus:expr, $device:expr, $range:expr) => {{
+        let device = Rc::new(RefCell::new($device));
+        $bus.attach_device($range, Box::new(device.clone()));
+        device
+    }};
+}
+
+impl Board {
+    /// Attaches the SDRAM, button, hex displays, LEDs, switches, timer and UART to the bus at
+    /// their addresses on the DTEK-V. The timer counts cycles, see [TimerClock](super::TimerClock).
+    pub fn attach(bus: &mut Bus) -> Board {
+        Board {
+            sdram: attach!(bus, SDRam::new(), (SDRAM_LOWER_ADDR, SDRAM_HIGHER_ADDR)),
+            button: attach!(bus, Button::new(), (BUTTON_LOWER_ADDR, BUTTON_HIGHER_ADDR)),
+            hex_display: attach!(
+                bus,
+                HexDisplay::new(),
+                (HEX_DISPLAY_LOWER_ADDR, HEX_DISPLAY_HIGHER_ADDR)
+            ),
+            led_strip: attach!(
+                bus,
+                LEDStrip::new(),
+                (LED_STRIP_LOWER_ADDR, LED_STRIP_HIGHER_ADDR)
+            ),
+            switch: attach!(bus, Switch::new(), (SWITCH_LOWER_ADDR, SWITCH_HIGHER_ADDR)),
+            timer: attach!(bus, Timer::new(), (TIMER_LOWER_ADDR, TIMER_HIGHER_ADDR)),
+            uart: attach!(bus, UART::new(), (UART_LOWER_ADDR, UART_HIGHER_ADDR)),
+        }
+    }
+}
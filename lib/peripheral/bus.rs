@@ -1,14 +1,23 @@
 use std::fmt;
 
-use crate::{interrupt::InterruptSignal, memory_mapped::MemoryMapped, peripheral::Peripheral};
+use crate::{
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    peripheral::Peripheral,
+    scheduler::Event,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
 /// You should probably not use the default bus implementation. It is very general purpose and is
 /// quite slow. You should implement your own bus for your specific needs. This is mostly here for
 /// completeness and for testing purposes.
 pub struct Bus {
-    devices: Vec<((u32, u32), Box<dyn Peripheral<()>>)>,
+    devices: Vec<Device>,
 }
 
+/// A device attached to the bus together with the address range it occupies
+type Device = ((u32, u32), Box<dyn Peripheral<()>>);
+
 impl Default for Bus {
     fn default() -> Self {
         Self::new()
@@ -41,6 +50,86 @@ impl Peripheral<()> for Bus {
 
         None
     }
+
+    fn pending_interrupts(&self) -> u32 {
+        self.devices.iter().fold(0, |pending, (_, device)| {
+            pending | device.pending_interrupts()
+        })
+    }
+
+    fn update_cycle(&mut self, cycle: u64) {
+        for (_, device) in &mut self.devices {
+            device.update_cycle(cycle);
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        for (_, device) in &mut self.devices {
+            device.handle_event(event);
+        }
+    }
+
+    fn set_replaying(&mut self, replaying: bool) {
+        for (_, device) in &mut self.devices {
+            device.set_replaying(replaying);
+        }
+    }
+
+    /// Every device is stored as its own length prefixed block, so a device that reads too little
+    /// or too much can't corrupt the devices after it
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.devices.len() as u32);
+
+        for ((lower, higher), device) in &self.devices {
+            let mut state = SnapshotWriter::new();
+            device.save_state(&mut state);
+
+            snapshot.write_u32(*lower);
+            snapshot.write_u32(*higher);
+            snapshot.write_bytes(&state.into_bytes());
+        }
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        if snapshot.read_u32()? as usize != self.devices.len() {
+            return Err(SnapshotError::DeviceMismatch);
+        }
+
+        for ((lower, higher), device) in &mut self.devices {
+            if snapshot.read_u32()? != *lower || snapshot.read_u32()? != *higher {
+                return Err(SnapshotError::DeviceMismatch);
+            }
+
+            let mut state = SnapshotReader::new(snapshot.read_bytes()?);
+            device.restore_state(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn save_store_state(&self, addr: u32, size: u32, snapshot: &mut SnapshotWriter) {
+        for ((lower, higher), device) in &self.devices {
+            if addr >= *lower && addr <= *higher {
+                device.save_store_state(addr, size, snapshot);
+                return;
+            }
+        }
+    }
+
+    fn restore_store_state(
+        &mut self,
+        addr: u32,
+        size: u32,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        for ((lower, higher), device) in &mut self.devices {
+            if addr >= *lower && addr <= *higher {
+                return device.restore_store_state(addr, size, snapshot);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl MemoryMapped<()> for Bus {
@@ -56,12 +145,24 @@ impl MemoryMapped<()> for Bus {
         Err(())
     }
 
+    fn peek_byte(&self, addr: u32) -> Result<u8, ()> {
+        for ((lower, higher), device) in &self.devices {
+            if addr >= *lower && addr <= *higher {
+                // Debuggers peek wherever they like, so a device error is theirs to report
+                return device.peek_byte(addr);
+            }
+        }
+
+        Err(())
+    }
+
     fn store_byte(&mut self, addr: u32, byte: u8) -> Result<(), ()> {
         for ((lower, higher), device) in &mut self.devices {
             if addr >= *lower && addr <= *higher {
-                return Ok(device.store_byte(addr, byte).unwrap_or_else(|_| {
+                device.store_byte(addr, byte).unwrap_or_else(|_| {
                     panic!("Device failed to store byte at address {:#010x}", addr)
-                }));
+                });
+                return Ok(());
             }
         }
 
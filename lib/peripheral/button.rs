@@ -1,4 +1,9 @@
-use crate::{interrupt::InterruptSignal, memory_mapped::MemoryMapped, utils};
+use crate::{
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    utils,
+};
 
 use super::peripheral::Peripheral;
 
@@ -12,6 +17,12 @@ pub struct Button {
 pub const BUTTON_LOWER_ADDR: u32 = 0x040000d0;
 pub const BUTTON_HIGHER_ADDR: u32 = 0x040000df;
 
+impl Default for Button {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Button {
     /// Returns a new Memory object with a given size all set to 0
     pub fn new() -> Self {
@@ -45,6 +56,19 @@ impl Peripheral<()> for Button {
             None
         }
     }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bool(self.pressed);
+        snapshot.write_u32(self.interrupt_mask);
+        snapshot.write_u32(self.edge_cap);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.pressed = snapshot.read_bool()?;
+        self.interrupt_mask = snapshot.read_u32()?;
+        self.edge_cap = snapshot.read_u32()?;
+        Ok(())
+    }
 }
 
 impl MemoryMapped<()> for Button {
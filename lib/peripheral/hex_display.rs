@@ -1,6 +1,10 @@
 use peripheral::Peripheral;
 
-use crate::{memory_mapped::MemoryMapped, peripheral};
+use crate::{
+    memory_mapped::MemoryMapped,
+    peripheral,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
 #[derive(Clone)]
 pub struct HexDisplay {
@@ -31,7 +35,18 @@ impl Default for HexDisplay {
     }
 }
 
-impl Peripheral<()> for HexDisplay {}
+impl Peripheral<()> for HexDisplay {
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_raw(&self.displays);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let displays = snapshot.read_raw(self.displays.len())?;
+        self.displays.copy_from_slice(displays);
+        Ok(())
+    }
+}
+
 impl MemoryMapped<()> for HexDisplay {
     fn load_byte(&self, _addr: u32) -> Result<u8, ()> {
         // hard wired to 0
@@ -1,4 +1,8 @@
-use crate::{memory_mapped, peripheral, utils};
+use crate::{
+    memory_mapped, peripheral,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    utils,
+};
 
 #[derive(Clone)]
 pub struct LEDStrip {
@@ -25,7 +29,17 @@ impl Default for LEDStrip {
     }
 }
 
-impl peripheral::Peripheral<()> for LEDStrip {}
+impl peripheral::Peripheral<()> for LEDStrip {
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.leds);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.leds = snapshot.read_u32()?;
+        Ok(())
+    }
+}
+
 impl memory_mapped::MemoryMapped<()> for LEDStrip {
     fn load_byte(&self, _addr: u32) -> Result<u8, ()> {
         // hard wired to 0
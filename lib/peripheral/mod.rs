@@ -1,11 +1,15 @@
 //! Peripherals
 
+#[allow(clippy::module_inception)]
 mod peripheral;
 pub use peripheral::Peripheral;
 
 mod bus;
 pub use bus::Bus;
 
+mod board;
+pub use board::Board;
+
 mod button;
 pub use button::*;
 
@@ -27,4 +31,7 @@ pub use timer::*;
 mod uart;
 pub use uart::*;
 
+mod uart_sink;
+pub use uart_sink::*;
+
 pub mod vga;
@@ -1,5 +1,7 @@
 use crate::interrupt::InterruptSignal;
 use crate::memory_mapped::MemoryMapped;
+use crate::scheduler::Event;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -9,7 +11,100 @@ pub trait Peripheral<T>: MemoryMapped<T> {
     fn poll_interrupt(&self) -> Option<InterruptSignal> {
         None
     }
+
+    /// Every interrupt line this peripheral is currently holding high, as mip bits. Peripherals
+    /// that can raise more than one interrupt at once, like the bus, should override this.
+    fn pending_interrupts(&self) -> u32 {
+        self.poll_interrupt()
+            .filter(|interrupt| interrupt.external())
+            .map_or(0, |interrupt| interrupt.mip_bit())
+    }
+
+    /// Tells the peripheral how many cycles the Cpu has executed, before every step. Peripherals
+    /// that keep time in cycles, like the UART for its timestamps, should override this.
+    fn update_cycle(&mut self, _cycle: u64) {}
+
+    /// Handles an event that is due, see the [scheduler](crate::scheduler) module. Every event is
+    /// given to every peripheral, so peripherals that schedule events should check that it has
+    /// their [DeviceId](crate::scheduler::DeviceId) and ignore it otherwise.
+    fn handle_event(&mut self, _event: Event) {}
+
+    /// Set while the Cpu executes steps again to step back through a checkpoint, see
+    /// [Cpu::step_back](crate::cpu::Cpu::step_back). The steps have already happened once, so
+    /// peripherals that pass anything on to the host, like the UART to its sink, shouldn't do it
+    /// again.
+    fn set_replaying(&mut self, _replaying: bool) {}
+
+    /// Writes the internal state of the peripheral to a snapshot, see the
+    /// [snapshot](crate::snapshot) module. Peripherals without any state can skip this.
+    fn save_state(&self, _snapshot: &mut SnapshotWriter) {}
+
+    /// Reads back the state written by [Peripheral::save_state]
+    fn restore_state(&mut self, _snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+
+    /// Saves the state a store of `size` bytes at `addr` is about to change, so that the Cpu can
+    /// undo the store later. Saves the whole state by default, peripherals with a lot of state,
+    /// like memories, should only save what is at `addr`.
+    fn save_store_state(&self, _addr: u32, _size: u32, snapshot: &mut SnapshotWriter) {
+        self.save_state(snapshot)
+    }
+
+    /// Undoes a store by reading back the state written by [Peripheral::save_store_state]
+    fn restore_store_state(
+        &mut self,
+        _addr: u32,
+        _size: u32,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        self.restore_state(snapshot)
+    }
 }
 
 /// Default implementation since it is a common use case
-impl<K, T> Peripheral<T> for Rc<RefCell<K>> where K: Peripheral<T> {}
+impl<K, T> Peripheral<T> for Rc<RefCell<K>>
+where
+    K: Peripheral<T>,
+{
+    fn poll_interrupt(&self) -> Option<InterruptSignal> {
+        self.borrow().poll_interrupt()
+    }
+
+    fn pending_interrupts(&self) -> u32 {
+        self.borrow().pending_interrupts()
+    }
+
+    fn update_cycle(&mut self, cycle: u64) {
+        self.borrow_mut().update_cycle(cycle)
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        self.borrow_mut().handle_event(event)
+    }
+
+    fn set_replaying(&mut self, replaying: bool) {
+        self.borrow_mut().set_replaying(replaying)
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        self.borrow().save_state(snapshot)
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.borrow_mut().restore_state(snapshot)
+    }
+
+    fn save_store_state(&self, addr: u32, size: u32, snapshot: &mut SnapshotWriter) {
+        self.borrow().save_store_state(addr, size, snapshot)
+    }
+
+    fn restore_store_state(
+        &mut self,
+        addr: u32,
+        size: u32,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        self.borrow_mut().restore_store_state(addr, size, snapshot)
+    }
+}
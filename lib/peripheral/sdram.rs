@@ -1,9 +1,16 @@
-use crate::{memory_mapped::MemoryMapped, peripheral};
+use crate::{
+    memory_mapped::MemoryMapped,
+    peripheral,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
 pub const SDRAM_SIZE: usize = 0x4000000;
 pub const SDRAM_LOWER_ADDR: u32 = 0;
 pub const SDRAM_HIGHER_ADDR: u32 = SDRAM_LOWER_ADDR + SDRAM_SIZE as u32 - 1;
 
+/// Snapshots only store the pages of the SDRAM that aren't all zeros
+const SNAPSHOT_PAGE_SIZE: usize = 4096;
+
 #[cfg(target_endian = "big")]
 pub use be::*;
 #[cfg(target_endian = "little")]
@@ -31,7 +38,69 @@ mod le {
         }
     }
 
-    impl peripheral::Peripheral<()> for SDRam {}
+    impl peripheral::Peripheral<()> for SDRam {
+        fn save_state(&self, snapshot: &mut SnapshotWriter) {
+            const PAGE_WORDS: usize = SNAPSHOT_PAGE_SIZE / 4;
+
+            let pages: Vec<usize> = self
+                .mem
+                .chunks(PAGE_WORDS)
+                .enumerate()
+                .filter(|(_, page)| page.iter().any(|word| *word != 0))
+                .map(|(i, _)| i)
+                .collect();
+
+            snapshot.write_u32(pages.len() as u32);
+            for i in pages {
+                snapshot.write_u32(i as u32);
+                for word in &self.mem[i * PAGE_WORDS..(i + 1) * PAGE_WORDS] {
+                    snapshot.write_u32(*word);
+                }
+            }
+        }
+
+        fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+            const PAGE_WORDS: usize = SNAPSHOT_PAGE_SIZE / 4;
+
+            self.mem.fill(0);
+
+            let pages = snapshot.read_u32()?;
+            for _ in 0..pages {
+                let i = snapshot.read_u32()? as usize;
+                let page = self
+                    .mem
+                    .get_mut(i * PAGE_WORDS..(i + 1) * PAGE_WORDS)
+                    .ok_or(SnapshotError::Invalid)?;
+
+                let bytes = snapshot.read_raw(SNAPSHOT_PAGE_SIZE)?;
+                for (word, bytes) in page.iter_mut().zip(bytes.chunks_exact(4)) {
+                    *word = u32::from_le_bytes(bytes.try_into().unwrap());
+                }
+            }
+
+            Ok(())
+        }
+
+        fn save_store_state(&self, addr: u32, size: u32, snapshot: &mut SnapshotWriter) {
+            for i in 0..size {
+                snapshot.write_u8(self.load_byte(addr.wrapping_add(i)).unwrap_or(0));
+            }
+        }
+
+        fn restore_store_state(
+            &mut self,
+            addr: u32,
+            size: u32,
+            snapshot: &mut SnapshotReader,
+        ) -> Result<(), SnapshotError> {
+            for i in 0..size {
+                let byte = snapshot.read_u8()?;
+                // Bytes outside the SDRAM were never stored
+                let _ = self.store_byte(addr.wrapping_add(i), byte);
+            }
+            Ok(())
+        }
+    }
 
     impl MemoryMapped<()> for SDRam {
         // A lot of unsafe code here, here's an explanation:
@@ -126,6 +195,7 @@ mod le {
     }
 }
 
+#[cfg_attr(target_endian = "little", allow(dead_code))]
 mod be {
     use super::*;
 
@@ -148,7 +218,59 @@ mod be {
         }
     }
 
-    impl peripheral::Peripheral<()> for SDRam {}
+    impl peripheral::Peripheral<()> for SDRam {
+        fn save_state(&self, snapshot: &mut SnapshotWriter) {
+            let pages: Vec<usize> = self
+                .mem
+                .chunks(SNAPSHOT_PAGE_SIZE)
+                .enumerate()
+                .filter(|(_, page)| page.iter().any(|byte| *byte != 0))
+                .map(|(i, _)| i)
+                .collect();
+
+            snapshot.write_u32(pages.len() as u32);
+            for i in pages {
+                snapshot.write_u32(i as u32);
+                snapshot.write_raw(&self.mem[i * SNAPSHOT_PAGE_SIZE..(i + 1) * SNAPSHOT_PAGE_SIZE]);
+            }
+        }
+
+        fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+            self.mem.fill(0);
+
+            let pages = snapshot.read_u32()?;
+            for _ in 0..pages {
+                let i = snapshot.read_u32()? as usize;
+                let page = self
+                    .mem
+                    .get_mut(i * SNAPSHOT_PAGE_SIZE..(i + 1) * SNAPSHOT_PAGE_SIZE)
+                    .ok_or(SnapshotError::Invalid)?;
+                page.copy_from_slice(snapshot.read_raw(SNAPSHOT_PAGE_SIZE)?);
+            }
+
+            Ok(())
+        }
+
+        fn save_store_state(&self, addr: u32, size: u32, snapshot: &mut SnapshotWriter) {
+            for i in 0..size {
+                snapshot.write_u8(self.load_byte(addr.wrapping_add(i)).unwrap_or(0));
+            }
+        }
+
+        fn restore_store_state(
+            &mut self,
+            addr: u32,
+            size: u32,
+            snapshot: &mut SnapshotReader,
+        ) -> Result<(), SnapshotError> {
+            for i in 0..size {
+                let byte = snapshot.read_u8()?;
+                // Bytes outside the SDRAM were never stored
+                let _ = self.store_byte(addr.wrapping_add(i), byte);
+            }
+            Ok(())
+        }
+    }
 
     impl MemoryMapped<()> for SDRam {
         fn load_byte(&self, addr: u32) -> Result<u8, ()> {
@@ -219,7 +341,7 @@ mod tests {
         assert_eq!(sdram.load_halfword(0), Ok(0x3AD0));
         assert_eq!(sdram.load_halfword(1), Ok(0x4B3A));
         assert_eq!(sdram.load_halfword(2), Ok(0x7A4B));
-        assert_eq!(sdram.load_halfword(3), Ok(0x7A7a));
+        assert_eq!(sdram.load_halfword(3), Ok(0x7A7A));
     }
 
     #[test]
@@ -291,7 +413,7 @@ mod tests {
         assert_eq!(sdram.load_halfword(0), Ok(0x3AD0));
         assert_eq!(sdram.load_halfword(1), Ok(0x4B3A));
         assert_eq!(sdram.load_halfword(2), Ok(0x7A4B));
-        assert_eq!(sdram.load_halfword(3), Ok(0x7A7a));
+        assert_eq!(sdram.load_halfword(3), Ok(0x7A7A));
     }
 
     #[test]
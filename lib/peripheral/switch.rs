@@ -1,4 +1,9 @@
-use crate::{interrupt::InterruptSignal, memory_mapped::MemoryMapped, utils};
+use crate::{
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    utils,
+};
 
 use super::Peripheral;
 
@@ -12,6 +17,12 @@ pub struct Switch {
 pub const SWITCH_LOWER_ADDR: u32 = 0x04000010;
 pub const SWITCH_HIGHER_ADDR: u32 = 0x400001f;
 
+impl Default for Switch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Switch {
     /// Returns a new Memory object with a given size all set to 0
     pub fn new() -> Self {
@@ -47,6 +58,19 @@ impl Peripheral<()> for Switch {
             None
         }
     }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.state);
+        snapshot.write_u32(self.interrupt_mask);
+        snapshot.write_u32(self.edge_cap);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.state = snapshot.read_u32()?;
+        self.interrupt_mask = snapshot.read_u32()?;
+        self.edge_cap = snapshot.read_u32()?;
+        Ok(())
+    }
 }
 
 impl MemoryMapped<()> for Switch {
@@ -1,4 +1,9 @@
-use crate::{interrupt::InterruptSignal, memory_mapped::MemoryMapped, utils};
+use crate::{
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    utils,
+};
 
 use super::Peripheral;
 
@@ -6,15 +11,66 @@ pub const TIMER_LOWER_ADDR: u32 = 0x4000020;
 pub const TIMER_HIGHER_ADDR: u32 = 0x400003f;
 pub const TIMER_FEQ: u32 = 30_000_000;
 
+// Status register
+const TO: u8 = 1 << 0;
+const RUN: u8 = 1 << 1;
+
+// Control register
+const ITO: u8 = 1 << 0;
+const CONT: u8 = 1 << 1;
+const START: u8 = 1 << 2;
+const STOP: u8 = 1 << 3;
+
+/// What the timer counts
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum TimerClock {
+    /// One tick per Cpu cycle, the timer and the Cpu share a clock on the DTEK-V. The Cpu advances
+    /// the timer before every step, so timeouts happen on the same instruction every run.
+    #[default]
+    Cycles,
+    /// [TIMER_FEQ] ticks per second of host time, advanced by the frontend through
+    /// [Timer::update_clock]. For interactive frontends where the program should keep up with
+    /// the real world rather than the emulated Cpu.
+    WallClock,
+}
+
+/// The counter and status at a cycle, the state at any later cycle follows from them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Mark {
+    cycle: u64,
+    counter: u32,
+    running: bool,
+    time_out: bool,
+}
+
+/// The Intel interval timer, 32 bits wide.
+///
+/// The registers are a word apart: status (TO and RUN), control (ITO, CONT, START and STOP), the
+/// low and high 16 bits of the period and the low and high 16 bits of the counter snapshot. The
+/// counter counts down from the period and times out when it passes 0, so a period of `n` times
+/// out every `n + 1` ticks. A timeout sets TO and reloads the counter, in continuous mode the
+/// timer keeps running and otherwise it stops.
+///
+/// Writing the period stops the timer and reloads the counter with the new period. Writing either
+/// snapshot register copies the counter into them. If START and STOP are written together the
+/// timer stops.
 #[derive(Clone)]
 pub struct Timer {
+    source: TimerClock,
     period: u32,
+    counter: u32,
+    snapshot: u32,
     running: bool,
     time_out: bool,
     cont: bool,
     irq: bool,
+    /// The last time from [Timer::update_clock], in milliseconds
     clock: u32,
-    timer: u32,
+    /// The last cycle count from [Peripheral::update_cycle]
+    cycle: u64,
+    /// The state after the last write to a register, which the counter is worked out from again
+    /// when the cycle count goes backwards
+    mark: Mark,
 }
 
 impl Default for Timer {
@@ -24,42 +80,129 @@ impl Default for Timer {
 }
 
 impl Timer {
-    /// Returns a new Memory object with a given size all set to 0
+    /// Returns a stopped timer with a period of 0, counting cycles, see [Timer::with_clock]
     pub fn new() -> Self {
         Timer {
+            source: TimerClock::Cycles,
             period: 0,
+            counter: 0,
+            snapshot: 0,
             running: false,
-            time_out: true,
+            time_out: false,
             cont: false,
             irq: false,
             clock: 0,
-            timer: 0,
+            cycle: 0,
+            mark: Mark {
+                cycle: 0,
+                counter: 0,
+                running: false,
+                time_out: false,
+            },
         }
     }
 
+    /// Sets what the timer counts, [TimerClock::Cycles] by default
+    pub fn with_clock(mut self, source: TimerClock) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn clock_source(&self) -> TimerClock {
+        self.source
+    }
+
     /// Set the clock to a new value.
-    /// 0 is the initial value, 1000 is 1 second
+    /// 0 is the initial value, 1000 is 1 second. Only counts with [TimerClock::WallClock].
+    ///
+    /// A timer from [Timer::new] counts cycles and ignores this, frontends that keep time for the
+    /// timer have to create it with `Timer::new().with_clock(TimerClock::WallClock)`.
     pub fn update_clock(&mut self, clock: u32) {
         let last_clock = self.clock;
-        // THis can potentially overflow, but come on you'd have to run this emulator for like 3
-        // years for that to happen.
         self.clock = clock;
+        if self.source == TimerClock::WallClock {
+            self.tick((TIMER_FEQ / 1000) as u64 * clock.wrapping_sub(last_clock) as u64);
+        }
+    }
+
+    /// If a timeout can still raise an interrupt, which needs the timer running with ITO set
+    pub fn can_interrupt(&self) -> bool {
+        self.running && self.irq
+    }
+
+    /// Counts the timer down by `ticks` of its clock
+    fn tick(&mut self, mut ticks: u64) {
         if !self.running {
             return;
         }
-        self.timer += (TIMER_FEQ / 1000) * (self.clock - last_clock);
-        if self.timer >= self.period {
-            self.timer -= self.period;
-            self.time_out = true;
+
+        let until_time_out = self.counter as u64 + 1;
+        if ticks < until_time_out {
+            self.counter -= ticks as u32;
+            return;
+        }
+
+        ticks -= until_time_out;
+        self.time_out = true;
+        self.counter = self.period;
+        if !self.cont {
+            self.running = false;
+            return;
         }
+
+        // Any timeouts after the first only set TO again
+        self.counter -= (ticks % (self.period as u64 + 1)) as u32;
+    }
+
+    /// Writing a byte of the period stops the timer and reloads the counter
+    fn set_period(&mut self, byte: u8, index: u32) {
+        self.period = utils::set_in_u32(self.period, byte, index);
+        self.counter = self.period;
+        self.running = false;
     }
 
     fn should_interrupt(&self) -> bool {
         self.time_out && self.irq
     }
+
+    fn set_mark(&mut self) {
+        self.mark = Mark {
+            cycle: self.cycle,
+            counter: self.counter,
+            running: self.running,
+            time_out: self.time_out,
+        };
+    }
+
+    /// Moves the counter to `cycle`. Only the last write changes the course of the timer, so
+    /// going backwards means going forward from the mark again.
+    fn count_to(&mut self, cycle: u64) {
+        if cycle >= self.cycle {
+            self.tick(cycle - self.cycle);
+        } else if cycle >= self.mark.cycle {
+            self.counter = self.mark.counter;
+            self.running = self.mark.running;
+            self.time_out = self.mark.time_out;
+            self.tick(cycle - self.mark.cycle);
+        } else {
+            // Earlier than anything the timer knows about, it picks up from here
+            self.mark.cycle = cycle;
+        }
+    }
 }
 
 impl Peripheral<()> for Timer {
+    /// Only counts with [TimerClock::Cycles]. If the cycle count goes backwards, because the Cpu
+    /// stepped back or the program wrote mcycle, the timer goes back with it as far as the last
+    /// write to one of its registers. Before that, like after a reset, it picks up from the new
+    /// count.
+    fn update_cycle(&mut self, cycle: u64) {
+        if self.source == TimerClock::Cycles {
+            self.count_to(cycle);
+        }
+        self.cycle = cycle;
+    }
+
     fn poll_interrupt(&self) -> Option<InterruptSignal> {
         if self.should_interrupt() {
             Some(InterruptSignal::TIMER_INTERRUPT)
@@ -67,32 +210,79 @@ impl Peripheral<()> for Timer {
             None
         }
     }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.period);
+        snapshot.write_u32(self.counter);
+        snapshot.write_u32(self.snapshot);
+        snapshot.write_bool(self.running);
+        snapshot.write_bool(self.time_out);
+        snapshot.write_bool(self.cont);
+        snapshot.write_bool(self.irq);
+        snapshot.write_u32(self.clock);
+        snapshot.write_u64(self.cycle);
+        snapshot.write_u64(self.mark.cycle);
+        snapshot.write_u32(self.mark.counter);
+        snapshot.write_bool(self.mark.running);
+        snapshot.write_bool(self.mark.time_out);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.period = snapshot.read_u32()?;
+        self.counter = snapshot.read_u32()?;
+        self.snapshot = snapshot.read_u32()?;
+        self.running = snapshot.read_bool()?;
+        self.time_out = snapshot.read_bool()?;
+        self.cont = snapshot.read_bool()?;
+        self.irq = snapshot.read_bool()?;
+        self.clock = snapshot.read_u32()?;
+        self.cycle = snapshot.read_u64()?;
+        self.mark = Mark {
+            cycle: snapshot.read_u64()?,
+            counter: snapshot.read_u32()?,
+            running: snapshot.read_bool()?,
+            time_out: snapshot.read_bool()?,
+        };
+        Ok(())
+    }
 }
 
 impl MemoryMapped<()> for Timer {
     fn load_byte(&self, addr: u32) -> Result<u8, ()> {
         let addr = addr - TIMER_LOWER_ADDR;
         let part = addr / 4;
+        let i = addr % 4;
+
+        // Every register is 16 bits wide
+        if i >= 2 {
+            return Ok(0);
+        }
 
         Ok(match part {
-            0 => utils::get_in_u32(
-                match (self.running, self.time_out) {
-                    (true, true) => 3,
-                    (true, false) => 2,
-                    (false, true) => 1,
-                    (false, false) => 0,
-                },
-                addr,
-            ),
-            1 => utils::get_in_u32(
-                match (self.cont, self.irq) {
-                    (true, true) => 3,
-                    (true, false) => 2,
-                    (false, true) => 1,
-                    (false, false) => 0,
-                },
-                addr,
-            ),
+            0 => {
+                let mut status = 0;
+                if self.time_out {
+                    status |= TO;
+                }
+                if self.running {
+                    status |= RUN;
+                }
+                utils::get_in_u32(status as u32, i)
+            }
+            1 => {
+                let mut control = 0;
+                if self.irq {
+                    control |= ITO;
+                }
+                if self.cont {
+                    control |= CONT;
+                }
+                utils::get_in_u32(control as u32, i)
+            }
+            2 => utils::get_in_u32(self.period, i),
+            3 => utils::get_in_u32(self.period, i + 2),
+            4 => utils::get_in_u32(self.snapshot, i),
+            5 => utils::get_in_u32(self.snapshot, i + 2),
             _ => 0,
         })
     }
@@ -102,45 +292,32 @@ impl MemoryMapped<()> for Timer {
         let part = addr / 4;
         let i = addr % 4;
 
-        match part {
-            0 => {
-                if i == 0 {
-                    self.time_out = byte & 1 == 1;
-                }
-            } // Data address, can store here
-            1 => {
-                if i == 0 {
-                    self.irq = byte & 1 != 0;
-                    self.cont = byte & 2 != 0;
-                    let start = byte & 4 != 0;
-                    let stop = byte & 8 != 0;
-
-                    if start && stop {
-                        unimplemented!("Sending start and stop signal is not supported");
-                    }
-
-                    if start {
-                        self.running = true;
-                    } else if stop {
-                        self.running = false;
-                    }
-                }
-            } // Direction address, can store here, but changes nothing
-            2 => {
-                // Lower 16 bits of the state
-                if i == 0 || i == 1 {
-                    self.period = utils::set_in_u32(self.period, byte, i);
-                }
-            }
-            3 => {
-                // Lower 16 bits of the state
-                if i == 0 || i == 1 {
-                    self.period = utils::set_in_u32(self.period, byte, i + 2);
+        // Every register is 16 bits wide
+        if i >= 2 {
+            return Ok(());
+        }
+
+        match (part, i) {
+            // RUN is read only and TO can only be cleared
+            (0, 0) if byte & TO == 0 => self.time_out = false,
+            (1, 0) => {
+                self.irq = byte & ITO != 0;
+                self.cont = byte & CONT != 0;
+
+                if byte & STOP != 0 {
+                    self.running = false;
+                } else if byte & START != 0 {
+                    self.running = true;
                 }
             }
-            _ => unreachable!("The timer address space is only 4 words long, if this error happens, update the bus module"),
+            (2, _) => self.set_period(byte, i),
+            (3, _) => self.set_period(byte, i + 2),
+            (4 | 5, _) => self.snapshot = self.counter,
+            // The rest of the address space is unused
+            _ => {}
         };
 
+        self.set_mark();
         Ok(())
     }
 }
@@ -160,6 +337,26 @@ mod tests {
     use super::*;
     use test_case::test_case;
 
+    const STATUS: u32 = TIMER_LOWER_ADDR;
+    const CONTROL: u32 = TIMER_LOWER_ADDR + 4;
+    const PERIODL: u32 = TIMER_LOWER_ADDR + 8;
+    const PERIODH: u32 = TIMER_LOWER_ADDR + 12;
+    const SNAPL: u32 = TIMER_LOWER_ADDR + 16;
+    const SNAPH: u32 = TIMER_LOWER_ADDR + 20;
+
+    fn timer_with_period(period: u32) -> Timer {
+        let mut timer = Timer::new().with_clock(TimerClock::WallClock);
+        timer.store_word(PERIODL, period & 0xFFFF).unwrap();
+        timer.store_word(PERIODH, period >> 16).unwrap();
+        timer
+    }
+
+    /// Reads the counter through the snapshot registers
+    fn snapshot(timer: &mut Timer) -> u32 {
+        timer.store_word(SNAPL, 0).unwrap();
+        timer.load_word(SNAPL).unwrap() | timer.load_word(SNAPH).unwrap() << 16
+    }
+
     #[test_case(vec![(0x3333, 0x2 << 2), (0x0, 0x3 << 2)] => 0x3333; "store only lower bits")]
     #[test_case(vec![(0x0, 0x2 << 2), (0xAAAA, 0x3 << 2)] => 0xAAAA0000; "store only higher bits")]
     #[test_case(vec![(0x33330000, 0x2 << 2), (0xAAAA0000, 0x3 << 2)] => 0x0; "upper bits ignored")]
@@ -174,13 +371,13 @@ mod tests {
     }
 
     #[test]
-    pub fn test_set_time_out() {
+    pub fn test_clear_time_out() {
         let mut timer = Timer::new();
-        timer.time_out = false;
-        // Set TIME OUT bit
-        timer.store_byte(TIMER_LOWER_ADDR, 0b1).unwrap();
+        timer.time_out = true;
+        // Writing 1 to TO doesn't change it, only clearing it does
+        timer.store_byte(STATUS, 0b1).unwrap();
         assert!(timer.time_out);
-        timer.store_byte(TIMER_LOWER_ADDR, 0b0).unwrap();
+        timer.store_byte(STATUS, 0b0).unwrap();
         assert!(!timer.time_out);
     }
 
@@ -188,63 +385,158 @@ mod tests {
     pub fn test_set_irq() {
         let mut timer = Timer::new();
         // Set IRQ bit
-        timer.store_byte(TIMER_LOWER_ADDR + 4, 0b1).unwrap();
+        timer.store_byte(CONTROL, 0b1).unwrap();
         assert!(timer.irq);
-        timer.store_byte(TIMER_LOWER_ADDR + 4, 0b0).unwrap();
+        timer.store_byte(CONTROL, 0b0).unwrap();
         assert!(!timer.irq);
     }
 
     #[test]
     pub fn test_set_start_stop() {
-        let mut timer = Timer::new();
-        // Set START bit
-        timer.store_byte(TIMER_LOWER_ADDR + 4, 0b1).unwrap();
-        assert!(timer.irq);
-        // Set STOP bit
-        timer.store_byte(TIMER_LOWER_ADDR + 4, 0b0).unwrap();
-        assert!(!timer.irq);
+        let mut timer = timer_with_period(100);
+        // START shows up as RUN in the status register, it reads as 0 itself
+        timer.store_byte(CONTROL, START).unwrap();
+        assert_eq!(timer.load_word(STATUS), Ok(RUN as u32));
+        assert_eq!(timer.load_word(CONTROL), Ok(0));
+
+        timer.store_byte(CONTROL, STOP).unwrap();
+        assert_eq!(timer.load_word(STATUS), Ok(0));
+
+        // Both at once stops the timer
+        timer.store_byte(CONTROL, START).unwrap();
+        timer.store_byte(CONTROL, START | STOP).unwrap();
+        assert!(!timer.running);
     }
 
     #[test]
     pub fn test_update_clock_correctly() {
-        let mut timer = Timer::new();
-        // Set a high period to make sure not to trigger overflow
-        timer.period = 60_000_000;
+        let mut timer = timer_with_period(60_000_000);
         timer.update_clock(1);
-        timer.running = true;
+        assert_eq!(snapshot(&mut timer), 60_000_000);
+
+        timer.store_byte(CONTROL, START).unwrap();
         timer.update_clock(2);
-        assert_eq!(timer.timer, TIMER_FEQ / 1000);
-        timer.timer = 0;
+        assert_eq!(snapshot(&mut timer), 60_000_000 - TIMER_FEQ / 1000);
         timer.update_clock(1002);
-        assert_eq!(timer.timer, TIMER_FEQ);
+        assert_eq!(
+            snapshot(&mut timer),
+            60_000_000 - TIMER_FEQ - TIMER_FEQ / 1000
+        );
     }
 
     #[test]
-    pub fn test_update_clock_overflow() {
-        let mut timer = Timer::new();
-        // Set a high period to make sure not to trigger overflow
-        timer.period = 300_000; // 100 times a second
-        timer.running = true;
-        timer.time_out = false;
+    pub fn test_continuous() {
+        // 100 times a second
+        let mut timer = timer_with_period(300_000 - 1);
+        timer.store_byte(CONTROL, CONT | START).unwrap();
+
         timer.update_clock(1);
-        assert_eq!(timer.timer, 30_000);
+        assert_eq!(snapshot(&mut timer), 300_000 - 1 - 30_000);
         assert!(!timer.time_out);
 
         timer.update_clock(11);
-        assert_eq!(timer.timer, 30_000);
+        assert_eq!(snapshot(&mut timer), 300_000 - 1 - 30_000);
+        assert!(timer.time_out);
+        assert!(timer.running);
+
+        timer.store_byte(STATUS, 0).unwrap();
+        timer.update_clock(100);
         assert!(timer.time_out);
     }
 
+    #[test]
+    pub fn test_one_shot() {
+        let mut timer = timer_with_period(300_000 - 1);
+        timer.store_byte(CONTROL, START).unwrap();
+
+        timer.update_clock(15);
+        assert!(timer.time_out);
+        // Stops with the counter reloaded
+        assert_eq!(timer.load_word(STATUS), Ok(TO as u32));
+        assert_eq!(snapshot(&mut timer), 300_000 - 1);
+
+        timer.store_byte(STATUS, 0).unwrap();
+        timer.update_clock(30);
+        assert!(!timer.time_out);
+    }
+
+    #[test]
+    pub fn test_period_write_reloads() {
+        let mut timer = timer_with_period(60_000_000);
+        timer.store_byte(CONTROL, START).unwrap();
+        timer.update_clock(1);
+        assert!(snapshot(&mut timer) < 60_000_000);
+
+        timer.store_word(PERIODH, 0).unwrap();
+        assert!(!timer.running);
+        timer.store_word(PERIODL, 1000).unwrap();
+        assert_eq!(snapshot(&mut timer), 1000);
+    }
+
+    #[test]
+    pub fn test_snapshot_registers() {
+        let mut timer = timer_with_period(0x12345678);
+        timer.store_byte(CONTROL, START).unwrap();
+        timer.update_clock(1);
+
+        // The snapshot stays until the next write
+        timer.store_word(SNAPH, 0).unwrap();
+        timer.update_clock(2);
+        assert_eq!(timer.load_word(SNAPL), Ok((0x12345678 - 30_000) & 0xFFFF));
+        assert_eq!(timer.load_word(SNAPH), Ok((0x12345678 - 30_000) >> 16));
+
+        // The unused words are ignored
+        timer.store_word(TIMER_LOWER_ADDR + 0x18, 1).unwrap();
+        timer.store_word(TIMER_LOWER_ADDR + 0x1C, 1).unwrap();
+        assert_eq!(timer.load_word(TIMER_LOWER_ADDR + 0x1C), Ok(0));
+    }
+
     #[test]
     pub fn test_interrupt() {
-        let mut timer = Timer::new();
-        timer.period = 300_000; // 100 times a second
-        timer.running = true;
-        timer.time_out = false;
-        timer.irq = true;
+        let mut timer = timer_with_period(300_000); // 100 times a second
+        timer.store_byte(CONTROL, ITO | CONT | START).unwrap();
 
         timer.update_clock(10);
+        assert!(timer.poll_interrupt().is_none());
+        timer.update_clock(11);
+        assert!(timer.poll_interrupt().is_some());
+    }
+
+    #[test]
+    pub fn test_cycles() {
+        let mut timer = Timer::new();
+        timer.store_word(PERIODL, 9).unwrap();
+        timer.store_byte(CONTROL, ITO | CONT | START).unwrap();
+
+        timer.update_cycle(5);
+        assert_eq!(snapshot(&mut timer), 4);
+        // The wall clock is ignored
+        timer.update_clock(1000);
+        assert_eq!(snapshot(&mut timer), 4);
 
+        timer.update_cycle(9);
+        assert!(timer.poll_interrupt().is_none());
+        timer.update_cycle(10);
         assert!(timer.poll_interrupt().is_some());
+        assert_eq!(snapshot(&mut timer), 9);
+    }
+
+    #[test]
+    pub fn test_cycles_going_back() {
+        let mut timer = Timer::new();
+        timer.store_word(PERIODL, 100).unwrap();
+        timer.store_byte(CONTROL, START).unwrap();
+
+        timer.update_cycle(50);
+        timer.update_cycle(10);
+        assert_eq!(snapshot(&mut timer), 90);
+        timer.update_cycle(20);
+        assert_eq!(snapshot(&mut timer), 80);
+
+        // Nothing to go back to before the last write, like after a reset
+        timer.update_cycle(5);
+        assert_eq!(snapshot(&mut timer), 80);
+        timer.update_cycle(15);
+        assert_eq!(snapshot(&mut timer), 70);
     }
 }
@@ -1,34 +1,334 @@
-use std::collections::LinkedList;
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
-use crate::memory_mapped::MemoryMapped;
+use crate::{
+    interrupt::InterruptSignal,
+    memory_mapped::MemoryMapped,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
-use super::Peripheral;
+use super::{Peripheral, UartByte, UartSink};
 
 pub const UART_LOWER_ADDR: u32 = 0x04000040;
 pub const UART_HIGHER_ADDR: u32 = 0x04000047;
 
+/// The depth of both FIFOs unless set with [UART::with_read_fifo_depth] or
+/// [UART::with_write_fifo_depth], the default of the Intel JTAG UART
+pub const UART_FIFO_DEPTH: usize = 64;
+
+/// A write interrupt is pending while fewer characters than this are waiting in the write FIFO
+const WRITE_IRQ_THRESHOLD: usize = 8;
+
+const CONTROL_OFFSET: u32 = 4;
+
+// Data register
+const RVALID: u8 = 1 << 7;
+
+// Control register, the bits in the first two bytes
+const RE: u8 = 1 << 0;
+const WE: u8 = 1 << 1;
+const RI: u8 = 1 << 0;
+const WI: u8 = 1 << 1;
+const AC: u8 = 1 << 2;
+
+/// The Intel JTAG UART, the console of the DTEK-V.
+///
+/// The data register (offset 0) holds a character from the read FIFO in bits 0-7, RVALID in bit 15
+/// and the number of characters left after the read in RAVAIL, bits 16-31. Loading it takes the
+/// character out of the FIFO, peeking at it leaves it there. Writing it puts bits 0-7 in the write
+/// FIFO, or drops them if the FIFO is full. The control register (offset 4) holds the read and
+/// write interrupt enables RE and WE in bits 0 and 1, the pending interrupts RI and WI in bits 8
+/// and 9, AC in bit 10 and the free space in the write FIFO in WSPACE, bits 16-31. AC is set
+/// whenever the host reads or writes a character and cleared by writing 1 to it.
+///
+/// The frontend is the host: it types into the program with [UART::push_input] and reads the
+/// output either by iterating over the UART, which takes the bytes out of the write FIFO, or
+/// through a [UartSink] that gets every byte as soon as it is written.
 #[derive(Clone)]
 pub struct UART {
-    values: LinkedList<char>,
+    /// The write FIFO, bytes the program wrote that the frontend hasn't read yet
+    values: VecDeque<UartByte>,
+    /// Takes the output instead of the write FIFO, see [UART::with_sink]
+    sink: Option<Rc<RefCell<dyn UartSink>>>,
+    /// The cycle count of the Cpu, which the output is stamped with
+    cycle: u64,
+    /// The read FIFO. Loads take it by reference, so it needs to be mutable through one.
+    input: RefCell<VecDeque<u8>>,
+    read_depth: usize,
+    write_depth: usize,
+    read_irq: bool,
+    write_irq: bool,
+    activity: bool,
+    /// Characters that were undone after the frontend had already read them
+    retracted: usize,
+    /// Set while the Cpu replays steps, when the sink already has the output
+    replaying: bool,
 }
 
 impl UART {
     pub fn new() -> Self {
         UART {
-            values: LinkedList::new(),
+            values: VecDeque::new(),
+            sink: None,
+            cycle: 0,
+            input: RefCell::new(VecDeque::new()),
+            read_depth: UART_FIFO_DEPTH,
+            write_depth: UART_FIFO_DEPTH,
+            read_irq: false,
+            write_irq: false,
+            activity: false,
+            retracted: 0,
+            replaying: false,
+        }
+    }
+
+    /// Sets how many characters the read FIFO holds, at least 1
+    pub fn with_read_fifo_depth(mut self, depth: usize) -> Self {
+        self.read_depth = depth.max(1);
+        self
+    }
+
+    /// Sets how many characters the write FIFO holds, at least 1
+    pub fn with_write_fifo_depth(mut self, depth: usize) -> Self {
+        self.write_depth = depth.max(1);
+        self
+    }
+
+    /// Sends the output to `sink` instead of keeping it in the write FIFO for the frontend to
+    /// read, see [UartSink]
+    pub fn with_sink(mut self, sink: Rc<RefCell<impl UartSink + 'static>>) -> Self {
+        self.set_sink(sink);
+        self
+    }
+
+    /// Sends the output to `sink` from now on. Anything still in the write FIFO is passed on to it
+    /// first.
+    pub fn set_sink(&mut self, sink: Rc<RefCell<impl UartSink + 'static>>) {
+        for output in self.values.drain(..) {
+            sink.borrow_mut().write(output.byte, output.cycle);
+            self.activity = true;
+        }
+        self.sink = Some(sink);
+    }
+
+    /// Keeps the output in the write FIFO again
+    pub fn remove_sink(&mut self) {
+        self.sink = None;
+    }
+
+    /// Sends a character to the program. Returns false and drops it if the read FIFO is full, the
+    /// frontend should hold on to it and try again later.
+    pub fn push_input(&mut self, byte: u8) -> bool {
+        let input = self.input.get_mut();
+        if input.len() >= self.read_depth {
+            return false;
+        }
+
+        input.push_back(byte);
+        self.activity = true;
+        true
+    }
+
+    /// How many more characters [UART::push_input] accepts right now
+    pub fn input_space(&self) -> usize {
+        self.read_depth - self.input.borrow().len()
+    }
+
+    /// Returns how many of the characters already read from the UART have since been undone by
+    /// [Cpu::step_back](crate::cpu::Cpu::step_back) and resets the count. A frontend should
+    /// remove that many characters from the end of what it shows.
+    pub fn take_retracted(&mut self) -> usize {
+        std::mem::take(&mut self.retracted)
+    }
+
+    fn write_space(&self) -> usize {
+        self.write_depth - self.values.len()
+    }
+
+    /// If a byte written now would make it to the frontend
+    fn accepts_output(&self) -> bool {
+        self.sink.is_some() || self.write_space() > 0
+    }
+
+    fn push(&mut self, byte: u8) {
+        let output = UartByte {
+            byte,
+            cycle: self.cycle,
+        };
+
+        if let Some(sink) = &self.sink {
+            if !self.replaying {
+                sink.borrow_mut().write(output.byte, output.cycle);
+            }
+            self.activity = true;
+        } else if self.write_space() > 0 {
+            self.values.push_back(output);
         }
     }
 
-    fn push(&mut self, value: char) {
-        self.values.push_back(value);
+    fn pop(&mut self) -> Option<u8> {
+        let output = self.values.pop_front()?;
+        self.activity = true;
+        Some(output.byte)
+    }
+
+    fn read_pending(&self) -> bool {
+        // The emulated host never holds characters back, so a read interrupt doesn't wait for the
+        // FIFO to fill up
+        self.read_irq && !self.input.borrow().is_empty()
     }
 
-    fn pop(&mut self) -> Option<char> {
-        self.values.pop_front()
+    fn write_pending(&self) -> bool {
+        self.write_irq && self.values.len() < WRITE_IRQ_THRESHOLD
+    }
+
+    /// Reads a register byte, only taking the character out of the read FIFO if `take` is set
+    fn read_byte(&self, addr: u32, take: bool) -> Result<u8, ()> {
+        let addr = addr - UART_LOWER_ADDR;
+
+        if addr >= CONTROL_OFFSET {
+            let space = self.write_space().min(u16::MAX as usize) as u16;
+            return Ok(match addr - CONTROL_OFFSET {
+                0 => {
+                    let mut byte = 0;
+                    if self.read_irq {
+                        byte |= RE;
+                    }
+                    if self.write_irq {
+                        byte |= WE;
+                    }
+                    byte
+                }
+                1 => {
+                    let mut byte = 0;
+                    if self.read_pending() {
+                        byte |= RI;
+                    }
+                    if self.write_pending() {
+                        byte |= WI;
+                    }
+                    if self.activity {
+                        byte |= AC;
+                    }
+                    byte
+                }
+                2 => space.to_le_bytes()[0],
+                _ => space.to_le_bytes()[1],
+            });
+        }
+
+        let mut input = self.input.borrow_mut();
+        let available = input.len().saturating_sub(1).min(u16::MAX as usize) as u16;
+        Ok(match addr {
+            0 if take => input.pop_front().unwrap_or(0),
+            0 => input.front().copied().unwrap_or(0),
+            1 if input.is_empty() => 0,
+            1 => RVALID,
+            2 => available.to_le_bytes()[0],
+            _ => available.to_le_bytes()[1],
+        })
     }
 }
 
-impl Peripheral<()> for UART {}
+impl Peripheral<()> for UART {
+    fn poll_interrupt(&self) -> Option<InterruptSignal> {
+        if self.read_pending() || self.write_pending() {
+            Some(InterruptSignal::UART_INTERRUPT)
+        } else {
+            None
+        }
+    }
+
+    fn update_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    fn set_replaying(&mut self, replaying: bool) {
+        self.replaying = replaying;
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.values.len() as u32);
+        for output in &self.values {
+            snapshot.write_u8(output.byte);
+            snapshot.write_u64(output.cycle);
+        }
+
+        let input = self.input.borrow();
+        snapshot.write_u32(input.len() as u32);
+        for byte in input.iter() {
+            snapshot.write_u8(*byte);
+        }
+
+        snapshot.write_bool(self.read_irq);
+        snapshot.write_bool(self.write_irq);
+        snapshot.write_bool(self.activity);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let len = snapshot.read_u32()?;
+        if len as usize > self.write_depth {
+            return Err(SnapshotError::Invalid);
+        }
+
+        self.values.clear();
+        for _ in 0..len {
+            self.values.push_back(UartByte {
+                byte: snapshot.read_u8()?,
+                cycle: snapshot.read_u64()?,
+            });
+        }
+
+        let len = snapshot.read_u32()?;
+        if len as usize > self.read_depth {
+            return Err(SnapshotError::Invalid);
+        }
+
+        let input = self.input.get_mut();
+        input.clear();
+        for _ in 0..len {
+            input.push_back(snapshot.read_u8()?);
+        }
+
+        self.read_irq = snapshot.read_bool()?;
+        self.write_irq = snapshot.read_bool()?;
+        self.activity = snapshot.read_bool()?;
+        Ok(())
+    }
+
+    // A write to the data register only appends a byte, so undoing it only needs to know if the
+    // byte fit. Bytes already passed on to the sink and characters the program read aren't put
+    // back.
+    fn save_store_state(&self, _addr: u32, _size: u32, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bool(self.accepts_output());
+        snapshot.write_bool(self.read_irq);
+        snapshot.write_bool(self.write_irq);
+        snapshot.write_bool(self.activity);
+    }
+
+    fn restore_store_state(
+        &mut self,
+        addr: u32,
+        _size: u32,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        let accepted = snapshot.read_bool()?;
+        let read_irq = snapshot.read_bool()?;
+        let write_irq = snapshot.read_bool()?;
+        let activity = snapshot.read_bool()?;
+
+        if addr == UART_LOWER_ADDR {
+            if accepted && self.values.pop_back().is_none() {
+                self.retracted += 1;
+            }
+        } else if addr - UART_LOWER_ADDR >= CONTROL_OFFSET {
+            self.read_irq = read_irq;
+            self.write_irq = write_irq;
+            self.activity = activity;
+        }
+
+        Ok(())
+    }
+}
 
 impl Default for UART {
     fn default() -> Self {
@@ -36,8 +336,9 @@ impl Default for UART {
     }
 }
 
+/// Takes the output out of the write FIFO, there is nothing to take with a sink attached
 impl Iterator for UART {
-    type Item = char;
+    type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.pop()
@@ -45,25 +346,29 @@ impl Iterator for UART {
 }
 
 impl MemoryMapped<()> for UART {
+    /// Word loads read the bytes from the highest address down, so RAVAIL and RVALID are read
+    /// before the character is taken out of the read FIFO by the load of byte 0
     fn load_byte(&self, addr: u32) -> Result<u8, ()> {
-        let addr = addr - UART_LOWER_ADDR;
-        Ok(if addr >= 4 {
-            // CTRL signal, always send high, aka ready
-            u8::MAX
-        } else {
-            0
-        })
+        self.read_byte(addr, true)
+    }
+
+    /// Leaves the character in the read FIFO
+    fn peek_byte(&self, addr: u32) -> Result<u8, ()> {
+        self.read_byte(addr, false)
     }
 
     fn store_byte(&mut self, addr: u32, byte: u8) -> Result<(), ()> {
         let addr = addr - UART_LOWER_ADDR;
 
-        if addr >= 4 {
-            return Ok(());
-        }
-
-        if addr == 0 {
-            self.push(byte as char);
+        match addr {
+            0 => self.push(byte),
+            4 => {
+                self.read_irq = byte & RE != 0;
+                self.write_irq = byte & WE != 0;
+            }
+            5 if byte & AC != 0 => self.activity = false,
+            // RVALID, RAVAIL, RI, WI and WSPACE are read only
+            _ => {}
         }
 
         Ok(())
@@ -75,3 +380,175 @@ impl std::fmt::Debug for UART {
         write!(f, "Uart {{ ... }}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peripheral::UartRingBuffer;
+
+    const DATA: u32 = UART_LOWER_ADDR;
+    const CONTROL: u32 = UART_LOWER_ADDR + CONTROL_OFFSET;
+
+    #[test]
+    fn test_read_fifo() {
+        let mut uart = UART::new();
+        assert_eq!(uart.load_word(DATA), Ok(0));
+
+        assert!(uart.push_input(b'h'));
+        assert!(uart.push_input(b'i'));
+        // RAVAIL counts what is left after the read
+        assert_eq!(uart.load_word(DATA), Ok(1 << 16 | 1 << 15 | b'h' as u32));
+        assert_eq!(uart.load_word(DATA), Ok(1 << 15 | b'i' as u32));
+        assert_eq!(uart.load_word(DATA), Ok(0));
+
+        // A byte load takes the character as well
+        uart.push_input(b'!');
+        assert_eq!(uart.load_byte(DATA), Ok(b'!'));
+        assert_eq!(uart.input_space(), UART_FIFO_DEPTH);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut uart = UART::new();
+        assert!(uart.push_input(b'h'));
+        assert!(uart.push_input(b'i'));
+
+        // The debugger sees what the program would read without taking it
+        assert_eq!(uart.peek_word(DATA), Ok(1 << 16 | 1 << 15 | b'h' as u32));
+        assert_eq!(uart.peek_byte(DATA), Ok(b'h'));
+        assert_eq!(uart.input_space(), UART_FIFO_DEPTH - 2);
+        assert_eq!(uart.load_byte(DATA), Ok(b'h'));
+        assert_eq!(uart.peek_byte(DATA), Ok(b'i'));
+    }
+
+    #[test]
+    fn test_read_fifo_full() {
+        let mut uart = UART::new().with_read_fifo_depth(2);
+        assert!(uart.push_input(b'a'));
+        assert!(uart.push_input(b'b'));
+        assert!(!uart.push_input(b'c'));
+        assert_eq!(uart.input_space(), 0);
+
+        assert_eq!(uart.load_byte(DATA), Ok(b'a'));
+        assert!(uart.push_input(b'c'));
+    }
+
+    #[test]
+    fn test_write_fifo() {
+        let mut uart = UART::new().with_write_fifo_depth(2);
+        assert_eq!(uart.load_word(CONTROL), Ok(2 << 16));
+
+        uart.store_word(DATA, b'a' as u32).unwrap();
+        uart.store_word(DATA, b'b' as u32).unwrap();
+        assert_eq!(uart.load_word(CONTROL), Ok(0));
+        // Dropped, the FIFO is full
+        uart.store_word(DATA, b'c' as u32).unwrap();
+
+        assert_eq!(uart.next(), Some(b'a'));
+        assert_eq!(uart.load_word(CONTROL).unwrap() >> 16, 1);
+        uart.store_word(DATA, b'd' as u32).unwrap();
+        assert_eq!(uart.collect::<Vec<_>>(), b"bd");
+    }
+
+    #[test]
+    fn test_sink() {
+        let output = Rc::new(RefCell::new(UartRingBuffer::new(16)));
+        let mut uart = UART::new().with_write_fifo_depth(1);
+        uart.update_cycle(5);
+        uart.store_byte(DATA, b'a').unwrap();
+
+        // The byte waiting in the FIFO is passed on when the sink is attached
+        uart.set_sink(output.clone());
+        uart.update_cycle(7);
+        uart.store_byte(DATA, 0xC3).unwrap();
+        uart.store_byte(DATA, 0xA5).unwrap();
+
+        assert_eq!(uart.next(), None);
+        // The write FIFO never fills up
+        assert_eq!(uart.load_word(CONTROL).unwrap() >> 16, 1);
+        assert_eq!(
+            output.borrow_mut().drain().collect::<Vec<_>>(),
+            vec![
+                UartByte {
+                    byte: b'a',
+                    cycle: 5
+                },
+                UartByte {
+                    byte: 0xC3,
+                    cycle: 7
+                },
+                UartByte {
+                    byte: 0xA5,
+                    cycle: 7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_activity() {
+        let mut uart = UART::new();
+        uart.store_word(DATA, b'a' as u32).unwrap();
+        assert_eq!(uart.load_word(CONTROL).unwrap() & 1 << 10, 0);
+
+        uart.next();
+        assert_ne!(uart.load_word(CONTROL).unwrap() & 1 << 10, 0);
+
+        // Writing 1 clears it
+        uart.store_word(CONTROL, 1 << 10).unwrap();
+        assert_eq!(uart.load_word(CONTROL).unwrap() & 1 << 10, 0);
+
+        uart.push_input(b'a');
+        assert_ne!(uart.load_word(CONTROL).unwrap() & 1 << 10, 0);
+    }
+
+    #[test]
+    fn test_read_interrupt() {
+        let mut uart = UART::new();
+        uart.push_input(b'a');
+        assert_eq!(uart.poll_interrupt(), None);
+
+        uart.store_word(CONTROL, 1).unwrap();
+        assert_eq!(uart.poll_interrupt(), Some(InterruptSignal::UART_INTERRUPT));
+        assert_eq!(uart.load_word(CONTROL).unwrap() & 0x3FF, 1 << 8 | 1);
+
+        uart.load_byte(DATA).unwrap();
+        assert_eq!(uart.poll_interrupt(), None);
+    }
+
+    #[test]
+    fn test_write_interrupt() {
+        let mut uart = UART::new();
+        uart.store_word(CONTROL, 2).unwrap();
+        assert_eq!(uart.poll_interrupt(), Some(InterruptSignal::UART_INTERRUPT));
+        assert_eq!(uart.load_word(CONTROL).unwrap() & 0x3FF, 1 << 9 | 2);
+
+        for _ in 0..WRITE_IRQ_THRESHOLD {
+            uart.store_byte(DATA, b'a').unwrap();
+        }
+        assert_eq!(uart.poll_interrupt(), None);
+
+        uart.next();
+        assert_eq!(uart.poll_interrupt(), Some(InterruptSignal::UART_INTERRUPT));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut uart = UART::new();
+        uart.store_byte(DATA, b'a').unwrap();
+        uart.push_input(b'b');
+        uart.store_word(CONTROL, 3).unwrap();
+
+        let mut snapshot = SnapshotWriter::new();
+        uart.save_state(&mut snapshot);
+        let data = snapshot.into_bytes();
+
+        let mut restored = UART::new();
+        restored
+            .restore_state(&mut SnapshotReader::new(&data))
+            .unwrap();
+        assert_eq!(restored.load_word(CONTROL), uart.load_word(CONTROL));
+        assert_eq!(restored.load_byte(DATA), Ok(b'b'));
+        assert_eq!(restored.next(), Some(b'a'));
+    }
+}
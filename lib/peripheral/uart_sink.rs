@@ -0,0 +1,262 @@
+use std::{collections::VecDeque, io};
+
+/// Receives the bytes the program writes to the [UART](super::UART)
+///
+/// Without a sink the characters the program writes wait in the write FIFO until the frontend
+/// reads them. With one the host side of the UART takes every character as soon as it is written
+/// and hands it to the sink, together with the cycle it was written at. A sink is attached with
+/// [UART::with_sink](super::UART::with_sink) and shared with the frontend through an
+/// `Rc<RefCell<_>>`, just like the peripherals themselves.
+///
+/// Any `FnMut(u8, u64)` is a sink. [UartRingBuffer] keeps the latest output, [UartWriter] passes it
+/// on to a [std::io::Write] and [UartLines] decodes it into lines of UTF-8 text.
+pub trait UartSink {
+    /// Called for every byte in the order they were written, `cycle` is the cycle the store
+    /// happened at
+    fn write(&mut self, byte: u8, cycle: u64);
+}
+
+impl<F: FnMut(u8, u64)> UartSink for F {
+    fn write(&mut self, byte: u8, cycle: u64) {
+        self(byte, cycle)
+    }
+}
+
+/// A byte written to the UART together with the cycle it was written at
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct UartByte {
+    pub byte: u8,
+    pub cycle: u64,
+}
+
+/// Keeps the last `capacity` bytes written to the UART, dropping the oldest ones when it is full
+#[derive(Debug, Clone, Default)]
+pub struct UartRingBuffer {
+    bytes: VecDeque<UartByte>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl UartRingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        UartRingBuffer {
+            bytes: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Takes the oldest byte out of the buffer
+    pub fn pop(&mut self) -> Option<UartByte> {
+        self.bytes.pop_front()
+    }
+
+    /// Takes every byte out of the buffer, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = UartByte> + '_ {
+        self.bytes.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// How many bytes were dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl UartSink for UartRingBuffer {
+    fn write(&mut self, byte: u8, cycle: u64) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+
+        if self.bytes.len() == self.capacity {
+            self.bytes.pop_front();
+            self.dropped += 1;
+        }
+        self.bytes.push_back(UartByte { byte, cycle });
+    }
+}
+
+/// Writes the output to a [std::io::Write], like stdout or a file. The program can't be told
+/// about a failed write, so the first error is kept for the frontend instead and later bytes are
+/// dropped.
+#[derive(Debug)]
+pub struct UartWriter<W: io::Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> UartWriter<W> {
+    pub fn new(writer: W) -> Self {
+        UartWriter {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Takes the error that stopped the output, if any, and starts writing again
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: io::Write> UartSink for UartWriter<W> {
+    fn write(&mut self, byte: u8, _cycle: u64) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.write_all(&[byte]) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// A line of text written to the UART
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UartLine {
+    /// The line without the line ending. Invalid UTF-8 is replaced with U+FFFD.
+    pub text: String,
+    /// The cycle the first byte of the line was written at
+    pub cycle: u64,
+}
+
+/// Decodes the output into lines of UTF-8 text, for frontends that show text rather than bytes.
+/// Lines end with `\n` or `\r\n`.
+#[derive(Debug, Clone, Default)]
+pub struct UartLines {
+    partial: Vec<u8>,
+    partial_cycle: u64,
+    lines: VecDeque<UartLine>,
+}
+
+impl UartLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the oldest complete line
+    pub fn pop_line(&mut self) -> Option<UartLine> {
+        self.lines.pop_front()
+    }
+
+    /// Takes every complete line, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = UartLine> + '_ {
+        self.lines.drain(..)
+    }
+
+    /// The text written since the last line ending
+    pub fn partial_line(&self) -> String {
+        String::from_utf8_lossy(&self.partial).into_owned()
+    }
+}
+
+impl UartSink for UartLines {
+    fn write(&mut self, byte: u8, cycle: u64) {
+        if self.partial.is_empty() {
+            self.partial_cycle = cycle;
+        }
+
+        if byte != b'\n' {
+            self.partial.push(byte);
+            return;
+        }
+
+        if self.partial.last() == Some(&b'\r') {
+            self.partial.pop();
+        }
+        self.lines.push_back(UartLine {
+            text: String::from_utf8_lossy(&self.partial).into_owned(),
+            cycle: self.partial_cycle,
+        });
+        self.partial.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_all(sink: &mut impl UartSink, bytes: &[u8], cycle: u64) {
+        for (i, byte) in bytes.iter().enumerate() {
+            sink.write(*byte, cycle + i as u64);
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut buffer = UartRingBuffer::new(3);
+        write_all(&mut buffer, b"abcd", 10);
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(
+            buffer.pop(),
+            Some(UartByte {
+                byte: b'b',
+                cycle: 11
+            })
+        );
+        assert_eq!(
+            buffer.drain().map(|byte| byte.byte).collect::<Vec<_>>(),
+            b"cd"
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = UartWriter::new(Vec::new());
+        write_all(&mut writer, "håll".as_bytes(), 0);
+        assert!(writer.take_error().is_none());
+        assert_eq!(writer.into_inner(), "håll".as_bytes());
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut cycles = Vec::new();
+        let mut sink = |_, cycle| cycles.push(cycle);
+        write_all(&mut sink, b"ab", 5);
+        assert_eq!(cycles, vec![5, 6]);
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut lines = UartLines::new();
+        write_all(&mut lines, "räksmörgås\r\nhej\nhal".as_bytes(), 100);
+
+        assert_eq!(
+            lines.pop_line(),
+            Some(UartLine {
+                text: "räksmörgås".to_string(),
+                cycle: 100
+            })
+        );
+        assert_eq!(
+            lines.pop_line(),
+            Some(UartLine {
+                text: "hej".to_string(),
+                cycle: 115
+            })
+        );
+        assert_eq!(lines.pop_line(), None);
+        assert_eq!(lines.partial_line(), "hal");
+
+        write_all(&mut lines, b"\xFFlo\n", 0);
+        assert_eq!(lines.drain().next().unwrap().text, "hal\u{FFFD}lo");
+    }
+}
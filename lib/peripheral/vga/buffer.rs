@@ -4,7 +4,12 @@ pub const VGA_BUFFER_HIGHER_ADDR: u32 = 0x80257ff;
 use std::{cell::RefCell, rc::Rc};
 
 use super::{channel::Channel, Renderer};
-use crate::{debug_console::DebugConsole, memory_mapped::MemoryMapped, peripheral::Peripheral};
+use crate::{
+    debug_console::DebugConsole,
+    memory_mapped::MemoryMapped,
+    peripheral::Peripheral,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
 pub struct Buffer<'a, T: Renderer> {
     channel: &'a Channel<T>,
@@ -32,7 +37,52 @@ impl<'a, T: Renderer> Buffer<'a, T> {
     }
 }
 
-impl<'a, T: Renderer> Peripheral<()> for Buffer<'a, T> {}
+impl<'a, T: Renderer> Peripheral<()> for Buffer<'a, T> {
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bytes(&self.buffer);
+    }
+
+    /// Every pixel is sent to the renderer again
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let buffer = snapshot.read_bytes()?;
+        if buffer.len() != self.buffer.len() {
+            return Err(SnapshotError::Invalid);
+        }
+
+        self.buffer.copy_from_slice(buffer);
+        for (i, pixel) in self.buffer.iter().enumerate() {
+            self.channel.set_pixel(i as u32, self.to_color(*pixel));
+        }
+
+        Ok(())
+    }
+
+    fn save_store_state(&self, addr: u32, size: u32, snapshot: &mut SnapshotWriter) {
+        for i in 0..size {
+            snapshot.write_u8(self.load_byte(addr.wrapping_add(i)).unwrap_or(0));
+        }
+    }
+
+    fn restore_store_state(
+        &mut self,
+        addr: u32,
+        size: u32,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        for i in 0..size {
+            let byte = snapshot.read_u8()?;
+            let index = addr.wrapping_add(i).wrapping_sub(VGA_BUFFER_LOWER_ADDR);
+
+            // Written directly instead of through store_byte, undoing a store while swapping
+            // isn't a mistake in the program
+            if let Some(pixel) = self.buffer.get_mut(index as usize) {
+                *pixel = byte;
+                self.channel.set_pixel(index, self.to_color(byte));
+            }
+        }
+        Ok(())
+    }
+}
 impl<'a, T: Renderer> MemoryMapped<()> for Buffer<'a, T> {
     fn load_byte(&self, addr: u32) -> Result<u8, ()> {
         let addr = addr - VGA_BUFFER_LOWER_ADDR;
@@ -1,15 +1,32 @@
-use crate::{memory_mapped::MemoryMapped, peripheral::Peripheral, utils};
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    cpu::CLOCK_FEQ,
+    memory_mapped::MemoryMapped,
+    peripheral::Peripheral,
+    scheduler::{DeviceId, Event, Scheduler},
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    utils,
+};
 
 use super::{buffer::VGA_BUFFER_LOWER_ADDR, channel::Channel, Renderer};
 
 pub const VGA_DMA_LOWER_ADDR: u32 = 0x4000100;
 pub const VGA_DMA_HIGHER_ADDR: u32 = 0x400010f;
 
+/// The number of cycles between two vertical syncs, the screen is redrawn at 60 Hz
+pub const VGA_FRAME_CYCLES: u64 = CLOCK_FEQ as u64 / 60;
+
+/// The event scheduled for the next vertical sync when a swap is requested
+const VSYNC_EVENT: u32 = 0;
+
 pub struct Dma<'a, T: Renderer> {
     channel: &'a Channel<T>,
     buffer_offset: u32,
     back_buffer: u32,
     enable: bool,
+    /// Finishes swaps at the vertical sync, see [Dma::with_scheduler]
+    scheduler: Option<(Rc<RefCell<Scheduler>>, DeviceId)>,
 }
 
 enum VgaDmaPart {
@@ -39,9 +56,19 @@ impl<'a, T: Renderer> Dma<'a, T> {
             buffer_offset: VGA_BUFFER_LOWER_ADDR,
             back_buffer: VGA_BUFFER_LOWER_ADDR,
             enable: false,
+            scheduler: None,
         }
     }
 
+    /// Finishes swaps at the next vertical sync by the cycle count of the Cpu, like the hardware
+    /// does, instead of when [Dma::handle_swap] is called. The Cpu has to be given the same
+    /// scheduler, see [Cpu::with_scheduler](crate::cpu::Cpu::with_scheduler).
+    pub fn with_scheduler(mut self, scheduler: Rc<RefCell<Scheduler>>) -> Self {
+        let id = scheduler.borrow_mut().register();
+        self.scheduler = Some((scheduler, id));
+        self
+    }
+
     /// **TL;DR: Call this function 60 times a second to handle scheduled swaps**
     ///
     /// The DMA swapping works by the code writing to a specific memory region to signal that it
@@ -56,6 +83,9 @@ impl<'a, T: Renderer> Dma<'a, T> {
     /// Therefore i've also implemented schedule functionality like this that will schedule a swap
     /// and execute the swap at a later time. Calling this function will handle a scheduled swap.
     /// Preferably call this function 60 times a second or something like that.
+    ///
+    /// With a scheduler, see [Dma::with_scheduler], this is called at every vertical sync with a
+    /// swap pending and the frontend doesn't have to.
     pub fn handle_swap(&mut self) {
         // Swap the buffers if needed
         if self.channel.is_swapping() {
@@ -69,7 +99,39 @@ impl<'a, T: Renderer> Dma<'a, T> {
     }
 }
 
-impl<'a, T: Renderer> Peripheral<()> for Dma<'a, T> {}
+impl<'a, T: Renderer> Peripheral<()> for Dma<'a, T> {
+    fn handle_event(&mut self, event: Event) {
+        if self
+            .scheduler
+            .as_ref()
+            .is_some_and(|(_, id)| event.device == *id && event.kind == VSYNC_EVENT)
+        {
+            self.handle_swap();
+        }
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.buffer_offset);
+        snapshot.write_u32(self.back_buffer);
+        snapshot.write_bool(self.enable);
+        snapshot.write_bool(self.channel.is_swapping());
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.buffer_offset = snapshot.read_u32()?;
+        self.back_buffer = snapshot.read_u32()?;
+        self.enable = snapshot.read_bool()?;
+
+        if snapshot.read_bool()? {
+            self.channel.start_swap();
+        } else {
+            self.channel.finish_swap();
+        }
+        self.channel.set_buffer_offset(self.buffer_offset);
+
+        Ok(())
+    }
+}
 
 impl<'a, T: Renderer> MemoryMapped<()> for Dma<'a, T> {
     fn load_byte(&self, addr: u32) -> Result<u8, ()> {
@@ -89,7 +151,7 @@ impl<'a, T: Renderer> MemoryMapped<()> for Dma<'a, T> {
                 if self.channel.is_swapping() {
                     value |= 0b1;
                 }
-                value = value | (1 << 1); // The Addressing mode is always 1
+                value |= 1 << 1; // The Addressing mode is always 1
                 if self.enable {
                     value |= 0b100;
                 }
@@ -113,6 +175,13 @@ impl<'a, T: Renderer> MemoryMapped<()> for Dma<'a, T> {
         match part {
             VgaDmaPart::Buffer => {
                 self.channel.start_swap();
+                if let Some((scheduler, id)) = &self.scheduler {
+                    let mut scheduler = scheduler.borrow_mut();
+                    if !scheduler.is_scheduled(*id, VSYNC_EVENT) {
+                        let vsync = (scheduler.now() / VGA_FRAME_CYCLES + 1) * VGA_FRAME_CYCLES;
+                        scheduler.schedule(vsync, *id, VSYNC_EVENT);
+                    }
+                }
             }
             VgaDmaPart::BackBuffer => {
                 self.back_buffer = utils::set_in_u32(self.back_buffer, byte, index);
@@ -122,7 +191,7 @@ impl<'a, T: Renderer> MemoryMapped<()> for Dma<'a, T> {
             }
             VgaDmaPart::StatusControl => {
                 if index == 0 {
-                    self.enable = byte & 0b100 == 1;
+                    self.enable = byte & 0b100 != 0;
                 }
             }
         };
@@ -136,3 +205,101 @@ impl<'a, T: Renderer> std::fmt::Debug for Dma<'a, T> {
         write!(f, "Vga {{ ... }}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::Cpu,
+        peripheral::{self, Bus, SDRam},
+    };
+
+    struct TestRenderer {
+        buffer_offset: u32,
+    }
+
+    impl Renderer for TestRenderer {
+        fn set_pixel(&mut self, _index: u32, _color: (u8, u8, u8)) {}
+
+        fn set_buffer_offset(&mut self, buffer: u32) {
+            self.buffer_offset = buffer;
+        }
+    }
+
+    fn new_channel() -> Channel<TestRenderer> {
+        Channel::new(TestRenderer { buffer_offset: 0 })
+    }
+
+    #[test]
+    fn test_enable() {
+        let channel = new_channel();
+        let mut dma = Dma::new(&channel);
+        assert_eq!(dma.load_word(VGA_DMA_LOWER_ADDR + 12).unwrap() & 0b100, 0);
+
+        dma.store_word(VGA_DMA_LOWER_ADDR + 12, 0b100).unwrap();
+        assert_eq!(
+            dma.load_word(VGA_DMA_LOWER_ADDR + 12).unwrap() & 0b100,
+            0b100
+        );
+
+        dma.store_word(VGA_DMA_LOWER_ADDR + 12, 0).unwrap();
+        assert_eq!(dma.load_word(VGA_DMA_LOWER_ADDR + 12).unwrap() & 0b100, 0);
+    }
+
+    #[test]
+    fn test_swap_at_vsync() {
+        let channel = new_channel();
+        let scheduler = Rc::new(RefCell::new(Scheduler::new()));
+        let mut dma = Dma::new(&channel).with_scheduler(scheduler.clone());
+
+        scheduler.borrow_mut().advance(1000);
+        dma.store_word(VGA_DMA_LOWER_ADDR + 4, 0x8001000).unwrap();
+        dma.store_word(VGA_DMA_LOWER_ADDR, 0).unwrap();
+        assert_eq!(dma.load_word(VGA_DMA_LOWER_ADDR + 12).unwrap() & 1, 1);
+        // All four byte stores request the same swap
+        assert_eq!(scheduler.borrow().len(), 1);
+        assert_eq!(scheduler.borrow().next_cycle(), Some(VGA_FRAME_CYCLES));
+
+        scheduler.borrow_mut().advance(VGA_FRAME_CYCLES);
+        let event = scheduler.borrow_mut().pop_due().unwrap();
+        dma.handle_event(event);
+        assert_eq!(dma.load_word(VGA_DMA_LOWER_ADDR), Ok(0x8001000));
+        assert_eq!(dma.load_word(VGA_DMA_LOWER_ADDR + 12).unwrap() & 1, 0);
+        channel.with_renderer_borrow(|renderer| assert_eq!(renderer.buffer_offset, 0x8001000));
+    }
+
+    #[test]
+    fn test_cpu_handles_swap() {
+        // The bus only takes 'static devices
+        let channel: &'static Channel<TestRenderer> = Box::leak(Box::new(new_channel()));
+        let scheduler = Rc::new(RefCell::new(Scheduler::new()));
+        let mut bus = Bus::new();
+        bus.attach_device(
+            (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR),
+            Box::new(SDRam::new()),
+        );
+        bus.attach_device(
+            (VGA_DMA_LOWER_ADDR, VGA_DMA_HIGHER_ADDR),
+            Box::new(Dma::new(channel).with_scheduler(scheduler.clone())),
+        );
+        let mut cpu = Cpu::new_with_bus(bus).with_scheduler(scheduler);
+
+        let program: [u32; 4] = [
+            0x040002b7, // lui t0, 0x4000
+            0x10028293, // addi t0, t0, 256
+            0x0002a023, // sw zero, 0(t0)
+            0x0000006f, // j 12
+        ];
+        cpu.store_at(0, program.into_iter().flat_map(u32::to_le_bytes))
+            .unwrap();
+
+        cpu.run(10);
+        while channel.is_swapping() {
+            assert!(cpu.cycles() <= VGA_FRAME_CYCLES + 10);
+            cpu.run(1);
+        }
+        // The swap finished before the first instruction at or after the vertical sync
+        assert!(cpu.cycles() >= VGA_FRAME_CYCLES);
+        assert!(!channel.is_swapping());
+    }
+}
@@ -1,5 +1,6 @@
 //! Register definitions for the RISC-V 32 architecture.
 
+#[allow(clippy::module_inception)]
 mod register;
 pub use register::Register;
 
@@ -15,8 +15,12 @@ impl Register {
     }
 
     /// Creates a new Register without checking if the given Register is a valid reg
+    ///
+    /// # Safety
+    ///
+    /// The value has to be a valid register index, i.e. between 0 and 31
     pub unsafe fn new_unchecked(reg: u8) -> Self {
-        if (reg as u32) > MAX_REG || (reg as u32) < MIN_REG {
+        if (reg as u32) > MAX_REG {
             if cfg!(debug_assertions) {
                 unreachable!("Register is set to an invalid value");
             } else {
@@ -49,16 +53,16 @@ impl TryFrom<u32> for Register {
     }
 }
 
-impl Into<u32> for Register {
-    fn into(self) -> u32 {
-        self.as_u32()
+impl From<Register> for u32 {
+    fn from(reg: Register) -> u32 {
+        reg.as_u32()
     }
 }
 
-impl Into<usize> for Register {
-    fn into(self) -> usize {
+impl From<Register> for usize {
+    fn from(reg: Register) -> usize {
         // Since Register can't be negative, this is safe
-        self.as_usize()
+        reg.as_usize()
     }
 }
 
@@ -5,6 +5,12 @@ pub struct RegisterBlock {
     registers: [u32; 31],
 }
 
+impl Default for RegisterBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RegisterBlock {
     pub fn new() -> RegisterBlock {
         RegisterBlock { registers: [0; 31] }
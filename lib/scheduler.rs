@@ -0,0 +1,283 @@
+//! Events that peripherals schedule for a future cycle
+//!
+//! Some peripherals have to do something at a point in time rather than when the program accesses
+//! them, like the VGA DMA finishing a buffer swap at the next vertical sync. Instead of having the
+//! frontend call them at the right moment they schedule an event in a [Scheduler] shared with the
+//! Cpu, see [Cpu::with_scheduler](crate::cpu::Cpu::with_scheduler). Between instructions the Cpu
+//! takes every event that is due and hands it to the bus through
+//! [Peripheral::handle_event](crate::peripheral::Peripheral::handle_event), where the peripheral
+//! that scheduled it picks it up.
+//!
+//! A peripheral gets a [DeviceId] from [Scheduler::register] once and tags its events with it, the
+//! meaning of [Event::kind] is up to the peripheral. Events are ordered by cycle, events at the
+//! same cycle are handled in the order they were scheduled.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// Identifies the peripheral an event belongs to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Event {
+    /// The cycle the event is due at
+    pub cycle: u64,
+    pub device: DeviceId,
+    /// What happens, decided by the peripheral
+    pub kind: u32,
+}
+
+/// An event in the queue, the sequence number keeps events at the same cycle in order
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    cycle: u64,
+    sequence: u64,
+    device: DeviceId,
+    kind: u32,
+}
+
+/// A priority queue of events ordered by the cycle they are due at
+#[derive(Debug, Default, Clone)]
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<Entry>>,
+    sequence: u64,
+    devices: u32,
+    now: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands out a new id for a peripheral to schedule its events with
+    pub fn register(&mut self) -> DeviceId {
+        self.devices += 1;
+        DeviceId(self.devices - 1)
+    }
+
+    /// The cycle the Cpu is at, as of the start of the current instruction
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Schedules an event at `cycle`. An event in the past is handled before the next instruction.
+    pub fn schedule(&mut self, cycle: u64, device: DeviceId, kind: u32) {
+        self.queue.push(Reverse(Entry {
+            cycle,
+            sequence: self.sequence,
+            device,
+            kind,
+        }));
+        self.sequence += 1;
+    }
+
+    /// Schedules an event `delay` cycles from now
+    pub fn schedule_in(&mut self, delay: u64, device: DeviceId, kind: u32) {
+        self.schedule(self.now.saturating_add(delay), device, kind);
+    }
+
+    /// Removes every pending event of the device with the given kind
+    pub fn cancel(&mut self, device: DeviceId, kind: u32) {
+        self.queue
+            .retain(|Reverse(entry)| entry.device != device || entry.kind != kind);
+    }
+
+    /// If the device has an event of the given kind pending
+    pub fn is_scheduled(&self, device: DeviceId, kind: u32) -> bool {
+        self.queue
+            .iter()
+            .any(|Reverse(entry)| entry.device == device && entry.kind == kind)
+    }
+
+    /// The cycle the next event is due at
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.queue.peek().map(|Reverse(entry)| entry.cycle)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Moves the scheduler to `cycle`. Events keep the cycle they were scheduled at, so if the
+    /// cycle count went backwards, because the Cpu stepped back or the program wrote mcycle, they
+    /// are further away than before.
+    pub(crate) fn advance(&mut self, cycle: u64) {
+        self.now = cycle;
+    }
+
+    /// Moves the scheduler to `cycle` together with the pending events, so they stay the same
+    /// number of cycles away. Used when the Cpu is reset and the cycle count starts over.
+    pub(crate) fn rebase(&mut self, cycle: u64) {
+        self.queue = self
+            .queue
+            .drain()
+            .map(|Reverse(entry)| {
+                let delay = entry.cycle.saturating_sub(self.now);
+                Reverse(Entry {
+                    cycle: cycle.saturating_add(delay),
+                    ..entry
+                })
+            })
+            .collect();
+        self.now = cycle;
+    }
+
+    /// Takes the next event that is due by now
+    pub(crate) fn pop_due(&mut self) -> Option<Event> {
+        if self.next_cycle()? > self.now {
+            return None;
+        }
+
+        let Reverse(entry) = self.queue.pop()?;
+        Some(Event {
+            cycle: entry.cycle,
+            device: entry.device,
+            kind: entry.kind,
+        })
+    }
+
+    /// Writes the pending events to a snapshot. The device ids aren't saved, the peripherals have
+    /// to register in the same order to get the same ids back.
+    pub(crate) fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.now);
+        let mut entries: Vec<Entry> = self.queue.iter().map(|Reverse(entry)| *entry).collect();
+        entries.sort();
+        snapshot.write_u32(entries.len() as u32);
+        for entry in entries {
+            snapshot.write_u64(entry.cycle);
+            snapshot.write_u32(entry.device.0);
+            snapshot.write_u32(entry.kind);
+        }
+    }
+
+    /// Replaces the pending events with the ones in a snapshot. Nothing changes if the snapshot
+    /// is invalid.
+    pub(crate) fn restore_state(
+        &mut self,
+        snapshot: &mut SnapshotReader,
+    ) -> Result<(), SnapshotError> {
+        let now = snapshot.read_u64()?;
+        let len = snapshot.read_u32()?;
+        let mut events = Vec::new();
+        for _ in 0..len {
+            let cycle = snapshot.read_u64()?;
+            let device = snapshot.read_u32()?;
+            if device >= self.devices {
+                return Err(SnapshotError::DeviceMismatch);
+            }
+            events.push((cycle, DeviceId(device), snapshot.read_u32()?));
+        }
+
+        self.now = now;
+        self.queue.clear();
+        self.sequence = 0;
+        for (cycle, device, kind) in events {
+            self.schedule(cycle, device, kind);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.register();
+        let b = scheduler.register();
+        scheduler.schedule(20, a, 0);
+        scheduler.schedule(10, b, 1);
+        scheduler.schedule(10, a, 2);
+        assert_eq!(scheduler.next_cycle(), Some(10));
+
+        scheduler.advance(9);
+        assert_eq!(scheduler.pop_due(), None);
+
+        scheduler.advance(15);
+        assert_eq!(
+            scheduler.pop_due(),
+            Some(Event {
+                cycle: 10,
+                device: b,
+                kind: 1
+            })
+        );
+        assert_eq!(scheduler.pop_due().map(|event| event.kind), Some(2));
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.register();
+        let b = scheduler.register();
+        scheduler.schedule(10, a, 0);
+        scheduler.schedule(10, b, 0);
+        scheduler.schedule(10, a, 1);
+
+        scheduler.cancel(a, 0);
+        assert!(!scheduler.is_scheduled(a, 0));
+        assert!(scheduler.is_scheduled(a, 1));
+        assert!(scheduler.is_scheduled(b, 0));
+    }
+
+    #[test]
+    fn test_going_back() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.register();
+        scheduler.advance(100);
+        scheduler.schedule_in(50, a, 0);
+
+        scheduler.advance(90);
+        assert_eq!(scheduler.next_cycle(), Some(150));
+
+        // A reset keeps the event 60 cycles away
+        scheduler.rebase(0);
+        assert_eq!(scheduler.next_cycle(), Some(60));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.register();
+        scheduler.advance(5);
+        scheduler.schedule(30, a, 1);
+        scheduler.schedule(20, a, 2);
+
+        let mut snapshot = SnapshotWriter::new();
+        scheduler.save_state(&mut snapshot);
+        let data = snapshot.into_bytes();
+
+        let mut restored = Scheduler::new();
+        restored.register();
+        restored
+            .restore_state(&mut SnapshotReader::new(&data))
+            .unwrap();
+        assert_eq!(restored.now(), 5);
+        restored.advance(30);
+        assert_eq!(restored.pop_due().map(|event| event.kind), Some(2));
+        assert_eq!(restored.pop_due().map(|event| event.kind), Some(1));
+
+        // The events belong to a device that isn't there
+        let mut empty = Scheduler::new();
+        empty.schedule(10, a, 3);
+        assert_eq!(
+            empty.restore_state(&mut SnapshotReader::new(&data)),
+            Err(SnapshotError::DeviceMismatch)
+        );
+        assert_eq!(empty.now(), 0);
+        assert_eq!(empty.pop_due(), None);
+        assert_eq!(empty.next_cycle(), Some(10));
+    }
+}
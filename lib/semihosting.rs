@@ -0,0 +1,602 @@
+//! RISC-V semihosting, letting a program use the console and files of the host
+//!
+//! A semihosting call is an ebreak surrounded by two special nops, which the Cpu recognises once
+//! a [Semihosting] is attached with [Cpu::with_semihosting](crate::cpu::Cpu::with_semihosting):
+//!
+//! ```text
+//! slli zero, zero, 0x1f
+//! ebreak
+//! srai zero, zero, 7
+//! ```
+//!
+//! The operation is in a0 and its parameter, usually the address of a block of arguments, in a1.
+//! The result is returned in a0. The operations are the ones from the Arm semihosting
+//! specification: SYS_OPEN, SYS_CLOSE, SYS_WRITEC, SYS_WRITE0, SYS_WRITE, SYS_READ, SYS_CLOCK,
+//! SYS_EXIT and SYS_EXIT_EXTENDED. Anything else returns -1.
+//!
+//! Files can only be opened inside the directory given to [Semihosting::with_root], paths that
+//! are absolute or go through `..` are rejected. Without a root only the console, `:tt`, can be
+//! opened.
+//!
+//! SYS_EXIT stops the Cpu with [StopReason::Exit](crate::cpu::StopReason::Exit), which is how a
+//! test program reports whether it passed to a headless runner.
+//!
+//! NOTE: Host files aren't part of the machine state, so restoring a snapshot or stepping back
+//! doesn't undo what was written to them. Steps the journal replays don't call the host again,
+//! they get the results from the first time.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Cursor, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{cpu::CLOCK_FEQ, memory_mapped::MemoryMapped};
+
+/// The instruction before the ebreak of a semihosting call, `slli zero, zero, 0x1f`
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f01013;
+/// The instruction after the ebreak of a semihosting call, `srai zero, zero, 7`
+pub const SEMIHOSTING_EXIT: u32 = 0x40705013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// The reason SYS_EXIT is given when the program finished normally
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Opened to find out which extensions are supported: SYS_EXIT_EXTENDED and `:tt` opened for
+/// appending being stderr
+const FEATURES_FILE: &str = ":semihosting-features";
+const FEATURES: [u8; 5] = [b'S', b'H', b'F', b'B', 0b11];
+
+/// The value returned in a0 when an operation fails
+const FAILED: u32 = u32::MAX;
+
+/// SYS_READ reads at most this many bytes at a time, the length comes from the program
+const READ_CHUNK: usize = 4096;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+    Features(Cursor<&'static [u8]>),
+}
+
+/// What the Cpu should do after a semihosting call
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Call {
+    /// Continue after the call with the value in a0
+    Return(u32),
+    /// The program exited with the exit code
+    Exit(u32),
+}
+
+pub struct Semihosting {
+    root: Option<PathBuf>,
+    stdin: Box<dyn Read>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    /// Open handles, the handle returned to the program is the index plus one since some C
+    /// libraries treat handle 0 as an error
+    handles: Vec<Option<Handle>>,
+    exit_code: Option<u32>,
+}
+
+impl fmt::Debug for Semihosting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semihosting")
+            .field("root", &self.root)
+            .field("open_handles", &self.handles.iter().flatten().count())
+            .field("exit_code", &self.exit_code)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Semihosting {
+    /// Connects the console to the stdin, stdout and stderr of the emulator, no files can be
+    /// opened
+    pub fn new() -> Self {
+        Semihosting {
+            root: None,
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            handles: Vec::new(),
+            exit_code: None,
+        }
+    }
+
+    /// Lets the program open files inside `root`
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    pub fn with_stdin(mut self, stdin: impl Read + 'static) -> Self {
+        self.stdin = Box::new(stdin);
+        self
+    }
+
+    /// Where SYS_WRITEC, SYS_WRITE0 and writes to `:tt` go
+    pub fn with_stdout(mut self, stdout: impl Write + 'static) -> Self {
+        self.stdout = Box::new(stdout);
+        self
+    }
+
+    pub fn with_stderr(mut self, stderr: impl Write + 'static) -> Self {
+        self.stderr = Box::new(stderr);
+        self
+    }
+
+    /// The exit code the program gave SYS_EXIT, if it has exited
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Carries out the operation `op` with the parameter in a1, accessing memory through `mem`.
+    /// `cycles` is the value of mcycle, which SYS_CLOCK is based on.
+    pub(crate) fn call(
+        &mut self,
+        op: u32,
+        param: u32,
+        mem: &mut impl MemoryMapped<()>,
+        cycles: u64,
+    ) -> Call {
+        let result = match op {
+            SYS_OPEN => self.open(param, mem),
+            SYS_CLOSE => self.close(param, mem),
+            SYS_WRITEC => self.write_char(param, mem),
+            SYS_WRITE0 => self.write_string(param, mem),
+            SYS_WRITE => self.write(param, mem),
+            SYS_READ => self.read(param, mem),
+            SYS_CLOCK => Ok((cycles / (CLOCK_FEQ as u64 / 100)) as u32),
+            SYS_EXIT => {
+                let code = if param == ADP_STOPPED_APPLICATION_EXIT {
+                    0
+                } else {
+                    1
+                };
+                return self.exit(code);
+            }
+            SYS_EXIT_EXTENDED => match args::<2>(param, mem) {
+                Ok([ADP_STOPPED_APPLICATION_EXIT, code]) => return self.exit(code),
+                _ => return self.exit(1),
+            },
+            _ => Err(()),
+        };
+
+        Call::Return(result.unwrap_or(FAILED))
+    }
+
+    fn exit(&mut self, code: u32) -> Call {
+        self.exit_code = Some(code);
+        Call::Exit(code)
+    }
+
+    fn open(&mut self, param: u32, mem: &impl MemoryMapped<()>) -> Result<u32, ()> {
+        let [name, mode, len] = args(param, mem)?;
+        let name = (0..len)
+            .map(|i| mem.load_byte(name.wrapping_add(i)))
+            .collect::<Result<Vec<u8>, ()>>()?;
+        let name = String::from_utf8(name).map_err(|_| ())?;
+
+        let handle = match (name.as_str(), mode) {
+            (":tt", 0..=3) => Handle::Stdin,
+            (":tt", 4..=7) => Handle::Stdout,
+            (":tt", 8..=11) => Handle::Stderr,
+            (FEATURES_FILE, 0..=1) => Handle::Features(Cursor::new(&FEATURES)),
+            (name, 0..=11) => Handle::File(self.open_file(name, mode).map_err(|_| ())?),
+            _ => return Err(()),
+        };
+
+        let free = self.handles.iter().position(Option::is_none);
+        let index = free.unwrap_or(self.handles.len());
+        if index == self.handles.len() {
+            self.handles.push(None);
+        }
+        self.handles[index] = Some(handle);
+        Ok(index as u32 + 1)
+    }
+
+    /// Opens a file inside the root, `mode` is the index of an fopen mode in "r", "rb", "r+",
+    /// "r+b", "w", "wb", "w+", "w+b", "a", "ab", "a+" and "a+b"
+    fn open_file(&self, name: &str, mode: u32) -> io::Result<File> {
+        let sandbox_error = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        let root = self.root.as_ref().ok_or_else(sandbox_error)?;
+        let path = Path::new(name);
+        let inside_root = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside_root {
+            return Err(sandbox_error());
+        }
+
+        let update = mode & 2 != 0;
+        let mut options = OpenOptions::new();
+        match mode / 4 {
+            0 => options.read(true).write(update),
+            1 => options.write(true).create(true).truncate(true).read(update),
+            _ => options.append(true).create(true).read(update),
+        };
+        options.open(root.join(path))
+    }
+
+    fn close(&mut self, param: u32, mem: &impl MemoryMapped<()>) -> Result<u32, ()> {
+        let [handle] = args(param, mem)?;
+        let index = handle.checked_sub(1).ok_or(())?;
+        let handle = self.handles.get_mut(index as usize).ok_or(())?;
+        handle.take().ok_or(())?;
+        Ok(0)
+    }
+
+    fn write_char(&mut self, param: u32, mem: &impl MemoryMapped<()>) -> Result<u32, ()> {
+        let c = mem.load_byte(param)?;
+        self.stdout.write_all(&[c]).map_err(|_| ())?;
+        self.stdout.flush().map_err(|_| ())?;
+        Ok(0)
+    }
+
+    fn write_string(&mut self, param: u32, mem: &impl MemoryMapped<()>) -> Result<u32, ()> {
+        let mut string = Vec::new();
+        let mut addr = param;
+        loop {
+            match mem.load_byte(addr)? {
+                0 => break,
+                c => string.push(c),
+            }
+            addr = addr.wrapping_add(1);
+        }
+
+        self.stdout.write_all(&string).map_err(|_| ())?;
+        self.stdout.flush().map_err(|_| ())?;
+        Ok(0)
+    }
+
+    /// Returns the number of bytes that weren't written
+    fn write(&mut self, param: u32, mem: &impl MemoryMapped<()>) -> Result<u32, ()> {
+        let [handle, buf, len] = args(param, mem)?;
+        let data = (0..len)
+            .map(|i| mem.load_byte(buf.wrapping_add(i)))
+            .collect::<Result<Vec<u8>, ()>>()?;
+
+        let written = match self.handle(handle)? {
+            Handle::Stdout => write_console(&mut self.stdout, &data),
+            Handle::Stderr => write_console(&mut self.stderr, &data),
+            Handle::File(file) => file.write_all(&data),
+            Handle::Stdin | Handle::Features(_) => return Ok(len),
+        };
+        Ok(if written.is_ok() { 0 } else { len })
+    }
+
+    /// Returns the number of bytes that weren't read, so `len` at the end of the file. Reads in
+    /// chunks until one comes back short, so a huge `len` doesn't allocate a huge buffer.
+    fn read(&mut self, param: u32, mem: &mut impl MemoryMapped<()>) -> Result<u32, ()> {
+        let [handle, buf, len] = args(param, mem)?;
+        let mut data = [0; READ_CHUNK];
+
+        let mut total = 0;
+        while total < len {
+            let chunk = &mut data[..(len - total).min(READ_CHUNK as u32) as usize];
+            let requested = chunk.len();
+            let read = match self.handle(handle)? {
+                Handle::Stdin => self.stdin.read(chunk),
+                Handle::File(file) => file.read(chunk),
+                Handle::Features(features) => features.read(chunk),
+                Handle::Stdout | Handle::Stderr => return Err(()),
+            }
+            .map_err(|_| ())?;
+
+            for (i, byte) in data[..read].iter().enumerate() {
+                mem.store_byte(buf.wrapping_add(total + i as u32), *byte)?;
+            }
+            total += read as u32;
+            if read < requested {
+                break;
+            }
+        }
+        Ok(len - total)
+    }
+
+    fn handle(&mut self, handle: u32) -> Result<&mut Handle, ()> {
+        let index = handle.checked_sub(1).ok_or(())?;
+        match self.handles.get_mut(index as usize) {
+            Some(Some(handle)) => Ok(handle),
+            _ => Err(()),
+        }
+    }
+}
+
+fn write_console(console: &mut Box<dyn Write>, data: &[u8]) -> io::Result<()> {
+    console.write_all(data)?;
+    console.flush()
+}
+
+/// Loads the first `N` words of the argument block at `param`
+fn args<const N: usize>(param: u32, mem: &impl MemoryMapped<()>) -> Result<[u32; N], ()> {
+    let mut args = [0; N];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = mem.load_word(param.wrapping_add(4 * i as u32))?;
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        cpu::{Cpu, StopReason},
+        peripheral::SDRam,
+        register::Register,
+        test_utils::SharedBuffer,
+    };
+
+    /// Assembles `source` with a `semihost` function that makes the call in a0 with a1
+    fn new_cpu(source: &str, semihosting: Semihosting) -> Cpu<SDRam> {
+        let source = format!(
+            "{}
+            semihost:
+                slli zero, zero, 0x1f
+                ebreak
+                srai zero, zero, 7
+                ret
+            ",
+            source
+        );
+        let program = assemble(&source, 0).unwrap();
+
+        let mut cpu = Cpu::new_with_bus(SDRam::new()).with_semihosting(semihosting);
+        cpu.store_at(0, program.bytes).unwrap();
+        cpu.pc = 0;
+        cpu
+    }
+
+    #[test]
+    fn test_console_and_exit() {
+        let stdout = SharedBuffer::default();
+        let mut cpu = new_cpu(
+            "
+                li a0, 4        # SYS_WRITE0
+                li a1, 0x1000
+                call semihost
+                li a0, 3        # SYS_WRITEC
+                li a1, 0x1006
+                call semihost
+                li a0, 0x18     # SYS_EXIT
+                li a1, 0x20026  # ADP_Stopped_ApplicationExit
+                call semihost
+            end:
+                j end
+            ",
+            Semihosting::new().with_stdout(stdout.clone()),
+        );
+        cpu.store_at(0x1000, *b"hello\0\n").unwrap();
+
+        assert_eq!(cpu.run(100), StopReason::Exit { code: 0 });
+        assert_eq!(&stdout.0.borrow()[..], b"hello\n");
+        assert_eq!(cpu.semihosting().unwrap().exit_code(), Some(0));
+    }
+
+    #[test]
+    fn test_exit_extended() {
+        let mut cpu = new_cpu(
+            "
+                li a0, 0x20     # SYS_EXIT_EXTENDED
+                li a1, 0x1000
+                call semihost
+            ",
+            Semihosting::new(),
+        );
+        cpu.store_word(0x1000, ADP_STOPPED_APPLICATION_EXIT)
+            .unwrap();
+        cpu.store_word(0x1004, 42).unwrap();
+
+        assert_eq!(cpu.run(100), StopReason::Exit { code: 42 });
+    }
+
+    #[test]
+    fn test_files() {
+        let root = std::env::temp_dir().join(format!("dtekv-semihosting-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("in.txt"), b"abc").unwrap();
+
+        let mut cpu = new_cpu(
+            "
+                li a0, 1        # SYS_OPEN in.txt for reading
+                li a1, 0x1000
+                call semihost
+                mv s0, a0
+                li t0, 0x1010   # SYS_READ 8 bytes into 0x1100
+                sw s0, 0(t0)
+                li a0, 6
+                mv a1, t0
+                call semihost
+                mv s1, a0
+                li a0, 1        # SYS_OPEN out.txt for writing
+                li a1, 0x1020
+                call semihost
+                mv s2, a0
+                li t0, 0x1030   # SYS_WRITE the 3 bytes read
+                sw s2, 0(t0)
+                li a0, 5
+                mv a1, t0
+                call semihost
+                mv s3, a0
+                li t0, 0x1040   # SYS_CLOSE out.txt
+                sw s2, 0(t0)
+                li a0, 2
+                mv a1, t0
+                call semihost
+                li a0, 1        # SYS_OPEN ../escape.txt
+                li a1, 0x1050
+                call semihost
+                mv s4, a0
+            end:
+                j end
+            ",
+            Semihosting::new().with_root(&root),
+        );
+        let words = [
+            (0x1000, 0x1060), // "in.txt"
+            (0x1004, 0),      // "r"
+            (0x1008, 6),
+            (0x1014, 0x1100),
+            (0x1018, 8),
+            (0x1020, 0x1070), // "out.txt"
+            (0x1024, 4),      // "w"
+            (0x1028, 7),
+            (0x1034, 0x1100),
+            (0x1038, 3),
+            (0x1050, 0x1080), // "../escape.txt"
+            (0x1054, 4),
+            (0x1058, 13),
+        ];
+        for (addr, word) in words {
+            cpu.store_word(addr, word).unwrap();
+        }
+        cpu.store_at(0x1060, *b"in.txt").unwrap();
+        cpu.store_at(0x1070, *b"out.txt").unwrap();
+        cpu.store_at(0x1080, *b"../escape.txt").unwrap();
+
+        assert!(matches!(cpu.run(1000), StopReason::HaltLoop { .. }));
+        assert_eq!(cpu.regs.get(Register::S0), 1);
+        assert_eq!(cpu.regs.get(Register::S1), 5); // 3 of 8 bytes read
+        assert_eq!(cpu.regs.get(Register::S2), 2);
+        assert_eq!(cpu.regs.get(Register::S3), 0);
+        assert_eq!(cpu.regs.get(Register::S4), FAILED);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"abc");
+        assert!(!root.join("../escape.txt").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_huge_read() {
+        let mut cpu = new_cpu(
+            "
+                li a0, 1        # SYS_OPEN :tt for reading
+                li a1, 0x1000
+                call semihost
+                li t0, 0x1010   # SYS_READ 0xffffffff bytes into 0x1100
+                sw a0, 0(t0)
+                li a0, 6
+                mv a1, t0
+                call semihost
+                mv s0, a0
+            end:
+                j end
+            ",
+            Semihosting::new().with_stdin(Cursor::new(vec![b'x'; 5000])),
+        );
+        let words = [
+            (0x1000, 0x1060), // ":tt"
+            (0x1004, 0),      // "r"
+            (0x1008, 3),
+            (0x1014, 0x1100),
+            (0x1018, u32::MAX),
+        ];
+        for (addr, word) in words {
+            cpu.store_word(addr, word).unwrap();
+        }
+        cpu.store_at(0x1060, *b":tt").unwrap();
+
+        assert!(matches!(cpu.run(100), StopReason::HaltLoop { .. }));
+        assert_eq!(cpu.regs.get(Register::S0), u32::MAX - 5000);
+        assert_eq!(cpu.load_byte(0x1100 + 4999), Ok(b'x'));
+        assert_eq!(cpu.load_byte(0x1100 + 5000), Ok(0));
+    }
+
+    #[test]
+    fn test_clock_and_unknown_operation() {
+        let mut cpu = new_cpu(
+            "
+                li a0, 0x10     # SYS_CLOCK
+                call semihost
+                mv s0, a0
+                li a0, 0x99
+                call semihost
+                mv s1, a0
+            end:
+                j end
+            ",
+            Semihosting::new(),
+        );
+        cpu.csr.store(crate::csr::Csr::MCYCLE, CLOCK_FEQ);
+
+        cpu.run(100);
+        assert_eq!(cpu.regs.get(Register::S0), 100);
+        assert_eq!(cpu.regs.get(Register::S1), FAILED);
+    }
+
+    #[test]
+    fn test_step_back_replays_calls() {
+        let stdout = SharedBuffer::default();
+        let mut cpu = new_cpu(
+            "
+                li a0, 1        # SYS_OPEN :tt for reading
+                li a1, 0x1000
+                call semihost
+                mv s0, a0
+                li t0, 0x1010   # SYS_READ 2 bytes into 0x1100
+                sw s0, 0(t0)
+                li a0, 6
+                mv a1, t0
+                call semihost
+                mv s1, a0
+                li a0, 4        # SYS_WRITE0
+                li a1, 0x1020
+                call semihost
+            end:
+                j end
+            ",
+            Semihosting::new()
+                .with_stdin(Cursor::new(b"xy"))
+                .with_stdout(stdout.clone()),
+        );
+        let words = [(0x1000, 0x1060), (0x1004, 0), (0x1008, 3), (0x1014, 0x1100)];
+        for (addr, word) in words {
+            cpu.store_word(addr, word).unwrap();
+        }
+        cpu.store_word(0x1018, 2).unwrap();
+        cpu.store_at(0x1020, *b"hi\0").unwrap();
+        cpu.store_at(0x1060, *b":tt").unwrap();
+        // Only the last step is in the journal, the rest is replayed from the start
+        cpu.enable_journal(crate::cpu::JournalConfig {
+            max_entries: 1,
+            checkpoint_interval: 1000,
+            max_checkpoints: 1,
+        });
+
+        assert!(matches!(cpu.run(100), StopReason::HaltLoop { .. }));
+        cpu.run(1);
+        assert_eq!(cpu.step_back(2), 2);
+
+        // The calls return what they returned the first time without reaching the host again
+        assert_eq!(cpu.regs.get(Register::S1), 0);
+        assert_eq!(cpu.load_halfword(0x1100), Ok(u16::from_le_bytes(*b"xy")));
+        assert_eq!(&stdout.0.borrow()[..], b"hi");
+    }
+
+    #[test]
+    fn test_plain_ebreak() {
+        // Without the surrounding nops it's a regular breakpoint
+        let mut cpu = new_cpu("ebreak", Semihosting::new());
+        assert_eq!(cpu.step(), Some(StopReason::Ebreak { pc: 0 }));
+    }
+}
@@ -0,0 +1,193 @@
+//! Snapshots of the full machine state
+//!
+//! A snapshot is a versioned binary blob containing the registers, CSRs and pc of the Cpu and the
+//! events pending in its [scheduler](crate::scheduler), followed by the state of every peripheral
+//! on the bus. Use
+//! [Cpu::save_snapshot](crate::cpu::Cpu::save_snapshot) and
+//! [Cpu::restore_snapshot](crate::cpu::Cpu::restore_snapshot) to create and restore them.
+//!
+//! Peripherals store their state through [Peripheral::save_state](crate::peripheral::Peripheral)
+//! and read it back in the same order in
+//! [Peripheral::restore_state](crate::peripheral::Peripheral). A snapshot can only be restored
+//! into a machine with the same peripherals attached in the same order.
+//!
+//! All values are stored little endian.
+
+use std::fmt;
+
+/// The bytes every snapshot starts with
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"DTEKVSNP";
+
+/// Increased every time the format changes, snapshots from other versions are rejected
+pub const SNAPSHOT_VERSION: u32 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The data doesn't start with [SNAPSHOT_MAGIC]
+    NotSnapshot,
+    /// The snapshot was made by another version of the emulator
+    UnsupportedVersion { version: u32 },
+    /// The snapshot ended before all state was read
+    Truncated,
+    /// A value in the snapshot is out of range for the peripheral it belongs to
+    Invalid,
+    /// The snapshot was made with different peripherals attached
+    DeviceMismatch,
+    /// The snapshot was made on a hart with another [HartConfig](crate::cpu::HartConfig)
+    HartMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotSnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(
+                f,
+                "snapshot version {} is not supported, expected version {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid => write!(f, "snapshot contains an invalid value"),
+            SnapshotError::DeviceMismatch => {
+                write!(f, "snapshot was made with different peripherals attached")
+            }
+            SnapshotError::HartMismatch => {
+                write!(f, "snapshot was made on a differently configured hart")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Debug, Default, Clone)]
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        SnapshotWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes the length followed by the bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes bytes without a length, the reader has to know how many to read
+    pub(crate) fn write_raw(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SnapshotReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos.checked_add(len).ok_or(SnapshotError::Truncated)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(SnapshotError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid),
+        }
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads bytes written by [SnapshotWriter::write_bytes]
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn read_raw(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        self.take(len)
+    }
+
+    /// True once everything has been read
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u8(7);
+        writer.write_bool(true);
+        writer.write_u32(0xdeadbeef);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_bytes(b"hello");
+        let data = writer.into_bytes();
+
+        let mut reader = SnapshotReader::new(&data);
+        assert_eq!(reader.read_u8(), Ok(7));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u32(), Ok(0xdeadbeef));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.read_bytes(), Ok(&b"hello"[..]));
+        assert!(reader.is_empty());
+        assert_eq!(reader.read_u8(), Err(SnapshotError::Truncated));
+    }
+
+    #[test]
+    fn test_invalid_bool() {
+        let mut reader = SnapshotReader::new(&[2]);
+        assert_eq!(reader.read_bool(), Err(SnapshotError::Invalid));
+    }
+}
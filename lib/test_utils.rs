@@ -1,9 +1,14 @@
 //! Some utils that are used throughout testing
 
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
 
 use crate::{
-    cpu::Cpu,
+    cpu::{Cpu, HartConfig},
+    csr::Csr,
     memory_mapped::MemoryMapped,
     peripheral::{self, Peripheral},
 };
@@ -15,84 +20,70 @@ pub struct TestCpuData {
     pub hex_display: Rc<RefCell<peripheral::HexDisplay>>,
     pub led_strip: Rc<RefCell<peripheral::LEDStrip>>,
     pub switch: Rc<RefCell<peripheral::Switch>>,
+    pub timer: Rc<RefCell<peripheral::Timer>>,
     pub uart: Rc<RefCell<peripheral::UART>>,
 }
 
-macro_rules! add_bus {
-    ($bus:expr, $device:expr, $range:expr) => {{
-        let device = Rc::new(RefCell::new($device));
-        $bus.attach_device($range, Box::new(device.clone()));
-        device
-    }};
-}
-
 /// Generates a new CPU with a panic on access device
 pub fn new_panic_io_cpu() -> Cpu<PanicOnAccess> {
     Cpu::new_with_bus(PanicOnAccess::new())
 }
 
+/// Generates a new CPU with only SDRAM attached and the program stored at address 0
+pub fn new_sdram_cpu(program: &[u32]) -> Cpu<peripheral::SDRam> {
+    new_sdram_cpu_with_config(HartConfig::default(), program)
+}
+
+/// Same as [new_sdram_cpu] but with another hart configuration
+pub fn new_sdram_cpu_with_config(hart: HartConfig, program: &[u32]) -> Cpu<peripheral::SDRam> {
+    let mut cpu = Cpu::new_with_bus_and_config(peripheral::SDRam::new(), hart);
+    for (i, instr) in program.iter().enumerate() {
+        cpu.store_word(i as u32 * 4, *instr).unwrap();
+    }
+    cpu
+}
+
+/// Same as [new_sdram_cpu] but with the trap handler at 0x100, so a trap can be told apart from a
+/// jump to 0
+pub fn new_trapping_cpu(program: &[u32]) -> Cpu<peripheral::SDRam> {
+    new_trapping_cpu_with_config(HartConfig::default(), program)
+}
+
+/// Same as [new_trapping_cpu] but with another hart configuration
+pub fn new_trapping_cpu_with_config(hart: HartConfig, program: &[u32]) -> Cpu<peripheral::SDRam> {
+    let mut cpu = new_sdram_cpu_with_config(hart, program);
+    cpu.csr.store(Csr::MTVEC, 0x100);
+    cpu
+}
+
 pub fn new_io_cpu() -> TestCpuData {
     let mut bus = peripheral::Bus::new();
-    let sdram = add_bus!(
-        bus,
-        peripheral::SDRam::new(),
-        (peripheral::SDRAM_LOWER_ADDR, peripheral::SDRAM_HIGHER_ADDR)
-    );
-    let button = add_bus!(
-        bus,
-        peripheral::Button::new(),
-        (
-            peripheral::BUTTON_LOWER_ADDR,
-            peripheral::BUTTON_HIGHER_ADDR
-        )
-    );
-    let hex_display = add_bus!(
-        bus,
-        peripheral::HexDisplay::new(),
-        (
-            peripheral::HEX_DISPLAY_LOWER_ADDR,
-            peripheral::HEX_DISPLAY_HIGHER_ADDR
-        )
-    );
-    let led_strip = add_bus!(
-        bus,
-        peripheral::LEDStrip::new(),
-        (
-            peripheral::LED_STRIP_LOWER_ADDR,
-            peripheral::LED_STRIP_HIGHER_ADDR
-        )
-    );
-    let switch = add_bus!(
-        bus,
-        peripheral::Switch::new(),
-        (
-            peripheral::SWITCH_LOWER_ADDR,
-            peripheral::SWITCH_HIGHER_ADDR
-        )
-    );
-    let uart = add_bus!(
-        bus,
-        peripheral::UART::new(),
-        (peripheral::UART_LOWER_ADDR, peripheral::UART_HIGHER_ADDR)
-    );
+    let board = peripheral::Board::attach(&mut bus);
 
     // Final, if we're out of bounds we panic
-    add_bus!(bus, PanicOnAccess::new(), (0x0, 0xFFFFFFFF));
+    bus.attach_device((0x0, 0xFFFFFFFF), Box::new(PanicOnAccess::new()));
 
     TestCpuData {
         cpu: Cpu::new_with_bus(bus),
-        sdram,
-        button,
-        hex_display,
-        led_strip,
-        switch,
-        uart,
+        sdram: board.sdram,
+        button: board.button,
+        hex_display: board.hex_display,
+        led_strip: board.led_strip,
+        switch: board.switch,
+        timer: board.timer,
+        uart: board.uart,
     }
 }
 
 #[derive(Clone)]
 pub struct PanicOnAccess {}
 
+impl Default for PanicOnAccess {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PanicOnAccess {
     /// Returns a new Memory object with a given size all set to 0
     pub fn new() -> Self {
@@ -120,3 +111,18 @@ impl std::fmt::Debug for PanicOnAccess {
         write!(f, "PanicOnAccess {{ ... }}")
     }
 }
+
+/// Output that can still be read after it has been handed to a tracer or a console
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
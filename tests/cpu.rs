@@ -41,8 +41,7 @@ fn test_factorial_riscv_program() {
         0x00000063, // beqz zero,2c <end>
     ]
     .into_iter()
-    .map(|x: u32| u32::to_le_bytes(x))
-    .flatten()
+    .flat_map(u32::to_le_bytes)
     .collect();
 
     cpu.store_at(0, bin).unwrap();
@@ -53,6 +52,41 @@ fn test_factorial_riscv_program() {
     assert_eq!(cpu.regs.get(register::Register::T2), 40320);
 }
 
+#[test]
+fn test_factorial_assembled() {
+    // The factorial program above, assembled from source instead
+
+    let mut cpu = new_cpu();
+    let program = assembler::assemble(
+        "
+            li t0, 8
+            li t2, 1
+        factorial_loop:
+            beqz t0, end
+            addi t0, t0, -1
+            mv t1, t0
+            mv tp, t2
+        mul_loop:
+            beqz t1, mul_end
+            add t2, t2, tp
+            addi t1, t1, -1
+            beqz zero, mul_loop
+        mul_end:
+            beqz zero, factorial_loop
+        end:
+            beqz zero, end
+        ",
+        0,
+    )
+    .unwrap();
+
+    cpu.store_at(0, program.bytes).unwrap();
+    for _ in 0..200 {
+        cpu.clock();
+    }
+    assert_eq!(cpu.regs.get(register::Register::T2), 40320);
+}
+
 #[test]
 fn test_sieves_c_program() {
     let debug_console = Rc::new(RefCell::new(DebugConsole::new()));
@@ -147,8 +181,7 @@ fn writing_and_running() {
         0x0000006f, // j	40 <end>
     ]
     .into_iter()
-    .map(|x| u32::to_le_bytes(x))
-    .flatten()
+    .flat_map(u32::to_le_bytes)
     .collect();
 
     let mut cpu = new_cpu();
@@ -163,3 +196,43 @@ fn writing_and_running() {
 
     assert_eq!(cpu.regs.get(register::Register::T0), 2);
 }
+
+#[test]
+fn writing_and_running_assembled() {
+    // The program above, assembled from source instead
+
+    let program = assembler::assemble(
+        "
+        _start:
+            nop
+            nop
+            li t0, 0x00200293   # li t0, 2
+            li t1, 256
+            sw t0, 0(t1)
+            li t0, 0x04000e13   # li t3, 64
+            li t1, 260
+            sw t0, 0(t1)
+            li t0, 0x000e0367   # jalr t1, 0(t3)
+            li t1, 264
+            sw t0, 0(t1)
+            li t1, 256
+            jalr t5, 0(t1)
+        end:
+            j end
+        ",
+        0,
+    )
+    .unwrap();
+
+    let mut cpu = new_cpu();
+    cpu.reset();
+
+    cpu.store_at(0, program.bytes).unwrap();
+    cpu.generate_instruction_cache();
+
+    for _ in 0..1000 {
+        cpu.clock();
+    }
+
+    assert_eq!(cpu.regs.get(register::Register::T0), 2);
+}
@@ -86,3 +86,160 @@ fn test_switch_display() {
     }
     assert_eq!(cpu.regs.get(register::Register::T0), 5);
 }
+
+#[test]
+fn test_io_assembled() {
+    // The hex display and switch programs above, assembled from source instead
+    let mut bus = peripheral::Bus::new();
+    let board = peripheral::Board::attach(&mut bus);
+    let mut cpu = cpu::Cpu::new_with_bus(bus);
+    let program = assembler::assemble(
+        "
+            li t0, 144
+            li t1, 0x4000050
+            sw t0, 0(t1)
+            li t1, 0x4000010
+            lw t0, 0(t1)
+        end:
+            j end
+        ",
+        0,
+    )
+    .unwrap();
+    cpu.bus.store_at(0, program.bytes).unwrap();
+
+    {
+        let mut switch = board.switch.borrow_mut();
+        switch.set(0, true);
+        switch.set(2, true);
+    }
+    for _ in 0..10 {
+        cpu.clock();
+    }
+    assert_eq!(board.hex_display.borrow().get(0), 144);
+    assert_eq!(cpu.regs.get(register::Register::T0), 5);
+}
+
+#[test]
+fn test_uart_echo() {
+    // Program that echoes everything typed into the UART back, waiting for a read interrupt
+    // between characters
+    let mut bus = peripheral::Bus::new();
+    let board = peripheral::Board::attach(&mut bus);
+    let mut cpu = cpu::Cpu::new_with_bus(bus);
+    let program = assembler::assemble(
+        "
+            li t0, 0x4000040
+            li t1, 1
+            sw t1, 4(t0)
+            li t1, 0x80000
+            csrw mie, t1
+        wait:
+            wfi
+            lw t1, 0(t0)
+            srli t2, t1, 15
+            andi t2, t2, 1
+            beqz t2, wait
+            sb t1, 0(t0)
+            j wait
+        ",
+        0,
+    )
+    .unwrap();
+    cpu.bus.store_at(0, program.bytes).unwrap();
+
+    for _ in 0..50 {
+        cpu.clock();
+    }
+    assert_eq!(board.uart.borrow_mut().next(), None);
+
+    for byte in b"hi" {
+        assert!(board.uart.borrow_mut().push_input(*byte));
+    }
+    for _ in 0..50 {
+        cpu.clock();
+    }
+    assert_eq!(board.uart.borrow_mut().by_ref().collect::<Vec<_>>(), b"hi");
+}
+
+#[test]
+fn test_uart_lines() {
+    // Program that prints "hå" and a newline, the å as two UTF-8 bytes
+    let mut bus = peripheral::Bus::new();
+    let board = peripheral::Board::attach(&mut bus);
+    let lines = Rc::new(RefCell::new(peripheral::UartLines::new()));
+    board.uart.borrow_mut().set_sink(lines.clone());
+    let mut cpu = cpu::Cpu::new_with_bus(bus);
+    let program = assembler::assemble(
+        "
+            li t0, 0x4000040
+            li t1, 104
+            sb t1, 0(t0)
+            li t1, 0xC3
+            sb t1, 0(t0)
+            li t1, 0xA5
+            sb t1, 0(t0)
+            li t1, 10
+            sb t1, 0(t0)
+        end:
+            j end
+        ",
+        0,
+    )
+    .unwrap();
+    cpu.bus.store_at(0, program.bytes).unwrap();
+
+    for _ in 0..12 {
+        cpu.clock();
+    }
+    let line = lines.borrow_mut().pop_line().unwrap();
+    assert_eq!(line.text, "hå");
+    // Stamped with the cycle the store of the first byte started at
+    assert!(line.cycle > 0 && line.cycle < cpu.cycles());
+}
+
+#[test]
+fn test_timer_counts_cycles() {
+    // Program that starts a one-shot timer with a period of 99 and polls it until it times out,
+    // noting the cycle count before the start and after the timeout
+    let program = assembler::assemble(
+        "
+            li t0, 0x4000020
+            li t1, 99
+            sw t1, 8(t0)
+            sw zero, 12(t0)
+            li t1, 4
+            csrr a1, mcycle
+            sw t1, 4(t0)
+        wait:
+            lw t1, 0(t0)
+            andi t1, t1, 1
+            beqz t1, wait
+            csrr a0, mcycle
+        end:
+            j end
+        ",
+        0,
+    )
+    .unwrap();
+
+    let run = || {
+        let mut bus = peripheral::Bus::new();
+        peripheral::Board::attach(&mut bus);
+        let mut cpu = cpu::Cpu::new_with_bus(bus);
+        cpu.bus.store_at(0, program.bytes.clone()).unwrap();
+        for _ in 0..200 {
+            cpu.clock();
+        }
+        (
+            cpu.regs.get(register::Register::A0),
+            cpu.regs.get(register::Register::A1),
+        )
+    };
+
+    let (timeout, start) = run();
+    // 100 cycles, and at most one more pass through the loop to notice
+    assert!(timeout - start >= 100, "{} {}", timeout, start);
+    assert!(timeout - start < 100 + 20, "{} {}", timeout, start);
+    assert_eq!(run(), (timeout, start));
+}
@@ -0,0 +1,162 @@
+/// Test the dtekv-run binary end to end on small programs
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use dtekv_emulator_core::assembler;
+
+/// Assembles the program into a raw binary and runs it with the arguments
+fn run(name: &str, program: &str, args: &[&str]) -> Output {
+    let program = assembler::assemble(program, 0).unwrap();
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("dtekv-run-{}-{}.bin", name, std::process::id()));
+    std::fs::write(&path, program.bytes).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_dtekv-run"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn test_output() {
+    let output = run(
+        "output",
+        "
+            li t0, 0x4000040
+            li t1, 104
+            sw t1, 0(t0)
+            li t1, 105
+            sw t1, 0(t0)
+            li t0, 0x4000050
+            li t1, 0x12
+            sw t1, 0(t0)
+            li t0, 0x4000000
+            li t1, 0b101
+            sw t1, 0(t0)
+        end:
+            j end
+        ",
+        &[],
+    );
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi");
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("hex: 12 "), "{}", report);
+    assert!(report.contains("leds: 0000000101"), "{}", report);
+}
+
+#[test]
+fn test_scripted_switch() {
+    // Waits for switch 3 and shows the switches on the LEDs
+    let output = run(
+        "switch",
+        "
+            li t0, 0x4000010
+        wait:
+            lw t1, 0(t0)
+            beqz t1, wait
+            li t0, 0x4000000
+            sw t1, 0(t0)
+        end:
+            j end
+        ",
+        &["--switch", "1000:3=1", "--max-cycles", "100000"],
+    );
+
+    assert_eq!(output.status.code(), Some(0));
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("leds: 0000001000"), "{}", report);
+}
+
+#[test]
+fn test_timer_wakes_wfi() {
+    // Waits for a timer interrupt before writing to the hex display
+    let output = run(
+        "timer",
+        "
+            li t0, 0x4000020
+            li t1, 1000
+            sw t1, 8(t0)
+            sw zero, 12(t0)
+            li t1, 0b101    # ITO and START
+            sw t1, 4(t0)
+            li t1, 0x10000
+            csrw mie, t1
+            wfi
+            li t0, 0x4000050
+            li t1, 0x34
+            sw t1, 0(t0)
+        end:
+            j end
+        ",
+        &["--max-cycles", "100000"],
+    );
+
+    assert_eq!(output.status.code(), Some(0));
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("hex: 34 "), "{}", report);
+}
+
+#[test]
+fn test_illegal_instruction() {
+    // Runs into the zeroed memory after the program
+    let output = run("illegal", "nop", &[]);
+    assert_eq!(output.status.code(), Some(132));
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        report.contains("illegal instruction at pc 0x00000004"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn test_timeout() {
+    let program = "
+        loop:
+            addi t0, t0, 1
+            j loop
+        ";
+
+    let output = run("instructions", program, &["--max-instructions", "100"]);
+    assert_eq!(output.status.code(), Some(124));
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("instructions: 100"), "{}", report);
+
+    let output = run("cycles", program, &["--max-cycles", "1000"]);
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn test_semihosting_exit() {
+    // SYS_EXIT with a reason other than ADP_Stopped_ApplicationExit
+    let output = run(
+        "exit",
+        "
+            li a0, 0x18
+            li a1, 0
+            slli zero, zero, 0x1f
+            ebreak
+            srai zero, zero, 7
+        ",
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8_lossy(&output.stderr);
+    assert!(report.contains("exited with code 1"), "{}", report);
+}
+
+#[test]
+fn test_usage_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_dtekv-run"))
+        .arg("--max-cycles")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}